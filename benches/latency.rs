@@ -0,0 +1,157 @@
+//! Latency of the enumeration, of opening the devices and of the challenge-response round-trips.
+//!
+//! The round-trips are measured against a simulated device, to catch the regressions of the protocol and
+//! transport layers. With the `bench-hardware` feature, the enumeration, the opening of the devices and the
+//! round-trips are also measured on the first connected device, with the compiled-in backend:
+//!
+//! ```text
+//! cargo bench --features bench-hardware
+//! cargo bench --no-default-features --features nusb,bench-hardware
+//! ```
+//!
+//! At least one device with a touchless HMAC-SHA1 challenge-response in slot 2 must be plugged in.
+extern crate challenge_response;
+extern crate criterion;
+extern crate hmac;
+extern crate sha1;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use challenge_response::config::Slot;
+use challenge_response::error::ChallengeResponseError;
+use challenge_response::protocol::{
+    crc16, Command, Flags, FRAME_SIZE, PAYLOAD_SIZE, REPORT_SIZE, WRITE_RESET_PAYLOAD,
+};
+use challenge_response::transport::{Transport, TransportDevice};
+
+const REPORT_DATA_SIZE: usize = REPORT_SIZE - 1;
+const SERIAL: u32 = 1234567;
+const SECRET: &[u8] = b"challenge-response benchmarks";
+
+/// A device answering the serial number and HMAC-SHA1 commands, like a YubiKey with both slots configured
+/// without touch.
+struct SimulatedDevice {
+    frame: [u8; FRAME_SIZE],
+    response: Vec<u8>,
+    next_report: Option<usize>,
+}
+
+impl SimulatedDevice {
+    fn new() -> Self {
+        SimulatedDevice {
+            frame: [0; FRAME_SIZE],
+            response: Vec::new(),
+            next_report: None,
+        }
+    }
+
+    fn respond(&mut self, data: &[u8]) {
+        let crc = 0xffff - crc16(data);
+        self.response = data.to_vec();
+        self.response.extend_from_slice(&crc.to_le_bytes());
+        self.next_report = Some(0);
+    }
+
+    fn process_frame(&mut self) {
+        let command = self.frame[PAYLOAD_SIZE];
+        if command == Command::DeviceSerial as u8 {
+            self.respond(&SERIAL.to_be_bytes());
+        } else if command == Command::ChallengeHmac1 as u8 || command == Command::ChallengeHmac2 as u8 {
+            let mut mac = Hmac::<Sha1>::new_from_slice(SECRET).unwrap();
+            mac.update(&self.frame[..PAYLOAD_SIZE]);
+            self.respond(&mac.finalize().into_bytes());
+        }
+        self.frame = [0; FRAME_SIZE];
+    }
+}
+
+impl Transport for SimulatedDevice {
+    fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, ChallengeResponseError> {
+        *report = [0; REPORT_SIZE];
+        if let Some(i) = self.next_report {
+            let chunks = self.response.len().div_ceil(REPORT_DATA_SIZE);
+            if i < chunks {
+                let data = &self.response[i * REPORT_DATA_SIZE..];
+                let len = data.len().min(REPORT_DATA_SIZE);
+                report[..len].copy_from_slice(&data[..len]);
+                report[REPORT_DATA_SIZE] = Flags::RESP_PENDING_FLAG.bits() | i as u8;
+                self.next_report = Some(i + 1);
+            } else {
+                // The sequence number wraps back to 0 after the last report of the response.
+                report[REPORT_DATA_SIZE] = Flags::RESP_PENDING_FLAG.bits();
+                self.next_report = None;
+            }
+        }
+        Ok(REPORT_SIZE)
+    }
+
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), ChallengeResponseError> {
+        if *report == WRITE_RESET_PAYLOAD {
+            self.next_report = None;
+            return Ok(());
+        }
+        let seq = (report[REPORT_DATA_SIZE] & !Flags::SLOT_WRITE_FLAG.bits()) as usize;
+        let offset = seq * REPORT_DATA_SIZE;
+        self.frame[offset..offset + REPORT_DATA_SIZE].copy_from_slice(&report[..REPORT_DATA_SIZE]);
+        if offset + REPORT_DATA_SIZE >= FRAME_SIZE {
+            self.process_frame();
+        }
+        Ok(())
+    }
+}
+
+fn simulated_device(c: &mut Criterion) {
+    let mut device = TransportDevice::new(SimulatedDevice::new());
+    let mut group = c.benchmark_group("simulated");
+    group.bench_function("read_serial_number", |b| {
+        b.iter(|| device.read_serial_number().unwrap())
+    });
+    group.bench_function("challenge_response_hmac", |b| {
+        b.iter(|| {
+            device
+                .challenge_response_hmac(b"benchmark", &Slot::Slot2, true)
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+#[cfg(feature = "bench-hardware")]
+fn hardware(c: &mut Criterion) {
+    use challenge_response::builder::BackendKind;
+    use challenge_response::config::Config;
+    use challenge_response::ChallengeResponse;
+
+    let mut cr = ChallengeResponse::new().unwrap();
+    let device = match cr.find_device() {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("Skipping the hardware benchmarks: {}", e);
+            return;
+        }
+    };
+
+    let mut group = c.benchmark_group(format!("hardware/{}", BackendKind::compiled().name()));
+    group.bench_function("find_all_devices", |b| b.iter(|| cr.find_all_devices().unwrap()));
+    // Reading the status opens the device and claims its interfaces, for a single exchange.
+    group.bench_function("open_read_status", |b| {
+        b.iter(|| cr.read_status(&device).unwrap())
+    });
+    group.bench_function("challenge_response_hmac", |b| {
+        b.iter(|| {
+            let conf = Config::new_from(device.clone())
+                .set_slot(Slot::Slot2)
+                .set_variable_size(true);
+            cr.challenge_response_hmac(b"benchmark", conf).unwrap()
+        })
+    });
+    group.finish();
+}
+
+#[cfg(not(feature = "bench-hardware"))]
+criterion_group!(benches, simulated_device);
+#[cfg(feature = "bench-hardware")]
+criterion_group!(benches, simulated_device, hardware);
+criterion_main!(benches);
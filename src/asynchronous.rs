@@ -0,0 +1,224 @@
+//! An async API, for services running on an async runtime such as tokio.
+//!
+//! The USB transfers and the wait for the user to touch the device are blocking, so `AsyncChallengeResponse`
+//! runs them on a worker thread owning a `ChallengeResponse`, and returns futures completed by the worker.
+//! The tasks awaiting them never block the threads of the runtime, and the futures do not depend on any
+//! particular runtime. The worker is used with both USB backends: the async transfers of nusb would still
+//! need a timer between two polls of the device while it waits for a touch, which only the runtime has.
+//!
+//! ```edition2018,no_run
+//! use challenge_response::asynchronous::AsyncChallengeResponse;
+//! use challenge_response::config::Config;
+//! use challenge_response::error::ChallengeResponseError;
+//! use challenge_response::hmacmode::Hmac;
+//!
+//! async fn respond(cr: &AsyncChallengeResponse) -> Result<Hmac, ChallengeResponseError> {
+//!     let devices = cr.find_all_devices().await?;
+//!     let conf = Config::new_from(devices[0].clone());
+//!     cr.challenge_response_hmac(b"my challenge", conf).await
+//! }
+//! ```
+//!
+//! The operations are run one after the other, in the order they were started. Dropping a future does not
+//! cancel its operation, which still completes on the worker; the worker stops once the
+//! `AsyncChallengeResponse` is dropped and the pending operations are done.
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use config::Config;
+#[cfg(feature = "configuration")]
+use configure::DeviceModeConfig;
+use error::ChallengeResponseError;
+use hmacmode::Hmac;
+use {ChallengeResponse, Device};
+
+type Job = Box<dyn FnOnce(&mut ChallengeResponse) + Send>;
+
+/// The error of the operations which could not complete because the worker thread stopped, after a
+/// panic of an operation.
+fn worker_stopped() -> ChallengeResponseError {
+    ChallengeResponseError::IOError(io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "the worker thread stopped",
+    ))
+}
+
+struct Shared<T> {
+    result: Option<Result<T, ChallengeResponseError>>,
+    /// Set when the worker is done with the operation, whether it completed or not.
+    done: bool,
+    waker: Option<Waker>,
+}
+
+/// The future of an operation started by `AsyncChallengeResponse`.
+pub struct Operation<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Future for Operation<T> {
+    type Output = Result<T, ChallengeResponseError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(result) = shared.result.take() {
+            return Poll::Ready(result);
+        }
+        if shared.done {
+            return Poll::Ready(Err(worker_stopped()));
+        }
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Completes an `Operation` from the worker. The operation fails if it is dropped without completing it.
+struct Completer<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Completer<T> {
+    fn complete(self, result: Result<T, ChallengeResponseError>) {
+        self.shared.lock().unwrap_or_else(PoisonError::into_inner).result = Some(result);
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap_or_else(PoisonError::into_inner);
+        shared.done = true;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+fn operation<T>() -> (Operation<T>, Completer<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        result: None,
+        done: false,
+        waker: None,
+    }));
+    (
+        Operation {
+            shared: shared.clone(),
+        },
+        Completer { shared },
+    )
+}
+
+/// The async equivalent of `ChallengeResponse`, see the module documentation.
+pub struct AsyncChallengeResponse {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl AsyncChallengeResponse {
+    /// Starts a worker with a `ChallengeResponse` created by `ChallengeResponse::new`.
+    pub fn new() -> Result<Self, ChallengeResponseError> {
+        AsyncChallengeResponse::with(ChallengeResponse::new)
+    }
+
+    /// Starts a worker with the `ChallengeResponse` returned by `create`, e.g. one configured with
+    /// `ChallengeResponse::builder`. It is created on the worker thread, as the touch prompts and the
+    /// observers of a `ChallengeResponse` can not be sent to another thread. Waits for the creation, and
+    /// returns its error.
+    pub fn with<F>(create: F) -> Result<Self, ChallengeResponseError>
+    where
+        F: FnOnce() -> Result<ChallengeResponse, ChallengeResponseError> + Send + 'static,
+    {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let (created, creation) = mpsc::channel();
+        thread::Builder::new()
+            .name("challenge-response".to_string())
+            .spawn(move || {
+                let mut cr = match create() {
+                    Ok(cr) => cr,
+                    Err(e) => {
+                        let _ = created.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = created.send(Ok(()));
+                for job in receiver {
+                    job(&mut cr);
+                }
+            })?;
+        creation.recv().map_err(|_| worker_stopped())??;
+        Ok(AsyncChallengeResponse { jobs })
+    }
+
+    /// Runs `operation` on the worker, for the methods of `ChallengeResponse` without an async equivalent.
+    pub fn run<F, T>(&self, operation: F) -> Operation<T>
+    where
+        F: FnOnce(&mut ChallengeResponse) -> Result<T, ChallengeResponseError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (future, completer) = self::operation();
+        // If the worker stopped, the job is dropped with its completer, and the operation fails.
+        let _ = self
+            .jobs
+            .send(Box::new(move |cr| completer.complete(operation(cr))));
+        future
+    }
+
+    /// See `ChallengeResponse::find_all_devices`.
+    pub fn find_all_devices(&self) -> Operation<Vec<Device>> {
+        self.run(|cr| cr.find_all_devices())
+    }
+
+    /// See `ChallengeResponse::challenge_response_hmac`. The future completes once the device responded,
+    /// including after the user touched it.
+    pub fn challenge_response_hmac(&self, chall: &[u8], conf: Config) -> Operation<Hmac> {
+        let chall = chall.to_vec();
+        self.run(move |cr| cr.challenge_response_hmac(&chall, conf))
+    }
+
+    /// See `ChallengeResponse::write_config`.
+    #[cfg(feature = "configuration")]
+    pub fn write_config(&self, conf: Config, mut device_config: DeviceModeConfig) -> Operation<()> {
+        self.run(move |cr| cr.write_config(conf, &mut device_config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::Wake;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = Pin::new(&mut future).poll(&mut context) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn test_operation() {
+        let (future, completer) = operation();
+        let worker = thread::spawn(move || completer.complete(Ok(42)));
+        assert_eq!(block_on(future).unwrap(), 42);
+        worker.join().unwrap();
+
+        let (future, completer) = operation::<u32>();
+        thread::spawn(move || drop(completer));
+        assert!(matches!(
+            block_on(future),
+            Err(ChallengeResponseError::IOError(_))
+        ));
+    }
+}
@@ -0,0 +1,102 @@
+//! An audit trail of the operations changing the configuration of the devices.
+//!
+//! An `AuditSink` registered with `ChallengeResponse::set_audit_sink` is called after every write of a slot
+//! configuration, swap of the slots, deletion of a slot and change of the device mode, whether it succeeded
+//! or not. Unlike an `Observer`, it is not notified of the challenge-responses and the other read-only
+//! operations.
+use std::time::SystemTime;
+
+use configure::DeviceModeConfig;
+use error::ChallengeResponseError;
+use protocol::Command;
+use {Device, Slot};
+
+/// The operations changing the configuration of a device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOperation {
+    /// A slot was programmed with a new configuration.
+    WriteSlot,
+    /// The configuration of a slot was updated without changing its secret.
+    UpdateSlot,
+    /// The configurations of the two slots were swapped.
+    SwapSlots,
+    /// The configuration of a slot was deleted, by writing an empty configuration.
+    DeleteSlot,
+    /// The USB mode of the device was changed.
+    SetDeviceMode,
+}
+
+impl AuditOperation {
+    /// The operation performed by writing `device_config` with `command`, or `None` if the command does
+    /// not change the configuration of the device.
+    pub fn from_command(command: Command, device_config: &DeviceModeConfig) -> Option<AuditOperation> {
+        match command {
+            Command::Configuration1 | Command::Configuration2 => {
+                if device_config.is_empty() {
+                    Some(AuditOperation::DeleteSlot)
+                } else {
+                    Some(AuditOperation::WriteSlot)
+                }
+            }
+            Command::Update1 | Command::Update2 => Some(AuditOperation::UpdateSlot),
+            Command::Swap => Some(AuditOperation::SwapSlots),
+            Command::DeviceConfig => Some(AuditOperation::SetDeviceMode),
+            _ => None,
+        }
+    }
+}
+
+/// An operation which changed, or attempted to change, the configuration of a device.
+#[derive(Debug)]
+pub struct AuditEvent<'a> {
+    pub operation: AuditOperation,
+    pub device: &'a Device,
+    /// The serial number of the device, if it is known.
+    pub serial: Option<u32>,
+    /// The slot the operation applies to. Swaps and mode changes apply to the whole device, and report the
+    /// slot of their `Config`.
+    pub slot: &'a Slot,
+    /// When the operation completed.
+    pub timestamp: SystemTime,
+    /// The error of the operation, if it failed.
+    pub error: Option<&'a ChallengeResponseError>,
+}
+
+pub trait AuditSink {
+    /// Called when an operation changing the configuration of a device completes, successfully or not.
+    fn record(&self, event: &AuditEvent);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use configure::ConfigFlags;
+
+    #[test]
+    fn test_audit_operation() {
+        let mut device_config = DeviceModeConfig::default();
+        assert_eq!(
+            AuditOperation::from_command(Command::Configuration2, &device_config),
+            Some(AuditOperation::DeleteSlot)
+        );
+        // The CRC is set when the configuration is written, and must not hide a deletion.
+        device_config.to_frame(Command::Configuration2);
+        assert_eq!(
+            AuditOperation::from_command(Command::Configuration2, &device_config),
+            Some(AuditOperation::DeleteSlot)
+        );
+        device_config.cfg_flags = ConfigFlags::CHAL_HMAC;
+        assert_eq!(
+            AuditOperation::from_command(Command::Configuration1, &device_config),
+            Some(AuditOperation::WriteSlot)
+        );
+        assert_eq!(
+            AuditOperation::from_command(Command::Swap, &device_config),
+            Some(AuditOperation::SwapSlots)
+        );
+        assert_eq!(
+            AuditOperation::from_command(Command::ChallengeHmac2, &device_config),
+            None
+        );
+    }
+}
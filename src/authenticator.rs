@@ -0,0 +1,324 @@
+//! A minimal authenticator, keeping TOTP and HOTP secrets in a vault file unlocked with the HMAC-SHA1
+//! response of a device, and generating their codes on demand.
+//!
+//! The accounts are stored as the secret of a `vault::Vault`, so the file can only be decrypted with the
+//! device it was created with. Each account is a line of the decrypted secret:
+//!
+//! ```text
+//! totp <digits> <period> <hex secret> <name>
+//! hotp <digits> <counter> <hex secret> <name>
+//! ```
+//!
+//! The HOTP counters are incremented when a code is generated, so `Authenticator::save` must be called
+//! afterwards, or the same code will be generated again. Each save wraps the accounts with the response to
+//! a new random challenge, and replaces the file atomically.
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use rand_core::CryptoRng;
+
+use clock::{Clock, SystemClock};
+use config::{Config, Slot};
+use error::ChallengeResponseError;
+use hmacmode::{HmacKey, HMAC_SECRET_SIZE};
+use validation::hotp;
+use vault::Vault;
+use wrap::{unwrap_key, wrap_key};
+use ChallengeResponse;
+
+/// How the codes of an account are generated.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OtpKind {
+    /// TOTP (RFC 6238), with the time step in seconds, usually 30.
+    Totp { period: u64 },
+    /// HOTP (RFC 4226), with the counter of the next code.
+    Hotp { counter: u64 },
+}
+
+/// An account of the authenticator.
+pub struct Account {
+    /// The name of the account, e.g. `Example:alice@example.com`. It must not contain a newline.
+    pub name: String,
+    /// The secret, padded with zeroes to 20 bytes, which does not change the codes.
+    pub secret: HmacKey,
+    /// The number of digits of the codes, 6 or 8.
+    pub digits: u8,
+    pub kind: OtpKind,
+}
+
+impl Account {
+    /// A TOTP account with 6 digits codes and a period of 30 seconds, the default of most services.
+    pub fn totp(name: &str, secret: &[u8]) -> Result<Account, ChallengeResponseError> {
+        Account::new(name, secret, 6, OtpKind::Totp { period: 30 })
+    }
+
+    /// An account with any parameters. The secret must have at most 20 bytes, as the secrets of
+    /// `otpauth::HotpUri`.
+    pub fn new(
+        name: &str,
+        secret: &[u8],
+        digits: u8,
+        kind: OtpKind,
+    ) -> Result<Account, ChallengeResponseError> {
+        if name.is_empty()
+            || name.contains('\n')
+            || secret.is_empty()
+            || secret.len() > HMAC_SECRET_SIZE
+            || (digits != 6 && digits != 8)
+            || kind == (OtpKind::Totp { period: 0 })
+        {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let mut key = HmacKey([0; HMAC_SECRET_SIZE]);
+        key.0[..secret.len()].copy_from_slice(secret);
+        Ok(Account {
+            name: name.to_string(),
+            secret: key,
+            digits,
+            kind,
+        })
+    }
+
+    /// Generates the code of the account at the UNIX time `timestamp`, for TOTP accounts, or for the
+    /// current counter, which is then incremented, for HOTP accounts.
+    pub fn code_at(&mut self, timestamp: u64) -> u32 {
+        match self.kind {
+            OtpKind::Totp { period } => hotp(&self.secret, timestamp / period, self.digits),
+            OtpKind::Hotp { ref mut counter } => {
+                let code = hotp(&self.secret, *counter, self.digits);
+                *counter += 1;
+                code
+            }
+        }
+    }
+
+    fn write_line(&self, out: &mut String) {
+        let (kind, parameter) = match self.kind {
+            OtpKind::Totp { period } => ("totp", period),
+            OtpKind::Hotp { counter } => ("hotp", counter),
+        };
+        let _ = write!(out, "{} {} {} ", kind, self.digits, parameter);
+        for b in self.secret.0.iter() {
+            let _ = write!(out, "{:02x}", b);
+        }
+        let _ = writeln!(out, " {}", self.name);
+    }
+
+    fn parse_line(line: &str) -> Result<Account, ChallengeResponseError> {
+        let mut fields = line.splitn(5, ' ');
+        let mut next = || fields.next().ok_or(ChallengeResponseError::InvalidFormat);
+        let kind = next()?;
+        let digits = next()?
+            .parse()
+            .map_err(|_| ChallengeResponseError::InvalidFormat)?;
+        let parameter = next()?
+            .parse()
+            .map_err(|_| ChallengeResponseError::InvalidFormat)?;
+        let secret = next()?;
+        let name = next()?;
+
+        let kind = match kind {
+            "totp" => OtpKind::Totp { period: parameter },
+            "hotp" => OtpKind::Hotp { counter: parameter },
+            _ => return Err(ChallengeResponseError::InvalidFormat),
+        };
+        if secret.len() != 2 * HMAC_SECRET_SIZE || !secret.is_ascii() {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let mut key = [0; HMAC_SECRET_SIZE];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&secret[2 * i..2 * i + 2], 16)
+                .map_err(|_| ChallengeResponseError::InvalidFormat)?;
+        }
+        Account::new(name, &key, digits, kind)
+    }
+}
+
+/// The accounts of an authenticator file, with the device and slot which unlock it.
+pub struct Authenticator {
+    path: PathBuf,
+    serial: Option<u32>,
+    slot: Slot,
+    variable: bool,
+    accounts: Vec<Account>,
+}
+
+impl Authenticator {
+    /// Creates a new authenticator file without any account, unlocked with the device and slot targeted by
+    /// `conf`. The file must not exist yet.
+    pub fn create<P: AsRef<Path>, R: CryptoRng + ?Sized>(
+        cr: &mut ChallengeResponse,
+        conf: Config,
+        path: P,
+        rng: &mut R,
+    ) -> Result<Authenticator, ChallengeResponseError> {
+        let vault = Vault::create(cr, conf, &path, b"", rng)?;
+        Ok(Authenticator {
+            path: path.as_ref().to_path_buf(),
+            serial: vault.serial,
+            slot: vault.wrapped.slot.clone(),
+            variable: vault.wrapped.variable,
+            accounts: Vec::new(),
+        })
+    }
+
+    /// Reads and decrypts an authenticator file written with `create` or `save`, with the device whose
+    /// serial number is stored in the file, or the first device found if it is not.
+    pub fn unlock<P: AsRef<Path>>(
+        cr: &mut ChallengeResponse,
+        path: P,
+    ) -> Result<Authenticator, ChallengeResponseError> {
+        let vault = Vault::from_bytes(&fs::read(&path)?)?;
+        let device = match vault.serial {
+            Some(serial) => cr.find_device_from_serial(serial)?,
+            None => cr.find_device()?,
+        };
+        let secret = unwrap_key(cr, Config::new_from(device), &vault.wrapped)?;
+        let accounts = std::str::from_utf8(&secret)
+            .map_err(|_| ChallengeResponseError::InvalidFormat)?
+            .lines()
+            .map(Account::parse_line)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Authenticator {
+            path: path.as_ref().to_path_buf(),
+            serial: vault.serial,
+            slot: vault.wrapped.slot,
+            variable: vault.wrapped.variable,
+            accounts,
+        })
+    }
+
+    pub fn accounts(&self) -> &[Account] {
+        &self.accounts
+    }
+
+    /// Adds an account, replacing the account with the same name if there is one.
+    pub fn add(&mut self, account: Account) {
+        self.remove(&account.name);
+        self.accounts.push(account);
+    }
+
+    /// Removes the account `name`, and returns whether it existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.accounts.len();
+        self.accounts.retain(|a| a.name != name);
+        self.accounts.len() != len
+    }
+
+    /// Generates the current code of the account `name`. See `Account::code_at`.
+    pub fn code(&mut self, name: &str) -> Result<u32, ChallengeResponseError> {
+        self.code_with(name, &SystemClock)
+    }
+
+    /// Generates the code of the account `name` at the current time of `clock`. See `Account::code_at`.
+    pub fn code_with<C: Clock + ?Sized>(
+        &mut self,
+        name: &str,
+        clock: &C,
+    ) -> Result<u32, ChallengeResponseError> {
+        self.code_at(name, clock.now())
+    }
+
+    /// Generates the code of the account `name` at the UNIX time `timestamp`. See `Account::code_at`.
+    pub fn code_at(&mut self, name: &str, timestamp: u64) -> Result<u32, ChallengeResponseError> {
+        self.accounts
+            .iter_mut()
+            .find(|a| a.name == name)
+            .map(|a| a.code_at(timestamp))
+            .ok_or_else(|| ChallengeResponseError::AccountNotFound(name.to_string()))
+    }
+
+    fn to_secret(&self) -> String {
+        let mut secret = String::new();
+        for account in self.accounts.iter() {
+            account.write_line(&mut secret);
+        }
+        secret
+    }
+
+    /// Encrypts the accounts with the response of the device to a new random challenge, and replaces the
+    /// file.
+    pub fn save<R: CryptoRng + ?Sized>(
+        &self,
+        cr: &mut ChallengeResponse,
+        rng: &mut R,
+    ) -> Result<(), ChallengeResponseError> {
+        let device = match self.serial {
+            Some(serial) => cr.find_device_from_serial(serial)?,
+            None => cr.find_device()?,
+        };
+        let conf = Config::new_from(device)
+            .set_slot(self.slot.clone())
+            .set_variable_size(self.variable);
+        let mut secret = self.to_secret().into_bytes();
+        let wrapped = wrap_key(cr, conf, &secret, rng);
+        for i in secret.iter_mut() {
+            *i = 0;
+        }
+        let vault = Vault {
+            serial: self.serial,
+            wrapped: wrapped?,
+        };
+
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut file = options.open(&tmp)?;
+            file.write_all(&vault.to_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account_codes() {
+        // The test vectors of RFC 6238 and RFC 4226, truncated to 6 digits.
+        let mut totp = Account::totp("Example:alice", b"12345678901234567890").unwrap();
+        assert_eq!(totp.code_at(59), 287082);
+        assert_eq!(totp.code_at(1111111109), 81804);
+        let mut hotp = Account::new("hotp", b"12345678901234567890", 6, OtpKind::Hotp { counter: 0 }).unwrap();
+        assert_eq!(hotp.code_at(0), 755224);
+        assert_eq!(hotp.code_at(0), 287082);
+        assert_eq!(hotp.kind, OtpKind::Hotp { counter: 2 });
+
+        assert!(Account::totp("", b"secret").is_err());
+        assert!(Account::totp("name", &[1; 21]).is_err());
+    }
+
+    #[test]
+    fn test_account_lines() {
+        let authenticator = Authenticator {
+            path: PathBuf::new(),
+            serial: None,
+            slot: Slot::Slot2,
+            variable: true,
+            accounts: vec![
+                Account::totp("Example: alice", b"secret").unwrap(),
+                Account::new("hotp", b"other", 8, OtpKind::Hotp { counter: 42 }).unwrap(),
+            ],
+        };
+        let secret = authenticator.to_secret();
+        assert!(secret.starts_with("totp 6 30 736563726574000000"));
+        let accounts: Vec<Account> = secret.lines().map(|l| Account::parse_line(l).unwrap()).collect();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].name, "Example: alice");
+        assert_eq!(accounts[1].kind, OtpKind::Hotp { counter: 42 });
+        assert_eq!(accounts[1].digits, 8);
+        assert_eq!(accounts[1].secret.0, authenticator.accounts[1].secret.0);
+
+        assert!(Account::parse_line("totp 6 30 00 name").is_err());
+    }
+}
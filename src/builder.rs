@@ -0,0 +1,350 @@
+//! Configuration of the library-wide settings of a `ChallengeResponse` in one place.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use challenge_response::builder::{ChallengeResponseBuilder, RetryPolicy};
+//!
+//! let cr = ChallengeResponseBuilder::new()
+//!     .response_timeout(Duration::from_secs(15))
+//!     .retry_policy(RetryPolicy::once())
+//!     .extra_device_id(0x1050, 0x0420)
+//!     .build()
+//!     .unwrap();
+//! ```
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(feature = "rusb")]
+use rusb::Context;
+
+#[cfg(feature = "configuration")]
+use audit::AuditSink;
+use error::ChallengeResponseError;
+use observer::Observer;
+use touch::TouchPrompt;
+use trace::PcapngTrace;
+use usb::{DetachPolicy, Device, ResetPolicy, Timing};
+use ChallengeResponse;
+
+/// The USB backends. With both the `rusb` and `nusb` features, both backends are compiled in and one of them
+/// is chosen at runtime, see `ChallengeResponse::with_backend`. Otherwise, only one of them is compiled in, the
+/// first of `rusb`, `nusb`, `hidapi` and `hidraw` whose feature is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    Rusb,
+    Nusb,
+    Hidapi,
+    Hidraw,
+}
+
+impl BackendKind {
+    /// The backend compiled in, or the default one if both `rusb` and `nusb` are. Builds without any of them
+    /// only use custom backends, and report `BackendKind::Hidraw` as unavailable.
+    pub fn compiled() -> BackendKind {
+        if cfg!(feature = "rusb") {
+            BackendKind::Rusb
+        } else if cfg!(feature = "nusb") {
+            BackendKind::Nusb
+        } else if cfg!(feature = "hidapi") {
+            BackendKind::Hidapi
+        } else {
+            BackendKind::Hidraw
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BackendKind::Rusb => "rusb",
+            BackendKind::Nusb => "nusb",
+            BackendKind::Hidapi => "hidapi",
+            BackendKind::Hidraw => "hidraw",
+        }
+    }
+
+    /// All the backends, whether they are compiled in or not.
+    pub fn all() -> &'static [BackendKind] {
+        &[
+            BackendKind::Rusb,
+            BackendKind::Nusb,
+            BackendKind::Hidapi,
+            BackendKind::Hidraw,
+        ]
+    }
+
+    /// The backend with the given name, as returned by `name`.
+    pub fn from_name(name: &str) -> Option<BackendKind> {
+        BackendKind::all()
+            .iter()
+            .find(|kind| kind.name() == name)
+            .copied()
+    }
+
+    /// Whether the backend can be used, i.e. it is compiled in.
+    pub fn is_available(&self) -> bool {
+        match *self {
+            BackendKind::Rusb => cfg!(feature = "rusb"),
+            BackendKind::Nusb => cfg!(feature = "nusb"),
+            BackendKind::Hidapi => cfg!(feature = "hidapi") && *self == BackendKind::compiled(),
+            BackendKind::Hidraw => cfg!(feature = "hidraw") && *self == BackendKind::compiled(),
+        }
+    }
+}
+
+/// How the operations which fail because the device was desynchronized are retried, e.g. with a stale
+/// pending response left by a client which crashed. Before each retry, the write state of the device is
+/// reset, or the device is reset if that fails.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of retries of an operation.
+    pub retries: u32,
+    /// The delay before each retry.
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Never retries the operations.
+    pub fn none() -> Self {
+        RetryPolicy::default()
+    }
+
+    /// Retries the operations once, right away.
+    pub fn once() -> Self {
+        RetryPolicy {
+            retries: 1,
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+/// A builder for `ChallengeResponse`, created with `ChallengeResponse::builder`.
+#[derive(Default)]
+pub struct ChallengeResponseBuilder {
+    backend: Option<BackendKind>,
+    #[cfg(feature = "rusb")]
+    rusb_context: Option<Context>,
+    timing: Timing,
+    retry_policy: RetryPolicy,
+    detach_policy: DetachPolicy,
+    reset_policy: ResetPolicy,
+    fips_mode: bool,
+    touch_prompt: Option<Box<dyn TouchPrompt>>,
+    observer: Option<Box<dyn Observer>>,
+    #[cfg(feature = "configuration")]
+    audit_sink: Option<Box<dyn AuditSink>>,
+    trace: Option<PcapngTrace>,
+    extra_device_ids: Vec<(u16, u16)>,
+    lock_directory: Option<PathBuf>,
+    lock_timeout: Option<Option<Duration>>,
+}
+
+impl ChallengeResponseBuilder {
+    pub fn new() -> Self {
+        ChallengeResponseBuilder::default()
+    }
+
+    /// Requires the given backend, see `ChallengeResponse::with_backend`. `build` fails with
+    /// `ChallengeResponseError::BackendUnavailable` if it is not compiled in.
+    pub fn backend(mut self, backend: BackendKind) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Uses an existing libusb context, see `ChallengeResponse::from_rusb_context`.
+    #[cfg(feature = "rusb")]
+    pub fn rusb_context(mut self, context: Context) -> Self {
+        self.rusb_context = Some(context);
+        self
+    }
+
+    /// Sets all the timeouts and the polling interval at once.
+    pub fn timing(mut self, timing: Timing) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    /// Sets the timeout of each USB transfer, 2 seconds by default.
+    pub fn transfer_timeout(mut self, timeout: Duration) -> Self {
+        self.timing.transfer_timeout = timeout;
+        self
+    }
+
+    /// Sets how long to wait for a response, including the time for the user to touch the device. By
+    /// default, the responses are waited for forever.
+    pub fn response_timeout(mut self, timeout: Duration) -> Self {
+        self.timing.response_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long to wait for the user to touch the device, counted from the first report of the device
+    /// that it waits for the touch. The `response_timeout`, if any, still applies.
+    pub fn touch_timeout(mut self, timeout: Duration) -> Self {
+        self.timing.touch_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the delay between two reads of the state of the device while waiting for it, 1 millisecond by
+    /// default.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.timing.poll_interval = interval;
+        self
+    }
+
+    /// Sets how desynchronized operations are retried. They are not retried by default.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub fn detach_policy(mut self, policy: DetachPolicy) -> Self {
+        self.detach_policy = policy;
+        self
+    }
+
+    /// Sets what is done with a device after a failed operation. By default, its write state is reset
+    /// after the errors which can leave it desynchronized.
+    pub fn reset_policy(mut self, policy: ResetPolicy) -> Self {
+        self.reset_policy = policy;
+        self
+    }
+
+    /// Enables the FIPS strict mode, see `ChallengeResponse::set_fips_mode`.
+    pub fn fips_mode(mut self, strict: bool) -> Self {
+        self.fips_mode = strict;
+        self
+    }
+
+    pub fn touch_prompt(mut self, prompt: Box<dyn TouchPrompt>) -> Self {
+        self.touch_prompt = Some(prompt);
+        self
+    }
+
+    /// Sets a callback called when an operation waits for the user to touch the device. See
+    /// `ChallengeResponse::on_touch_required`.
+    pub fn on_touch_required<F>(self, callback: F) -> Self
+    where
+        F: Fn(&Device) + 'static,
+    {
+        self.touch_prompt(Box::new(callback))
+    }
+
+    /// Sets the observer notified of the operations, for logging or metrics.
+    pub fn observer(mut self, observer: Box<dyn Observer>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Sets the sink recording the operations which change the configuration of the devices.
+    #[cfg(feature = "configuration")]
+    pub fn audit_sink(mut self, sink: Box<dyn AuditSink>) -> Self {
+        self.audit_sink = Some(sink);
+        self
+    }
+
+    pub fn trace(mut self, trace: PcapngTrace) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Also uses the devices with the given vendor and product IDs, e.g. new models or compatible keys.
+    pub fn extra_device_id(mut self, vendor_id: u16, product_id: u16) -> Self {
+        self.extra_device_ids.push((vendor_id, product_id));
+        self
+    }
+
+    /// Serializes the operations on each device with the other processes, see
+    /// `ChallengeResponse::set_lock_directory`. `lock::DeviceLock::default_directory` is a sensible choice.
+    pub fn lock_directory(mut self, directory: PathBuf) -> Self {
+        self.lock_directory = Some(directory);
+        self
+    }
+
+    /// Sets how long the operations wait for a device locked by another process, or `None` to wait
+    /// forever. By default, they fail right away.
+    pub fn lock_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    pub fn build(self) -> Result<ChallengeResponse, ChallengeResponseError> {
+        // The libusb context is only used with the rusb backend.
+        #[cfg(feature = "rusb")]
+        let mut cr = match (self.rusb_context, self.backend) {
+            (Some(context), None | Some(BackendKind::Rusb)) => ChallengeResponse::from_rusb_context(context)?,
+            (_, Some(backend)) => ChallengeResponse::with_backend(backend)?,
+            (None, None) => ChallengeResponse::new()?,
+        };
+        #[cfg(not(feature = "rusb"))]
+        let mut cr = match self.backend {
+            Some(backend) => ChallengeResponse::with_backend(backend)?,
+            None => ChallengeResponse::new()?,
+        };
+        cr.set_timing(self.timing);
+        cr.set_retry_policy(self.retry_policy);
+        cr.set_detach_policy(self.detach_policy);
+        cr.set_reset_policy(self.reset_policy);
+        cr.set_fips_mode(self.fips_mode);
+        cr.set_extra_device_ids(self.extra_device_ids);
+        cr.set_lock_directory(self.lock_directory);
+        if let Some(timeout) = self.lock_timeout {
+            cr.set_lock_timeout(timeout);
+        }
+        if let Some(prompt) = self.touch_prompt {
+            cr.set_touch_prompt(prompt);
+        }
+        if let Some(observer) = self.observer {
+            cr.set_observer(observer);
+        }
+        #[cfg(feature = "configuration")]
+        if let Some(sink) = self.audit_sink {
+            cr.set_audit_sink(sink);
+        }
+        cr.set_trace(self.trace);
+        Ok(cr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unavailable_backend() {
+        let unavailable = BackendKind::all()
+            .iter()
+            .copied()
+            .filter(|kind| !kind.is_available());
+        for backend in unavailable {
+            assert!(matches!(
+                ChallengeResponseBuilder::new().backend(backend).build(),
+                Err(ChallengeResponseError::BackendUnavailable(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_backend_names() {
+        for backend in BackendKind::all() {
+            assert_eq!(BackendKind::from_name(backend.name()), Some(*backend));
+        }
+        assert_eq!(BackendKind::from_name("libusb"), None);
+        let usb = cfg!(any(
+            feature = "rusb",
+            feature = "nusb",
+            feature = "hidapi",
+            feature = "hidraw"
+        ));
+        assert_eq!(BackendKind::compiled().is_available(), usb);
+    }
+
+    #[test]
+    fn test_timing() {
+        let builder = ChallengeResponseBuilder::new()
+            .transfer_timeout(Duration::from_millis(500))
+            .touch_timeout(Duration::from_secs(15))
+            .poll_interval(Duration::from_millis(5));
+        assert_eq!(builder.timing.transfer_timeout, Duration::from_millis(500));
+        assert_eq!(builder.timing.response_timeout, None);
+        assert_eq!(builder.timing.touch_timeout, Some(Duration::from_secs(15)));
+        assert_eq!(builder.timing.poll_interval, Duration::from_millis(5));
+    }
+}
@@ -0,0 +1,89 @@
+//! The source of the current time of the time-based helpers, i.e. the TOTP validation and challenges.
+//!
+//! The helpers take a `Clock` instead of reading the system time, so the time-dependent flows can be
+//! tested with a `FakeClock`, and hosts without a wall clock, such as the embedded hosts of the `embedded`
+//! module, can supply their own source, e.g. a RTC or a time synchronized over the network. This module
+//! only depends on `core`, except for `SystemClock`.
+use core::cell::Cell;
+
+/// A source of the current time.
+pub trait Clock {
+    /// The current UNIX time, in seconds.
+    fn now(&self) -> u64;
+}
+
+/// The system time. Times before the UNIX epoch are reported as 0.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A clock which only moves when told to, for tests.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FakeClock {
+    now: Cell<u64>,
+}
+
+impl FakeClock {
+    /// Creates a clock at the UNIX time `now`.
+    pub fn new(now: u64) -> Self {
+        FakeClock { now: Cell::new(now) }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.set(now);
+    }
+
+    /// Moves the clock forward by `seconds`.
+    pub fn advance(&self, seconds: u64) {
+        self.now.set(self.now.get().saturating_add(seconds));
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
+
+/// The current time step of `clock`, for steps of `period` seconds.
+pub fn time_step<C: Clock + ?Sized>(clock: &C, period: u64) -> u64 {
+    clock.now() / period
+}
+
+/// The TOTP challenge of the current time step of `clock`, i.e. the step as a big-endian 64-bit integer, as
+/// sent to a HMAC-SHA1 slot or to the OATH application to compute a TOTP code.
+pub fn totp_challenge<C: Clock + ?Sized>(clock: &C, period: u64) -> [u8; 8] {
+    time_step(clock, period).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fake_clock() {
+        let clock = FakeClock::new(59);
+        assert_eq!(time_step(&clock, 30), 1);
+        assert_eq!(totp_challenge(&clock, 30), [0, 0, 0, 0, 0, 0, 0, 1]);
+        clock.advance(1);
+        assert_eq!(totp_challenge(&clock, 30), [0, 0, 0, 0, 0, 0, 0, 2]);
+        clock.set(1_111_111_109);
+        assert_eq!(totp_challenge(&clock, 30), 37_037_036u64.to_be_bytes());
+    }
+}
@@ -1,36 +1,71 @@
 use std::fmt::Display;
 
 use crate::Device;
+use error::ChallengeResponseError;
+
+pub use protocol::Command;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Slot {
     Slot1,
     Slot2,
+    /// Another slot, by number from 3, for the devices with more than the two slots of the Yubico devices.
+    /// The operations on a slot which the model of the device does not have, see `Device::supports_slot`,
+    /// fail with `ChallengeResponseError::SlotNotSupported`, as do the operations through the OTP HID
+    /// protocol on the slots from 3, which none of its commands addresses.
+    Other(u8),
 }
 
 impl Slot {
     /// Parses a slot number from a slice.
     /// Returns None if the slot number is invalid.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(slot_number: &str) -> Option<Slot> {
-        if slot_number == "1" {
-            return Some(Slot::Slot1);
-        }
-        if slot_number == "2" {
-            return Some(Slot::Slot2);
-        }
-        None
+        slot_number.parse().ok().and_then(Slot::from_int)
     }
 
     /// Parses a slot number from an integer.
     /// Returns None if the slot number is invalid.
     pub fn from_int(slot_number: usize) -> Option<Slot> {
-        if slot_number == 1 {
-            return Some(Slot::Slot1);
+        match slot_number {
+            1 => Some(Slot::Slot1),
+            2 => Some(Slot::Slot2),
+            3..=255 => Some(Slot::Other(slot_number as u8)),
+            _ => None,
+        }
+    }
+
+    /// The number of the slot, from 1.
+    pub fn number(&self) -> u8 {
+        match *self {
+            Slot::Slot1 => 1,
+            Slot::Slot2 => 2,
+            Slot::Other(number) => number,
         }
-        if slot_number == 2 {
-            return Some(Slot::Slot2);
+    }
+
+    /// Picks the command of an operation on the slot, among the commands for the first and the second
+    /// slots. No command addresses the other slots.
+    pub(crate) fn command(&self, slot1: Command, slot2: Command) -> Result<Command, ChallengeResponseError> {
+        match *self {
+            Slot::Slot1 => Ok(slot1),
+            Slot::Slot2 => Ok(slot2),
+            Slot::Other(number) => Err(ChallengeResponseError::SlotNotSupported(number)),
+        }
+    }
+
+    /// Picks the command of an operation on the slot of `device`, like `command`, after checking that the
+    /// model of the device has the slot.
+    pub(crate) fn command_for(
+        &self,
+        device: &Device,
+        slot1: Command,
+        slot2: Command,
+    ) -> Result<Command, ChallengeResponseError> {
+        if !device.supports_slot(self) {
+            return Err(ChallengeResponseError::SlotNotSupported(self.number()));
         }
-        None
+        self.command(slot1, slot2)
     }
 }
 
@@ -40,6 +75,23 @@ pub enum Mode {
     Otp,
 }
 
+/// How a slot configured for HMAC-SHA1 reads the challenges, see `ChallengeResponse::slot_input_mode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputMode {
+    /// The challenges are shorter than 64 bytes, and padded to 64 bytes with a byte different from their
+    /// last byte, which the device strips: the slot was configured with `HMAC_LT64`.
+    Variable,
+    /// The challenges are always 64 bytes long.
+    Fixed,
+}
+
+impl InputMode {
+    /// Whether the challenges are variable-length, as expected by `Config::set_variable_size`.
+    pub fn is_variable(&self) -> bool {
+        *self == InputMode::Variable
+    }
+}
+
 /// From the Validation Protocol documentation:
 ///
 /// A value 0 to 100 indicating percentage of syncing required by client,
@@ -72,22 +124,6 @@ impl Display for SyncLevel {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-#[repr(u8)]
-pub enum Command {
-    Configuration1 = 0x01,
-    Configuration2 = 0x03,
-    Update1 = 0x04,
-    Update2 = 0x05,
-    Swap = 0x06,
-    DeviceSerial = 0x10,
-    DeviceConfig = 0x11,
-    ChallengeOtp1 = 0x20,
-    ChallengeOtp2 = 0x28,
-    ChallengeHmac1 = 0x30,
-    ChallengeHmac2 = 0x38,
-}
-
 #[derive(Clone, Debug, PartialEq)]
 pub struct Config {
     pub device: Device,
@@ -128,3 +164,21 @@ impl Config {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slot() {
+        assert_eq!(Slot::from_str("2"), Some(Slot::Slot2));
+        assert_eq!(Slot::from_str("4"), Some(Slot::Other(4)));
+        assert_eq!(Slot::from_str("0"), None);
+        assert_eq!(Slot::from_int(256), None);
+        assert_eq!(Slot::Other(4).number(), 4);
+        assert!(matches!(
+            Slot::Other(4).command(Command::ChallengeHmac1, Command::ChallengeHmac2),
+            Err(ChallengeResponseError::SlotNotSupported(4))
+        ));
+    }
+}
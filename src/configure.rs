@@ -1,15 +1,80 @@
 use config::Command;
+use error::ChallengeResponseError;
 use hmacmode::HmacKey;
+use modhex::PublicId;
+use otpauth::HotpUri;
+#[cfg(feature = "otp")]
 use otpmode::Aes128Key;
-use sec::crc16;
+use protocol::crc16;
+use protocol::{Frame, PAYLOAD_SIZE};
 use std;
-use usb::{Frame, PAYLOAD_SIZE};
+use std::convert::TryFrom;
 
 const FIXED_SIZE: usize = 16;
 const UID_SIZE: usize = 6;
 const KEY_SIZE: usize = 16;
 const ACC_CODE_SIZE: usize = 6;
 
+/// The device only stores the 16 high bits of the 20 bits initial counter of OATH-HOTP slots.
+const OATH_IMF_SHIFT: u32 = 4;
+const OATH_IMF_MAX: u32 = 0xffff << OATH_IMF_SHIFT;
+
+const OATH_TOKEN_ID_SIZE: usize = 6;
+
+/// The OATH token identifier of an OATH-HOTP slot, which validation servers use to find the record of
+/// the token.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OathTokenId {
+    /// The OATH manufacturer prefix.
+    pub omp: u8,
+    /// The token type.
+    pub tt: u8,
+    /// The manufacturer unique identifier.
+    pub mui: [u8; 4],
+}
+
+impl OathTokenId {
+    /// The identifier used by Yubico for its keys, `ubhe` in modhex followed by the 8 decimal digits of the
+    /// serial number.
+    pub fn yubico(serial: u32) -> OathTokenId {
+        OathTokenId::from_serial(0xe1, 0x63, serial)
+    }
+
+    /// An identifier whose MUI is the serial number, as 8 BCD digits. Serial numbers above 99999999 are
+    /// truncated to their 8 lowest digits.
+    pub fn from_serial(omp: u8, tt: u8, serial: u32) -> OathTokenId {
+        let mut mui = [0; 4];
+        let mut serial = serial;
+        for b in mui.iter_mut().rev() {
+            *b = (serial % 10) as u8 | ((serial / 10 % 10) as u8) << 4;
+            serial /= 100;
+        }
+        OathTokenId { omp, tt, mui }
+    }
+}
+
+/// Which parts of the token identifier are typed in modhex, the others being typed as decimal digits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OathTokenIdEncoding {
+    Numeric,
+    /// The manufacturer prefix is typed in modhex.
+    ModhexOmp,
+    /// The manufacturer prefix and token type are typed in modhex, as recommended by OATH.
+    ModhexOmpTt,
+    Modhex,
+}
+
+/// Whether a slot can be changed after it is programmed, without writing its secret again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UpdatePolicy {
+    /// The slot can only be reprogrammed as a whole.
+    #[default]
+    Locked,
+    /// The flags of the slot can be changed with `Command::Update1` or `Command::Update2`, and the slot can
+    /// be swapped with the other slot if it is updatable too. Requires firmware 2.3.
+    Updatable,
+}
+
 /// The configuration of a YubiKey.
 #[repr(C)]
 #[repr(packed)]
@@ -43,28 +108,83 @@ impl std::default::Default for DeviceModeConfig {
     }
 }
 
-const SIZEOF_CONFIG: usize = 52;
+/// The size of a serialized `DeviceModeConfig`.
+pub const SIZEOF_CONFIG: usize = 52;
 
 impl DeviceModeConfig {
+    /// Serializes the configuration as sent to the device, with the fields in order and the CRC encoded
+    /// little-endian.
+    pub fn to_bytes(&self) -> [u8; SIZEOF_CONFIG] {
+        let mut bytes = [0; SIZEOF_CONFIG];
+        let mut offset = 0;
+        for field in [
+            &self.fixed[..],
+            &self.uid[..],
+            &self.key[..],
+            &self.acc_code[..],
+            &[
+                self.fixed_size,
+                self.ext_flags.bits(),
+                self.tkt_flags.bits(),
+                self.cfg_flags.bits(),
+            ],
+            &self.rfu[..],
+            &self.crc.to_le_bytes(),
+        ] {
+            bytes[offset..offset + field.len()].copy_from_slice(field);
+            offset += field.len();
+        }
+        bytes
+    }
+
+    /// Whether the configuration is empty, which deletes the configuration of a slot when written. The CRC
+    /// is ignored, as it is set when the configuration is written.
+    pub fn is_empty(&self) -> bool {
+        self.to_bytes()[..SIZEOF_CONFIG - 2].iter().all(|&b| b == 0)
+    }
+
     #[doc(hidden)]
     pub fn to_frame(&mut self, command: Command) -> Frame {
         let mut payload = [0; PAYLOAD_SIZE];
         // First set CRC.
-        self.crc = {
-            let first_fields = unsafe {
-                std::slice::from_raw_parts(self as *const DeviceModeConfig as *const u8, SIZEOF_CONFIG - 2)
-            };
-            (0xffff - crc16(&first_fields)).to_le()
-        };
+        self.crc = 0xffff - crc16(&self.to_bytes()[..SIZEOF_CONFIG - 2]);
 
         // Then write to the payload.
-        let s =
-            unsafe { std::slice::from_raw_parts(self as *const DeviceModeConfig as *const u8, SIZEOF_CONFIG) };
-        (&mut payload[..SIZEOF_CONFIG]).clone_from_slice(s);
+        payload[..SIZEOF_CONFIG].copy_from_slice(&self.to_bytes());
 
         Frame::new(payload, command)
     }
 
+    /// The configuration writing the flags of an updatable slot with `Command::Update1` or
+    /// `Command::Update2`, keeping its secret. Fails with `ChallengeResponseError::InvalidFormat` if a flag
+    /// can not be updated, see the `UPDATE_MASK` of the flags. Clearing `ExtendedFlags::ALLOW_UPDATE` locks
+    /// the slot.
+    pub fn update(
+        tkt_flags: TicketFlags,
+        cfg_flags: ConfigFlags,
+        ext_flags: ExtendedFlags,
+    ) -> Result<DeviceModeConfig, ChallengeResponseError> {
+        if !TicketFlags::UPDATE_MASK.contains(tkt_flags)
+            || !ConfigFlags::UPDATE_MASK.contains(cfg_flags)
+            || !ExtendedFlags::UPDATE_MASK.contains(ext_flags)
+        {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        Ok(DeviceModeConfig {
+            tkt_flags,
+            cfg_flags,
+            ext_flags,
+            ..DeviceModeConfig::default()
+        })
+    }
+
+    /// Sets whether the slot can be updated and swapped once programmed. This must be called after the
+    /// mode is set, e.g. with `challenge_response_hmac`, which clears the extended flags.
+    pub fn set_update_policy(&mut self, policy: UpdatePolicy) {
+        self.ext_flags
+            .set(ExtendedFlags::ALLOW_UPDATE, policy == UpdatePolicy::Updatable);
+    }
+
     /// Sets the configuration in challenge-response, HMAC-SHA1
     /// mode. This mode has two sub-modes: if `variable` is `true`,
     /// the challenges can be of variable length up to 63 bytes. Else,
@@ -87,10 +207,11 @@ impl DeviceModeConfig {
         }
         let (a, b) = secret.0.split_at(16);
         self.key.copy_from_slice(a);
-        (&mut self.uid[..4]).copy_from_slice(b);
+        self.uid[..4].copy_from_slice(b);
     }
 
     /// Sets the configuration in challenge-response, OTP mode.
+    #[cfg(feature = "otp")]
     pub fn challenge_response_otp(&mut self, secret: &Aes128Key, priv_id: &[u8; 6], button_press: bool) {
         self.tkt_flags = TicketFlags::empty();
         self.cfg_flags = ConfigFlags::empty();
@@ -106,9 +227,73 @@ impl DeviceModeConfig {
         self.uid.copy_from_slice(priv_id);
         self.key.copy_from_slice(&secret.0);
     }
+
+    /// Sets the configuration in OATH-HOTP mode, where a code of 6 digits, or 8 if `eight_digits` is
+    /// `true`, is typed each time the button is pressed. `initial_counter` must be a multiple of 16 up to
+    /// 1048560, else `ChallengeResponseError::InvalidFormat` is returned.
+    pub fn oath_hotp(
+        &mut self,
+        secret: &HmacKey,
+        eight_digits: bool,
+        initial_counter: u32,
+    ) -> Result<(), ChallengeResponseError> {
+        if !initial_counter.is_multiple_of(1 << OATH_IMF_SHIFT) || initial_counter > OATH_IMF_MAX {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+
+        self.tkt_flags = TicketFlags::empty();
+        self.cfg_flags = ConfigFlags::empty();
+        self.ext_flags = ExtendedFlags::empty();
+
+        self.tkt_flags.insert(TicketFlags::OATH_HOTP);
+        if eight_digits {
+            self.cfg_flags.insert(ConfigFlags::OATH_HOTP8);
+        }
+        let (a, b) = secret.0.split_at(16);
+        self.key.copy_from_slice(a);
+        self.uid[..4].copy_from_slice(b);
+        let imf = (initial_counter >> OATH_IMF_SHIFT) as u16;
+        self.uid[4..].copy_from_slice(&imf.to_be_bytes());
+        Ok(())
+    }
+
+    /// Sets the token identifier of an OATH-HOTP slot, typed before each code. This must be called after
+    /// `oath_hotp`, which clears it.
+    pub fn set_oath_token_id(&mut self, id: &OathTokenId, encoding: OathTokenIdEncoding) {
+        self.fixed = [0; FIXED_SIZE];
+        self.fixed[0] = id.omp;
+        self.fixed[1] = id.tt;
+        self.fixed[2..OATH_TOKEN_ID_SIZE].copy_from_slice(&id.mui);
+        self.fixed_size = OATH_TOKEN_ID_SIZE as u8;
+
+        self.cfg_flags.remove(ConfigFlags::OATH_FIXED_MASK);
+        self.cfg_flags.insert(match encoding {
+            OathTokenIdEncoding::Numeric => ConfigFlags::empty(),
+            OathTokenIdEncoding::ModhexOmp => ConfigFlags::OATH_FIXED_MODHEX1,
+            OathTokenIdEncoding::ModhexOmpTt => ConfigFlags::OATH_FIXED_MODHEX2,
+            OathTokenIdEncoding::Modhex => ConfigFlags::OATH_FIXED_MODHEX,
+        });
+    }
+
+    /// Sets the public identity of a slot, typed in modhex before each Yubico OTP, e.g.
+    /// `PublicId::from_serial` to register the slot with YubiCloud or a self-hosted validation server.
+    pub fn set_public_id(&mut self, id: &PublicId) {
+        let id = id.as_bytes();
+        self.fixed = [0; FIXED_SIZE];
+        self.fixed[..id.len()].copy_from_slice(id);
+        self.fixed_size = id.len() as u8;
+    }
+
+    /// Sets the configuration in OATH-HOTP mode, with the secret, number of digits and counter of an
+    /// `otpauth://hotp/` URI.
+    pub fn oath_hotp_uri(&mut self, uri: &HotpUri) -> Result<(), ChallengeResponseError> {
+        let counter = u32::try_from(uri.counter).map_err(|_| ChallengeResponseError::InvalidFormat)?;
+        self.oath_hotp(&uri.secret, uri.digits == 8, counter)
+    }
 }
 
 bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
     pub struct TicketFlags: u8 {
         const TAB_FIRST = 0x1;
         const APPEND_TAB1 = 0x2;
@@ -123,6 +308,7 @@ bitflags! {
 }
 
 bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
     pub struct ConfigFlags: u8 {
         // Yubikey 1.0
         const SEND_REF = 0x1;
@@ -150,6 +336,7 @@ bitflags! {
 }
 
 bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
     pub struct ExtendedFlags: u8 {
         const SERIAL_BTN_VISIBLE = 0x01;
         const SERIAL_USB_VISIBLE = 0x02;
@@ -161,3 +348,81 @@ bitflags! {
         const DORMANT = 0x40;
     }
 }
+
+impl TicketFlags {
+    /// The flags which can be changed by updating a slot.
+    pub const UPDATE_MASK: TicketFlags = TicketFlags::TAB_FIRST
+        .union(TicketFlags::APPEND_TAB1)
+        .union(TicketFlags::APPEND_TAB2)
+        .union(TicketFlags::APPEND_DELAY1)
+        .union(TicketFlags::APPEND_DELAY2)
+        .union(TicketFlags::APPEND_CR);
+}
+
+impl ConfigFlags {
+    /// The flags which can be changed by updating a slot.
+    pub const UPDATE_MASK: ConfigFlags = ConfigFlags::PACING_10MS.union(ConfigFlags::PACING_20MS);
+}
+
+impl ExtendedFlags {
+    /// The flags which can be changed by updating a slot.
+    pub const UPDATE_MASK: ExtendedFlags = ExtendedFlags::all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_policy() {
+        let mut config = DeviceModeConfig::default();
+        config.challenge_response_hmac(&HmacKey([0; 20]), true, false);
+        config.set_update_policy(UpdatePolicy::Updatable);
+        let ext_flags = config.ext_flags;
+        assert_eq!(ext_flags, ExtendedFlags::ALLOW_UPDATE);
+
+        let update = DeviceModeConfig::update(
+            TicketFlags::APPEND_CR,
+            ConfigFlags::empty(),
+            ExtendedFlags::ALLOW_UPDATE | ExtendedFlags::FAST_TRIG,
+        )
+        .unwrap();
+        assert_eq!(update.key, [0; KEY_SIZE]);
+        // The mode of the slot can not be changed by an update.
+        assert!(DeviceModeConfig::update(
+            TicketFlags::CHAL_RESP,
+            ConfigFlags::empty(),
+            ExtendedFlags::empty()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_oath_token_id() {
+        let id = OathTokenId::yubico(1234567);
+        assert_eq!(id.mui, [0x01, 0x23, 0x45, 0x67]);
+
+        let mut config = DeviceModeConfig::default();
+        config.oath_hotp(&HmacKey([0; 20]), false, 16).unwrap();
+        config.set_oath_token_id(&id, OathTokenIdEncoding::ModhexOmpTt);
+        assert_eq!(config.fixed[..6], [0xe1, 0x63, 0x01, 0x23, 0x45, 0x67]);
+        assert_eq!(config.fixed_size, 6);
+        assert_eq!(config.uid[4..], [0, 1]);
+        let cfg_flags = config.cfg_flags;
+        assert_eq!(cfg_flags, ConfigFlags::OATH_FIXED_MODHEX2);
+
+        assert!(config.oath_hotp(&HmacKey([0; 20]), false, 17).is_err());
+    }
+
+    #[test]
+    fn test_to_frame() {
+        let mut config = DeviceModeConfig::default();
+        config.challenge_response_hmac(&HmacKey([0x42; 20]), true, false);
+        let frame = config.to_frame(Command::Configuration1);
+        let bytes = &frame.payload[..SIZEOF_CONFIG];
+        assert_eq!(bytes[22..38], [0x42; 16]);
+        assert_eq!(bytes[16..20], [0x42; 4]);
+        // The CRC of a configuration followed by its complemented CRC is the residual of CRC-16/X-25.
+        assert_eq!(crc16(bytes), 0xf0b8);
+    }
+}
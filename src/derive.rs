@@ -0,0 +1,119 @@
+//! Derivation of keys from the HMAC-SHA1 responses of several slots, or of several devices.
+//!
+//! The responses are concatenated and fed through HKDF-SHA256, with the challenge as salt. Challenging both
+//! slots of a device, one configured without and one with touch, gives a key which needs both the presence
+//! of the device and a deliberate action of the user. Challenging several devices gives a key which can
+//! only be derived when all of them are plugged in.
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use config::{Config, Slot};
+use error::ChallengeResponseError;
+use ChallengeResponse;
+
+/// The size of the derived keys.
+pub const DERIVED_KEY_SIZE: usize = 32;
+
+/// A key derived from device responses.
+pub struct DerivedKey(pub [u8; DERIVED_KEY_SIZE]);
+
+impl Drop for DerivedKey {
+    fn drop(&mut self) {
+        for i in self.0.iter_mut() {
+            *i = 0;
+        }
+    }
+}
+
+impl std::ops::Deref for DerivedKey {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Combines the responses to `challenge` into a single key. The order of the responses matters.
+pub fn combine_responses(challenge: &[u8], responses: &[&[u8]], info: &[u8]) -> DerivedKey {
+    let mut ikm = Vec::new();
+    for response in responses {
+        ikm.extend_from_slice(response);
+    }
+    let mut key = DerivedKey([0; DERIVED_KEY_SIZE]);
+    Hkdf::<Sha256>::new(Some(challenge), &ikm)
+        .expand(info, &mut key.0)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+    for i in ikm.iter_mut() {
+        *i = 0;
+    }
+    key
+}
+
+/// Derives a key from the responses of both slots of the device targeted by `conf` to `challenge`. The
+/// slot 1 is challenged first, so an application using a touch-required slot 2 only prompts for the touch
+/// once the touchless slot 1 responded.
+pub fn derive_dual_slot(
+    cr: &mut ChallengeResponse,
+    conf: Config,
+    challenge: &[u8],
+    info: &[u8],
+) -> Result<DerivedKey, ChallengeResponseError> {
+    let first = cr.challenge_response_hmac(challenge, conf.clone().set_slot(Slot::Slot1))?;
+    let second = cr.challenge_response_hmac(challenge, conf.set_slot(Slot::Slot2))?;
+    Ok(combine_responses(challenge, &[&first, &second], info))
+}
+
+/// Derives a key from the responses to `challenge` of the given slot of all the devices with the given
+/// serial numbers. The devices are identified by the serial number they report, and must be distinct. The
+/// responses are combined in the order of the serial numbers, so the order of `serials` does not matter.
+pub fn derive_multi_device(
+    cr: &mut ChallengeResponse,
+    serials: &[u32],
+    slot: Slot,
+    variable: bool,
+    challenge: &[u8],
+    info: &[u8],
+) -> Result<DerivedKey, ChallengeResponseError> {
+    // Without any device, the key would only depend on the challenge.
+    if serials.is_empty() {
+        return Err(ChallengeResponseError::DeviceNotFound);
+    }
+    let mut serials = serials.to_vec();
+    serials.sort_unstable();
+    if let Some(pair) = serials.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(ChallengeResponseError::DuplicateDevice(pair[0]));
+    }
+
+    let mut responses = Vec::with_capacity(serials.len());
+    for serial in serials {
+        let device = cr.find_device_from_serial(serial)?;
+        let conf = Config::new_from(device)
+            .set_slot(slot.clone())
+            .set_variable_size(variable);
+        responses.push(cr.challenge_response_hmac(challenge, conf)?);
+    }
+
+    let responses: Vec<&[u8]> = responses.iter().map(|r| &r[..]).collect();
+    Ok(combine_responses(challenge, &responses, info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_combine_responses() {
+        let key = combine_responses(b"challenge", &[&[1; 20], &[2; 20]], b"test");
+        assert_eq!(
+            &*key,
+            &*combine_responses(b"challenge", &[&[1; 20], &[2; 20]], b"test")
+        );
+        assert_ne!(
+            &*key,
+            &*combine_responses(b"challenge", &[&[2; 20], &[1; 20]], b"test")
+        );
+        assert_ne!(
+            &*key,
+            &*combine_responses(b"challenge", &[&[1; 20], &[2; 20]], b"other")
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Diagnostic reports, to attach to bug reports.
+use std::fmt;
+use std::time::Duration;
+
+use protocol::Status;
+use usb::{Device, InterfaceInfo};
+
+/// The maximum number of errors kept by a `ChallengeResponse` instance for the diagnostic reports.
+pub const MAX_RECENT_ERRORS: usize = 16;
+
+/// A report about a device and its handling by the library, returned by
+/// `ChallengeResponse::collect_diagnostics`. The information which could not be read from the device is
+/// replaced by the error message.
+#[derive(Clone, Debug)]
+pub struct Diagnostics {
+    pub library_version: &'static str,
+    pub backend: &'static str,
+    pub os: &'static str,
+    pub device: Device,
+    pub status: Result<Status, String>,
+    pub interfaces: Result<Vec<InterfaceInfo>, String>,
+    /// The last errors returned by the `ChallengeResponse` instance, from the oldest to the most recent.
+    pub recent_errors: Vec<String>,
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "challenge_response {} ({} backend, {})",
+            self.library_version, self.backend, self.os
+        )?;
+        writeln!(
+            f,
+            "Device: {} [{:04x}:{:04x}] bus {} address {}",
+            self.device.name.as_deref().unwrap_or("unknown"),
+            self.device.vendor_id,
+            self.device.product_id,
+            self.device.bus_id,
+            self.device.address_id
+        )?;
+        match self.device.serial {
+            Some(serial) => writeln!(f, "Serial: {}", serial)?,
+            None => writeln!(f, "Serial: unknown")?,
+        }
+        if let Some(ref port_path) = self.device.port_path {
+            writeln!(f, "Port: {}", port_path)?;
+        }
+
+        match self.status {
+            Ok(ref status) => writeln!(
+                f,
+                "Firmware: {}.{}.{}, programming sequence {}, touch level {:04x}, flags {:02x}",
+                status.version_major,
+                status.version_minor,
+                status.version_build,
+                status.pgm_seq,
+                status.touch_level,
+                status.flags.bits()
+            )?,
+            Err(ref e) => writeln!(f, "Status: {}", e)?,
+        }
+
+        match self.interfaces {
+            Ok(ref interfaces) => {
+                writeln!(f, "Interfaces:")?;
+                for interface in interfaces {
+                    writeln!(
+                        f,
+                        "  {}: class {:02x}, subclass {:02x}, protocol {:02x}, driver {}",
+                        interface.number,
+                        interface.class,
+                        interface.subclass,
+                        interface.protocol,
+                        interface.kernel_driver.as_deref().unwrap_or("none")
+                    )?;
+                }
+            }
+            Err(ref e) => writeln!(f, "Interfaces: {}", e)?,
+        }
+
+        writeln!(f, "Recent errors:")?;
+        for error in &self.recent_errors {
+            writeln!(f, "  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// The round-trip times of challenge-responses, measured by `ChallengeResponse::measure_latency`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Latency {
+    pub iterations: u32,
+    pub min: Duration,
+    pub average: Duration,
+    pub max: Duration,
+}
+
+impl Latency {
+    pub(crate) fn from_samples(samples: &[Duration]) -> Latency {
+        let total: Duration = samples.iter().sum();
+        Latency {
+            iterations: samples.len() as u32,
+            min: samples.iter().min().copied().unwrap_or_default(),
+            average: total.checked_div(samples.len() as u32).unwrap_or_default(),
+            max: samples.iter().max().copied().unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "min {:?}, average {:?}, max {:?} over {} challenges",
+            self.min, self.average, self.max, self.iterations
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let diagnostics = Diagnostics {
+            library_version: "0.0.0",
+            backend: "rusb",
+            os: "linux",
+            device: Device {
+                name: Some("YubiKey OTP+FIDO+CCID".to_string()),
+                serial: Some(42),
+                product_id: 0x0407,
+                vendor_id: 0x1050,
+                bus_id: 1,
+                address_id: 3,
+                port_path: None,
+                manufacturer: None,
+                release: 0x0543,
+                interfaces: Vec::new(),
+            },
+            status: Err("Can not open device".to_string()),
+            interfaces: Ok(vec![InterfaceInfo {
+                number: 0,
+                class: 3,
+                subclass: 1,
+                protocol: 1,
+                kernel_driver: Some("usbhid".to_string()),
+            }]),
+            recent_errors: vec!["Wrong CRC".to_string()],
+        };
+
+        let report = diagnostics.to_string();
+        assert!(report.contains("[1050:0407] bus 1 address 3"));
+        assert!(report.contains("Status: Can not open device"));
+        assert!(report.contains("0: class 03, subclass 01, protocol 01, driver usbhid"));
+        assert!(report.contains("  Wrong CRC"));
+    }
+
+    #[test]
+    fn test_latency() {
+        let samples = [15, 12, 21].map(Duration::from_millis);
+        let latency = Latency::from_samples(&samples);
+        assert_eq!(latency.min, Duration::from_millis(12));
+        assert_eq!(latency.average, Duration::from_millis(16));
+        assert_eq!(latency.max, Duration::from_millis(21));
+        assert_eq!(
+            latency.to_string(),
+            "min 12ms, average 16ms, max 21ms over 3 challenges"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Discovery of the physical devices, whatever the transports they are reachable through.
+//!
+//! A key may be found more than once: enumerated twice by the USB backend while it re-enumerates, or
+//! reachable both through its OTP HID interface and, with the `pcsc` feature, through its CCID smart card
+//! interface. `discover` merges the devices with the same serial number, and records the transports each
+//! of them is reachable through, so a caller iterating over the results challenges every key once.
+//!
+//! The serial numbers of the smart card readers are read from the OTP application of the YubiKeys over
+//! CCID. The readers without one, e.g. of other vendors, are listed as separate devices.
+#[cfg(feature = "pcsc")]
+use std::ffi::CString;
+
+#[cfg(feature = "pcsc")]
+use pcsc::{Context, Protocols, Scope, ShareMode, MAX_BUFFER_SIZE};
+
+use error::ChallengeResponseError;
+use {ChallengeResponse, Device, DeviceId};
+
+/// A transport through which a device is reachable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportKind {
+    /// The OTP HID interface, used by `ChallengeResponse`.
+    Hid,
+    /// The CCID smart card interface, used by the `oath` module.
+    Ccid,
+}
+
+/// A physical device, with the transports it was found through.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiscoveredDevice {
+    /// The serial number, if one of the transports could read it.
+    pub serial: Option<u32>,
+    /// The device of the USB backend, if the OTP HID interface was found.
+    pub usb: Option<Device>,
+    /// The names of the smart card readers of the device.
+    pub readers: Vec<String>,
+}
+
+impl DiscoveredDevice {
+    /// The transports through which the device is reachable.
+    pub fn transports(&self) -> Vec<TransportKind> {
+        let mut transports = Vec::new();
+        if self.usb.is_some() {
+            transports.push(TransportKind::Hid);
+        }
+        if !self.readers.is_empty() {
+            transports.push(TransportKind::Ccid);
+        }
+        transports
+    }
+
+    pub fn is_reachable_through(&self, transport: TransportKind) -> bool {
+        self.transports().contains(&transport)
+    }
+}
+
+/// Removes the devices found more than once by the USB backend, keeping the first one of each identity.
+pub fn dedup_devices(devices: Vec<Device>) -> Vec<Device> {
+    let mut ids: Vec<DeviceId> = Vec::with_capacity(devices.len());
+    let mut unique = Vec::with_capacity(devices.len());
+    for device in devices {
+        let id = device.id();
+        if !ids.contains(&id) {
+            ids.push(id);
+            unique.push(device);
+        }
+    }
+    unique
+}
+
+/// Merges the USB devices with the smart card readers, given with their serial numbers, into physical
+/// devices. The readers are merged with the USB device or with the other readers of the same serial
+/// number.
+pub fn merge(usb: Vec<Device>, readers: Vec<(String, Option<u32>)>) -> Vec<DiscoveredDevice> {
+    let mut devices: Vec<DiscoveredDevice> = dedup_devices(usb)
+        .into_iter()
+        .map(|device| DiscoveredDevice {
+            serial: device.serial,
+            usb: Some(device),
+            readers: Vec::new(),
+        })
+        .collect();
+    for (reader, serial) in readers {
+        let existing = serial.and_then(|serial| devices.iter_mut().find(|d| d.serial == Some(serial)));
+        match existing {
+            Some(device) => device.readers.push(reader),
+            None => devices.push(DiscoveredDevice {
+                serial,
+                usb: None,
+                readers: vec![reader],
+            }),
+        }
+    }
+    devices
+}
+
+/// The application identifier of the OTP application of the YubiKeys.
+#[cfg(feature = "pcsc")]
+const OTP_AID: [u8; 8] = [0xa0, 0x00, 0x00, 0x05, 0x27, 0x20, 0x01, 0x01];
+
+/// Reads the serial number of the key of a smart card reader, from its OTP application.
+#[cfg(feature = "pcsc")]
+fn read_reader_serial(context: &Context, reader: &CString) -> Result<u32, ChallengeResponseError> {
+    let card = context.connect(reader, ShareMode::Shared, Protocols::ANY)?;
+    let mut buf = [0; MAX_BUFFER_SIZE];
+    let mut select = vec![0x00, 0xa4, 0x04, 0x00, OTP_AID.len() as u8];
+    select.extend_from_slice(&OTP_AID);
+    match card.transmit(&select, &mut buf)? {
+        [.., 0x90, 0x00] => {}
+        _ => return Err(ChallengeResponseError::InvalidResponse),
+    }
+    // The OTP commands are sent in the P1 of the APDU, here the one reading the serial number.
+    match card.transmit(&[0x00, 0x01, 0x10, 0x00], &mut buf)? {
+        [a, b, c, d, 0x90, 0x00] => Ok(u32::from_be_bytes([*a, *b, *c, *d])),
+        _ => Err(ChallengeResponseError::InvalidResponse),
+    }
+}
+
+/// The smart card readers, with the serial numbers of their keys if they could be read.
+#[cfg(feature = "pcsc")]
+fn readers() -> Result<Vec<(String, Option<u32>)>, ChallengeResponseError> {
+    let context = match Context::establish(Scope::User) {
+        Ok(context) => context,
+        // Without a running PC/SC service, no key is reachable over CCID.
+        Err(pcsc::Error::NoService) | Err(pcsc::Error::ServiceStopped) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let names = match context.list_readers_owned() {
+        Ok(names) => names,
+        Err(pcsc::Error::NoReadersAvailable) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(names
+        .into_iter()
+        .map(|name| {
+            let serial = read_reader_serial(&context, &name).ok();
+            (name.to_string_lossy().into_owned(), serial)
+        })
+        .collect())
+}
+
+/// Discovers the physical devices reachable through the USB backend of `cr` and, with the `pcsc` feature,
+/// through the smart card readers. Returns an empty list if no device is connected.
+pub fn discover(cr: &mut ChallengeResponse) -> Result<Vec<DiscoveredDevice>, ChallengeResponseError> {
+    let usb = match cr.find_all_devices() {
+        Ok(devices) => devices,
+        Err(ChallengeResponseError::DeviceNotFound) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    #[cfg(feature = "pcsc")]
+    let readers = readers()?;
+    #[cfg(not(feature = "pcsc"))]
+    let readers = Vec::new();
+    Ok(merge(usb, readers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(serial: Option<u32>, address_id: u8) -> Device {
+        Device {
+            name: None,
+            serial,
+            product_id: 0x0407,
+            vendor_id: 0x1050,
+            bus_id: 1,
+            address_id,
+            port_path: None,
+            manufacturer: None,
+            release: 0x0543,
+            interfaces: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let usb = vec![
+            device(Some(1), 2),
+            device(Some(1), 3),
+            device(None, 4),
+            device(Some(2), 5),
+        ];
+        let readers = vec![
+            ("Yubico YubiKey OTP+FIDO+CCID 00 00".to_string(), Some(2)),
+            ("Yubico YubiKey OTP+FIDO+CCID 01 00".to_string(), Some(3)),
+            ("Other reader".to_string(), None),
+        ];
+        let devices = merge(usb, readers);
+        assert_eq!(devices.len(), 5);
+        assert_eq!(devices[0].usb.as_ref().unwrap().address_id, 2);
+        assert_eq!(devices[0].transports(), vec![TransportKind::Hid]);
+        assert_eq!(devices[2].serial, Some(2));
+        assert_eq!(
+            devices[2].transports(),
+            vec![TransportKind::Hid, TransportKind::Ccid]
+        );
+        assert_eq!(devices[3].serial, Some(3));
+        assert!(!devices[3].is_reachable_through(TransportKind::Hid));
+        assert_eq!(devices[4].serial, None);
+    }
+}
@@ -0,0 +1,257 @@
+//! Challenge-response from embedded USB hosts.
+//!
+//! This module is built on top of the `protocol` module and is available in `no_std` builds. Instead of
+//! depending on a particular USB host stack, it only requires the stack to implement the `UsbHost` trait,
+//! which covers the two HID class requests used by the protocol. Adapting a stack such as `usbh` or
+//! `embassy-usb`'s host support is typically a few lines of code forwarding to its control pipe.
+use protocol::{
+    check_crc, Command, Exchange, Frame, RawResponse, Step, CHALLENGE_SIZE, HID_GET_REPORT, HID_SET_REPORT,
+    REPORT_SIZE, REPORT_TYPE_FEATURE,
+};
+
+/// The number of times the device status is polled before giving up.
+const DEFAULT_MAX_POLLS: u32 = 30_000;
+/// The delay between two polls of the device status, in milliseconds.
+const POLL_INTERVAL_MS: u32 = 1;
+
+/// A USB host stack with a device attached.
+///
+/// Control transfers are class requests targeting an interface, and must use the provided `request`,
+/// `value` and `index` fields as-is.
+pub trait UsbHost {
+    type Error;
+
+    /// Performs a control transfer from the device to the host.
+    fn control_in(&mut self, request: u8, value: u16, index: u16, buf: &mut [u8])
+        -> Result<usize, Self::Error>;
+
+    /// Performs a control transfer from the host to the device.
+    fn control_out(&mut self, request: u8, value: u16, index: u16, data: &[u8]) -> Result<usize, Self::Error>;
+
+    /// Blocks for the given amount of milliseconds.
+    fn delay_ms(&mut self, ms: u32);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error<E> {
+    Usb(E),
+    CanNotWriteToDevice,
+    CanNotReadFromDevice,
+    WrongCRC,
+    Timeout,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(err: E) -> Error<E> {
+        Error::Usb(err)
+    }
+}
+
+/// A device attached to an embedded USB host.
+pub struct EmbeddedDevice<H> {
+    host: H,
+    interface: u16,
+    max_polls: u32,
+}
+
+impl<H: UsbHost> EmbeddedDevice<H> {
+    /// Creates a new device using the OTP HID interface with the given number.
+    pub fn new(host: H, interface: u16) -> Self {
+        EmbeddedDevice {
+            host,
+            interface,
+            max_polls: DEFAULT_MAX_POLLS,
+        }
+    }
+
+    /// Sets how many times the device status is polled before returning `Error::Timeout`.
+    pub fn set_max_polls(mut self, max_polls: u32) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Returns the underlying USB host.
+    pub fn into_inner(self) -> H {
+        self.host
+    }
+
+    fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, Error<H::Error>> {
+        Ok(self
+            .host
+            .control_in(HID_GET_REPORT, REPORT_TYPE_FEATURE << 8, self.interface, report)?)
+    }
+
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), Error<H::Error>> {
+        let written =
+            self.host
+                .control_out(HID_SET_REPORT, REPORT_TYPE_FEATURE << 8, self.interface, report)?;
+        if written != REPORT_SIZE {
+            return Err(Error::CanNotWriteToDevice);
+        }
+        Ok(())
+    }
+
+    /// Sends a command and reads its response, driving an `Exchange` with the host.
+    fn transaction(
+        &mut self,
+        payload: [u8; CHALLENGE_SIZE],
+        command: Command,
+    ) -> Result<RawResponse, Error<H::Error>> {
+        let mut exchange = Exchange::command(&Frame::new(payload, command));
+        let mut report = [0; REPORT_SIZE];
+        let mut polls = 0;
+        loop {
+            match exchange.step() {
+                Step::Read { poll: Some(_) } => {
+                    polls += 1;
+                    if polls >= self.max_polls {
+                        return Err(Error::Timeout);
+                    }
+                    self.host.delay_ms(POLL_INTERVAL_MS);
+                    let read = self.read_report(&mut report)?;
+                    exchange.report_read(&report, read);
+                }
+                Step::Read { poll: None } => {
+                    let read = self.read_report(&mut report)?;
+                    exchange.report_read(&report, read);
+                }
+                Step::Ready(_) => exchange.proceed(),
+                Step::Write(report) => {
+                    self.write_report(&report)?;
+                    exchange.report_written();
+                    polls = 0;
+                }
+                Step::Done => return Ok(exchange.into_response()),
+                Step::Stuck => return Err(Error::Timeout),
+            }
+        }
+    }
+
+    /// Performs an HMAC-SHA1 challenge-response. `command` must be either `Command::ChallengeHmac1` or
+    /// `Command::ChallengeHmac2`. See `Config::variable` for the meaning of `variable`.
+    pub fn challenge_response_hmac(
+        &mut self,
+        chall: &[u8],
+        command: Command,
+        variable: bool,
+    ) -> Result<[u8; 20], Error<H::Error>> {
+        let mut challenge = [0; CHALLENGE_SIZE];
+        if variable && chall.last() == Some(&0) {
+            challenge = [0xff; CHALLENGE_SIZE];
+        }
+        challenge[..chall.len()].copy_from_slice(chall);
+
+        let raw = self.transaction(challenge, command)?;
+        let response = raw.data();
+        if response.len() < 22 {
+            return Err(Error::CanNotReadFromDevice);
+        }
+        if !check_crc(&response[..22]) {
+            return Err(Error::WrongCRC);
+        }
+
+        let mut hmac = [0; 20];
+        hmac.copy_from_slice(&response[..20]);
+        Ok(hmac)
+    }
+
+    /// Performs a Yubico OTP challenge-response. `command` must be either `Command::ChallengeOtp1` or
+    /// `Command::ChallengeOtp2`. The returned block can be decrypted with the slot's AES key.
+    pub fn challenge_response_otp(
+        &mut self,
+        chall: &[u8],
+        command: Command,
+    ) -> Result<[u8; 16], Error<H::Error>> {
+        let mut challenge = [0; CHALLENGE_SIZE];
+        challenge[..chall.len()].copy_from_slice(chall);
+
+        let raw = self.transaction(challenge, command)?;
+        let response = raw.data();
+        if response.len() < 18 {
+            return Err(Error::CanNotReadFromDevice);
+        }
+        if !check_crc(&response[..18]) {
+            return Err(Error::WrongCRC);
+        }
+
+        let mut block = [0; 16];
+        block.copy_from_slice(&response[..16]);
+        Ok(block)
+    }
+
+    pub fn read_serial_number(&mut self) -> Result<u32, Error<H::Error>> {
+        let raw = self.transaction([0; CHALLENGE_SIZE], Command::DeviceSerial)?;
+        let response = raw.data();
+        if response.len() < 6 {
+            return Err(Error::CanNotReadFromDevice);
+        }
+        if !check_crc(&response[..6]) {
+            return Err(Error::WrongCRC);
+        }
+
+        Ok(u32::from_be_bytes([
+            response[0],
+            response[1],
+            response[2],
+            response[3],
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{crc16, Flags, WRITE_RESET_PAYLOAD};
+
+    /// Answers every frame with the same response.
+    struct FakeHost {
+        response: [u8; 28],
+        next_report: Option<usize>,
+    }
+
+    impl UsbHost for FakeHost {
+        type Error = ();
+
+        fn control_in(&mut self, _: u8, _: u16, _: u16, buf: &mut [u8]) -> Result<usize, ()> {
+            for b in buf.iter_mut() {
+                *b = 0;
+            }
+            if let Some(i) = self.next_report {
+                if i < 4 {
+                    buf[..7].copy_from_slice(&self.response[i * 7..i * 7 + 7]);
+                }
+                buf[7] = Flags::RESP_PENDING_FLAG.bits() | (i as u8 % 4);
+                self.next_report = Some(i + 1);
+            }
+            Ok(buf.len())
+        }
+
+        fn control_out(&mut self, _: u8, _: u16, _: u16, data: &[u8]) -> Result<usize, ()> {
+            if data == WRITE_RESET_PAYLOAD {
+                self.next_report = None;
+            } else if data[7] == Flags::SLOT_WRITE_FLAG.bits() + 9 {
+                self.next_report = Some(0);
+            }
+            Ok(data.len())
+        }
+
+        fn delay_ms(&mut self, _: u32) {}
+    }
+
+    #[test]
+    fn test_read_serial_number() {
+        let mut response = [0; 28];
+        response[..4].copy_from_slice(&1234567u32.to_be_bytes());
+        let crc = 0xffff - crc16(&response[..4]);
+        response[4..6].copy_from_slice(&crc.to_le_bytes());
+
+        let mut device = EmbeddedDevice::new(
+            FakeHost {
+                response,
+                next_report: None,
+            },
+            0,
+        );
+        assert_eq!(device.read_serial_number(), Ok(1234567));
+    }
+}
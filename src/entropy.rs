@@ -0,0 +1,121 @@
+//! A random number generator mixing the responses of an OTP slot with the randomness of the operating
+//! system, so a device participates in the generation of seeds on headless systems.
+//!
+//! The Yubico OTP responses differ at every call, even for the same challenge, as they include the usage
+//! counters, timestamp and random field of the device. Each block of 32 bytes of output is
+//! `SHA-256(domain || counter || OS randomness || OTP response)`, with 32 bytes of OS randomness and the 16
+//! bytes block of the response, still encrypted. The output is at least as unpredictable as the OS
+//! randomness, even if the device is compromised.
+//!
+//! **The `RngCore` methods panic if the device or the OS randomness fails**, e.g. when the device is
+//! unplugged, as `RngCore` can not report errors. Call `MixedRng::try_fill_bytes` to handle the failures,
+//! and only pass the generator where an `RngCore` is required and a panic is acceptable.
+use rand::rngs::OsRng;
+use rand_core::{CryptoRng, RngCore, TryRngCore};
+use sha2::{Digest, Sha256};
+
+use config::Config;
+use error::ChallengeResponseError;
+use ChallengeResponse;
+
+const DOMAIN: &[u8] = b"challenge-response mixed entropy";
+const OS_RANDOM_SIZE: usize = 32;
+const BLOCK_SIZE: usize = 32;
+
+/// A `RngCore` mixing the responses of an OTP slot with OS randomness. Every 32 bytes of output take a
+/// challenge-response, so it is meant for generating seeds and keys rather than bulk randomness. The
+/// `RngCore` methods panic on errors, see `try_fill_bytes`.
+pub struct MixedRng<'a> {
+    cr: &'a mut ChallengeResponse,
+    conf: Config,
+    counter: u64,
+    block: [u8; BLOCK_SIZE],
+    used: usize,
+}
+
+impl<'a> MixedRng<'a> {
+    /// Uses the OTP slot of `conf`, which must not require a touch for unattended use.
+    pub fn new(cr: &'a mut ChallengeResponse, conf: Config) -> Self {
+        MixedRng {
+            cr,
+            conf,
+            counter: 0,
+            block: [0; BLOCK_SIZE],
+            used: BLOCK_SIZE,
+        }
+    }
+
+    fn next_block(&mut self) -> Result<(), ChallengeResponseError> {
+        let mut os_random = [0; OS_RANDOM_SIZE];
+        OsRng
+            .try_fill_bytes(&mut os_random)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        // The challenge only needs to differ between calls, the device adds its own variation.
+        let challenge = self.counter.to_be_bytes();
+        let response = self
+            .cr
+            .challenge_response_otp(&challenge[2..], self.conf.clone())?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(DOMAIN);
+        hasher.update(self.counter.to_be_bytes());
+        hasher.update(os_random);
+        hasher.update(response.block);
+        self.block.copy_from_slice(&hasher.finalize());
+        os_random.fill(0);
+        self.counter += 1;
+        self.used = 0;
+        Ok(())
+    }
+
+    /// Fills `dest` with random bytes, failing if the device or the OS randomness fails. Unlike
+    /// `RngCore::fill_bytes`, it does not panic.
+    pub fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), ChallengeResponseError> {
+        let mut filled = 0;
+        while filled < dest.len() {
+            if self.used == BLOCK_SIZE {
+                self.next_block()?;
+            }
+            let len = (dest.len() - filled).min(BLOCK_SIZE - self.used);
+            dest[filled..filled + len].copy_from_slice(&self.block[self.used..self.used + len]);
+            // The bytes are used only once.
+            self.block[self.used..self.used + len].fill(0);
+            self.used += len;
+            filled += len;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for MixedRng<'a> {
+    fn drop(&mut self) {
+        self.block.fill(0);
+    }
+}
+
+impl<'a> RngCore for MixedRng<'a> {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Panics if the device or the OS randomness fails, as `RngCore` can not report errors. Use
+    /// `try_fill_bytes` to handle them.
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        if let Err(e) = self.try_fill_bytes(dest) {
+            panic!(
+                "The mixed entropy source failed: {}. Use MixedRng::try_fill_bytes to handle the errors",
+                e
+            );
+        }
+    }
+}
+
+impl<'a> CryptoRng for MixedRng<'a> {}
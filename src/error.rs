@@ -1,22 +1,136 @@
+#[cfg(feature = "hidapi")]
+use hidapi::HidError as hidError;
+#[cfg(feature = "keyring")]
+use keyring_crate::Error as keyringError;
+#[cfg(feature = "pcsc")]
+use pcsc::Error as pcscError;
 #[cfg(feature = "rusb")]
 use rusb::Error as usbError;
 use std::error;
 use std::fmt;
 use std::io::Error as ioError;
 
+use config::Slot;
+use protocol::{crc16, CRC_RESIDUAL_OK};
+use usb::DeviceId;
+
+/// The details of a failed CRC check, to tell corrupted transfers apart from misconfigured slots.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrcError {
+    /// The checked bytes, including the CRC. They may hold a valid response, so they are not displayed.
+    pub data: Vec<u8>,
+    /// The CRC residual computed over `data`.
+    pub residual: u16,
+    /// The residual of valid data.
+    pub expected: u16,
+}
+
+impl CrcError {
+    /// Checks the CRC of `data`, which ends with the CRC.
+    pub(crate) fn check(data: &[u8]) -> Result<(), ChallengeResponseError> {
+        let residual = crc16(data);
+        if residual == CRC_RESIDUAL_OK {
+            return Ok(());
+        }
+        Err(ChallengeResponseError::WrongCRC(CrcError {
+            data: data.to_vec(),
+            residual,
+            expected: CRC_RESIDUAL_OK,
+        }))
+    }
+}
+
 #[derive(Debug)]
 pub enum ChallengeResponseError {
     IOError(ioError),
     #[cfg(feature = "rusb")]
     UsbError(usbError),
+    #[cfg(feature = "hidapi")]
+    HidError(hidError),
+    #[cfg(feature = "pcsc")]
+    PcscError(pcscError),
+    #[cfg(feature = "keyring")]
+    KeyringError(keyringError),
     CommandNotSupported,
     DeviceNotFound,
+    /// No longer returned, opening a device fails with `OpenFailed` instead.
     OpenDeviceError,
+    /// The device could not be opened, or its interfaces could not be claimed.
+    OpenFailed(Box<ChallengeResponseError>),
+    /// Polling the status of the device, before sending a command, failed.
+    StatusPollFailed(Box<ChallengeResponseError>),
+    /// Writing the frame of a command failed, at its feature report number `packet_seq`.
+    WriteFrameFailed {
+        packet_seq: usize,
+        source: Box<ChallengeResponseError>,
+    },
+    /// Reading the response of the device failed, after `bytes_read` bytes of it were read.
+    ReadResponseFailed {
+        bytes_read: usize,
+        source: Box<ChallengeResponseError>,
+    },
     CanNotWriteToDevice,
     CanNotReadFromDevice,
-    WrongCRC,
+    WrongCRC(CrcError),
     ConfigNotWritten,
     ListDevicesError,
+    /// The device sent a response which could not be parsed.
+    InvalidResponse,
+    /// The device answered an APDU with the given status word.
+    ApduError(u16),
+    /// Some serialized data could not be parsed.
+    InvalidFormat,
+    /// Some encrypted data could not be decrypted, most likely because the device response was wrong.
+    DecryptionFailed,
+    /// The device with the given serial number was given more than once, where distinct devices are needed.
+    DuplicateDevice(u32),
+    /// A freshly configured slot did not answer a test challenge with the expected response.
+    VerificationFailed,
+    /// A one-time code was rejected by a validator.
+    InvalidCode,
+    /// A one-time code was not checked, because the token had too many failed validations.
+    Throttled,
+    /// The device did not send its response before the response timeout, e.g. because the user did not
+    /// touch it.
+    ResponseTimeout,
+    /// The slot requires the user to touch the device, so it can not be used unattended.
+    TouchRequired,
+    /// No slot of the device requires the user to touch it, so its LED can not be made to blink.
+    NoTouchSlot,
+    /// No slot of the device is configured.
+    NoConfiguredSlot,
+    /// The device does not have the slot with the given number.
+    SlotNotSupported(u8),
+    /// The slot with the given number is already configured, and can not be used for staging a new
+    /// configuration, see `provisioning::apply_profile_staged`.
+    SlotOccupied(u8),
+    /// The requested USB backend is not compiled in, see the `rusb`, `nusb`, `hidapi` and `hidraw`
+    /// features.
+    BackendUnavailable(&'static str),
+    /// Another process holds the lock of the device, see the `lock` module.
+    DeviceBusy,
+    /// The signature of a provisioning profile does not match its content or the trusted key.
+    InvalidSignature,
+    /// The operation is not allowed in FIPS strict mode, for the given reason. See the `fips` module.
+    FipsViolation(&'static str),
+    /// The entry at the given line of a provisioning log does not match the hash chain, see the
+    /// `provisioning_log` module.
+    LogTampered(usize),
+    /// The authenticator does not have an account with the given name, see the `authenticator` module.
+    AccountNotFound(String),
+    /// An operation on a device failed. Returned by all the operations of `ChallengeResponse` on a device,
+    /// so the logs of services using several devices show which one failed. See `root_cause`.
+    DeviceError {
+        device: DeviceId,
+        /// The targeted slot, for the operations on a slot.
+        slot: Option<Slot>,
+        source: Box<ChallengeResponseError>,
+    },
+    /// An interface of the device is held by a kernel driver, which the `DetachPolicy` forbids to detach.
+    InterfaceBusy {
+        interface: u8,
+        driver: Option<String>,
+    },
 }
 
 impl fmt::Display for ChallengeResponseError {
@@ -25,14 +139,92 @@ impl fmt::Display for ChallengeResponseError {
             ChallengeResponseError::IOError(ref err) => write!(f, "IO error: {}", err),
             #[cfg(feature = "rusb")]
             ChallengeResponseError::UsbError(ref err) => write!(f, "USB  error: {}", err),
+            #[cfg(feature = "hidapi")]
+            ChallengeResponseError::HidError(ref err) => write!(f, "HID error: {}", err),
+            #[cfg(feature = "pcsc")]
+            ChallengeResponseError::PcscError(ref err) => write!(f, "PC/SC error: {}", err),
+            #[cfg(feature = "keyring")]
+            ChallengeResponseError::KeyringError(ref err) => write!(f, "Keyring error: {}", err),
             ChallengeResponseError::DeviceNotFound => write!(f, "Device not found"),
             ChallengeResponseError::OpenDeviceError => write!(f, "Can not open device"),
+            ChallengeResponseError::OpenFailed(ref err) => write!(f, "Can not open device: {}", err),
+            ChallengeResponseError::StatusPollFailed(ref err) => {
+                write!(f, "Can not poll the device status: {}", err)
+            }
+            ChallengeResponseError::WriteFrameFailed {
+                packet_seq,
+                ref source,
+            } => write!(f, "Can not write packet {} of the frame: {}", packet_seq, source),
+            ChallengeResponseError::ReadResponseFailed {
+                bytes_read,
+                ref source,
+            } => write!(
+                f,
+                "Can not read the response after {} bytes: {}",
+                bytes_read, source
+            ),
             ChallengeResponseError::CommandNotSupported => write!(f, "Command Not Supported"),
-            ChallengeResponseError::WrongCRC => write!(f, "Wrong CRC"),
+            ChallengeResponseError::WrongCRC(ref e) => write!(
+                f,
+                "Wrong CRC: residual {:04x} instead of {:04x} over {} bytes",
+                e.residual,
+                e.expected,
+                e.data.len()
+            ),
             ChallengeResponseError::CanNotWriteToDevice => write!(f, "Can not write to Device"),
             ChallengeResponseError::CanNotReadFromDevice => write!(f, "Can not read from Device"),
             ChallengeResponseError::ConfigNotWritten => write!(f, "Configuration has failed"),
             ChallengeResponseError::ListDevicesError => write!(f, "Could not list available devices"),
+            ChallengeResponseError::InvalidResponse => write!(f, "Invalid response from device"),
+            ChallengeResponseError::ApduError(sw) => write!(f, "APDU failed with status {:04x}", sw),
+            ChallengeResponseError::InvalidFormat => write!(f, "Invalid format"),
+            ChallengeResponseError::DecryptionFailed => write!(f, "Decryption failed"),
+            ChallengeResponseError::InvalidCode => write!(f, "Invalid one-time code"),
+            ChallengeResponseError::Throttled => write!(f, "Too many failed validations"),
+            ChallengeResponseError::VerificationFailed => write!(f, "The slot failed the test challenge"),
+            ChallengeResponseError::DeviceError {
+                ref device,
+                slot: Some(ref slot),
+                ref source,
+            } => write!(f, "Device {}, slot {}: {}", device, slot.number(), source),
+            ChallengeResponseError::DeviceError {
+                ref device,
+                slot: None,
+                ref source,
+            } => write!(f, "Device {}: {}", device, source),
+            ChallengeResponseError::ResponseTimeout => write!(f, "The device did not respond in time"),
+            ChallengeResponseError::SlotNotSupported(number) => {
+                write!(f, "The device does not support slot {}", number)
+            }
+            ChallengeResponseError::SlotOccupied(number) => write!(f, "Slot {} is already configured", number),
+            ChallengeResponseError::TouchRequired => write!(f, "The slot requires touching the device"),
+            ChallengeResponseError::NoTouchSlot => write!(f, "No slot of the device requires touching it"),
+            ChallengeResponseError::NoConfiguredSlot => write!(f, "No slot of the device is configured"),
+            ChallengeResponseError::BackendUnavailable(name) => {
+                write!(f, "The {} backend is not available in this build", name)
+            }
+            ChallengeResponseError::DeviceBusy => write!(f, "The device is used by another process"),
+            ChallengeResponseError::InvalidSignature => write!(f, "Invalid signature"),
+            ChallengeResponseError::FipsViolation(reason) => write!(f, "Not allowed in FIPS mode: {}", reason),
+            ChallengeResponseError::LogTampered(line) => {
+                write!(f, "The provisioning log was tampered with at line {}", line)
+            }
+            ChallengeResponseError::AccountNotFound(ref name) => write!(f, "No account named {}", name),
+            ChallengeResponseError::InterfaceBusy {
+                interface,
+                driver: Some(ref driver),
+            } => write!(
+                f,
+                "Interface {} is held by the {} kernel driver",
+                interface, driver
+            ),
+            ChallengeResponseError::InterfaceBusy {
+                interface,
+                driver: None,
+            } => write!(f, "Interface {} is held by another driver", interface),
+            ChallengeResponseError::DuplicateDevice(serial) => {
+                write!(f, "Device {} given more than once", serial)
+            }
         }
     }
 }
@@ -42,11 +234,50 @@ impl error::Error for ChallengeResponseError {
         match *self {
             #[cfg(feature = "rusb")]
             ChallengeResponseError::UsbError(ref err) => Some(err),
+            #[cfg(feature = "hidapi")]
+            ChallengeResponseError::HidError(ref err) => Some(err),
+            #[cfg(feature = "pcsc")]
+            ChallengeResponseError::PcscError(ref err) => Some(err),
+            #[cfg(feature = "keyring")]
+            ChallengeResponseError::KeyringError(ref err) => Some(err),
+            ChallengeResponseError::OpenFailed(ref err) | ChallengeResponseError::StatusPollFailed(ref err) => {
+                Some(&**err)
+            }
+            ChallengeResponseError::WriteFrameFailed { ref source, .. }
+            | ChallengeResponseError::ReadResponseFailed { ref source, .. }
+            | ChallengeResponseError::DeviceError { ref source, .. } => Some(&**source),
             _ => None,
         }
     }
 }
 
+impl ChallengeResponseError {
+    /// The error at the origin of this one, without the context added by `DeviceError`, `OpenFailed`,
+    /// `StatusPollFailed`, `WriteFrameFailed` and `ReadResponseFailed`.
+    pub fn root_cause(&self) -> &ChallengeResponseError {
+        match *self {
+            ChallengeResponseError::OpenFailed(ref err)
+            | ChallengeResponseError::StatusPollFailed(ref err)
+            | ChallengeResponseError::WriteFrameFailed { source: ref err, .. }
+            | ChallengeResponseError::ReadResponseFailed { source: ref err, .. }
+            | ChallengeResponseError::DeviceError { source: ref err, .. } => err.root_cause(),
+            _ => self,
+        }
+    }
+
+    /// Adds the device and slot of a failed operation to the error, unless it already has them.
+    pub(crate) fn on_device(self, device: DeviceId, slot: Option<Slot>) -> ChallengeResponseError {
+        match self {
+            e @ ChallengeResponseError::DeviceError { .. } => e,
+            e => ChallengeResponseError::DeviceError {
+                device,
+                slot,
+                source: Box::new(e),
+            },
+        }
+    }
+}
+
 impl From<ioError> for ChallengeResponseError {
     fn from(err: ioError) -> ChallengeResponseError {
         ChallengeResponseError::IOError(err)
@@ -59,3 +290,67 @@ impl From<usbError> for ChallengeResponseError {
         ChallengeResponseError::UsbError(err)
     }
 }
+
+#[cfg(feature = "hidapi")]
+impl From<hidError> for ChallengeResponseError {
+    fn from(err: hidError) -> ChallengeResponseError {
+        ChallengeResponseError::HidError(err)
+    }
+}
+
+#[cfg(feature = "pcsc")]
+impl From<pcscError> for ChallengeResponseError {
+    fn from(err: pcscError) -> ChallengeResponseError {
+        ChallengeResponseError::PcscError(err)
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl From<keyringError> for ChallengeResponseError {
+    fn from(err: keyringError) -> ChallengeResponseError {
+        ChallengeResponseError::KeyringError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc_error() {
+        let mut data = b"response".to_vec();
+        let crc = !crc16(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        assert!(CrcError::check(&data).is_ok());
+
+        data[0] ^= 1;
+        match CrcError::check(&data) {
+            Err(ChallengeResponseError::WrongCRC(e)) => {
+                assert_eq!(e.data, data);
+                assert_eq!(e.residual, crc16(&data));
+                assert_eq!(e.expected, CRC_RESIDUAL_OK);
+            }
+            _ => panic!("the CRC should be wrong"),
+        }
+    }
+
+    #[test]
+    fn test_device_error() {
+        let e = ChallengeResponseError::ReadResponseFailed {
+            bytes_read: 8,
+            source: Box::new(ChallengeResponseError::CanNotReadFromDevice),
+        }
+        .on_device(DeviceId::Serial(1234), Some(Slot::Slot2));
+        assert_eq!(
+            e.to_string(),
+            "Device serial 1234, slot 2: Can not read the response after 8 bytes: Can not read from Device"
+        );
+        assert!(matches!(
+            e.root_cause(),
+            ChallengeResponseError::CanNotReadFromDevice
+        ));
+
+        let e = e.on_device(DeviceId::Serial(5678), None);
+        assert!(e.to_string().starts_with("Device serial 1234, slot 2"));
+    }
+}
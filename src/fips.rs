@@ -0,0 +1,90 @@
+//! Detection of the YubiKey FIPS devices, and the checks of the FIPS strict mode.
+//!
+//! The YubiKey FIPS devices share the product IDs of the YubiKey 4, and are told apart by their firmware,
+//! in the 4.4 series. Their OTP application is in FIPS approved mode only while every configured slot is
+//! protected by an access code.
+//!
+//! With `ChallengeResponse::set_fips_mode`, the operations on devices which are not FIPS devices fail with
+//! `ChallengeResponseError::FipsViolation`, and so do the slot configurations without an access code, which
+//! would take the device out of FIPS approved mode.
+#[cfg(feature = "configuration")]
+use configure::DeviceModeConfig;
+use error::ChallengeResponseError;
+#[cfg(feature = "configuration")]
+use protocol::Command;
+use protocol::Status;
+
+const YUBICO: u16 = 0x1050;
+
+/// Whether a device is a YubiKey FIPS, from its vendor ID and its status.
+pub fn is_fips_device(vendor_id: u16, status: &Status) -> bool {
+    vendor_id == YUBICO && (status.version_major, status.version_minor) == (4, 4)
+}
+
+/// Checks that a device can be used in FIPS strict mode.
+pub fn check_device(vendor_id: u16, status: &Status) -> Result<(), ChallengeResponseError> {
+    if !is_fips_device(vendor_id, status) {
+        return Err(ChallengeResponseError::FipsViolation(
+            "the device is not a FIPS device",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that writing `device_config` with `command` keeps the device in FIPS approved mode: the
+/// configured slots must be protected by an access code. Deleting a slot is allowed.
+#[cfg(feature = "configuration")]
+pub fn check_config(command: Command, device_config: &DeviceModeConfig) -> Result<(), ChallengeResponseError> {
+    let writes_slot = matches!(
+        command,
+        Command::Configuration1 | Command::Configuration2 | Command::Update1 | Command::Update2
+    );
+    if writes_slot && !device_config.is_empty() && device_config.acc_code.iter().all(|&b| b == 0) {
+        return Err(ChallengeResponseError::FipsViolation(
+            "the configured slots must have an access code",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::Flags;
+
+    fn status(major: u8, minor: u8) -> Status {
+        Status {
+            version_major: major,
+            version_minor: minor,
+            version_build: 7,
+            pgm_seq: 0,
+            touch_level: 0,
+            flags: Flags::empty(),
+        }
+    }
+
+    #[test]
+    fn test_fips_device() {
+        assert!(is_fips_device(YUBICO, &status(4, 4)));
+        assert!(!is_fips_device(YUBICO, &status(4, 3)));
+        assert!(!is_fips_device(YUBICO, &status(5, 4)));
+        assert!(check_device(YUBICO, &status(5, 4)).is_err());
+    }
+
+    #[cfg(feature = "configuration")]
+    #[test]
+    fn test_check_config() {
+        use configure::ConfigFlags;
+
+        let mut device_config = DeviceModeConfig::default();
+        // Deleting a slot is allowed.
+        assert!(check_config(Command::Configuration2, &device_config).is_ok());
+        device_config.cfg_flags = ConfigFlags::CHAL_HMAC;
+        assert!(matches!(
+            check_config(Command::Configuration2, &device_config),
+            Err(ChallengeResponseError::FipsViolation(_))
+        ));
+        device_config.acc_code = [1, 2, 3, 4, 5, 6];
+        assert!(check_config(Command::Configuration2, &device_config).is_ok());
+    }
+}
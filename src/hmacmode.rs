@@ -1,5 +1,7 @@
+use protocol::verify_hmac_sha1;
+#[cfg(feature = "rand")]
 use rand::Rng;
-use sec::hmac_sha1;
+use rand_core::CryptoRng;
 use std;
 
 /// Size of the secret used by the HMAC algorithm
@@ -28,7 +30,7 @@ impl std::ops::Deref for Hmac {
 
 impl Hmac {
     pub fn check(&self, key: &HmacKey, challenge: &[u8]) -> bool {
-        &self.0[..] == hmac_sha1(key, challenge)
+        verify_hmac_sha1(&key.0, challenge, &self.0)
     }
 }
 
@@ -46,10 +48,18 @@ impl Drop for HmacKey {
 impl HmacKey {
     pub fn from_slice(s: &[u8]) -> Self {
         let mut key = HmacKey([0; HMAC_SECRET_SIZE]);
-        (&mut key.0).clone_from_slice(s);
+        key.0.clone_from_slice(s);
         key
     }
 
+    /// Generates a random key using the provided cryptographically secure RNG.
+    pub fn from_rng<R: CryptoRng + ?Sized>(rng: &mut R) -> Self {
+        let mut key = HmacKey([0; HMAC_SECRET_SIZE]);
+        rng.fill_bytes(&mut key.0);
+        key
+    }
+
+    #[cfg(feature = "rand")]
     pub fn generate<R: Rng>(mut rng: R) -> Self {
         let mut key = HmacKey([0; HMAC_SECRET_SIZE]);
         for i in key.0.iter_mut() {
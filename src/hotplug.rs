@@ -0,0 +1,179 @@
+//! Notifications of the devices plugged in and unplugged, see `ChallengeResponse::watch_devices`.
+//!
+//! The watcher waits for the hotplug events of the backend, the hotplug callbacks of libusb with rusb and
+//! the device watcher of nusb, and enumerates the supported devices again after each of them, so the events
+//! describe the devices like `ChallengeResponse::find_all_devices`. On the platforms where libusb does not
+//! support hotplug, e.g. Windows, the devices are enumerated every second instead.
+//!
+//! ```no_run
+//! use challenge_response::hotplug::DeviceEvent;
+//! use challenge_response::ChallengeResponse;
+//!
+//! let mut cr = ChallengeResponse::new().unwrap();
+//! for event in cr.watch_devices() {
+//!     match event.unwrap() {
+//!         DeviceEvent::Arrived(device) => println!("Plugged in: {:?}", device.serial),
+//!         DeviceEvent::Removed(device) => println!("Unplugged: {:?}", device.serial),
+//!     }
+//! }
+//! ```
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use error::ChallengeResponseError;
+use usb::Backend;
+use {ChallengeResponse, Device};
+
+/// The longest wait for a hotplug event before enumerating the devices again, in case an event was missed
+/// or the platform does not support hotplug.
+const ENUMERATION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A change of the connected devices.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceEvent {
+    /// A supported device was plugged in.
+    Arrived(Device),
+    /// A device was unplugged. The device is described as it was when it arrived.
+    Removed(Device),
+}
+
+/// Whether `a` and `b` describe the same connection of a device. A device plugged in again gets a new
+/// address, and is reported as a new device.
+fn same_connection(a: &Device, b: &Device) -> bool {
+    a.bus_id == b.bus_id && a.address_id == b.address_id
+}
+
+/// The events turning the list of devices `previous` into `current`: the removals first, then the arrivals.
+fn diff(previous: &[Device], current: &[Device]) -> Vec<DeviceEvent> {
+    let removed = previous
+        .iter()
+        .filter(|p| !current.iter().any(|c| same_connection(p, c)))
+        .map(|p| DeviceEvent::Removed(p.clone()));
+    let arrived = current
+        .iter()
+        .filter(|c| !previous.iter().any(|p| same_connection(p, c)))
+        .map(|c| DeviceEvent::Arrived(c.clone()));
+    removed.chain(arrived).collect()
+}
+
+/// Watches the devices plugged in and unplugged, returned by `ChallengeResponse::watch_devices`.
+///
+/// The devices already connected when the watcher is created are reported as arrived first. As an iterator,
+/// the watcher blocks until the next event, and never ends; an enumeration error is returned as an item,
+/// after which the watcher can still be used.
+pub struct DeviceWatcher<'a> {
+    cr: &'a mut ChallengeResponse,
+    devices: Vec<Device>,
+    pending: VecDeque<DeviceEvent>,
+    /// Whether the devices must be enumerated before waiting for the next hotplug event.
+    stale: bool,
+}
+
+impl<'a> DeviceWatcher<'a> {
+    pub(crate) fn new(cr: &'a mut ChallengeResponse) -> Self {
+        DeviceWatcher {
+            cr,
+            devices: Vec::new(),
+            pending: VecDeque::new(),
+            stale: true,
+        }
+    }
+
+    /// The devices connected at the last enumeration, which is done by `next_event`.
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// Enumerates the devices, and queues the events since the last enumeration.
+    fn scan(&mut self) -> Result<(), ChallengeResponseError> {
+        let current = match self.cr.find_all_devices() {
+            Ok(devices) => devices,
+            Err(ChallengeResponseError::DeviceNotFound) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        self.pending.extend(diff(&self.devices, &current));
+        self.devices = current;
+        self.stale = false;
+        Ok(())
+    }
+
+    /// Waits for the next event, for at most `timeout`, or forever if `timeout` is `None`. Returns `None` if
+    /// no device was plugged in or unplugged in time.
+    pub fn next_event(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<DeviceEvent>, ChallengeResponseError> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if self.stale {
+                self.scan()?;
+            }
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+            let mut wait = ENUMERATION_INTERVAL;
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(None);
+                }
+                wait = cmp::min(wait, deadline - now);
+            }
+            self.cr.backend.wait_for_change(wait)?;
+            self.stale = true;
+        }
+    }
+}
+
+impl<'a> Iterator for DeviceWatcher<'a> {
+    type Item = Result<DeviceEvent, ChallengeResponseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_event(None) {
+            Ok(event) => event.map(Ok),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(serial: u32, address_id: u8) -> Device {
+        Device {
+            name: None,
+            serial: Some(serial),
+            product_id: 0x0407,
+            vendor_id: 0x1050,
+            bus_id: 1,
+            address_id,
+            port_path: None,
+            manufacturer: None,
+            release: 0x0543,
+            interfaces: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let first = device(1, 2);
+        let second = device(2, 3);
+        assert_eq!(
+            diff(&[], &[first.clone(), second.clone()]),
+            vec![
+                DeviceEvent::Arrived(first.clone()),
+                DeviceEvent::Arrived(second.clone())
+            ]
+        );
+        let unchanged = vec![first.clone()];
+        assert!(diff(&unchanged, &unchanged).is_empty());
+        // The first device was plugged in again, at a new address.
+        let replugged = device(1, 4);
+        assert_eq!(
+            diff(&[first.clone(), second.clone()], &[second, replugged.clone()]),
+            vec![DeviceEvent::Removed(first), DeviceEvent::Arrived(replugged)]
+        );
+    }
+}
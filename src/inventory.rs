@@ -0,0 +1,420 @@
+//! Inventory of the connected devices, for asset-management tooling.
+//!
+//! `collect` returns a record for every connected device, with its serial number, model, firmware version
+//! and the state of its slots. The records can be exported as JSON with `to_json`, or as CSV with `to_csv`.
+//! Long-running services collecting the inventory repeatedly can keep the records in an `InventoryCache`,
+//! which only reads the status of the devices it has not seen yet.
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use error::ChallengeResponseError;
+use fips;
+use protocol::{Command, Status};
+use vendor;
+use {ChallengeResponse, Device};
+
+pub use protocol::SlotState;
+
+/// The header of the CSV export, matching the fields of `InventoryRecord::to_csv_row`.
+pub const CSV_HEADER: &str = "serial,model,vendor_id,product_id,firmware,slot1,slot2,capabilities";
+
+bitflags! {
+    /// The features of a device, inferred from its firmware version with the `quirks` table, as the devices do
+    /// not report them.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Capabilities: u8 {
+        /// HMAC-SHA1 and Yubico OTP challenge-response, from firmware 2.2.
+        const CHALLENGE_RESPONSE = 0x01;
+        /// Updating and swapping the slot configurations, from firmware 2.3.
+        const SLOT_UPDATE = 0x02;
+        /// Reading the serial number.
+        const SERIAL_NUMBER = 0x04;
+        /// A YubiKey FIPS, see the `fips` module.
+        const FIPS = 0x08;
+    }
+}
+
+fn slot_state_str(state: Option<SlotState>) -> &'static str {
+    match state {
+        None => "unknown",
+        Some(SlotState::Empty) => "empty",
+        Some(SlotState::Configured { require_touch: false }) => "configured",
+        Some(SlotState::Configured { require_touch: true }) => "configured-touch",
+    }
+}
+
+/// The inventory record of a device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InventoryRecord {
+    pub serial: Option<u32>,
+    /// The product name reported by the device, or else the name of its model in `DeviceModel::all`.
+    pub model: Option<String>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// The major, minor and build numbers of the firmware, if the status of the device could be read.
+    pub firmware: Option<(u8, u8, u8)>,
+    /// The state of the slots, if the status of the device could be read.
+    pub slot1: Option<SlotState>,
+    pub slot2: Option<SlotState>,
+    pub capabilities: Capabilities,
+}
+
+impl InventoryRecord {
+    pub(crate) fn new(device: &Device, status: Option<Status>) -> InventoryRecord {
+        let mut record = InventoryRecord {
+            serial: device.serial,
+            model: device
+                .name
+                .clone()
+                .or_else(|| device.model().map(|m| m.name.to_string())),
+            vendor_id: device.vendor_id,
+            product_id: device.product_id,
+            firmware: None,
+            slot1: None,
+            slot2: None,
+            capabilities: Capabilities::empty(),
+        };
+        if device.serial.is_some() {
+            record.capabilities |= Capabilities::SERIAL_NUMBER;
+        }
+        if let Some(status) = status {
+            let version = (status.version_major, status.version_minor, status.version_build);
+            record.firmware = Some(version);
+            record.slot1 = Some(status.slot1());
+            record.slot2 = Some(status.slot2());
+            if vendor::supports_command(
+                device.vendor_id,
+                device.product_id,
+                &status,
+                Command::ChallengeHmac2,
+            ) {
+                record.capabilities |= Capabilities::CHALLENGE_RESPONSE;
+            }
+            if vendor::supports_slot_update(device.vendor_id, device.product_id, &status) {
+                record.capabilities |= Capabilities::SLOT_UPDATE;
+            }
+            if fips::is_fips_device(device.vendor_id, &status) {
+                record.capabilities |= Capabilities::FIPS;
+            }
+        }
+        record
+    }
+
+    fn firmware_string(&self) -> Option<String> {
+        self.firmware
+            .map(|(major, minor, build)| format!("{}.{}.{}", major, minor, build))
+    }
+
+    fn capabilities_string(&self) -> String {
+        let mut s = String::new();
+        bitflags::parser::to_writer(&self.capabilities, &mut s).expect("writing to a String can not fail");
+        s
+    }
+
+    /// Serializes the record as a JSON object. Missing values are `null`.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{");
+        match self.serial {
+            Some(serial) => write!(json, "\"serial\":{},", serial),
+            None => write!(json, "\"serial\":null,"),
+        }
+        .expect("writing to a String can not fail");
+        json.push_str("\"model\":");
+        push_json_string(&mut json, self.model.as_deref());
+        write!(
+            json,
+            ",\"vendor_id\":{},\"product_id\":{},\"firmware\":",
+            self.vendor_id, self.product_id
+        )
+        .expect("writing to a String can not fail");
+        push_json_string(&mut json, self.firmware_string().as_deref());
+        json.push_str(",\"slot1\":");
+        push_json_string(&mut json, Some(slot_state_str(self.slot1)));
+        json.push_str(",\"slot2\":");
+        push_json_string(&mut json, Some(slot_state_str(self.slot2)));
+        json.push_str(",\"capabilities\":[");
+        for (i, (name, _)) in self.capabilities.iter_names().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_json_string(&mut json, Some(name));
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Serializes the record as a CSV row, with the columns of `CSV_HEADER`. Missing values are empty.
+    pub fn to_csv_row(&self) -> String {
+        let fields = [
+            self.serial.map(|s| s.to_string()).unwrap_or_default(),
+            self.model.clone().unwrap_or_default(),
+            format!("{:04x}", self.vendor_id),
+            format!("{:04x}", self.product_id),
+            self.firmware_string().unwrap_or_default(),
+            slot_state_str(self.slot1).to_string(),
+            slot_state_str(self.slot2).to_string(),
+            self.capabilities_string(),
+        ];
+        fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(",")
+    }
+}
+
+fn push_json_string(json: &mut String, value: Option<&str>) {
+    let value = match value {
+        Some(value) => value,
+        None => {
+            json.push_str("null");
+            return;
+        }
+    };
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(json, "\\u{:04x}", c as u32).expect("writing to a String can not fail");
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Quotes a CSV field if needed, as described in RFC 4180.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Collects the inventory records of all the connected devices. The firmware and slot states of the
+/// devices whose status can not be read are left unknown. Returns an empty inventory if no device is
+/// connected.
+pub fn collect(cr: &mut ChallengeResponse) -> Result<Vec<InventoryRecord>, ChallengeResponseError> {
+    let devices = match cr.find_all_devices() {
+        Ok(devices) => devices,
+        Err(ChallengeResponseError::DeviceNotFound) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(devices
+        .iter()
+        .map(|device| InventoryRecord::new(device, cr.read_status(device).ok()))
+        .collect())
+}
+
+/// Caches the inventory records of the devices with a serial number, so collecting the inventory again
+/// does not read the status of every device. A record is read again after `invalidate`, after its max age,
+/// or after its device was unplugged: `collect` forgets the devices which are not connected. Reconfiguring
+/// a slot changes its state, so the record of the device should then be invalidated.
+#[derive(Clone, Debug, Default)]
+pub struct InventoryCache {
+    records: HashMap<u32, (InventoryRecord, Instant)>,
+    max_age: Option<Duration>,
+}
+
+impl InventoryCache {
+    /// A cache keeping the records until they are invalidated.
+    pub fn new() -> Self {
+        InventoryCache::default()
+    }
+
+    /// Sets how long the records are kept, or `None` to keep them until they are invalidated.
+    pub fn set_max_age(&mut self, max_age: Option<Duration>) {
+        self.max_age = max_age;
+    }
+
+    /// The cached record of the device with the given serial number, if it is still valid.
+    pub fn get(&self, serial: u32) -> Option<&InventoryRecord> {
+        self.records
+            .get(&serial)
+            .filter(|(_, cached)| self.max_age.is_none_or(|age| cached.elapsed() < age))
+            .map(|(record, _)| record)
+    }
+
+    /// Forgets the record of the device with the given serial number.
+    pub fn invalidate(&mut self, serial: u32) {
+        self.records.remove(&serial);
+    }
+
+    /// Forgets all the records.
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    fn lookup<F>(&mut self, device: &Device, read_status: F) -> InventoryRecord
+    where
+        F: FnOnce() -> Option<Status>,
+    {
+        let serial = match device.serial {
+            Some(serial) => serial,
+            None => return InventoryRecord::new(device, read_status()),
+        };
+        // The same key switched to another mode has another product ID, and maybe other capabilities.
+        if let Some(record) = self
+            .get(serial)
+            .filter(|r| r.vendor_id == device.vendor_id && r.product_id == device.product_id)
+        {
+            return record.clone();
+        }
+        let record = InventoryRecord::new(device, read_status());
+        // The devices whose status could not be read are tried again next time.
+        if record.firmware.is_some() {
+            self.records.insert(serial, (record.clone(), Instant::now()));
+        } else {
+            self.records.remove(&serial);
+        }
+        record
+    }
+
+    /// The record of a device, read from the device if it is not cached.
+    pub fn record(&mut self, cr: &mut ChallengeResponse, device: &Device) -> InventoryRecord {
+        self.lookup(device, || cr.read_status(device).ok())
+    }
+
+    fn retain_connected(&mut self, devices: &[Device]) {
+        self.records
+            .retain(|serial, _| devices.iter().any(|d| d.serial == Some(*serial)));
+    }
+
+    /// Collects the inventory records of all the connected devices like `collect`, reading the status of
+    /// the devices which are not cached only.
+    pub fn collect(
+        &mut self,
+        cr: &mut ChallengeResponse,
+    ) -> Result<Vec<InventoryRecord>, ChallengeResponseError> {
+        let devices = match cr.find_all_devices() {
+            Ok(devices) => devices,
+            Err(ChallengeResponseError::DeviceNotFound) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        self.retain_connected(&devices);
+        Ok(devices.iter().map(|device| self.record(cr, device)).collect())
+    }
+}
+
+/// Serializes records as a JSON array.
+pub fn to_json(records: &[InventoryRecord]) -> String {
+    let objects: Vec<String> = records.iter().map(InventoryRecord::to_json).collect();
+    format!("[{}]", objects.join(","))
+}
+
+/// Serializes records as CSV, starting with `CSV_HEADER`.
+pub fn to_csv(records: &[InventoryRecord]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push_str("\r\n");
+    for record in records {
+        csv.push_str(&record.to_csv_row());
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::Flags;
+
+    fn record() -> InventoryRecord {
+        let device = Device {
+            name: Some("YubiKey \"5\", NFC".to_string()),
+            serial: Some(1234),
+            product_id: 0x0407,
+            vendor_id: 0x1050,
+            bus_id: 1,
+            address_id: 2,
+            port_path: None,
+            manufacturer: None,
+            release: 0x0543,
+            interfaces: Vec::new(),
+        };
+        let status = Status {
+            version_major: 5,
+            version_minor: 4,
+            version_build: 3,
+            pgm_seq: 1,
+            touch_level: 0x0a,
+            flags: Flags::empty(),
+        };
+        InventoryRecord::new(&device, Some(status))
+    }
+
+    #[test]
+    fn test_record() {
+        let record = record();
+        assert_eq!(record.firmware, Some((5, 4, 3)));
+        assert_eq!(record.slot1, Some(SlotState::Empty));
+        assert_eq!(record.slot2, Some(SlotState::Configured { require_touch: true }));
+        assert!(record
+            .capabilities
+            .contains(Capabilities::CHALLENGE_RESPONSE | Capabilities::SLOT_UPDATE));
+    }
+
+    #[test]
+    fn test_cache() {
+        let record = record();
+        let device = Device {
+            name: record.model.clone(),
+            serial: Some(1234),
+            product_id: 0x0407,
+            vendor_id: 0x1050,
+            bus_id: 1,
+            address_id: 2,
+            port_path: None,
+            manufacturer: None,
+            release: 0x0543,
+            interfaces: Vec::new(),
+        };
+        let status = || {
+            Some(Status {
+                version_major: 5,
+                version_minor: 4,
+                version_build: 3,
+                pgm_seq: 1,
+                touch_level: 0x0a,
+                flags: Flags::empty(),
+            })
+        };
+        let mut cache = InventoryCache::new();
+        assert_eq!(cache.lookup(&device, status), record);
+        // The status is not read again while the record is cached.
+        assert_eq!(cache.lookup(&device, || panic!("the record is cached")), record);
+
+        cache.invalidate(1234);
+        assert_eq!(cache.lookup(&device, || None).firmware, None);
+        assert!(cache.get(1234).is_none());
+
+        cache.lookup(&device, status);
+        cache.retain_connected(&[]);
+        assert!(cache.get(1234).is_none());
+
+        cache.lookup(&device, status);
+        cache.set_max_age(Some(Duration::from_secs(0)));
+        assert!(cache.get(1234).is_none());
+    }
+
+    #[test]
+    fn test_export() {
+        let records = [record()];
+        assert_eq!(
+            to_json(&records),
+            "[{\"serial\":1234,\"model\":\"YubiKey \\\"5\\\", NFC\",\"vendor_id\":4176,\"product_id\":1031,\
+             \"firmware\":\"5.4.3\",\"slot1\":\"empty\",\"slot2\":\"configured-touch\",\
+             \"capabilities\":[\"CHALLENGE_RESPONSE\",\"SLOT_UPDATE\",\"SERIAL_NUMBER\"]}]"
+        );
+        assert_eq!(
+            to_csv(&records),
+            format!(
+                "{}\r\n1234,\"YubiKey \"\"5\"\", NFC\",1050,0407,5.4.3,empty,configured-touch,\
+                 CHALLENGE_RESPONSE | SLOT_UPDATE | SERIAL_NUMBER\r\n",
+                CSV_HEADER
+            )
+        );
+    }
+}
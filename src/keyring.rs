@@ -0,0 +1,151 @@
+//! Caching of challenge-derived secrets in the OS keyring.
+//!
+//! Deriving a secret from a challenge-response may require the user to touch the device. Applications
+//! which need the secret repeatedly (for example to unlock a database) can cache the derived secret in the
+//! keyring of the operating system for a bounded amount of time, instead of prompting on every unlock.
+//! Only derived secrets should be cached this way, never the secrets programmed in the slots.
+//!
+//! On Linux, the kernel keyutils session keyring is used, so cached secrets never touch the disk.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use keyring_crate::{Entry, Error as KeyringError};
+
+use error::ChallengeResponseError;
+
+const EXPIRY_SIZE: usize = 8;
+
+/// A secret retrieved from the cache.
+pub struct CachedSecret(pub Vec<u8>);
+
+impl Drop for CachedSecret {
+    fn drop(&mut self) {
+        for i in self.0.iter_mut() {
+            *i = 0;
+        }
+    }
+}
+
+impl std::ops::Deref for CachedSecret {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Prefixes the secret with its expiry time, as seconds since the UNIX epoch.
+fn encode(secret: &[u8], expiry: u64) -> Vec<u8> {
+    let mut value = Vec::with_capacity(EXPIRY_SIZE + secret.len());
+    value.extend_from_slice(&expiry.to_be_bytes());
+    value.extend_from_slice(secret);
+    value
+}
+
+/// Returns the secret if it is not expired at the time `now`.
+fn decode(mut value: Vec<u8>, now: u64) -> Option<CachedSecret> {
+    if value.len() < EXPIRY_SIZE {
+        return None;
+    }
+    let mut expiry = [0; EXPIRY_SIZE];
+    expiry.copy_from_slice(&value[..EXPIRY_SIZE]);
+    let secret = CachedSecret(value.split_off(EXPIRY_SIZE));
+    for i in value.iter_mut() {
+        *i = 0;
+    }
+    if u64::from_be_bytes(expiry) <= now {
+        return None;
+    }
+    Some(secret)
+}
+
+/// A cache of derived secrets, stored in the OS keyring under a service name.
+pub struct SecretCache {
+    service: String,
+    lifetime: Duration,
+}
+
+impl SecretCache {
+    /// Creates a cache storing entries under `service`. Entries expire `lifetime` after being stored.
+    pub fn new(service: &str, lifetime: Duration) -> Self {
+        SecretCache {
+            service: service.to_string(),
+            lifetime,
+        }
+    }
+
+    /// The name of the entry for a secret derived from the device with the given serial number.
+    /// `label` identifies what the secret is used for.
+    pub fn entry_name(serial: u32, label: &str) -> String {
+        format!("{}:{}", serial, label)
+    }
+
+    fn entry(&self, name: &str) -> Result<Entry, ChallengeResponseError> {
+        Ok(Entry::new(&self.service, name)?)
+    }
+
+    pub fn store(&self, name: &str, secret: &[u8]) -> Result<(), ChallengeResponseError> {
+        let mut value = encode(secret, now().saturating_add(self.lifetime.as_secs()));
+        let result = self.entry(name)?.set_secret(&value);
+        for i in value.iter_mut() {
+            *i = 0;
+        }
+        Ok(result?)
+    }
+
+    /// Retrieves a secret from the cache. Returns `None` if the secret is not in the cache, or if it
+    /// expired, in which case it is also removed from the keyring.
+    pub fn retrieve(&self, name: &str) -> Result<Option<CachedSecret>, ChallengeResponseError> {
+        let entry = self.entry(name)?;
+        let value = match entry.get_secret() {
+            Ok(v) => v,
+            Err(KeyringError::NoEntry) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match decode(value, now()) {
+            Some(secret) => Ok(Some(secret)),
+            None => {
+                self.remove(name)?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), ChallengeResponseError> {
+        match self.entry(name)?.delete_credential() {
+            Ok(()) | Err(KeyringError::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Retrieves a secret from the cache, or derives it with `derive` and caches it if it is missing.
+    pub fn get_or_derive<F>(&self, name: &str, derive: F) -> Result<CachedSecret, ChallengeResponseError>
+    where
+        F: FnOnce() -> Result<Vec<u8>, ChallengeResponseError>,
+    {
+        if let Some(secret) = self.retrieve(name)? {
+            return Ok(secret);
+        }
+        let secret = CachedSecret(derive()?);
+        self.store(name, &secret)?;
+        Ok(secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry() {
+        let value = encode(b"secret", 1000);
+        assert_eq!(&*decode(value.clone(), 999).unwrap(), b"secret");
+        assert!(decode(value, 1000).is_none());
+        assert!(decode(vec![0; 4], 0).is_none());
+    }
+}
@@ -0,0 +1,105 @@
+//! Deprecated wrappers with the names of the former API, for migrating code written against it
+//! incrementally. `Yubico` wraps a `ChallengeResponse`, and its methods forward to the current methods:
+//!
+//! | Former API                    | Current API                                 |
+//! |-------------------------------|---------------------------------------------|
+//! | `Yubico::new`                 | `ChallengeResponse::new`                    |
+//! | `find_yubikey`                | `ChallengeResponse::find_device`            |
+//! | `find_yubikey_from_serial`    | `ChallengeResponse::find_device_from_serial` |
+//! | `find_all_yubikeys`           | `ChallengeResponse::find_all_devices`       |
+//! | `Yubikey`                     | `Device`                                    |
+//!
+//! As with the former API, `Yubico::new` does not fail: if no USB backend can be initialized, the error
+//! is returned by the first method called instead, after trying to initialize the backend again.
+#![allow(deprecated)]
+
+use config::Config;
+#[cfg(feature = "configuration")]
+use configure::DeviceModeConfig;
+use error::ChallengeResponseError;
+use hmacmode::Hmac;
+#[cfg(feature = "otp")]
+use otpmode::Aes128Block;
+use {ChallengeResponse, Device};
+
+/// The former name of `Device`.
+#[deprecated(note = "use `Device`")]
+pub type Yubikey = Device;
+
+/// The former entry point of the crate, see the module documentation.
+#[deprecated(note = "use `ChallengeResponse`")]
+#[derive(Default)]
+pub struct Yubico {
+    cr: Option<ChallengeResponse>,
+}
+
+impl Yubico {
+    #[deprecated(note = "use `ChallengeResponse::new`")]
+    pub fn new() -> Self {
+        Yubico {
+            cr: ChallengeResponse::new().ok(),
+        }
+    }
+
+    fn cr(&mut self) -> Result<&mut ChallengeResponse, ChallengeResponseError> {
+        if self.cr.is_none() {
+            self.cr = Some(ChallengeResponse::new()?);
+        }
+        Ok(self.cr.as_mut().expect("the backend was initialized"))
+    }
+
+    /// Returns the wrapped `ChallengeResponse`, to migrate the rest of the code.
+    pub fn into_inner(mut self) -> Result<ChallengeResponse, ChallengeResponseError> {
+        self.cr()?;
+        Ok(self.cr.take().expect("the backend was initialized"))
+    }
+
+    #[deprecated(note = "use `ChallengeResponse::find_device`")]
+    pub fn find_yubikey(&mut self) -> Result<Yubikey, ChallengeResponseError> {
+        self.cr()?.find_device()
+    }
+
+    #[deprecated(note = "use `ChallengeResponse::find_device_from_serial`")]
+    pub fn find_yubikey_from_serial(&mut self, serial: u32) -> Result<Yubikey, ChallengeResponseError> {
+        self.cr()?.find_device_from_serial(serial)
+    }
+
+    #[deprecated(note = "use `ChallengeResponse::find_all_devices`")]
+    pub fn find_all_yubikeys(&mut self) -> Result<Vec<Yubikey>, ChallengeResponseError> {
+        self.cr()?.find_all_devices()
+    }
+
+    #[deprecated(note = "use `ChallengeResponse::read_serial_number`")]
+    pub fn read_serial_number(&mut self, conf: Config) -> Result<u32, ChallengeResponseError> {
+        self.cr()?.read_serial_number(conf)
+    }
+
+    #[deprecated(note = "use `ChallengeResponse::challenge_response_hmac`")]
+    pub fn challenge_response_hmac(
+        &mut self,
+        chall: &[u8],
+        conf: Config,
+    ) -> Result<Hmac, ChallengeResponseError> {
+        self.cr()?.challenge_response_hmac(chall, conf)
+    }
+
+    #[cfg(feature = "otp")]
+    #[deprecated(note = "use `ChallengeResponse::challenge_response_otp`")]
+    pub fn challenge_response_otp(
+        &mut self,
+        chall: &[u8],
+        conf: Config,
+    ) -> Result<Aes128Block, ChallengeResponseError> {
+        self.cr()?.challenge_response_otp(chall, conf)
+    }
+
+    #[cfg(feature = "configuration")]
+    #[deprecated(note = "use `ChallengeResponse::write_config`")]
+    pub fn write_config(
+        &mut self,
+        conf: Config,
+        device_config: &mut DeviceModeConfig,
+    ) -> Result<(), ChallengeResponseError> {
+        self.cr()?.write_config(conf, device_config)
+    }
+}
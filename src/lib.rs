@@ -1,57 +1,527 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![doc = include_str!("../README.md")]
+#![deny(unsafe_code)]
 
-#[cfg(not(any(feature = "rusb", feature = "nusb")))]
-compile_error!("Either the rusb or nusb feature must be enabled for this crate");
+#[cfg(all(
+    feature = "hidraw",
+    not(any(feature = "rusb", feature = "nusb", feature = "hidapi")),
+    not(any(target_os = "linux", target_os = "android"))
+))]
+compile_error!("The hidraw backend is only available on Linux");
 
+#[cfg(feature = "nusb")]
+extern crate futures_core;
+#[cfg(feature = "hidapi")]
+extern crate hidapi;
+#[cfg(feature = "keyring")]
+extern crate keyring as keyring_crate;
+#[cfg(feature = "hidraw")]
+extern crate libc;
 #[cfg(feature = "nusb")]
 extern crate nusb;
+#[cfg(feature = "pcsc")]
+extern crate pcsc;
 #[cfg(feature = "rusb")]
 extern crate rusb;
 
-#[macro_use]
-extern crate structure;
+#[cfg(feature = "std")]
+extern crate core;
 
+#[cfg(feature = "otp")]
 extern crate aes;
+#[cfg(feature = "otp")]
 extern crate block_modes;
+#[cfg(any(feature = "software-token", feature = "wrap"))]
+extern crate chacha20poly1305;
+#[cfg(feature = "mac")]
+extern crate digest;
+#[cfg(any(feature = "signed-profiles", feature = "ssh"))]
+extern crate ed25519_dalek;
+#[cfg(any(feature = "derive", feature = "ssh", feature = "wrap"))]
+extern crate hkdf;
 extern crate hmac;
+#[cfg(feature = "password-hash")]
+extern crate password_hash;
+#[cfg(any(test, feature = "software-token"))]
+extern crate pbkdf2;
+#[cfg(feature = "rand")]
 extern crate rand;
+extern crate rand_core;
 extern crate sha1;
+#[cfg(any(
+    test,
+    feature = "derive",
+    feature = "entropy",
+    feature = "mnemonic",
+    feature = "password-hash",
+    feature = "provisioning-log",
+    feature = "software-token",
+    feature = "ssh",
+    feature = "wrap"
+))]
+extern crate sha2;
 #[macro_use]
 extern crate bitflags;
 
+#[cfg(feature = "async")]
+pub mod asynchronous;
+#[cfg(all(feature = "std", feature = "configuration"))]
+pub mod audit;
+#[cfg(feature = "authenticator")]
+pub mod authenticator;
+#[cfg(feature = "std")]
+mod base64;
+#[cfg(feature = "std")]
+pub mod builder;
+pub mod clock;
+#[cfg(feature = "std")]
 pub mod config;
+#[cfg(all(feature = "std", feature = "configuration"))]
 pub mod configure;
+#[cfg(feature = "derive")]
+pub mod derive;
+#[cfg(feature = "std")]
+pub mod diagnostics;
+#[cfg(feature = "std")]
+pub mod discovery;
+pub mod embedded;
+#[cfg(feature = "entropy")]
+pub mod entropy;
+#[cfg(feature = "std")]
 pub mod error;
+#[cfg(feature = "std")]
+pub mod fips;
+#[cfg(feature = "std")]
 pub mod hmacmode;
+#[cfg(feature = "std")]
+pub mod hotplug;
+#[cfg(feature = "std")]
+pub mod inventory;
+#[cfg(feature = "keyring")]
+pub mod keyring;
+#[cfg(feature = "legacy")]
+pub mod legacy;
+#[cfg(feature = "std")]
+pub mod lock;
+#[cfg(feature = "mac")]
+pub mod mac;
+#[cfg(feature = "mnemonic")]
+pub mod mnemonic;
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(feature = "std")]
+pub mod modhex;
+#[cfg(feature = "pcsc")]
+pub mod oath;
+#[cfg(feature = "std")]
+pub mod observer;
+#[cfg(feature = "std")]
+pub mod otpauth;
+#[cfg(feature = "otp")]
 pub mod otpmode;
+#[cfg(feature = "password-hash")]
+pub mod phc;
+pub mod protocol;
+#[cfg(all(feature = "std", feature = "configuration"))]
+pub mod provisioning;
+#[cfg(feature = "provisioning-log")]
+pub mod provisioning_log;
+pub mod quirks;
 mod sec;
+#[cfg(feature = "std")]
+pub mod selection;
+#[cfg(all(feature = "setup", target_os = "linux"))]
+pub mod setup;
+#[cfg(feature = "std")]
+pub mod simple;
+#[cfg(feature = "software-token")]
+pub mod software;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+#[cfg(feature = "std")]
+pub mod state;
+#[cfg(feature = "std")]
+pub mod token;
+#[cfg(feature = "std")]
+pub mod touch;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod transform;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
 mod usb;
+#[cfg(feature = "std")]
+pub mod validation;
+#[cfg(feature = "vault")]
+pub mod vault;
+#[cfg(feature = "std")]
+pub mod vendor;
+#[cfg(feature = "wireguard")]
+pub mod wireguard;
+#[cfg(feature = "wrap")]
+pub mod wrap;
+
+#[cfg(feature = "std")]
+use std::cmp;
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::net::TcpStream;
+#[cfg(feature = "std")]
+use std::path::PathBuf;
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(all(feature = "std", feature = "configuration"))]
+use std::time::SystemTime;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
+#[cfg(feature = "otp")]
 use aes::cipher::generic_array::GenericArray;
 
+#[cfg(all(feature = "std", feature = "configuration"))]
+use audit::{AuditEvent, AuditOperation, AuditSink};
+#[cfg(feature = "std")]
+use builder::{BackendKind, ChallengeResponseBuilder, RetryPolicy};
+#[cfg(feature = "std")]
+use clock::Clock;
+#[cfg(feature = "std")]
 use config::Command;
-use config::{Config, Slot};
+#[cfg(feature = "std")]
+use config::{Config, InputMode, Slot};
+#[cfg(all(feature = "std", feature = "configuration"))]
 use configure::DeviceModeConfig;
-use error::ChallengeResponseError;
+#[cfg(feature = "std")]
+use diagnostics::{Diagnostics, Latency, MAX_RECENT_ERRORS};
+#[cfg(feature = "std")]
+use error::{ChallengeResponseError, CrcError};
+#[cfg(feature = "std")]
 use hmacmode::Hmac;
+#[cfg(feature = "std")]
+use hotplug::DeviceWatcher;
+#[cfg(feature = "std")]
+use lock::DeviceLock;
+#[cfg(feature = "mock")]
+use mock::MockBackend;
+#[cfg(feature = "std")]
+use observer::{Observer, Operation, OperationEvent};
+#[cfg(feature = "otp")]
 use otpmode::Aes128Block;
-use sec::{crc16, CRC_RESIDUAL_OK};
-use usb::{Backend, BackendType, Flags, Frame, CHALLENGE_SIZE};
+#[cfg(feature = "std")]
+use protocol::{RawResponse, SlotState, Status, StatusByte};
+#[cfg(feature = "std")]
+use selection::SelectionPolicy;
+#[cfg(feature = "std")]
+use touch::TouchPrompt;
+#[cfg(feature = "std")]
+use trace::PcapngTrace;
+#[cfg(feature = "std")]
+use usb::{BackendType, Frame, CHALLENGE_SIZE};
 
-pub use usb::Device;
+#[cfg(feature = "std")]
+pub use usb::{
+    Backend, DetachPolicy, Device, DeviceId, DeviceModel, InterfaceInfo, InterfaceUsage, PortPath, ResetPolicy,
+    Timing,
+};
+
+#[cfg(feature = "std")]
+const WAIT_FOR_DEVICE_INTERVAL: Duration = Duration::from_secs(1);
 
 /// The `Result` type used in this crate.
+#[cfg(feature = "std")]
 type Result<T> = ::std::result::Result<T, ChallengeResponseError>;
 
+#[cfg(feature = "std")]
 pub struct ChallengeResponse {
     backend: BackendType,
+    touch_prompt: Option<Box<dyn TouchPrompt>>,
+    observer: Option<Box<dyn Observer>>,
+    #[cfg(feature = "configuration")]
+    audit_sink: Option<Box<dyn AuditSink>>,
+    retry_policy: RetryPolicy,
+    fips_mode: bool,
+    lock_directory: Option<PathBuf>,
+    lock_timeout: Option<Duration>,
+    recent_errors: VecDeque<String>,
 }
 
+#[cfg(feature = "std")]
 impl ChallengeResponse {
-    /// Creates a new ChallengeResponse instance.
+    /// Creates a builder to configure the library-wide settings of a new instance.
+    pub fn builder() -> ChallengeResponseBuilder {
+        ChallengeResponseBuilder::new()
+    }
+
+    /// Creates a new ChallengeResponse instance. With both the `rusb` and `nusb` features, it uses the backend
+    /// named by the `CHALLENGE_RESPONSE_BACKEND` environment variable, or rusb if it is not set, like
+    /// `with_backend`.
     pub fn new() -> Result<Self> {
-        let backend = BackendType::new()?;
-        Ok(ChallengeResponse { backend })
+        Ok(Self::from_backend(BackendType::new()?))
+    }
+
+    /// Creates a new instance using the given backend. With both the `rusb` and `nusb` features, the other
+    /// one is used if it fails to initialize, e.g. when libusb can not be loaded. Fails with
+    /// `ChallengeResponseError::BackendUnavailable` if it is not compiled in.
+    pub fn with_backend(kind: BackendKind) -> Result<Self> {
+        Ok(Self::from_backend(BackendType::Usb(usb::new_usb_backend(kind)?)))
+    }
+
+    /// Creates a new instance using an existing libusb context, so applications which already use rusb for
+    /// other devices do not need a second one.
+    #[cfg(feature = "rusb")]
+    pub fn from_rusb_context(context: rusb::Context) -> Result<Self> {
+        Ok(Self::from_backend(BackendType::with_context(context)?))
+    }
+
+    /// Creates a new instance operating a device already opened by the application, using the context of
+    /// the handle. Returns the device, to use in the `Config` of the operations.
+    #[cfg(feature = "rusb")]
+    pub fn from_rusb_handle(handle: rusb::DeviceHandle<rusb::Context>) -> Result<(Self, Device)> {
+        let mut backend = BackendType::with_context(handle.context().clone())?;
+        let device = backend.adopt_handle(handle)?;
+        Ok((Self::from_backend(backend), device))
+    }
+
+    /// Creates a new instance operating a device already enumerated and opened by the application with nusb,
+    /// without enumerating the devices again. Returns the device, to use in the `Config` of the operations.
+    #[cfg(feature = "nusb")]
+    pub fn from_nusb_device(device_info: nusb::DeviceInfo, device: nusb::Device) -> Result<(Self, Device)> {
+        let mut cr = Self::with_backend(BackendKind::Nusb)?;
+        let device = cr.backend.adopt_device(device_info, device)?;
+        Ok((cr, device))
+    }
+
+    /// Creates a new instance operating the virtual devices of `backend` instead of the USB devices, to test
+    /// the code using it without a device. See the `mock` module.
+    #[cfg(feature = "mock")]
+    pub fn from_mock(backend: MockBackend) -> Self {
+        Self::from_custom_backend(backend)
+    }
+
+    /// Creates a new instance operating the devices of `backend`, a transport implemented by the
+    /// application, e.g. a proxy to the devices plugged in another machine. See `Backend`.
+    pub fn from_custom_backend<B, H, I>(backend: B) -> Self
+    where
+        B: Backend<H, I> + 'static,
+        H: 'static,
+        I: 'static,
+    {
+        Self::from_backend(BackendType::custom(backend))
+    }
+
+    fn from_backend(backend: BackendType) -> Self {
+        ChallengeResponse {
+            backend,
+            touch_prompt: None,
+            observer: None,
+            #[cfg(feature = "configuration")]
+            audit_sink: None,
+            retry_policy: RetryPolicy::none(),
+            fips_mode: false,
+            lock_directory: None,
+            lock_timeout: Some(Duration::ZERO),
+            recent_errors: VecDeque::new(),
+        }
+    }
+
+    /// Sets the prompt notified when an operation waits for the user to touch the device.
+    pub fn set_touch_prompt(&mut self, prompt: Box<dyn TouchPrompt>) {
+        self.touch_prompt = Some(prompt);
+    }
+
+    /// Sets a callback called when an operation waits for the user to touch the device, so the application
+    /// can ask for it, e.g. with "touch your key now". Replaces the touch prompt.
+    pub fn on_touch_required<F>(&mut self, callback: F)
+    where
+        F: Fn(&Device) + 'static,
+    {
+        self.set_touch_prompt(Box::new(callback));
+    }
+
+    /// Enables the automatic recovery of challenge-responses which fail because of a desynchronized device,
+    /// for example with a stale pending response left by a client which crashed. When enabled, such
+    /// operations are retried once after resetting the write state of the device, or after a USB reset of
+    /// the device if that fails. See `set_retry_policy` to retry them more than once.
+    pub fn set_auto_recovery(&mut self, auto_recovery: bool) {
+        self.retry_policy = if auto_recovery {
+            RetryPolicy::once()
+        } else {
+            RetryPolicy::none()
+        };
+    }
+
+    /// Enables the FIPS strict mode, in which the operations fail with `ChallengeResponseError::FipsViolation`
+    /// on the devices which are not YubiKey FIPS, and when writing a slot configuration without an access
+    /// code. See the `fips` module.
+    pub fn set_fips_mode(&mut self, strict: bool) {
+        self.fips_mode = strict;
+    }
+
+    /// Sets how the challenge-responses which fail because of a desynchronized device are retried.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Serializes the operations on each device with the other processes using the same directory, with
+    /// the advisory locks of the `lock` module, or stops locking the devices if `None`. While another
+    /// process operates a device, the operations on it wait up to the lock timeout, then fail with
+    /// `ChallengeResponseError::DeviceBusy`.
+    pub fn set_lock_directory(&mut self, directory: Option<PathBuf>) {
+        self.lock_directory = directory;
+    }
+
+    /// Sets how long the operations wait for a device locked by another process, or `None` to wait
+    /// forever. By default, they fail right away.
+    pub fn set_lock_timeout(&mut self, timeout: Option<Duration>) {
+        self.lock_timeout = timeout;
+    }
+
+    /// Takes the lock of the device, if locking is enabled.
+    fn lock(&self, device: &Device) -> Result<Option<DeviceLock>> {
+        match self.lock_directory {
+            Some(ref directory) => DeviceLock::acquire(directory, device, self.lock_timeout).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the observer notified of the operations, for logging or metrics.
+    pub fn set_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observer = Some(observer);
+    }
+
+    /// Sets the sink recording the operations which change the configuration of the devices.
+    #[cfg(feature = "configuration")]
+    pub fn set_audit_sink(&mut self, sink: Box<dyn AuditSink>) {
+        self.audit_sink = Some(sink);
+    }
+
+    /// Sets the timeouts and the polling interval of the exchanges with the devices.
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.backend.set_timing(timing);
+    }
+
+    /// Sets what is done with a device after a failed operation. See `ResetPolicy`.
+    pub fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        self.backend.set_reset_policy(policy);
+    }
+
+    /// Sets the vendor and product IDs of the devices to use in addition to the supported ones.
+    pub fn set_extra_device_ids(&mut self, ids: Vec<(u16, u16)>) {
+        self.backend.set_extra_device_ids(ids);
+    }
+
+    /// Keeps the error of a failed operation for the diagnostic reports, and notifies the observer.
+    fn record<T>(
+        &mut self,
+        operation: Operation,
+        device: &Device,
+        slot: Option<&Slot>,
+        started: Instant,
+        result: Result<T>,
+    ) -> Result<T> {
+        let result = result.map_err(|e| e.on_device(device.id(), slot.cloned()));
+        if let Err(ref e) = result {
+            if self.recent_errors.len() == MAX_RECENT_ERRORS {
+                self.recent_errors.pop_front();
+            }
+            self.recent_errors.push_back(e.to_string());
+        }
+        if let Some(ref observer) = self.observer {
+            observer.operation_completed(&OperationEvent {
+                operation,
+                device,
+                elapsed: started.elapsed(),
+                error: result.as_ref().err(),
+            });
+        }
+        result
+    }
+
+    /// Runs `operation`, retrying it after a recovery, as allowed by the retry policy, while it fails because
+    /// the device was desynchronized.
+    fn with_recovery<T, F>(&mut self, kind: Operation, conf: &Config, operation: F) -> Result<T>
+    where
+        F: Fn(&mut Self, &Config) -> Result<T>,
+    {
+        let mut result = operation(self, conf);
+        let mut recovered: Option<Config> = None;
+        for _ in 0..self.retry_policy.retries {
+            match result {
+                Err(ref e @ ChallengeResponseError::WrongCRC(_))
+                | Err(ref e @ ChallengeResponseError::InvalidResponse)
+                | Err(ref e @ ChallengeResponseError::ReadResponseFailed { .. }) => {
+                    if let Some(ref observer) = self.observer {
+                        observer.retrying(kind, &conf.device, e);
+                    }
+                }
+                r => return r,
+            }
+            thread::sleep(self.retry_policy.delay);
+
+            let mut current = recovered.take().unwrap_or_else(|| conf.clone());
+            if usb::clear_device_state(
+                &mut self.backend,
+                current.device.bus_id,
+                current.device.address_id,
+            )
+            .is_err()
+            {
+                self.backend
+                    .reset_device(current.device.bus_id, current.device.address_id)?;
+                current.device = self.find_device_from_id(&current.device.id())?;
+            }
+            result = operation(self, &current);
+            recovered = Some(current);
+        }
+        result
+    }
+
+    /// Sets how the kernel drivers bound to the interfaces of the devices are handled. See `DetachPolicy`.
+    pub fn set_detach_policy(&mut self, policy: DetachPolicy) {
+        self.backend.set_detach_policy(policy);
+    }
+
+    /// Records the reports exchanged with the devices in `trace`, or stops recording them if `None`.
+    pub fn set_trace(&mut self, trace: Option<PcapngTrace>) {
+        self.backend.set_trace(trace);
+    }
+
+    /// Sends a command to the device and reads its response, notifying the touch prompt if the device waits
+    /// for a touch.
+    fn run_command(
+        &self,
+        handle: &mut usb::DeviceHandleType,
+        device: &Device,
+        frame: &Frame,
+    ) -> Result<RawResponse> {
+        let command = frame.command();
+        let fips_mode = self.fips_mode;
+        let mut on_ready = |status: &Status| check_command(device, status, command, fips_mode);
+        let prompt = match self.touch_prompt {
+            Some(ref p) => p,
+            None => return usb::run_command(&self.backend, handle, frame, &mut on_ready, &mut || {}),
+        };
+
+        let mut prompted = false;
+        let result = usb::run_command(&self.backend, handle, frame, &mut on_ready, &mut || {
+            if !prompted {
+                prompted = true;
+                prompt.touch_required(device);
+            }
+        });
+        if prompted {
+            prompt.touch_completed(device, result.is_ok());
+        }
+        result
+    }
+
+    /// Serves the devices to `TcpTransport` clients connected to `stream`, which can list them with
+    /// `TcpTransport::find_all_devices` and select one for their exchanges, until the connection is closed.
+    /// See the `transport` module.
+    pub fn serve_devices(&mut self, stream: TcpStream) -> Result<()> {
+        transport::serve_devices(stream, &mut usb::UsbDeviceSet::new(self))
     }
 
     pub fn find_device(&mut self) -> Result<Device> {
@@ -66,120 +536,476 @@ impl ChallengeResponse {
         self.backend.find_all_devices()
     }
 
-    pub fn read_serial_number(&mut self, conf: Config) -> Result<u32> {
-        self.backend
-            .read_serial_from_device(conf.device.bus_id, conf.device.address_id)
+    /// Enumerates the devices lazily, so callers looking for a specific device can stop at the first match
+    /// without reading the serial numbers of all the other devices. Unlike `find_all_devices`, no error is
+    /// returned when no device is connected: the iterator is empty.
+    pub fn devices(&mut self) -> impl Iterator<Item = Result<Device>> + '_ {
+        self.backend.enumerate_devices()
     }
 
-    pub fn write_config(&mut self, conf: Config, device_config: &mut DeviceModeConfig) -> Result<()> {
-        let d = device_config.to_frame(conf.command);
-        let mut buf = [0; usb::STATUS_UPDATE_PAYLOAD_SIZE];
+    /// Finds all the devices whose product name contains `name`, ignoring case.
+    pub fn find_devices_by_name(&mut self, name: &str) -> Result<Vec<Device>> {
+        let name = name.to_lowercase();
+        let devices: Vec<Device> = self
+            .find_all_devices()?
+            .into_iter()
+            .filter(|d| match d.name {
+                Some(ref n) => n.to_lowercase().contains(&name),
+                None => false,
+            })
+            .collect();
 
-        let (mut handle, interfaces) = self
-            .backend
-            .open_device(conf.device.bus_id, conf.device.address_id)?;
-
-        self.backend
-            .wait(&mut handle, |f| !f.contains(Flags::SLOT_WRITE_FLAG), &mut buf)?;
+        if devices.is_empty() {
+            return Err(ChallengeResponseError::DeviceNotFound);
+        }
+        Ok(devices)
+    }
 
-        // TODO: Should check version number.
+    /// Finds the device with the given identity, as returned by `Device::id`.
+    pub fn find_device_from_id(&mut self, id: &DeviceId) -> Result<Device> {
+        if let DeviceId::Serial(serial) = *id {
+            return self.find_device_from_serial(serial);
+        }
+        self.find_all_devices()?
+            .into_iter()
+            .find(|d| d.id() == *id)
+            .ok_or(ChallengeResponseError::DeviceNotFound)
+    }
 
-        self.backend.write_frame(&mut handle, &d)?;
-        self.backend
-            .wait(&mut handle, |f| !f.contains(Flags::SLOT_WRITE_FLAG), &mut buf)?;
-        self.backend.close_device(handle, interfaces)?;
+    /// Finds the device plugged in the given physical port, whatever its serial number and address.
+    pub fn find_device_at_port(&mut self, path: &PortPath) -> Result<Device> {
+        self.find_all_devices()?
+            .into_iter()
+            .find(|d| d.port_path.as_ref() == Some(path))
+            .ok_or(ChallengeResponseError::DeviceNotFound)
+    }
 
-        Ok(())
+    /// Watches the devices plugged in and unplugged, instead of polling `find_all_devices`. See the
+    /// `hotplug` module.
+    pub fn watch_devices(&mut self) -> DeviceWatcher<'_> {
+        DeviceWatcher::new(self)
     }
 
-    pub fn challenge_response_hmac(&mut self, chall: &[u8], conf: Config) -> Result<Hmac> {
-        let mut hmac = Hmac([0; 20]);
+    /// Selects a connected device with `policy`, see the `selection` module. Fails with
+    /// `ChallengeResponseError::DeviceNotFound` if no device is connected, or if no rule matches and the
+    /// policy has no prompt or the prompt was cancelled.
+    pub fn select_device(&mut self, policy: &mut SelectionPolicy) -> Result<Device> {
+        let devices = self.find_all_devices()?;
+        policy
+            .select(devices, |device| self.read_status(device).ok())
+            .ok_or(ChallengeResponseError::DeviceNotFound)
+    }
 
-        let (mut handle, interfaces) = self
-            .backend
-            .open_device(conf.device.bus_id, conf.device.address_id)?;
+    /// Waits until a device with the given serial number is plugged in, or any device if `serial` is
+    /// `None`. Returns immediately if such a device is already plugged in. Fails with
+    /// `ChallengeResponseError::DeviceNotFound` if no matching device was plugged in before `timeout`.
+    pub fn wait_for_device(&mut self, serial: Option<u32>, timeout: Duration) -> Result<Device> {
+        self.wait_for_device_matching(|d| serial.is_none() || d.serial == serial, timeout)
+    }
 
-        let mut challenge = [0; CHALLENGE_SIZE];
+    /// Waits until a device for which `matches` returns `true` is plugged in, like `wait_for_device`.
+    pub fn wait_for_device_matching<F>(&mut self, mut matches: F, timeout: Duration) -> Result<Device>
+    where
+        F: FnMut(&Device) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Ok(devices) = self.find_all_devices() {
+                let found = devices.into_iter().find(|d| matches(d));
+                if let Some(device) = found {
+                    return Ok(device);
+                }
+            }
 
-        if conf.variable && chall.last() == Some(&0) {
-            challenge = [0xff; CHALLENGE_SIZE];
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ChallengeResponseError::DeviceNotFound);
+            }
+            // Enumerate again at least every second, in case a device was plugged in between the enumeration
+            // and the start of the wait.
+            self.backend
+                .wait_for_connection(cmp::min(deadline - now, WAIT_FOR_DEVICE_INTERVAL))?;
         }
+    }
 
-        let mut command = Command::ChallengeHmac1;
-        if let Slot::Slot2 = conf.slot {
-            command = Command::ChallengeHmac2;
-        }
+    pub fn read_serial_number(&mut self, conf: Config) -> Result<u32> {
+        let started = Instant::now();
+        let result = self.lock(&conf.device).and_then(|_lock| {
+            usb::read_serial_from_device(&mut self.backend, conf.device.bus_id, conf.device.address_id)
+        });
+        self.record(Operation::ReadSerialNumber, &conf.device, None, started, result)
+    }
 
-        (&mut challenge[..chall.len()]).copy_from_slice(chall);
-        let d = Frame::new(challenge, command);
-        let mut buf = [0; usb::STATUS_UPDATE_PAYLOAD_SIZE];
-        self.backend.wait(
-            &mut handle,
-            |f| !f.contains(usb::Flags::SLOT_WRITE_FLAG),
-            &mut buf,
-        )?;
+    /// Reads the status of the device: firmware version, programming sequence and touch level.
+    pub fn read_status(&mut self, device: &Device) -> Result<Status> {
+        let started = Instant::now();
+        let result = self.lock(device).and_then(|_lock| {
+            usb::read_status_from_device(&mut self.backend, device.bus_id, device.address_id)
+        });
+        self.record(Operation::ReadStatus, device, None, started, result)
+    }
 
-        self.backend.write_frame(&mut handle, &d)?;
+    /// Reads the flags of the device with a single feature report, without sending any command: a cheap way
+    /// to check whether the device is idle, busy, or waiting for a touch before starting an operation.
+    pub fn poll_status_byte(&mut self, device: &Device) -> Result<StatusByte> {
+        let started = Instant::now();
+        let result = self.lock(device).and_then(|_lock| {
+            usb::read_report_from_device(&mut self.backend, device.bus_id, device.address_id)
+        });
+        self.record(Operation::ReadStatus, device, None, started, result)
+            .map(|report| StatusByte::from_report(&report))
+    }
 
-        // Read the response.
-        let mut response = [0; usb::RESPONSE_SIZE];
-        self.backend.read_response(&mut handle, &mut response)?;
-        self.backend.close_device(handle, interfaces)?;
+    /// Checks whether the device was reprogrammed since its programming sequence was last read. See
+    /// `Status::is_reconfigured_since`.
+    pub fn is_reconfigured_since(&mut self, device: &Device, known_pgm_seq: u8) -> Result<bool> {
+        Ok(self.read_status(device)?.is_reconfigured_since(known_pgm_seq))
+    }
+
+    /// Performs a USB reset of the device, to recover a device left in a bad state, for example with a
+    /// pending response, by a client which crashed. The device may be enumerated again with a new address, so
+    /// it should be looked up again, e.g. with `find_device_from_id`.
+    pub fn reset(&mut self, device: &Device) -> Result<()> {
+        let started = Instant::now();
+        let result = self
+            .lock(device)
+            .and_then(|_lock| self.backend.reset_device(device.bus_id, device.address_id));
+        self.record(Operation::Reset, device, None, started, result)
+    }
 
-        // Check response.
-        if crc16(&response[..22]) != CRC_RESIDUAL_OK {
-            return Err(ChallengeResponseError::WrongCRC);
+    /// Collects a report about the device and the library, to attach to bug reports. The lock of the device
+    /// is held while it is read, so the status and interfaces are a consistent snapshot.
+    pub fn collect_diagnostics(&mut self, device: &Device) -> Diagnostics {
+        let (status, interfaces) = match self.lock(device) {
+            Ok(_lock) => (
+                usb::read_status_from_device(&mut self.backend, device.bus_id, device.address_id)
+                    .map_err(|e| e.to_string()),
+                self.backend
+                    .read_interfaces(device.bus_id, device.address_id)
+                    .map_err(|e| e.to_string()),
+            ),
+            Err(e) => (Err(e.to_string()), Err(e.to_string())),
+        };
+        Diagnostics {
+            library_version: env!("CARGO_PKG_VERSION"),
+            backend: self.backend.name(),
+            os: std::env::consts::OS,
+            device: device.clone(),
+            status,
+            interfaces,
+            recent_errors: self.recent_errors.iter().cloned().collect(),
         }
+    }
 
-        hmac.0.clone_from_slice(&response[..20]);
+    /// Measures the round-trip time of `iterations` HMAC-SHA1 challenge-responses (at least one) with a
+    /// slot of the device, to detect degraded devices or USB paths, and to tune the timeouts. Fails with
+    /// `ChallengeResponseError::TouchRequired` if the slot requires touching the device.
+    pub fn measure_latency(&mut self, device: &Device, slot: Slot, iterations: u32) -> Result<Latency> {
+        if let Err(e) = slot.command_for(device, Command::ChallengeHmac1, Command::ChallengeHmac2) {
+            return Err(e.on_device(device.id(), Some(slot)));
+        }
+        let status = self.read_status(device)?;
+        let state = match slot {
+            Slot::Slot1 => status.slot1(),
+            _ => status.slot2(),
+        };
+        if state == (SlotState::Configured { require_touch: true }) {
+            return Err(ChallengeResponseError::TouchRequired.on_device(device.id(), Some(slot)));
+        }
 
-        Ok(hmac)
+        let conf = Config::new_from(device.clone()).set_slot(slot);
+        let mut samples = Vec::with_capacity(iterations.max(1) as usize);
+        for _ in 0..iterations.max(1) {
+            let started = Instant::now();
+            self.challenge_response_hmac(&[0; 32], conf.clone())?;
+            samples.push(started.elapsed());
+        }
+        Ok(Latency::from_samples(&samples))
     }
 
-    pub fn challenge_response_otp(&mut self, chall: &[u8], conf: Config) -> Result<Aes128Block> {
-        let mut block = Aes128Block {
-            block: GenericArray::clone_from_slice(&[0; 16]),
+    /// Tells whether an HMAC-SHA1 slot expects variable-length or fixed-length challenges, so the `Config` of
+    /// the challenge-responses can match it. The devices do not report the configuration of their slots, so
+    /// two 64-byte challenges which only differ by their padding are sent: a slot in variable-length mode
+    /// strips the padding and answers both with the same HMAC. A slot requiring a touch is touched twice.
+    pub fn slot_input_mode(&mut self, device: &Device, slot: Slot) -> Result<InputMode> {
+        let (first, second) = input_mode_challenges();
+        let conf = Config::new_from(device.clone())
+            .set_slot(slot)
+            .set_variable_size(false);
+        let first = self.challenge_response_hmac(&first, conf.clone())?;
+        let second = self.challenge_response_hmac(&second, conf)?;
+        Ok(if first.0 == second.0 {
+            InputMode::Variable
+        } else {
+            InputMode::Fixed
+        })
+    }
+
+    /// Makes the LED of the device blink for `duration`, so the user can tell it from identical devices.
+    /// The device blinks while it waits for a touch, so a challenge is sent to a slot which requires a
+    /// touch, and cancelled after `duration` or as soon as the user touches the device. Fails with
+    /// `ChallengeResponseError::NoTouchSlot` if no slot requires a touch.
+    pub fn identify(&mut self, device: &Device, duration: Duration) -> Result<()> {
+        let started = Instant::now();
+        let result = self.lock(device).and_then(|_lock| {
+            let status = usb::read_status_from_device(&mut self.backend, device.bus_id, device.address_id)?;
+            self.identify_once(device, &status, duration)
+        });
+        self.record(Operation::Identify, device, None, started, result)
+    }
+
+    fn identify_once(&mut self, device: &Device, status: &Status, duration: Duration) -> Result<()> {
+        let touch = SlotState::Configured { require_touch: true };
+        let command = if status.slot2() == touch {
+            Command::ChallengeHmac2
+        } else if status.slot1() == touch {
+            Command::ChallengeHmac1
+        } else {
+            return Err(ChallengeResponseError::NoTouchSlot);
+        };
+        let frame = Frame::new([0; CHALLENGE_SIZE], command);
+        let timing = Timing {
+            response_timeout: Some(duration),
+            touch_timeout: None,
+            ..self.backend.timing().clone()
         };
 
+        let fips_mode = self.fips_mode;
+        let (mut handle, interfaces) = self.backend.open_device(device.bus_id, device.address_id)?;
+        let result = usb::run_exchange_with_timing(
+            &self.backend,
+            &mut handle,
+            &timing,
+            &mut protocol::Exchange::command(&frame),
+            &mut |status| check_command(device, status, command, fips_mode),
+            &mut || {},
+        );
+        // Cancels the challenge, whose response is not used.
+        let reset = usb::write_reset(&self.backend, &mut handle);
+        self.backend.close_device(handle, interfaces)?;
+        match result {
+            Ok(()) | Err(ChallengeResponseError::ResponseTimeout) => reset,
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(feature = "configuration")]
+    pub fn write_config(&mut self, conf: Config, device_config: &mut DeviceModeConfig) -> Result<()> {
+        let started = Instant::now();
+        let audit_operation = AuditOperation::from_command(conf.command, device_config);
+        let result = self
+            .lock(&conf.device)
+            .and_then(|_lock| self.write_config_once(&conf, device_config));
+        let result = self.record(
+            Operation::WriteConfig,
+            &conf.device,
+            Some(&conf.slot),
+            started,
+            result,
+        );
+        if let (Some(sink), Some(operation)) = (self.audit_sink.as_ref(), audit_operation) {
+            sink.record(&AuditEvent {
+                operation,
+                device: &conf.device,
+                serial: conf.device.serial,
+                slot: &conf.slot,
+                timestamp: SystemTime::now(),
+                error: result.as_ref().err(),
+            });
+        }
+        result
+    }
+
+    #[cfg(feature = "configuration")]
+    fn write_config_once(&mut self, conf: &Config, device_config: &mut DeviceModeConfig) -> Result<()> {
+        if self.fips_mode {
+            fips::check_config(conf.command, device_config)?;
+        }
+        let d = device_config.to_frame(conf.command);
+        let fips_mode = self.fips_mode;
+
         let (mut handle, interfaces) = self
             .backend
             .open_device(conf.device.bus_id, conf.device.address_id)?;
 
-        let mut challenge = [0; CHALLENGE_SIZE];
+        let result = usb::run_exchange(
+            &self.backend,
+            &mut handle,
+            &mut protocol::Exchange::write(&d),
+            &mut |status| check_command(&conf.device, status, conf.command, fips_mode),
+            &mut || {},
+        );
+        let result = usb::reset_after(&self.backend, &mut handle, result);
+        self.backend.close_device(handle, interfaces)?;
+        result
+    }
+
+    pub fn challenge_response_hmac(&mut self, chall: &[u8], conf: Config) -> Result<Hmac> {
+        let mut hmac = Hmac([0; 20]);
+        self.challenge_response_hmac_into(chall, conf, &mut hmac.0)?;
+        Ok(hmac)
+    }
 
-        let mut command = Command::ChallengeOtp1;
-        if let Slot::Slot2 = conf.slot {
-            command = Command::ChallengeOtp2;
+    /// Computes a TOTP code (RFC 6238) with a HMAC-SHA1 slot holding the TOTP secret: the slot is
+    /// challenged with the time step of `clock`, see `clock::totp_challenge`, and the response is truncated
+    /// to `digits` digits. Fails with `ChallengeResponseError::InvalidFormat` if `digits` is not between 1
+    /// and 9.
+    pub fn challenge_response_totp<C: Clock + ?Sized>(
+        &mut self,
+        conf: Config,
+        clock: &C,
+        period: u64,
+        digits: u8,
+    ) -> Result<u32> {
+        if digits == 0 || digits > 9 {
+            return Err(ChallengeResponseError::InvalidFormat);
         }
+        let hmac = self.challenge_response_hmac(&clock::totp_challenge(clock, period), conf)?;
+        Ok(validation::truncate(&hmac.0, digits))
+    }
+
+    /// Performs a HMAC-SHA1 challenge-response like `challenge_response_hmac`, writing the response in
+    /// `out`. The challenge and the response only go through stack buffers, so nothing is allocated unless
+    /// the operation fails.
+    pub fn challenge_response_hmac_into(
+        &mut self,
+        chall: &[u8],
+        conf: Config,
+        out: &mut [u8; 20],
+    ) -> Result<()> {
+        let raw = self.challenge_response_hmac_response(chall, conf)?;
+        out.copy_from_slice(&raw.bytes[..20]);
+        Ok(())
+    }
 
-        (&mut challenge[..chall.len()]).copy_from_slice(chall);
-        let d = Frame::new(challenge, command);
-        let mut buf = [0; usb::STATUS_UPDATE_PAYLOAD_SIZE];
+    /// Performs a HMAC-SHA1 challenge-response, also returning the whole response of the device with the
+    /// flags of its last report, for debugging or validating the trailing bytes. The response also tells
+    /// whether a stale response left by a previous client had to be cleared first.
+    pub fn challenge_response_hmac_raw(&mut self, chall: &[u8], conf: Config) -> Result<(Hmac, RawResponse)> {
+        let raw = self.challenge_response_hmac_response(chall, conf)?;
+        let mut hmac = Hmac([0; 20]);
+        hmac.0.copy_from_slice(&raw.bytes[..20]);
+        Ok((hmac, raw))
+    }
 
-        self.backend.wait(
-            &mut handle,
-            |f| !f.contains(usb::Flags::SLOT_WRITE_FLAG),
-            &mut buf,
+    fn challenge_response_hmac_response(&mut self, chall: &[u8], conf: Config) -> Result<RawResponse> {
+        let started = Instant::now();
+        let result = self.lock(&conf.device).and_then(|_lock| {
+            self.with_recovery(Operation::ChallengeResponseHmac, &conf, |cr, conf| {
+                let mut challenge = [0; CHALLENGE_SIZE];
+                if conf.variable && chall.last() == Some(&0) {
+                    challenge = [0xff; CHALLENGE_SIZE];
+                }
+                let command =
+                    conf.slot
+                        .command_for(&conf.device, Command::ChallengeHmac1, Command::ChallengeHmac2)?;
+                cr.challenge_response_once(chall, challenge, command, 22, conf)
+            })
+        });
+        self.record(
+            Operation::ChallengeResponseHmac,
+            &conf.device,
+            Some(&conf.slot),
+            started,
+            result,
+        )
+    }
+
+    #[cfg(feature = "otp")]
+    pub fn challenge_response_otp(&mut self, chall: &[u8], conf: Config) -> Result<Aes128Block> {
+        let mut response = [0; 16];
+        self.challenge_response_otp_into(chall, conf, &mut response)?;
+        Ok(Aes128Block {
+            block: GenericArray::clone_from_slice(&response),
+        })
+    }
+
+    /// Performs a Yubico OTP challenge-response like `challenge_response_otp`, writing the response in
+    /// `out`, without allocating unless the operation fails.
+    #[cfg(feature = "otp")]
+    pub fn challenge_response_otp_into(
+        &mut self,
+        chall: &[u8],
+        conf: Config,
+        out: &mut [u8; 16],
+    ) -> Result<()> {
+        let started = Instant::now();
+        let result = self.lock(&conf.device).and_then(|_lock| {
+            self.with_recovery(Operation::ChallengeResponseOtp, &conf, |cr, conf| {
+                let command =
+                    conf.slot
+                        .command_for(&conf.device, Command::ChallengeOtp1, Command::ChallengeOtp2)?;
+                cr.challenge_response_once(chall, [0; CHALLENGE_SIZE], command, 18, conf)
+            })
+        });
+        let raw = self.record(
+            Operation::ChallengeResponseOtp,
+            &conf.device,
+            Some(&conf.slot),
+            started,
+            result,
         )?;
+        out.copy_from_slice(&raw.bytes[..16]);
+        Ok(())
+    }
 
-        self.backend.write_frame(&mut handle, &d)?;
+    /// Sends `chall`, padded with the bytes of `padding`, to a slot of the device and reads the response,
+    /// whose CRC covers its first `crc_len` bytes.
+    fn challenge_response_once(
+        &mut self,
+        chall: &[u8],
+        padding: [u8; CHALLENGE_SIZE],
+        command: Command,
+        crc_len: usize,
+        conf: &Config,
+    ) -> Result<RawResponse> {
+        let mut challenge = padding;
+        challenge[..chall.len()].copy_from_slice(chall);
+        let frame = Frame::new(challenge, command);
 
-        let mut response = [0; usb::RESPONSE_SIZE];
-        self.backend.read_response(&mut handle, &mut response)?;
-        self.backend.close_device(handle, interfaces)?;
+        let (mut handle, interfaces) = self
+            .backend
+            .open_device(conf.device.bus_id, conf.device.address_id)?;
 
-        // Check response.
-        if crc16(&response[..18]) != CRC_RESIDUAL_OK {
-            return Err(ChallengeResponseError::WrongCRC);
-        }
+        let raw = self
+            .run_command(&mut handle, &conf.device, &frame)
+            .and_then(|raw| {
+                CrcError::check(&raw.bytes[..crc_len])?;
+                Ok(raw)
+            });
+        let raw = usb::reset_after(&self.backend, &mut handle, raw);
+        self.backend.close_device(handle, interfaces)?;
+        raw
+    }
+}
 
-        block.block.copy_from_slice(&response[..16]);
+/// Two challenges which are the same variable-length challenge with different padding bytes.
+#[cfg(feature = "std")]
+fn input_mode_challenges() -> ([u8; CHALLENGE_SIZE], [u8; CHALLENGE_SIZE]) {
+    let mut first = [0xaa; CHALLENGE_SIZE];
+    let mut second = [0x55; CHALLENGE_SIZE];
+    for i in 0..CHALLENGE_SIZE / 2 {
+        first[i] = i as u8;
+        second[i] = i as u8;
+    }
+    (first, second)
+}
 
-        Ok(block)
+/// Fails with `ChallengeResponseError::CommandNotSupported` if the device, whose status is `status`, does not
+/// implement `command`, and with `ChallengeResponseError::FipsViolation` if `fips_mode` is enabled and the
+/// device is not a FIPS device.
+#[cfg(feature = "std")]
+fn check_command(device: &Device, status: &Status, command: Command, fips_mode: bool) -> Result<()> {
+    if !vendor::supports_command(device.vendor_id, device.product_id, status, command) {
+        return Err(ChallengeResponseError::CommandNotSupported);
+    }
+    if fips_mode {
+        fips::check_device(device.vendor_id, status)?;
     }
+    Ok(())
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
 
@@ -212,4 +1038,36 @@ mod tests {
             assert!(matches!(e, ChallengeResponseError::DeviceNotFound));
         };
     }
+
+    #[test]
+    fn test_devices() {
+        let mut cr_client = match ChallengeResponse::new() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                return;
+            }
+        };
+
+        // Enumerating lazily finds the same devices, whether or not any is connected.
+        let lazy: Vec<Device> = cr_client.devices().filter_map(|d| d.ok()).collect();
+        match cr_client.find_all_devices() {
+            Ok(devices) => assert_eq!(devices, lazy),
+            Err(_) => assert!(lazy.is_empty()),
+        }
+    }
+
+    #[test]
+    fn test_input_mode_challenges() {
+        // The padding stripped by a slot in variable-length mode is the trailing run of the last byte.
+        fn strip(challenge: &[u8]) -> &[u8] {
+            let last = challenge[challenge.len() - 1];
+            let len = challenge.iter().rposition(|&b| b != last).map_or(0, |i| i + 1);
+            &challenge[..len]
+        }
+        let (first, second) = input_mode_challenges();
+        assert_ne!(first, second);
+        assert_eq!(strip(&first), strip(&second));
+        assert_eq!(strip(&first).len(), CHALLENGE_SIZE / 2);
+    }
 }
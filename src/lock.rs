@@ -0,0 +1,123 @@
+//! Advisory locks serializing the operations of several processes on a device.
+//!
+//! An operation on a device is made of several transfers, and the device handles one exchange at a time:
+//! when two processes operate the same device, their frames are interleaved and their transactions fail, or
+//! one of them reads the response to the challenge of the other. When locking is enabled with
+//! `ChallengeResponse::set_lock_directory`, each operation holds an exclusive lock on a file named after the
+//! device. If another process holds it, the operation waits for it up to the timeout set with
+//! `ChallengeResponse::set_lock_timeout`, then fails with `ChallengeResponseError::DeviceBusy`. The locks
+//! are advisory: only the processes which use the same directory are serialized.
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use error::ChallengeResponseError;
+use usb::Device;
+
+/// The delay between two attempts to take a lock held by another process.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The exclusive lock of a device, released when dropped.
+#[derive(Debug)]
+pub struct DeviceLock {
+    _file: File,
+}
+
+impl DeviceLock {
+    /// The directory of the lock files, when no other is configured.
+    pub fn default_directory() -> PathBuf {
+        std::env::temp_dir()
+    }
+
+    /// The lock file of a device. It is named after the serial number of the device, or the port it is
+    /// plugged in if the serial number is not readable, so it does not change when the device is reset and
+    /// enumerated again with a new address.
+    pub fn path<P: AsRef<Path>>(directory: P, device: &Device) -> PathBuf {
+        let name = match (device.serial, &device.port_path) {
+            (Some(serial), _) => format!("challenge-response-serial-{}.lock", serial),
+            (None, Some(path)) => format!("challenge-response-port-{}.lock", path),
+            (None, None) => format!(
+                "challenge-response-address-{}-{}.lock",
+                device.bus_id, device.address_id
+            ),
+        };
+        directory.as_ref().join(name)
+    }
+
+    fn open<P: AsRef<Path>>(directory: P, device: &Device) -> Result<File, ChallengeResponseError> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(Self::path(directory, device))?)
+    }
+
+    /// Takes the lock of a device, without waiting. Fails with `ChallengeResponseError::DeviceBusy` if
+    /// another process, or another instance of this process, holds it.
+    pub fn try_acquire<P: AsRef<Path>>(directory: P, device: &Device) -> Result<Self, ChallengeResponseError> {
+        Self::acquire(directory, device, Some(Duration::ZERO))
+    }
+
+    /// Takes the lock of a device, waiting up to `timeout` for the process holding it, or forever if
+    /// `None`.
+    pub fn acquire<P: AsRef<Path>>(
+        directory: P,
+        device: &Device,
+        timeout: Option<Duration>,
+    ) -> Result<Self, ChallengeResponseError> {
+        let file = Self::open(directory, device)?;
+        let deadline = match timeout {
+            Some(timeout) => Instant::now() + timeout,
+            None => {
+                file.lock()?;
+                return Ok(DeviceLock { _file: file });
+            }
+        };
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(DeviceLock { _file: file }),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => thread::sleep(LOCK_POLL_INTERVAL),
+                Err(TryLockError::WouldBlock) => return Err(ChallengeResponseError::DeviceBusy),
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_lock() {
+        let directory = std::env::temp_dir().join(format!("challenge-response-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let device = Device {
+            name: None,
+            serial: Some(1234),
+            product_id: 0x0407,
+            vendor_id: 0x1050,
+            bus_id: 1,
+            address_id: 7,
+            port_path: None,
+            manufacturer: None,
+            release: 0x0543,
+            interfaces: Vec::new(),
+        };
+
+        let lock = DeviceLock::try_acquire(&directory, &device).unwrap();
+        assert!(matches!(
+            DeviceLock::try_acquire(&directory, &device),
+            Err(ChallengeResponseError::DeviceBusy)
+        ));
+        assert!(DeviceLock::path(&directory, &device).ends_with("challenge-response-serial-1234.lock"));
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(lock);
+        });
+        DeviceLock::acquire(&directory, &device, Some(Duration::from_secs(5))).unwrap();
+        holder.join().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
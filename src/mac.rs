@@ -0,0 +1,98 @@
+//! An adapter of a HMAC-SHA1 slot to the `Mac` trait of RustCrypto, for generic code written against the
+//! `digest` traits.
+//!
+//! The device computes the HMAC of a challenge of at most 64 bytes, so `DeviceMac` buffers the data passed
+//! to `update`, and sends it as a single challenge when finalized. Unlike the software MACs, it can not be
+//! created from a key, as the secret never leaves the device.
+//!
+//! **`finalize` panics if the challenge-response fails**, e.g. when the device is unplugged or the message is
+//! too long, as the `digest` traits can not report errors. Code which must not panic calls
+//! `DeviceMac::try_finalize` instead. The feature is not enabled by default for this reason.
+//!
+//! ```no_run
+//! use challenge_response::config::Config;
+//! use challenge_response::mac::{DeviceMac, Mac};
+//! use challenge_response::ChallengeResponse;
+//!
+//! fn authenticate<M: Mac>(mut mac: M, message: &[u8]) -> Vec<u8> {
+//!     mac.update(message);
+//!     mac.finalize().into_bytes().to_vec()
+//! }
+//!
+//! let mut cr = ChallengeResponse::new().unwrap();
+//! let device = cr.find_device().unwrap();
+//! let tag = authenticate(DeviceMac::new(&mut cr, Config::new_from(device)), b"message");
+//! ```
+use digest::consts::U20;
+use digest::{FixedOutput, MacMarker, Output, OutputSizeUser, Update};
+
+use config::Config;
+use error::ChallengeResponseError;
+use hmacmode::Hmac;
+use usb::CHALLENGE_SIZE;
+use ChallengeResponse;
+
+pub use digest::Mac;
+
+/// A HMAC-SHA1 computed by a slot of a device. The `Mac::finalize` of the device panics on errors, see
+/// `try_finalize`.
+pub struct DeviceMac<'a> {
+    cr: &'a mut ChallengeResponse,
+    conf: Config,
+    message: Vec<u8>,
+}
+
+impl<'a> DeviceMac<'a> {
+    /// Computes the HMAC with the slot and challenge mode of `conf`.
+    pub fn new(cr: &'a mut ChallengeResponse, conf: Config) -> Self {
+        DeviceMac {
+            cr,
+            conf,
+            message: Vec::new(),
+        }
+    }
+
+    /// The longest message the slot accepts: 63 bytes in variable-length mode, else 64 bytes.
+    pub fn max_message_len(&self) -> usize {
+        if self.conf.variable {
+            CHALLENGE_SIZE - 1
+        } else {
+            CHALLENGE_SIZE
+        }
+    }
+
+    /// Sends the message to the device, and returns its HMAC. Fails with
+    /// `ChallengeResponseError::InvalidFormat` if the message is longer than `max_message_len`.
+    pub fn try_finalize(self) -> Result<Hmac, ChallengeResponseError> {
+        if self.message.len() > self.max_message_len() {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        self.cr.challenge_response_hmac(&self.message, self.conf)
+    }
+}
+
+impl<'a> Update for DeviceMac<'a> {
+    fn update(&mut self, data: &[u8]) {
+        self.message.extend_from_slice(data);
+    }
+}
+
+impl<'a> OutputSizeUser for DeviceMac<'a> {
+    type OutputSize = U20;
+}
+
+impl<'a> FixedOutput for DeviceMac<'a> {
+    /// Panics if the challenge-response fails, as the `digest` traits can not report errors. Use
+    /// `try_finalize` to handle them.
+    fn finalize_into(self, out: &mut Output<Self>) {
+        match self.try_finalize() {
+            Ok(hmac) => out.copy_from_slice(&hmac.0),
+            Err(e) => panic!(
+                "The device failed to compute the HMAC: {}. Use DeviceMac::try_finalize to handle the errors",
+                e
+            ),
+        }
+    }
+}
+
+impl<'a> MacMarker for DeviceMac<'a> {}
@@ -0,0 +1,149 @@
+//! Paper backups of HMAC-SHA1 secrets, as BIP39 mnemonics.
+//!
+//! The 20 bytes secret of a slot is encoded as 15 words of the BIP39 English word list: the 160 bits of the
+//! secret are followed by the first 5 bits of their SHA-256 checksum, and each group of 11 bits is the index
+//! of a word. The checksum catches most transcription errors when the mnemonic is imported again.
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use error::ChallengeResponseError;
+use hmacmode::{HmacKey, HMAC_SECRET_SIZE};
+
+/// The number of words of the mnemonic of an HMAC-SHA1 secret.
+pub const MNEMONIC_WORDS: usize = 15;
+
+const BITS_PER_WORD: usize = 11;
+const CHECKSUM_BITS: usize = MNEMONIC_WORDS * BITS_PER_WORD - HMAC_SECRET_SIZE * 8;
+
+const WORDLIST: &str = include_str!("bip39_english.txt");
+
+fn words() -> impl Iterator<Item = &'static str> {
+    WORDLIST.lines()
+}
+
+fn checksum(secret: &[u8]) -> u8 {
+    Sha256::digest(secret)[0] >> (8 - CHECKSUM_BITS)
+}
+
+/// The mnemonic of a secret. It is erased from memory when dropped.
+pub struct Mnemonic(String);
+
+impl Drop for Mnemonic {
+    fn drop(&mut self) {
+        let mut bytes = std::mem::take(&mut self.0).into_bytes();
+        for i in bytes.iter_mut() {
+            *i = 0;
+        }
+    }
+}
+
+impl std::ops::Deref for Mnemonic {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Encodes an HMAC-SHA1 secret as a mnemonic of `MNEMONIC_WORDS` words, separated by spaces.
+pub fn to_mnemonic(key: &HmacKey) -> Mnemonic {
+    let checksum = checksum(&key.0);
+    let bit = |i: usize| -> usize {
+        if i < HMAC_SECRET_SIZE * 8 {
+            (key.0[i / 8] >> (7 - i % 8)) as usize & 1
+        } else {
+            (checksum >> (CHECKSUM_BITS - 1 - (i - HMAC_SECRET_SIZE * 8))) as usize & 1
+        }
+    };
+
+    let mut mnemonic = String::new();
+    for w in 0..MNEMONIC_WORDS {
+        let index = (0..BITS_PER_WORD).fold(0, |index, b| index << 1 | bit(w * BITS_PER_WORD + b));
+        if w > 0 {
+            mnemonic.push(' ');
+        }
+        mnemonic.push_str(words().nth(index).expect("the word list has 2048 words"));
+    }
+    Mnemonic(mnemonic)
+}
+
+/// Decodes a mnemonic produced by `to_mnemonic`. The words may be separated by any whitespace, and their
+/// case is ignored. Fails with `ChallengeResponseError::InvalidFormat` if a word is not in the BIP39
+/// English word list, if the number of words is wrong, or if the checksum does not match.
+pub fn from_mnemonic(mnemonic: &str) -> Result<HmacKey, ChallengeResponseError> {
+    let mut key = HmacKey([0; HMAC_SECRET_SIZE]);
+    let mut checksum_value = 0;
+    let mut count = 0;
+    for word in mnemonic.split_whitespace() {
+        if count == MNEMONIC_WORDS {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let word = word.to_lowercase();
+        let index = words()
+            .position(|w| w == word)
+            .ok_or(ChallengeResponseError::InvalidFormat)?;
+        for b in 0..BITS_PER_WORD {
+            let i = count * BITS_PER_WORD + b;
+            let bit = (index >> (BITS_PER_WORD - 1 - b)) as u8 & 1;
+            if i < HMAC_SECRET_SIZE * 8 {
+                key.0[i / 8] |= bit << (7 - i % 8);
+            } else {
+                checksum_value = checksum_value << 1 | bit;
+            }
+        }
+        count += 1;
+    }
+
+    if count != MNEMONIC_WORDS || checksum(&key.0) != checksum_value {
+        return Err(ChallengeResponseError::InvalidFormat);
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mnemonic() {
+        let zero = HmacKey([0; HMAC_SECRET_SIZE]);
+        assert_eq!(
+            &*to_mnemonic(&zero),
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+             abandon abandon address"
+        );
+
+        let mut secret = [0; HMAC_SECRET_SIZE];
+        for (i, b) in secret.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mnemonic = to_mnemonic(&HmacKey(secret));
+        assert_eq!(
+            &*mnemonic,
+            "abandon amount liar amount expire adjust cage candy arch gather drum bullet absurd math exhibit"
+        );
+        assert_eq!(from_mnemonic(&mnemonic.to_uppercase()).unwrap().0, secret);
+    }
+
+    #[test]
+    fn test_invalid_mnemonic() {
+        // Wrong checksum.
+        assert!(from_mnemonic(
+            "abandon amount liar amount expire adjust cage candy arch gather drum bullet absurd math exist"
+        )
+        .is_err());
+        // Unknown word.
+        assert!(from_mnemonic(
+            "abandon amount liar amount expire adjust cage candy arch gather drum bullet absurd math exhibits"
+        )
+        .is_err());
+        // Too short.
+        assert!(from_mnemonic("abandon amount liar").is_err());
+    }
+}
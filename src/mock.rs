@@ -0,0 +1,620 @@
+//! A virtual backend, to test the code using a `ChallengeResponse` without a device, e.g. in CI.
+//!
+//! A `MockBackend` holds `MockDevice`s, which it enumerates like USB devices. They emulate the YubiKeys at
+//! the level of the HID feature reports, so the operations of `ChallengeResponse` go through the same
+//! protocol code as with a real device. Their responses are scripted per command with
+//! `MockDevice::respond`. A `MockDevice` is shared by its clones, so it can still be scripted and inspected
+//! once its backend was moved into a `ChallengeResponse`:
+//!
+//! ```
+//! use challenge_response::config::{Config, Slot};
+//! use challenge_response::mock::{MockBackend, MockDevice};
+//! use challenge_response::ChallengeResponse;
+//!
+//! let device = MockDevice::new(1234567);
+//! device.set_hmac_secret(Slot::Slot2, b"secret", true).unwrap();
+//! let mut cr = ChallengeResponse::from_mock(MockBackend::new().with_device(device.clone()));
+//!
+//! let conf = Config::new_from(cr.find_device().unwrap());
+//! let hmac = cr.challenge_response_hmac(b"challenge", conf).unwrap();
+//! assert_eq!(device.received().len(), 1);
+//! ```
+//!
+//! A `MockDevice` is also a `Transport`, to be used with a `transport::TransportDevice`.
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use config::Slot;
+#[cfg(feature = "configuration")]
+use configure::SIZEOF_CONFIG;
+use error::ChallengeResponseError;
+use protocol::{
+    compute_mac, crc16, Command, Flags, HmacSha1, Status, CONFIG1_TOUCH, CONFIG1_VALID, CONFIG2_TOUCH,
+    CONFIG2_VALID, FRAME_SIZE, PAYLOAD_SIZE, REPORT_SIZE, RESPONSE_SIZE, WRITE_RESET_PAYLOAD,
+};
+use trace::PcapngTrace;
+use transport::Transport;
+use usb::{
+    trace_report, Backend, DetachPolicy, Device, InterfaceInfo, ResetPolicy, Timing, HID_GET_REPORT,
+    HID_SET_REPORT,
+};
+
+const REPORT_DATA_SIZE: usize = REPORT_SIZE - 1;
+/// The bus of the virtual devices.
+const MOCK_BUS: u8 = 0;
+/// The request types of the HID feature reports read from and written to a device, for the traces.
+const REQUEST_TYPE_IN: u8 = 0xa1;
+const REQUEST_TYPE_OUT: u8 = 0x21;
+
+/// The commands a `MockDevice` can receive.
+const COMMANDS: &[Command] = &[
+    #[cfg(feature = "configuration")]
+    Command::Configuration1,
+    #[cfg(feature = "configuration")]
+    Command::Configuration2,
+    #[cfg(feature = "configuration")]
+    Command::Update1,
+    #[cfg(feature = "configuration")]
+    Command::Update2,
+    #[cfg(feature = "configuration")]
+    Command::Swap,
+    Command::DeviceSerial,
+    #[cfg(feature = "configuration")]
+    Command::DeviceConfig,
+    Command::ChallengeOtp1,
+    Command::ChallengeOtp2,
+    Command::ChallengeHmac1,
+    Command::ChallengeHmac2,
+];
+
+/// What a `MockDevice` does after receiving a command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    /// Responds with the given bytes, followed by their CRC.
+    Data(Vec<u8>),
+    /// Responds with the given bytes followed by an invalid CRC, like a response corrupted in transfer.
+    Corrupted(Vec<u8>),
+    /// Accepts the command without responding, like the configuration commands. The configuration commands
+    /// accepted update the status of the device.
+    Accept,
+    /// Never responds, like a device whose slot is not configured. The operation fails once the response
+    /// timeout set with `ChallengeResponse::set_timing` expires, and never if there is none.
+    Silent,
+}
+
+type Responder = Box<dyn FnMut(&[u8; PAYLOAD_SIZE]) -> Reply + Send>;
+
+struct State {
+    device: Device,
+    status: Status,
+    responders: Vec<(Command, Responder)>,
+    received: Vec<(Command, [u8; PAYLOAD_SIZE])>,
+    /// The frame being written, report by report.
+    frame: [u8; FRAME_SIZE],
+    /// The response being read, with its CRC, and the index of the next report to read.
+    response: Option<(Vec<u8>, usize)>,
+}
+
+impl State {
+    fn process_frame(&mut self) {
+        let frame = self.frame;
+        self.frame = [0; FRAME_SIZE];
+        let command = match COMMANDS.iter().find(|&&c| c as u8 == frame[PAYLOAD_SIZE]) {
+            Some(&command) => command,
+            None => return,
+        };
+        let mut payload = [0; PAYLOAD_SIZE];
+        payload.copy_from_slice(&frame[..PAYLOAD_SIZE]);
+        self.received.push((command, payload));
+
+        let reply = match self.responders.iter_mut().rev().find(|r| r.0 == command) {
+            Some(&mut (_, ref mut respond)) => respond(&payload),
+            None => self.default_reply(command),
+        };
+        match reply {
+            Reply::Data(data) => self.respond(&data, !crc16(&data)),
+            Reply::Corrupted(data) => self.respond(&data, crc16(&data)),
+            Reply::Accept => self.accept(command, &payload),
+            Reply::Silent => {}
+        }
+    }
+
+    fn default_reply(&self, command: Command) -> Reply {
+        match command {
+            Command::DeviceSerial => match self.device.serial {
+                Some(serial) => Reply::Data(serial.to_be_bytes().to_vec()),
+                None => Reply::Silent,
+            },
+            Command::ChallengeOtp1
+            | Command::ChallengeOtp2
+            | Command::ChallengeHmac1
+            | Command::ChallengeHmac2 => Reply::Silent,
+            #[cfg(feature = "configuration")]
+            _ => Reply::Accept,
+        }
+    }
+
+    fn respond(&mut self, data: &[u8], crc: u16) {
+        let mut response = data.to_vec();
+        response.extend_from_slice(&crc.to_le_bytes());
+        response.truncate(RESPONSE_SIZE);
+        self.response = Some((response, 0));
+    }
+
+    /// Updates the status after an accepted command: the configuration commands increment the programming
+    /// sequence, which is reset to 0 once both slots are empty, like on the YubiKeys.
+    #[cfg(feature = "configuration")]
+    fn accept(&mut self, command: Command, payload: &[u8; PAYLOAD_SIZE]) {
+        let slot1 = CONFIG1_VALID | CONFIG1_TOUCH;
+        let slot2 = CONFIG2_VALID | CONFIG2_TOUCH;
+        // Deleting a slot writes an empty configuration, followed by its CRC.
+        let deleted = payload[..SIZEOF_CONFIG - 2].iter().all(|&b| b == 0);
+        let touch_level = self.status.touch_level;
+        self.status.touch_level = match command {
+            Command::Configuration1 if deleted => touch_level & !slot1,
+            Command::Configuration1 => (touch_level & !slot1) | CONFIG1_VALID,
+            Command::Configuration2 if deleted => touch_level & !slot2,
+            Command::Configuration2 => (touch_level & !slot2) | CONFIG2_VALID,
+            Command::Swap => {
+                (touch_level & !(slot1 | slot2)) | ((touch_level & slot1) << 1) | ((touch_level & slot2) >> 1)
+            }
+            Command::Update1 | Command::Update2 | Command::DeviceConfig => touch_level,
+            _ => return,
+        };
+        self.status.pgm_seq = if self.status.touch_level & (CONFIG1_VALID | CONFIG2_VALID) == 0 {
+            0
+        } else {
+            self.status.pgm_seq.wrapping_add(1)
+        };
+    }
+
+    #[cfg(not(feature = "configuration"))]
+    fn accept(&mut self, _command: Command, _payload: &[u8; PAYLOAD_SIZE]) {}
+
+    fn read_report(&mut self) -> [u8; REPORT_SIZE] {
+        let mut report = [0; REPORT_SIZE];
+        let (response, next) = match self.response {
+            Some((ref response, ref mut next)) => (response, next),
+            None => {
+                report[1] = self.status.version_major;
+                report[2] = self.status.version_minor;
+                report[3] = self.status.version_build;
+                report[4] = self.status.pgm_seq;
+                report[5..7].copy_from_slice(&self.status.touch_level.to_le_bytes());
+                return report;
+            }
+        };
+        let data = &response[(*next * REPORT_DATA_SIZE).min(response.len())..];
+        let len = data.len().min(REPORT_DATA_SIZE);
+        report[..len].copy_from_slice(&data[..len]);
+        // The sequence number wraps back to 0 after the last report of the response.
+        let sequence = if len == 0 { 0 } else { *next as u8 };
+        report[REPORT_DATA_SIZE] = Flags::RESP_PENDING_FLAG.bits() | sequence;
+        *next += 1;
+        report
+    }
+
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) {
+        if *report == WRITE_RESET_PAYLOAD {
+            self.response = None;
+            return;
+        }
+        let offset = (report[REPORT_DATA_SIZE] & !Flags::SLOT_WRITE_FLAG.bits()) as usize * REPORT_DATA_SIZE;
+        if offset + REPORT_DATA_SIZE > FRAME_SIZE {
+            return;
+        }
+        self.frame[offset..offset + REPORT_DATA_SIZE].copy_from_slice(&report[..REPORT_DATA_SIZE]);
+        if offset + REPORT_DATA_SIZE == FRAME_SIZE {
+            self.process_frame();
+        }
+    }
+}
+
+/// A virtual device, see the module documentation.
+///
+/// The device answers the serial number and the configuration commands by default, updating its status
+/// after the configuration commands. The challenges are only answered once scripted with `respond` or
+/// `set_hmac_secret`.
+#[derive(Clone)]
+pub struct MockDevice {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockDevice {
+    /// Creates a YubiKey 5 with the firmware 5.4.3 and the serial number `serial`, with both slots empty.
+    pub fn new(serial: u32) -> Self {
+        let device = Device {
+            name: Some("Mock YubiKey".to_string()),
+            serial: Some(serial),
+            product_id: 0x0407,
+            vendor_id: 0x1050,
+            bus_id: MOCK_BUS,
+            address_id: 0,
+            port_path: None,
+            manufacturer: Some("Yubico".to_string()),
+            release: 0x0543,
+            interfaces: Vec::new(),
+        };
+        MockDevice::from_device(device, 5, 4, 3)
+    }
+
+    /// Creates a device enumerated as `device`, with the firmware `major.minor.build` and both slots empty.
+    /// The bus and address of `device` are replaced by the ones assigned by `MockBackend::with_device`.
+    pub fn from_device(device: Device, major: u8, minor: u8, build: u8) -> Self {
+        let status = Status {
+            version_major: major,
+            version_minor: minor,
+            version_build: build,
+            pgm_seq: 0,
+            touch_level: 0,
+            flags: Flags::empty(),
+        };
+        MockDevice {
+            state: Arc::new(Mutex::new(State {
+                device,
+                status,
+                responders: Vec::new(),
+                received: Vec::new(),
+                frame: [0; FRAME_SIZE],
+                response: None,
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The device, as enumerated by its backend.
+    pub fn device(&self) -> Device {
+        self.state().device.clone()
+    }
+
+    /// The status reported by the device.
+    pub fn status(&self) -> Status {
+        self.state().status
+    }
+
+    pub fn set_status(&self, status: Status) {
+        self.state().status = status;
+    }
+
+    /// Scripts the reply to `command`, computed from the payload of the frame received, e.g. the padded
+    /// challenge. It replaces the previous script of the command. `respond` is called while the device is
+    /// locked, so it must not use the device.
+    pub fn respond<F>(&self, command: Command, respond: F)
+    where
+        F: FnMut(&[u8; PAYLOAD_SIZE]) -> Reply + Send + 'static,
+    {
+        self.state().responders.push((command, Box::new(respond)));
+    }
+
+    /// Configures `slot` as a HMAC-SHA1 challenge-response with `secret`, without touch. With
+    /// `variable_size`, the padding of the challenges is removed before computing their HMAC, like the
+    /// slots configured with `configure::DeviceModeConfig::challenge_response_hmac` with a variable size.
+    pub fn set_hmac_secret(
+        &self,
+        slot: Slot,
+        secret: &[u8],
+        variable_size: bool,
+    ) -> Result<(), ChallengeResponseError> {
+        let command = slot.command_for(&self.device(), Command::ChallengeHmac1, Command::ChallengeHmac2)?;
+        let (valid, touch) = match slot {
+            Slot::Slot1 => (CONFIG1_VALID, CONFIG1_TOUCH),
+            _ => (CONFIG2_VALID, CONFIG2_TOUCH),
+        };
+        {
+            let mut state = self.state();
+            state.status.touch_level = (state.status.touch_level & !touch) | valid;
+        }
+        let secret = secret.to_vec();
+        self.respond(command, move |challenge| {
+            let mut len = PAYLOAD_SIZE;
+            if variable_size {
+                let padding = challenge[PAYLOAD_SIZE - 1];
+                while len > 0 && challenge[len - 1] == padding {
+                    len -= 1;
+                }
+            }
+            match compute_mac::<HmacSha1>(&secret, &challenge[..len]) {
+                Some(mac) => Reply::Data(mac.to_vec()),
+                None => Reply::Silent,
+            }
+        });
+        Ok(())
+    }
+
+    /// The commands received, with the payload of their frame, in the order they were received.
+    pub fn received(&self) -> Vec<(Command, [u8; PAYLOAD_SIZE])> {
+        self.state().received.clone()
+    }
+
+    /// Discards the frame being written and the pending response, like a USB reset.
+    fn reset(&self) {
+        let mut state = self.state();
+        state.frame = [0; FRAME_SIZE];
+        state.response = None;
+    }
+}
+
+impl fmt::Debug for MockDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state();
+        f.debug_struct("MockDevice")
+            .field("device", &state.device)
+            .field("status", &state.status)
+            .finish()
+    }
+}
+
+impl Transport for MockDevice {
+    fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, ChallengeResponseError> {
+        *report = self.state().read_report();
+        Ok(REPORT_SIZE)
+    }
+
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), ChallengeResponseError> {
+        self.state().write_report(report);
+        Ok(())
+    }
+}
+
+/// A backend enumerating `MockDevice`s, used with `ChallengeResponse::from_mock`.
+///
+/// The devices are enumerated in the order they were added, and never change, so waiting for a device to
+/// be connected or disconnected only sleeps. The vendor and product IDs of the devices are not checked.
+#[derive(Default)]
+pub struct MockBackend {
+    devices: Vec<MockDevice>,
+    trace: Option<Mutex<PcapngTrace>>,
+    timing: Timing,
+    reset_policy: ResetPolicy,
+}
+
+impl MockBackend {
+    /// Creates a backend without devices.
+    pub fn new() -> Self {
+        MockBackend::default()
+    }
+
+    /// Adds a device, at the next address of the bus 0.
+    pub fn with_device(mut self, device: MockDevice) -> Self {
+        {
+            let mut state = device.state();
+            state.device.bus_id = MOCK_BUS;
+            state.device.address_id = self.devices.len() as u8 + 1;
+        }
+        self.devices.push(device);
+        self
+    }
+
+    fn device(&self, bus_id: u8, address_id: u8) -> Result<&MockDevice, ChallengeResponseError> {
+        self.devices
+            .iter()
+            .find(|d| bus_id == MOCK_BUS && d.state().device.address_id == address_id)
+            .ok_or(ChallengeResponseError::DeviceNotFound)
+    }
+}
+
+impl fmt::Debug for MockBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockBackend")
+            .field("devices", &self.devices)
+            .field("timing", &self.timing)
+            .finish()
+    }
+}
+
+impl Backend<MockDevice, ()> for MockBackend {
+    fn new() -> Result<Self, ChallengeResponseError> {
+        Ok(MockBackend::default())
+    }
+
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn set_detach_policy(&mut self, _policy: DetachPolicy) {}
+
+    fn set_trace(&mut self, trace: Option<PcapngTrace>) {
+        self.trace = trace.map(Mutex::new);
+    }
+
+    fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    fn timing(&self) -> &Timing {
+        &self.timing
+    }
+
+    fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        self.reset_policy = policy;
+    }
+
+    fn reset_policy(&self) -> ResetPolicy {
+        self.reset_policy
+    }
+
+    fn set_extra_device_ids(&mut self, _ids: Vec<(u16, u16)>) {}
+
+    fn open_device(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<(MockDevice, Vec<()>), ChallengeResponseError> {
+        Ok((self.device(bus_id, address_id)?.clone(), Vec::new()))
+    }
+
+    fn close_device(&self, _handle: MockDevice, _interfaces: Vec<()>) -> Result<(), ChallengeResponseError> {
+        Ok(())
+    }
+
+    fn read_interfaces(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<Vec<InterfaceInfo>, ChallengeResponseError> {
+        Ok(self.device(bus_id, address_id)?.device().interfaces)
+    }
+
+    fn reset_device(&mut self, bus_id: u8, address_id: u8) -> Result<(), ChallengeResponseError> {
+        self.device(bus_id, address_id)?.reset();
+        Ok(())
+    }
+
+    fn read(&self, handle: &mut MockDevice, buf: &mut [u8]) -> Result<usize, ChallengeResponseError> {
+        let report =
+            <&mut [u8; REPORT_SIZE]>::try_from(buf).map_err(|_| ChallengeResponseError::InvalidResponse)?;
+        let read = handle.read_report(report)?;
+        let address_id = handle.state().device.address_id;
+        trace_report(
+            &self.trace,
+            MOCK_BUS as u16,
+            address_id,
+            REQUEST_TYPE_IN,
+            HID_GET_REPORT,
+            &report[..read],
+        );
+        Ok(read)
+    }
+
+    fn raw_write(&self, handle: &mut MockDevice, packet: &[u8]) -> Result<(), ChallengeResponseError> {
+        let report =
+            <&[u8; REPORT_SIZE]>::try_from(packet).map_err(|_| ChallengeResponseError::CanNotWriteToDevice)?;
+        handle.write_report(report)?;
+        let address_id = handle.state().device.address_id;
+        trace_report(
+            &self.trace,
+            MOCK_BUS as u16,
+            address_id,
+            REQUEST_TYPE_OUT,
+            HID_SET_REPORT,
+            packet,
+        );
+        Ok(())
+    }
+
+    fn find_device(&mut self) -> Result<Device, ChallengeResponseError> {
+        self.devices
+            .first()
+            .map(MockDevice::device)
+            .ok_or(ChallengeResponseError::DeviceNotFound)
+    }
+
+    fn find_device_from_serial(&mut self, serial: u32) -> Result<Device, ChallengeResponseError> {
+        self.devices
+            .iter()
+            .map(MockDevice::device)
+            .find(|d| d.serial == Some(serial))
+            .ok_or(ChallengeResponseError::DeviceNotFound)
+    }
+
+    fn find_all_devices(&mut self) -> Result<Vec<Device>, ChallengeResponseError> {
+        if self.devices.is_empty() {
+            return Err(ChallengeResponseError::DeviceNotFound);
+        }
+        Ok(self.devices.iter().map(MockDevice::device).collect())
+    }
+
+    fn enumerate_devices<'a>(
+        &'a mut self,
+    ) -> Box<dyn Iterator<Item = Result<Device, ChallengeResponseError>> + 'a> {
+        Box::new(self.devices.iter().map(|d| Ok(d.device())))
+    }
+
+    fn wait_for_connection(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        if self.devices.is_empty() {
+            thread::sleep(timeout);
+        }
+        Ok(())
+    }
+
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        thread::sleep(timeout);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    #[cfg(feature = "configuration")]
+    use configure::DeviceModeConfig;
+    #[cfg(feature = "configuration")]
+    use hmacmode::HmacKey;
+    #[cfg(feature = "configuration")]
+    use protocol::SlotState;
+    use ChallengeResponse;
+
+    #[test]
+    fn test_challenge_response_hmac() {
+        let device = MockDevice::new(1234567);
+        device.set_hmac_secret(Slot::Slot2, b"secret", true).unwrap();
+        device.set_hmac_secret(Slot::Slot1, b"fixed", false).unwrap();
+        let mut cr = ChallengeResponse::from_mock(MockBackend::new().with_device(device.clone()));
+
+        let found = cr.find_device().unwrap();
+        assert_eq!(found.address_id, 1);
+        assert_eq!(
+            cr.read_serial_number(Config::new_from(found.clone())).unwrap(),
+            1234567
+        );
+
+        let hmac = cr
+            .challenge_response_hmac(b"challenge", Config::new_from(found.clone()))
+            .unwrap();
+        let expected = compute_mac::<HmacSha1>(b"secret", b"challenge").unwrap();
+        assert_eq!(&hmac.0[..], &expected[..]);
+
+        let conf = Config::new_from(found.clone())
+            .set_slot(Slot::Slot1)
+            .set_variable_size(false);
+        let hmac = cr.challenge_response_hmac(b"challenge", conf).unwrap();
+        let mut padded = [0; PAYLOAD_SIZE];
+        padded[..9].copy_from_slice(b"challenge");
+        let expected = compute_mac::<HmacSha1>(b"fixed", &padded).unwrap();
+        assert_eq!(&hmac.0[..], &expected[..]);
+
+        let received: Vec<Command> = device.received().iter().map(|r| r.0).collect();
+        assert_eq!(
+            received,
+            vec![
+                Command::DeviceSerial,
+                Command::ChallengeHmac2,
+                Command::ChallengeHmac1
+            ]
+        );
+
+        device.respond(Command::ChallengeHmac2, |_| Reply::Corrupted(vec![0; 20]));
+        let error = cr
+            .challenge_response_hmac(b"challenge", Config::new_from(found))
+            .unwrap_err();
+        assert!(matches!(error.root_cause(), ChallengeResponseError::WrongCRC(_)));
+    }
+
+    #[cfg(feature = "configuration")]
+    #[test]
+    fn test_write_config() {
+        let device = MockDevice::new(1234567);
+        let mut cr = ChallengeResponse::from_mock(MockBackend::new().with_device(device.clone()));
+        let conf = Config::new_from(device.device()).set_command(Command::Configuration2);
+
+        let mut device_config = DeviceModeConfig::default();
+        device_config.challenge_response_hmac(&HmacKey([0x42; 20]), true, false);
+        cr.write_config(conf.clone(), &mut device_config).unwrap();
+        let status = device.status();
+        assert_eq!(status.slot1(), SlotState::Empty);
+        assert_eq!(status.slot2(), SlotState::Configured { require_touch: false });
+        assert_eq!(status.pgm_seq, 1);
+
+        cr.write_config(conf, &mut DeviceModeConfig::default()).unwrap();
+        assert_eq!(device.status().slot2(), SlotState::Empty);
+        assert_eq!(device.status().pgm_seq, 0);
+        assert_eq!(device.received().len(), 2);
+    }
+}
@@ -0,0 +1,119 @@
+//! Modhex, the encoding of the Yubico OTP public identities and OTPs, and the public identities
+//! themselves.
+//!
+//! The devices type their output as keyboard scan codes, so the bytes are encoded with the 16 characters
+//! whose scan codes are the same on most keyboard layouts: `cbdefghijklnrtuv` stand for the hexadecimal
+//! digits `0` to `f`.
+use std::fmt;
+use std::str::FromStr;
+
+use error::ChallengeResponseError;
+
+const ALPHABET: &[u8; 16] = b"cbdefghijklnrtuv";
+
+/// The maximum size of a public identity, which is stored in the fixed part of the slot configuration.
+pub const MAX_PUBLIC_ID_SIZE: usize = 16;
+
+/// The size of the public identities accepted by YubiCloud.
+pub const YUBICLOUD_PUBLIC_ID_SIZE: usize = 6;
+
+/// Encodes bytes in modhex.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        encoded.push(ALPHABET[(b >> 4) as usize] as char);
+        encoded.push(ALPHABET[(b & 0x0f) as usize] as char);
+    }
+    encoded
+}
+
+/// Decodes a modhex string, ignoring case. Fails with `ChallengeResponseError::InvalidFormat` if it has
+/// an odd length or a character outside of the modhex alphabet.
+pub fn decode(s: &str) -> Result<Vec<u8>, ChallengeResponseError> {
+    let digit = |c: u8| {
+        ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_lowercase())
+            .map(|d| d as u8)
+            .ok_or(ChallengeResponseError::InvalidFormat)
+    };
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(2) {
+        return Err(ChallengeResponseError::InvalidFormat);
+    }
+    s.chunks(2)
+        .map(|pair| Ok(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+/// The public identity of a Yubico OTP slot, typed in modhex before each OTP, which validation servers use
+/// to find the record of the token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicId(Vec<u8>);
+
+impl PublicId {
+    /// The conventional identity derived from a serial number, as programmed by the Yubico tools: the bytes
+    /// `ff 00` followed by the serial number, big-endian, i.e. `vvcc` followed by 8 modhex characters.
+    pub fn from_serial(serial: u32) -> PublicId {
+        let mut id = vec![0xff, 0x00];
+        id.extend_from_slice(&serial.to_be_bytes());
+        PublicId(id)
+    }
+
+    /// A custom identity of up to `MAX_PUBLIC_ID_SIZE` bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<PublicId, ChallengeResponseError> {
+        if bytes.len() > MAX_PUBLIC_ID_SIZE {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        Ok(PublicId(bytes.to_vec()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Whether the identity can be registered with YubiCloud, which only accepts 6 bytes identities.
+    pub fn is_yubicloud_compatible(&self) -> bool {
+        self.0.len() == YUBICLOUD_PUBLIC_ID_SIZE
+    }
+}
+
+impl FromStr for PublicId {
+    type Err = ChallengeResponseError;
+
+    /// Parses a custom identity, in modhex.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PublicId::from_bytes(&decode(s)?)
+    }
+}
+
+impl fmt::Display for PublicId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&encode(&self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modhex() {
+        assert_eq!(
+            encode(&[0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]),
+            "cbdefghijklnrtuv"
+        );
+        assert_eq!(decode("CBDEfghijklnrtuv").unwrap()[..2], [0x01, 0x23]);
+        assert!(decode("cbd").is_err());
+        assert!(decode("cbda").is_err());
+    }
+
+    #[test]
+    fn test_public_id() {
+        let id = PublicId::from_serial(1234567);
+        assert_eq!(id.to_string(), "vvccccbdthji");
+        assert!(id.is_yubicloud_compatible());
+        assert_eq!("vvccccbdthji".parse::<PublicId>().unwrap(), id);
+        assert!("vvcccccccccccccccccccccccccccccccc".parse::<PublicId>().is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! Access to the OATH application of a key, over CCID (smart card interface).
+//!
+//! This complements the slot-based challenge-response: the OATH application stores any number of
+//! HOTP/TOTP credentials and computes their codes on the device. Password-protected OATH applications are
+//! not supported, and operations on them fail with `ChallengeResponseError::ApduError(0x6982)`.
+use std::ffi::CString;
+use std::fmt;
+
+use pcsc::{Card, Context, Protocols, Scope, ShareMode, MAX_BUFFER_SIZE};
+
+use clock::{totp_challenge, Clock, SystemClock};
+use error::ChallengeResponseError;
+
+/// The application identifier of the OATH application.
+const OATH_AID: [u8; 7] = [0xa0, 0x00, 0x00, 0x05, 0x27, 0x21, 0x01];
+
+const INS_SELECT: u8 = 0xa4;
+const INS_LIST: u8 = 0xa1;
+const INS_CALCULATE: u8 = 0xa2;
+const INS_SEND_REMAINING: u8 = 0xa5;
+
+const TAG_NAME: u8 = 0x71;
+const TAG_NAME_LIST: u8 = 0x72;
+const TAG_CHALLENGE: u8 = 0x74;
+const TAG_TRUNCATED_RESPONSE: u8 = 0x76;
+
+const SW_SUCCESS: u16 = 0x9000;
+const SW_MORE_DATA: u8 = 0x61;
+
+/// The default time step of TOTP credentials, in seconds.
+pub const DEFAULT_TOTP_PERIOD: u64 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OathType {
+    Hotp,
+    Totp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OathAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+/// A credential stored in the OATH application.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credential {
+    pub name: String,
+    pub oath_type: OathType,
+    pub algorithm: OathAlgorithm,
+}
+
+impl Credential {
+    fn from_name_list_entry(value: &[u8]) -> Option<Credential> {
+        let (&kind, name) = value.split_first()?;
+        let oath_type = match kind & 0xf0 {
+            0x10 => OathType::Hotp,
+            0x20 => OathType::Totp,
+            _ => return None,
+        };
+        let algorithm = match kind & 0x0f {
+            0x01 => OathAlgorithm::Sha1,
+            0x02 => OathAlgorithm::Sha256,
+            0x03 => OathAlgorithm::Sha512,
+            _ => return None,
+        };
+        Some(Credential {
+            name: String::from_utf8_lossy(name).into_owned(),
+            oath_type,
+            algorithm,
+        })
+    }
+}
+
+/// A one-time code computed by the OATH application.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Code {
+    pub value: u32,
+    pub digits: u8,
+}
+
+impl Code {
+    fn from_truncated_response(value: &[u8]) -> Option<Code> {
+        if value.len() != 5 {
+            return None;
+        }
+        let digits = value[0];
+        let truncated = u32::from_be_bytes([value[1], value[2], value[3], value[4]]) & 0x7fff_ffff;
+        Some(Code {
+            value: truncated % 10u32.checked_pow(digits as u32)?,
+            digits,
+        })
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:0width$}", self.value, width = self.digits as usize)
+    }
+}
+
+/// Iterates over the BER-TLV encoded values of an APDU response.
+fn parse_tlvs(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>, ChallengeResponseError> {
+    let mut tlvs = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 2 {
+            return Err(ChallengeResponseError::InvalidResponse);
+        }
+        let tag = data[0];
+        let (len, header_len) = match data[1] {
+            0x81 if data.len() >= 3 => (data[2] as usize, 3),
+            0x82 if data.len() >= 4 => (u16::from_be_bytes([data[2], data[3]]) as usize, 4),
+            l if l < 0x80 => (l as usize, 2),
+            _ => return Err(ChallengeResponseError::InvalidResponse),
+        };
+        if data.len() < header_len + len {
+            return Err(ChallengeResponseError::InvalidResponse);
+        }
+        tlvs.push((tag, &data[header_len..header_len + len]));
+        data = &data[header_len + len..];
+    }
+    Ok(tlvs)
+}
+
+fn push_tlv(apdu: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    apdu.push(tag);
+    apdu.push(value.len() as u8);
+    apdu.extend_from_slice(value);
+}
+
+/// A session with the OATH application of a key.
+pub struct OathSession {
+    card: Card,
+}
+
+impl OathSession {
+    /// Opens a session with the first smart card reader exposing an OATH application.
+    pub fn open() -> Result<Self, ChallengeResponseError> {
+        let context = Context::establish(Scope::User)?;
+        for reader in context.list_readers_owned()? {
+            if let Ok(session) = Self::open_reader(&context, &reader) {
+                return Ok(session);
+            }
+        }
+        Err(ChallengeResponseError::DeviceNotFound)
+    }
+
+    /// Opens a session with the OATH application available through the given reader.
+    pub fn open_reader(context: &Context, reader: &CString) -> Result<Self, ChallengeResponseError> {
+        let card = context.connect(reader, ShareMode::Shared, Protocols::ANY)?;
+        Self::from_card(card)
+    }
+
+    /// Selects the OATH application on an already connected card.
+    pub fn from_card(card: Card) -> Result<Self, ChallengeResponseError> {
+        let session = OathSession { card };
+        let mut apdu = vec![0x00, INS_SELECT, 0x04, 0x00, OATH_AID.len() as u8];
+        apdu.extend_from_slice(&OATH_AID);
+        session.transmit(&apdu)?;
+        Ok(session)
+    }
+
+    /// Sends an APDU, collecting the whole response if the device sends it in multiple parts.
+    fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, ChallengeResponseError> {
+        let mut response = Vec::new();
+        let mut buf = [0; MAX_BUFFER_SIZE];
+        let mut received = self.card.transmit(apdu, &mut buf)?;
+        loop {
+            if received.len() < 2 {
+                return Err(ChallengeResponseError::InvalidResponse);
+            }
+            let (data, sw) = received.split_at(received.len() - 2);
+            response.extend_from_slice(data);
+            if sw[0] == SW_MORE_DATA {
+                received = self
+                    .card
+                    .transmit(&[0x00, INS_SEND_REMAINING, 0x00, 0x00], &mut buf)?;
+                continue;
+            }
+            let sw = u16::from_be_bytes([sw[0], sw[1]]);
+            if sw != SW_SUCCESS {
+                return Err(ChallengeResponseError::ApduError(sw));
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Lists the credentials stored in the OATH application.
+    pub fn list_credentials(&self) -> Result<Vec<Credential>, ChallengeResponseError> {
+        let response = self.transmit(&[0x00, INS_LIST, 0x00, 0x00])?;
+        let mut credentials = Vec::new();
+        for (tag, value) in parse_tlvs(&response)? {
+            if tag != TAG_NAME_LIST {
+                continue;
+            }
+            credentials
+                .push(Credential::from_name_list_entry(value).ok_or(ChallengeResponseError::InvalidResponse)?);
+        }
+        Ok(credentials)
+    }
+
+    /// Calculates a code for the credential with the given name, using an arbitrary challenge.
+    pub fn calculate(&self, name: &str, challenge: &[u8]) -> Result<Code, ChallengeResponseError> {
+        let mut data = Vec::new();
+        push_tlv(&mut data, TAG_NAME, name.as_bytes());
+        push_tlv(&mut data, TAG_CHALLENGE, challenge);
+
+        // P2 = 0x01 requests a truncated response.
+        let mut apdu = vec![0x00, INS_CALCULATE, 0x00, 0x01, data.len() as u8];
+        apdu.extend_from_slice(&data);
+
+        let response = self.transmit(&apdu)?;
+        for (tag, value) in parse_tlvs(&response)? {
+            if tag == TAG_TRUNCATED_RESPONSE {
+                return Code::from_truncated_response(value).ok_or(ChallengeResponseError::InvalidResponse);
+            }
+        }
+        Err(ChallengeResponseError::InvalidResponse)
+    }
+
+    /// Calculates the next code of an HOTP credential. This increments the counter stored on the device.
+    pub fn calculate_hotp(&self, name: &str) -> Result<Code, ChallengeResponseError> {
+        self.calculate(name, &[])
+    }
+
+    /// Calculates the code of a TOTP credential for the given UNIX timestamp and period, in seconds.
+    pub fn calculate_totp_at(
+        &self,
+        name: &str,
+        timestamp: u64,
+        period: u64,
+    ) -> Result<Code, ChallengeResponseError> {
+        self.calculate(name, &(timestamp / period).to_be_bytes())
+    }
+
+    /// Calculates the current code of a TOTP credential using the default 30 seconds period.
+    pub fn calculate_totp(&self, name: &str) -> Result<Code, ChallengeResponseError> {
+        self.calculate_totp_with(name, &SystemClock, DEFAULT_TOTP_PERIOD)
+    }
+
+    /// Calculates the code of a TOTP credential at the current time of `clock`, for the given period.
+    pub fn calculate_totp_with<C: Clock + ?Sized>(
+        &self,
+        name: &str,
+        clock: &C,
+        period: u64,
+    ) -> Result<Code, ChallengeResponseError> {
+        self.calculate(name, &totp_challenge(clock, period))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list_response() {
+        let response = [
+            0x72, 0x05, 0x21, b'm', b'a', b'i', b'l', //
+            0x72, 0x04, 0x12, b'v', b'p', b'n',
+        ];
+        let credentials: Vec<Credential> = parse_tlvs(&response)
+            .unwrap()
+            .into_iter()
+            .map(|(_, v)| Credential::from_name_list_entry(v).unwrap())
+            .collect();
+
+        assert_eq!(credentials[0].name, "mail");
+        assert_eq!(credentials[0].oath_type, OathType::Totp);
+        assert_eq!(credentials[0].algorithm, OathAlgorithm::Sha1);
+        assert_eq!(credentials[1].name, "vpn");
+        assert_eq!(credentials[1].oath_type, OathType::Hotp);
+        assert_eq!(credentials[1].algorithm, OathAlgorithm::Sha256);
+    }
+
+    #[test]
+    fn test_truncated_code() {
+        // RFC 4226, appendix D, count 0.
+        let code = Code::from_truncated_response(&[6, 0x4c, 0x93, 0xcf, 0x18]).unwrap();
+        assert_eq!(code.to_string(), "755224");
+    }
+
+    #[test]
+    fn test_invalid_tlv() {
+        assert!(parse_tlvs(&[0x72, 0x05, 0x21]).is_err());
+    }
+}
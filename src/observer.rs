@@ -0,0 +1,55 @@
+//! Hooks for logging the operations performed on the devices, and for collecting metrics about them.
+//!
+//! An `Observer` registered with `ChallengeResponse::set_observer` is notified when an operation completes,
+//! with its duration and its outcome, and before a failed operation is retried.
+use std::time::Duration;
+
+use error::ChallengeResponseError;
+use Device;
+
+/// The operations reported to an `Observer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    ChallengeResponseHmac,
+    ChallengeResponseOtp,
+    WriteConfig,
+    ReadSerialNumber,
+    ReadStatus,
+    Reset,
+    Identify,
+}
+
+/// A completed operation.
+#[derive(Debug)]
+pub struct OperationEvent<'a> {
+    pub operation: Operation,
+    pub device: &'a Device,
+    /// The time taken by the operation, including its retries and the time for the user to touch the device.
+    pub elapsed: Duration,
+    /// The error of the operation, if it failed.
+    pub error: Option<&'a ChallengeResponseError>,
+}
+
+pub trait Observer {
+    /// Called when an operation completes, successfully or not.
+    fn operation_completed(&self, event: &OperationEvent);
+
+    /// Called before an operation which failed with `error` is retried, as allowed by the `RetryPolicy`.
+    fn retrying(&self, _operation: Operation, _device: &Device, _error: &ChallengeResponseError) {}
+}
+
+/// Prints a line per operation on the standard error, for debugging.
+pub struct StderrLogger;
+
+impl Observer for StderrLogger {
+    fn operation_completed(&self, event: &OperationEvent) {
+        match event.error {
+            Some(e) => eprintln!("{:?} failed after {:?}: {}", event.operation, event.elapsed, e),
+            None => eprintln!("{:?} completed in {:?}", event.operation, event.elapsed),
+        }
+    }
+
+    fn retrying(&self, operation: Operation, _device: &Device, error: &ChallengeResponseError) {
+        eprintln!("Retrying {:?} after: {}", operation, error);
+    }
+}
@@ -0,0 +1,150 @@
+//! Parsing of `otpauth://hotp/` URIs, as issued by identity providers for HOTP tokens.
+//!
+//! The URIs look like `otpauth://hotp/Example:alice@example.com?secret=JBSWY3DPEHPK3PXP&counter=0`, with
+//! the base32 encoded secret, and optional `issuer`, `digits` (6 or 8) and `algorithm` (only `SHA1` is
+//! supported by the devices) parameters. The secret can be programmed in a slot with
+//! `DeviceModeConfig::oath_hotp_uri`.
+use std::str::FromStr;
+
+use error::ChallengeResponseError;
+use hmacmode::{HmacKey, HMAC_SECRET_SIZE};
+
+const SCHEME: &str = "otpauth://hotp/";
+
+/// The parameters of an `otpauth://hotp/` URI.
+#[derive(Debug)]
+pub struct HotpUri {
+    /// The account name, with the issuer prefix, e.g. `Example:alice@example.com`.
+    pub label: String,
+    pub issuer: Option<String>,
+    /// The secret, padded with zeroes to 20 bytes, which does not change the HMAC-SHA1 codes.
+    pub secret: HmacKey,
+    pub digits: u8,
+    pub counter: u64,
+}
+
+fn decode_base32(s: &str) -> Result<Vec<u8>, ChallengeResponseError> {
+    let mut bytes = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in s.chars().filter(|&c| c != '=') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return Err(ChallengeResponseError::InvalidFormat),
+        };
+        buffer = buffer << 5 | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Ok(bytes)
+}
+
+fn percent_decode(s: &str) -> Result<String, ChallengeResponseError> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [
+                    iter.next().ok_or(ChallengeResponseError::InvalidFormat)?,
+                    iter.next().ok_or(ChallengeResponseError::InvalidFormat)?,
+                ];
+                let hex = std::str::from_utf8(&hex).map_err(|_| ChallengeResponseError::InvalidFormat)?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| ChallengeResponseError::InvalidFormat)?);
+            }
+            b'+' => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| ChallengeResponseError::InvalidFormat)
+}
+
+impl FromStr for HotpUri {
+    type Err = ChallengeResponseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix(SCHEME)
+            .ok_or(ChallengeResponseError::InvalidFormat)?;
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut secret = None;
+        let mut issuer = None;
+        let mut digits = 6;
+        let mut counter = None;
+        for parameter in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            let value = percent_decode(value)?;
+            match key {
+                "secret" => secret = Some(decode_base32(&value)?),
+                "issuer" => issuer = Some(value),
+                "digits" => digits = value.parse().map_err(|_| ChallengeResponseError::InvalidFormat)?,
+                "counter" => counter = Some(value.parse().map_err(|_| ChallengeResponseError::InvalidFormat)?),
+                "algorithm" if value.eq_ignore_ascii_case("SHA1") => {}
+                "algorithm" => return Err(ChallengeResponseError::InvalidFormat),
+                // Other parameters, e.g. `image`, do not affect the codes.
+                _ => {}
+            }
+        }
+
+        let mut secret = secret.ok_or(ChallengeResponseError::InvalidFormat)?;
+        if secret.is_empty() || secret.len() > HMAC_SECRET_SIZE || (digits != 6 && digits != 8) {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let mut key = HmacKey([0; HMAC_SECRET_SIZE]);
+        key.0[..secret.len()].copy_from_slice(&secret);
+        for i in secret.iter_mut() {
+            *i = 0;
+        }
+
+        Ok(HotpUri {
+            label: percent_decode(label)?,
+            issuer,
+            secret: key,
+            digits,
+            counter: counter.ok_or(ChallengeResponseError::InvalidFormat)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uri() {
+        let uri: HotpUri =
+            "otpauth://hotp/Example:alice%40example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Example&counter=32&digits=8"
+                .parse()
+                .unwrap();
+        assert_eq!(uri.label, "Example:alice@example.com");
+        assert_eq!(uri.issuer.as_deref(), Some("Example"));
+        // The secret of the RFC 4226 test vectors.
+        assert_eq!(&uri.secret.0, b"12345678901234567890");
+        assert_eq!(uri.digits, 8);
+        assert_eq!(uri.counter, 32);
+    }
+
+    #[test]
+    fn test_parse_invalid_uri() {
+        assert!("otpauth://totp/alice?secret=GEZDGNBV&counter=0"
+            .parse::<HotpUri>()
+            .is_err());
+        assert!("otpauth://hotp/alice?secret=GEZDGNBV".parse::<HotpUri>().is_err());
+        assert!("otpauth://hotp/alice?secret=GEZDGNBV&counter=0&algorithm=SHA256"
+            .parse::<HotpUri>()
+            .is_err());
+        assert!("otpauth://hotp/alice?secret=GEZDGNB1&counter=0"
+            .parse::<HotpUri>()
+            .is_err());
+
+        let uri: HotpUri = "otpauth://hotp/alice?secret=gezdgnbv&counter=0".parse().unwrap();
+        assert_eq!(&uri.secret.0[..5], b"12345");
+        assert_eq!(uri.secret.0[5..], [0; 15]);
+    }
+}
@@ -1,10 +1,11 @@
-use crate::error::ChallengeResponseError;
-use crate::sec::{crc16, CRC_RESIDUAL_OK};
+use crate::error::{ChallengeResponseError, CrcError};
 use aes::cipher::generic_array::typenum::U16;
 use aes::cipher::generic_array::GenericArray;
 use aes::cipher::{BlockDecrypt, KeyInit};
 use aes::Aes128;
+#[cfg(feature = "rand")]
 use rand::Rng;
+use rand_core::CryptoRng;
 use std;
 
 #[repr(C)]
@@ -25,6 +26,22 @@ pub struct Otp {
     pub crc: u16,
 }
 
+impl Otp {
+    /// Parses a decrypted OTP, whose integer fields are encoded little-endian.
+    fn from_bytes(bytes: &[u8]) -> Otp {
+        let mut uid = [0; 6];
+        uid.copy_from_slice(&bytes[..6]);
+        Otp {
+            uid,
+            use_counter: u16::from_le_bytes([bytes[6], bytes[7]]),
+            timestamp: [bytes[8], bytes[9], bytes[10]],
+            session_counter: bytes[11],
+            random_number: u16::from_le_bytes([bytes[12], bytes[13]]),
+            crc: u16::from_le_bytes([bytes[14], bytes[15]]),
+        }
+    }
+}
+
 /// A secret key for AES128 / OTP challenge-response.
 #[derive(Debug)]
 pub struct Aes128Key(pub [u8; 16]);
@@ -39,10 +56,18 @@ impl Drop for Aes128Key {
 impl Aes128Key {
     pub fn from_slice(s: &[u8]) -> Self {
         let mut key = Aes128Key([0; 16]);
-        (&mut key.0).clone_from_slice(s);
+        key.0.clone_from_slice(s);
+        key
+    }
+
+    /// Generates a random key using the provided cryptographically secure RNG.
+    pub fn from_rng<R: CryptoRng + ?Sized>(rng: &mut R) -> Self {
+        let mut key = Aes128Key([0; 16]);
+        rng.fill_bytes(&mut key.0);
         key
     }
 
+    #[cfg(feature = "rand")]
     pub fn generate<R: Rng>(mut rng: R) -> Self {
         let mut key = Aes128Key([0; 16]);
         for i in key.0.iter_mut() {
@@ -79,20 +104,13 @@ impl Aes128Block {
     /// larger than the last value seen.
     pub fn check(&self, key: &Aes128Key, challenge: &[u8]) -> Result<Otp, ChallengeResponseError> {
         let aes_dec = Aes128::new(GenericArray::from_slice(&key.0));
-        let mut tmp = Otp::default();
-        {
-            let tmp = unsafe { std::slice::from_raw_parts_mut(&mut tmp as *mut Otp as *mut u8, 16) };
-            let mut block_copy = &mut self.block.clone();
-            aes_dec.decrypt_block(&mut block_copy);
-            tmp.copy_from_slice(block_copy);
-
-            if crc16(&tmp) != CRC_RESIDUAL_OK {
-                return Err(ChallengeResponseError::WrongCRC);
-            }
-        }
+        let block_copy = &mut self.block.clone();
+        aes_dec.decrypt_block(block_copy);
+        CrcError::check(block_copy)?;
+        let mut tmp = Otp::from_bytes(block_copy);
 
-        for i in 0..6 {
-            tmp.uid[i] ^= challenge[i]
+        for (uid, c) in tmp.uid.iter_mut().zip(challenge.iter()) {
+            *uid ^= c
         }
 
         Ok(tmp)
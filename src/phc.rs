@@ -0,0 +1,161 @@
+//! Device-bound password hashing, storing the hashes as standard PHC strings.
+//!
+//! `PepperedHasher` wraps a `password_hash::PasswordHasher`, e.g. `argon2::Argon2` or `pbkdf2::Pbkdf2`,
+//! and hashes the password followed by the HMAC-SHA1 response of a token to a per-user challenge, the
+//! SHA-256 hash of the salt. The response acts as a pepper: the hashes can only be verified with the
+//! token, and a leaked database can not be brute-forced without it.
+//!
+//! The PHC strings are those of the wrapped hasher, with its algorithm and parameters, so the usual
+//! storage formats and parameter upgrades apply. `PasswordVerifier` is implemented by `PepperedHasher`
+//! through `PasswordHasher`, so verifying a hash also takes a challenge-response.
+//!
+//! ```no_run
+//! # extern crate pbkdf2;
+//! use challenge_response::config::Config;
+//! use challenge_response::phc::{PasswordHash, PasswordHasher, PasswordVerifier, PepperedHasher, SaltString};
+//! use challenge_response::token::UsbToken;
+//! use challenge_response::ChallengeResponse;
+//!
+//! let mut cr = ChallengeResponse::new().unwrap();
+//! let device = cr.find_device().unwrap();
+//! let hasher = PepperedHasher::new(UsbToken::new(cr, Config::new_from(device)), pbkdf2::Pbkdf2);
+//!
+//! let salt = SaltString::from_b64("c2FsdHNhbHRzYWx0").unwrap();
+//! let hash = hasher.hash_password(b"hunter2", &salt).unwrap().to_string();
+//! assert!(hasher
+//!     .verify_password(b"hunter2", &PasswordHash::new(&hash).unwrap())
+//!     .is_ok());
+//! ```
+use std::cell::RefCell;
+
+use password_hash::{Ident, Salt};
+use sha2::{Digest, Sha256};
+
+use token::HmacToken;
+
+pub use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+
+/// A `PasswordHasher` peppering the passwords with the response of a token.
+pub struct PepperedHasher<T: HmacToken, H: PasswordHasher> {
+    token: RefCell<T>,
+    hasher: H,
+}
+
+impl<T: HmacToken, H: PasswordHasher> PepperedHasher<T, H> {
+    pub fn new(token: T, hasher: H) -> Self {
+        PepperedHasher {
+            token: RefCell::new(token),
+            hasher,
+        }
+    }
+
+    pub fn into_inner(self) -> (T, H) {
+        (self.token.into_inner(), self.hasher)
+    }
+
+    /// The password followed by the response of the token to the challenge of `salt`.
+    fn pepper(&self, password: &[u8], salt: Salt) -> password_hash::Result<Vec<u8>> {
+        let challenge = Sha256::digest(salt.as_str().as_bytes());
+        // The `password_hash` errors can not carry the error of the token.
+        let response = self
+            .token
+            .borrow_mut()
+            .challenge_response(&challenge)
+            .map_err(|_| password_hash::Error::Crypto)?;
+        let mut peppered = Vec::with_capacity(password.len() + response.0.len());
+        peppered.extend_from_slice(password);
+        peppered.extend_from_slice(&response.0);
+        Ok(peppered)
+    }
+}
+
+impl<T: HmacToken, H: PasswordHasher> PasswordHasher for PepperedHasher<T, H> {
+    type Params = H::Params;
+
+    /// Hashes the peppered password with the wrapped hasher. Fails with `password_hash::Error::Crypto` if
+    /// the challenge-response fails.
+    fn hash_password_customized<'a>(
+        &self,
+        password: &[u8],
+        algorithm: Option<Ident<'a>>,
+        version: Option<u32>,
+        params: Self::Params,
+        salt: impl Into<Salt<'a>>,
+    ) -> password_hash::Result<PasswordHash<'a>> {
+        let salt = salt.into();
+        let mut peppered = self.pepper(password, salt)?;
+        let hash = self
+            .hasher
+            .hash_password_customized(&peppered, algorithm, version, params, salt);
+        peppered.fill(0);
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::ChallengeResponseError;
+    use hmacmode::{Hmac, HmacKey};
+    use protocol::{compute_mac, HmacSha1};
+    use token::TokenInfo;
+
+    /// Computes the responses like a slot programmed with `key`.
+    struct SoftToken {
+        key: HmacKey,
+    }
+
+    impl HmacToken for SoftToken {
+        fn challenge_response(&mut self, challenge: &[u8]) -> Result<Hmac, ChallengeResponseError> {
+            let mut hmac = Hmac([0; 20]);
+            hmac.0
+                .copy_from_slice(&compute_mac::<HmacSha1>(&self.key.0, challenge).unwrap());
+            Ok(hmac)
+        }
+
+        fn info(&self) -> TokenInfo {
+            TokenInfo {
+                name: None,
+                serial: None,
+            }
+        }
+
+        fn requires_touch(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_peppered_hasher() {
+        let salt = SaltString::from_b64("c2FsdHNhbHRzYWx0").unwrap();
+        let params = pbkdf2::Params {
+            rounds: 1000,
+            output_length: 32,
+        };
+        let hasher = PepperedHasher::new(
+            SoftToken {
+                key: HmacKey([1; 20]),
+            },
+            pbkdf2::Pbkdf2,
+        );
+        let hash = hasher
+            .hash_password_customized(b"hunter2", None, None, params, &salt)
+            .unwrap()
+            .to_string();
+        assert!(hash.starts_with("$pbkdf2-sha256$i=1000,l=32$c2FsdHNhbHRzYWx0$"));
+
+        let parsed = PasswordHash::new(&hash).unwrap();
+        assert!(hasher.verify_password(b"hunter2", &parsed).is_ok());
+        assert!(hasher.verify_password(b"hunter3", &parsed).is_err());
+
+        // The hash can not be verified without the token, nor with another one.
+        assert!(pbkdf2::Pbkdf2.verify_password(b"hunter2", &parsed).is_err());
+        let other = PepperedHasher::new(
+            SoftToken {
+                key: HmacKey([2; 20]),
+            },
+            pbkdf2::Pbkdf2,
+        );
+        assert!(other.verify_password(b"hunter2", &parsed).is_err());
+    }
+}
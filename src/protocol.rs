@@ -0,0 +1,832 @@
+//! Transport-independent parts of the challenge-response protocol.
+//!
+//! This module only depends on `core`, so it is available when the crate is built without its default
+//! `std` feature. It covers everything that does not involve talking to a USB stack: encoding frames into
+//! HID feature reports, decoding status reports, reassembling responses and validating them. `Exchange`
+//! sequences them into a whole command, leaving only the transfers of the reports to the transport.
+pub use sec::{compute_mac, verify_hmac_sha1, verify_mac, HmacSha1};
+
+/// If using a variable-length challenge, the challenge must be stricly smaller than this value.
+/// If using a fixed-length challenge, the challenge must be exactly equal to this value.
+pub const CHALLENGE_SIZE: usize = 64;
+
+/// The size of the payload when writing a request to the device.
+pub const PAYLOAD_SIZE: usize = 64;
+/// The size of a serialized frame (payload, command, CRC and filler).
+pub const FRAME_SIZE: usize = 70;
+/// The size of a response after writing a request to the device.
+pub const RESPONSE_SIZE: usize = 36;
+/// The size of a single HID feature report exchanged with the device.
+pub const REPORT_SIZE: usize = 8;
+
+/// Number of data bytes carried by a single feature report. The last byte holds the flags.
+const REPORT_DATA_SIZE: usize = REPORT_SIZE - 1;
+const SEQUENCE_MASK: u8 = 0b0001_1111;
+
+pub const HID_GET_REPORT: u8 = 0x01;
+pub const HID_SET_REPORT: u8 = 0x09;
+pub const REPORT_TYPE_FEATURE: u16 = 0x03;
+
+/// The report to write to the device to reset its write state after a response was read.
+pub const WRITE_RESET_PAYLOAD: [u8; REPORT_SIZE] = [0, 0, 0, 0, 0, 0, 0, 0x8f];
+
+const CRC_PRESET_VALUE: u16 = 0xFFFF;
+const CRC_POLYNOMIAL: u16 = 0x8408;
+/// The residual of `crc16` over data followed by its own (valid) CRC.
+pub const CRC_RESIDUAL_OK: u16 = 0xf0b8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Command {
+    #[cfg(feature = "configuration")]
+    Configuration1 = 0x01,
+    #[cfg(feature = "configuration")]
+    Configuration2 = 0x03,
+    #[cfg(feature = "configuration")]
+    Update1 = 0x04,
+    #[cfg(feature = "configuration")]
+    Update2 = 0x05,
+    #[cfg(feature = "configuration")]
+    Swap = 0x06,
+    DeviceSerial = 0x10,
+    #[cfg(feature = "configuration")]
+    DeviceConfig = 0x11,
+    ChallengeOtp1 = 0x20,
+    ChallengeOtp2 = 0x28,
+    ChallengeHmac1 = 0x30,
+    ChallengeHmac2 = 0x38,
+}
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Flags: u8 {
+        const SLOT_WRITE_FLAG = 0x80;
+        const RESP_PENDING_FLAG = 0x40;
+        /// Set while the device waits for the user to touch it.
+        const RESP_TIMEOUT_WAIT_FLAG = 0x20;
+    }
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc_value = CRC_PRESET_VALUE;
+    for &b in data {
+        crc_value ^= b as u16;
+        for _ in 0..8 {
+            let j = crc_value & 1;
+            crc_value >>= 1;
+            if j != 0 {
+                crc_value ^= CRC_POLYNOMIAL
+            }
+        }
+    }
+    crc_value
+}
+
+/// Checks that `data` is terminated by a valid CRC.
+pub fn check_crc(data: &[u8]) -> bool {
+    crc16(data) == CRC_RESIDUAL_OK
+}
+
+#[repr(C)]
+#[repr(packed)]
+pub struct Frame {
+    pub payload: [u8; PAYLOAD_SIZE],
+    command: Command,
+    crc: u16,
+    filler: [u8; 3],
+}
+
+impl Frame {
+    pub fn new(payload: [u8; PAYLOAD_SIZE], command: Command) -> Self {
+        let mut f = Frame {
+            payload,
+            command,
+            crc: 0,
+            filler: [0; 3],
+        };
+        f.crc = crc16(&f.payload);
+        f
+    }
+
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    /// Serializes the frame in the layout expected by the device.
+    pub fn to_bytes(&self) -> [u8; FRAME_SIZE] {
+        let mut bytes = [0; FRAME_SIZE];
+        bytes[..PAYLOAD_SIZE].copy_from_slice(&self.payload);
+        bytes[PAYLOAD_SIZE] = self.command as u8;
+        let crc = self.crc;
+        bytes[PAYLOAD_SIZE + 1..PAYLOAD_SIZE + 3].copy_from_slice(&crc.to_le_bytes());
+        bytes[PAYLOAD_SIZE + 3..].copy_from_slice(&self.filler);
+        bytes
+    }
+
+    /// Returns the feature reports to write to the device to send this frame.
+    pub fn reports(&self) -> FrameReports {
+        FrameReports {
+            data: self.to_bytes(),
+            seq: 0,
+        }
+    }
+}
+
+/// Iterator over the feature reports making up a frame.
+///
+/// Each report carries 7 bytes of the frame and its sequence number. Reports which only contain zeroes
+/// are skipped, except for the first and the last ones.
+pub struct FrameReports {
+    data: [u8; FRAME_SIZE],
+    seq: u8,
+}
+
+impl Iterator for FrameReports {
+    type Item = [u8; REPORT_SIZE];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let offset = self.seq as usize * REPORT_DATA_SIZE;
+            if offset >= FRAME_SIZE {
+                return None;
+            }
+            let seq = self.seq;
+            self.seq += 1;
+
+            let chunk = &self.data[offset..offset + REPORT_DATA_SIZE];
+            let is_last = offset + REPORT_DATA_SIZE >= FRAME_SIZE;
+            if seq == 0 || is_last || chunk.iter().any(|&x| x != 0) {
+                let mut report = [0; REPORT_SIZE];
+                report[..REPORT_DATA_SIZE].copy_from_slice(chunk);
+                report[REPORT_DATA_SIZE] = Flags::SLOT_WRITE_FLAG.bits() + seq;
+                return Some(report);
+            }
+        }
+    }
+}
+
+/// The bits of `Status::touch_level` describing the slots.
+pub(crate) const CONFIG1_VALID: u16 = 0x01;
+pub(crate) const CONFIG2_VALID: u16 = 0x02;
+pub(crate) const CONFIG1_TOUCH: u16 = 0x04;
+pub(crate) const CONFIG2_TOUCH: u16 = 0x08;
+
+/// The state of a slot, as reported in the status of the device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlotState {
+    Empty,
+    Configured {
+        /// Whether the slot is programmed to wait for the user to touch the device.
+        require_touch: bool,
+    },
+}
+
+impl SlotState {
+    fn from_touch_level(touch_level: u16, valid: u16, touch: u16) -> SlotState {
+        if touch_level & valid == 0 {
+            return SlotState::Empty;
+        }
+        SlotState::Configured {
+            require_touch: touch_level & touch != 0,
+        }
+    }
+
+    pub fn is_configured(&self) -> bool {
+        matches!(*self, SlotState::Configured { .. })
+    }
+}
+
+/// The status of the device, as returned in a feature report.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Status {
+    pub version_major: u8,
+    pub version_minor: u8,
+    pub version_build: u8,
+    /// Incremented every time the device configuration is written.
+    pub pgm_seq: u8,
+    /// The state of the slots, decoded by `slot1` and `slot2`.
+    pub touch_level: u16,
+    pub flags: Flags,
+}
+
+impl Status {
+    pub fn from_report(report: &[u8; REPORT_SIZE]) -> Status {
+        Status {
+            version_major: report[1],
+            version_minor: report[2],
+            version_build: report[3],
+            pgm_seq: report[4],
+            touch_level: u16::from_le_bytes([report[5], report[6]]),
+            flags: Flags::from_bits_truncate(report[7]),
+        }
+    }
+
+    pub fn slot1(&self) -> SlotState {
+        SlotState::from_touch_level(self.touch_level, CONFIG1_VALID, CONFIG1_TOUCH)
+    }
+
+    pub fn slot2(&self) -> SlotState {
+        SlotState::from_touch_level(self.touch_level, CONFIG2_VALID, CONFIG2_TOUCH)
+    }
+
+    /// Checks whether the device was reprogrammed since the programming sequence `known_pgm_seq` was
+    /// read from it. The device with its programming sequence should be persisted by the caller when it is
+    /// first enrolled.
+    ///
+    /// Any difference is reported, including the sequence wrapping around or being reset to 0, which
+    /// happens when all the slots of the device are erased.
+    pub fn is_reconfigured_since(&self, known_pgm_seq: u8) -> bool {
+        self.pgm_seq != known_pgm_seq
+    }
+}
+
+/// What a device is doing, as reported by the flags of its feature reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Activity {
+    /// The device accepts commands.
+    Idle,
+    /// The device is processing the frame last written to it, e.g. writing a configuration.
+    Busy,
+    /// The device holds a response which was not read yet.
+    ResponsePending,
+    /// The device waits for the user to touch it before responding.
+    AwaitingTouch,
+}
+
+/// The last byte of a feature report read from the device, with its flags and the sequence number of the
+/// report.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatusByte {
+    pub flags: Flags,
+    pub sequence: u8,
+}
+
+impl StatusByte {
+    pub fn from_report(report: &[u8; REPORT_SIZE]) -> StatusByte {
+        StatusByte {
+            flags: Flags::from_bits_truncate(report[REPORT_DATA_SIZE]),
+            sequence: report[REPORT_DATA_SIZE] & SEQUENCE_MASK,
+        }
+    }
+
+    pub fn activity(&self) -> Activity {
+        if self.flags.contains(Flags::RESP_TIMEOUT_WAIT_FLAG) {
+            Activity::AwaitingTouch
+        } else if self.flags.contains(Flags::RESP_PENDING_FLAG) {
+            Activity::ResponsePending
+        } else if self.flags.contains(Flags::SLOT_WRITE_FLAG) {
+            Activity::Busy
+        } else {
+            Activity::Idle
+        }
+    }
+}
+
+/// What the caller should do after feeding a report to a `ResponseReader`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseState {
+    /// The device is not sending a response yet. Poll again later.
+    Waiting,
+    /// The device waits for the user to touch it before sending the response. Poll again later.
+    WaitingForTouch,
+    /// Part of the response was received. Read the next report immediately.
+    Receiving,
+    /// The response is complete. The device write state must now be reset.
+    Complete,
+}
+
+/// A response of the device, before being parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawResponse {
+    pub bytes: [u8; RESPONSE_SIZE],
+    pub len: usize,
+    /// The flags of the last report read from the device.
+    pub flags: Flags,
+    /// Whether a stale response, left pending by a previous client, was discarded before the command was
+    /// written.
+    pub stale_response_cleared: bool,
+}
+
+impl RawResponse {
+    /// The bytes received from the device.
+    pub fn data(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Reassembles a response from the feature reports read from the device.
+pub struct ResponseReader {
+    buf: [u8; RESPONSE_SIZE],
+    len: usize,
+    started: bool,
+    flags: Flags,
+}
+
+impl Default for ResponseReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseReader {
+    pub fn new() -> Self {
+        ResponseReader {
+            buf: [0; RESPONSE_SIZE],
+            len: 0,
+            started: false,
+            flags: Flags::empty(),
+        }
+    }
+
+    pub fn feed(&mut self, report: &[u8; REPORT_SIZE]) -> ResponseState {
+        let flags = Flags::from_bits_truncate(report[REPORT_DATA_SIZE]);
+        self.flags = flags;
+        if !self.started {
+            if flags.contains(Flags::RESP_TIMEOUT_WAIT_FLAG) {
+                return ResponseState::WaitingForTouch;
+            }
+            if !flags.contains(Flags::RESP_PENDING_FLAG) {
+                return ResponseState::Waiting;
+            }
+            self.started = true;
+        } else {
+            // The sequence number wraps back to 0 once the device is done sending the response.
+            if !flags.contains(Flags::RESP_PENDING_FLAG) || report[REPORT_DATA_SIZE] & SEQUENCE_MASK == 0 {
+                return ResponseState::Complete;
+            }
+        }
+
+        if self.len + REPORT_DATA_SIZE > RESPONSE_SIZE {
+            return ResponseState::Complete;
+        }
+        self.buf[self.len..self.len + REPORT_DATA_SIZE].copy_from_slice(&report[..REPORT_DATA_SIZE]);
+        self.len += REPORT_DATA_SIZE;
+        ResponseState::Receiving
+    }
+
+    /// The bytes received so far.
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// The flags of the last report fed to the reader.
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn into_raw(self) -> RawResponse {
+        RawResponse {
+            bytes: self.buf,
+            len: self.len,
+            flags: self.flags,
+            stale_response_cleared: false,
+        }
+    }
+}
+
+/// What the device is busy with, while an `Exchange` polls it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pending {
+    /// The device is processing a report written to it.
+    Write,
+    /// The device is computing its response.
+    Response,
+    /// The device waits for the user to touch it before computing its response.
+    Touch,
+}
+
+/// What the transport should do next to drive an `Exchange`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    /// Read a feature report and pass it to `Exchange::report_read`. If the device was busy at the last read,
+    /// `poll` tells why, and the transport should wait for its poll interval before reading again.
+    Read { poll: Option<Pending> },
+    /// The device is ready to receive the command. Its status can be checked, e.g. with
+    /// `quirks::supports_command`, before calling `Exchange::proceed`.
+    Ready(Status),
+    /// Write this feature report and call `Exchange::report_written`.
+    Write([u8; REPORT_SIZE]),
+    /// The exchange is complete. A response, if any, is returned by `Exchange::into_response`.
+    Done,
+    /// The device still has a response pending after the stale one was discarded, so the command can not
+    /// be written. The transport should fail, as if the device never became ready.
+    Stuck,
+}
+
+/// The part of an `Exchange` in progress, to describe the transfers which failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// Polling the status of the device, before or after writing.
+    Polling,
+    /// Writing the report number `packet_seq` of the frame, or waiting for the device to accept it.
+    WritingFrame { packet_seq: usize },
+    /// Reading the response, after `bytes_read` bytes were received, or resetting the write state after it.
+    ReadingResponse { bytes_read: usize },
+    /// Writing the report resetting the write state of the device.
+    Resetting,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ExchangeState {
+    /// Waiting for the device to accept the command.
+    Start {
+        poll: bool,
+    },
+    Ready(Status),
+    /// Discarding a response left pending by a previous client, before starting again.
+    ClearStale,
+    /// The response was still pending after it was discarded.
+    Stuck,
+    /// Waiting for the device to accept the next report of the frame.
+    Writable {
+        poll: bool,
+        report: [u8; REPORT_SIZE],
+    },
+    Writing([u8; REPORT_SIZE]),
+    Response {
+        poll: Option<Pending>,
+    },
+    Reset,
+    /// Waiting for the device to be done with the last report written.
+    Settle {
+        poll: bool,
+    },
+    Done,
+}
+
+/// The exchange of a frame, and of its response if any, with a device, independent of the transport.
+///
+/// The exchange produces the feature reports to write and consumes the ones read from the device, so it can
+/// be driven by any transport, blocking or not, or by tests. The transport repeatedly performs
+/// `Exchange::step` until it returns `Step::Done`.
+pub struct Exchange {
+    state: ExchangeState,
+    reports: Option<FrameReports>,
+    packet_seq: usize,
+    expects_response: bool,
+    stale_response_cleared: bool,
+    reader: ResponseReader,
+}
+
+impl Exchange {
+    fn new(reports: Option<FrameReports>, expects_response: bool) -> Exchange {
+        Exchange {
+            state: if reports.is_some() {
+                ExchangeState::Start { poll: false }
+            } else {
+                ExchangeState::Reset
+            },
+            reports,
+            packet_seq: 0,
+            expects_response,
+            stale_response_cleared: false,
+            reader: ResponseReader::new(),
+        }
+    }
+
+    /// Sends a command with a response, e.g. a challenge.
+    pub fn command(frame: &Frame) -> Exchange {
+        Exchange::new(Some(frame.reports()), true)
+    }
+
+    /// Sends a command without a response, e.g. a configuration.
+    pub fn write(frame: &Frame) -> Exchange {
+        Exchange::new(Some(frame.reports()), false)
+    }
+
+    /// Resets the write state of the device, discarding any pending response.
+    pub fn reset() -> Exchange {
+        Exchange::new(None, false)
+    }
+
+    /// What to do next.
+    pub fn step(&self) -> Step {
+        match self.state {
+            ExchangeState::Start { poll }
+            | ExchangeState::Writable { poll, .. }
+            | ExchangeState::Settle { poll } => Step::Read {
+                poll: if poll { Some(Pending::Write) } else { None },
+            },
+            ExchangeState::Ready(status) => Step::Ready(status),
+            ExchangeState::Writing(report) => Step::Write(report),
+            ExchangeState::Response { poll } => Step::Read { poll },
+            ExchangeState::Reset | ExchangeState::ClearStale => Step::Write(WRITE_RESET_PAYLOAD),
+            ExchangeState::Done => Step::Done,
+            ExchangeState::Stuck => Step::Stuck,
+        }
+    }
+
+    /// The part of the exchange in progress, to describe a failure of the transfer of `step`.
+    pub fn phase(&self) -> Phase {
+        match self.state {
+            ExchangeState::Writable { .. } | ExchangeState::Writing(_) => Phase::WritingFrame {
+                packet_seq: self.packet_seq - 1,
+            },
+            ExchangeState::Response { .. } => Phase::ReadingResponse {
+                bytes_read: self.reader.data().len(),
+            },
+            ExchangeState::Reset | ExchangeState::Settle { .. } if self.expects_response => {
+                Phase::ReadingResponse {
+                    bytes_read: self.reader.data().len(),
+                }
+            }
+            ExchangeState::Reset | ExchangeState::ClearStale => Phase::Resetting,
+            _ => Phase::Polling,
+        }
+    }
+
+    /// Continues after `Step::Ready`.
+    pub fn proceed(&mut self) {
+        if let ExchangeState::Ready(_) = self.state {
+            self.next_report(true);
+        }
+    }
+
+    /// Moves to the next report of the frame, which is written as soon as the device accepts it, or if
+    /// `writable` is set, immediately.
+    fn next_report(&mut self, writable: bool) {
+        self.state = match self.reports.as_mut().and_then(Iterator::next) {
+            Some(report) => {
+                self.packet_seq += 1;
+                if writable {
+                    ExchangeState::Writing(report)
+                } else {
+                    ExchangeState::Writable { poll: false, report }
+                }
+            }
+            None if self.expects_response => ExchangeState::Response { poll: None },
+            None => ExchangeState::Settle { poll: false },
+        };
+    }
+
+    /// Consumes a feature report of `len` bytes read from the device, after `Step::Read`.
+    pub fn report_read(&mut self, report: &[u8; REPORT_SIZE], len: usize) {
+        let flags = Flags::from_bits_truncate(report[REPORT_DATA_SIZE]);
+        let writable = !flags.contains(Flags::SLOT_WRITE_FLAG);
+        self.state = match self.state {
+            // A previous client died before reading the response to its command. The response is only
+            // discarded once, a device which keeps it pending is not usable.
+            ExchangeState::Start { .. } if flags.contains(Flags::RESP_PENDING_FLAG) => {
+                if self.stale_response_cleared {
+                    ExchangeState::Stuck
+                } else {
+                    self.stale_response_cleared = true;
+                    ExchangeState::ClearStale
+                }
+            }
+            ExchangeState::Start { .. } if writable => ExchangeState::Ready(Status::from_report(report)),
+            ExchangeState::Start { .. } => ExchangeState::Start { poll: true },
+            ExchangeState::Writable { report, .. } if writable => ExchangeState::Writing(report),
+            ExchangeState::Writable { report, .. } => ExchangeState::Writable { poll: true, report },
+            ExchangeState::Settle { .. } if writable => ExchangeState::Done,
+            ExchangeState::Settle { .. } => ExchangeState::Settle { poll: true },
+            ExchangeState::Response { .. } => match self.reader.feed(report) {
+                ResponseState::Waiting => ExchangeState::Response {
+                    poll: Some(Pending::Response),
+                },
+                ResponseState::WaitingForTouch => ExchangeState::Response {
+                    poll: Some(Pending::Touch),
+                },
+                ResponseState::Receiving if len == REPORT_SIZE => ExchangeState::Response { poll: None },
+                _ => ExchangeState::Reset,
+            },
+            state => state,
+        };
+    }
+
+    /// Continues after the report of `Step::Write` was written.
+    pub fn report_written(&mut self) {
+        match self.state {
+            ExchangeState::Writing(_) => self.next_report(false),
+            ExchangeState::Reset => self.state = ExchangeState::Settle { poll: false },
+            ExchangeState::ClearStale => self.state = ExchangeState::Start { poll: false },
+            _ => {}
+        }
+    }
+
+    /// Whether a stale response, left pending by a previous client, was discarded before the frame was
+    /// written.
+    pub fn stale_response_cleared(&self) -> bool {
+        self.stale_response_cleared
+    }
+
+    /// The response read from the device.
+    pub fn into_response(self) -> RawResponse {
+        RawResponse {
+            stale_response_cleared: self.stale_response_cleared,
+            ..self.reader.into_raw()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_reports_skip_empty_chunks() {
+        let mut payload = [0; PAYLOAD_SIZE];
+        payload[..3].copy_from_slice(b"abc");
+        let frame = Frame::new(payload, Command::ChallengeHmac2);
+
+        let reports: [[u8; REPORT_SIZE]; 2] = {
+            let mut it = frame.reports();
+            let first = it.next().unwrap();
+            let second = it.next().unwrap();
+            assert!(it.next().is_none());
+            [first, second]
+        };
+
+        assert_eq!(&reports[0][..3], b"abc");
+        assert_eq!(reports[0][7], 0x80);
+        // The command, CRC and filler are all in the last report.
+        assert_eq!(reports[1][7], 0x80 + 9);
+        assert_eq!(reports[1][1], Command::ChallengeHmac2 as u8);
+    }
+
+    #[test]
+    fn test_response_reader() {
+        let mut reader = ResponseReader::new();
+        assert_eq!(reader.feed(&[0, 0, 0, 0, 0, 0, 0, 0]), ResponseState::Waiting);
+        assert_eq!(
+            reader.feed(&[0, 0, 0, 0, 0, 0, 0, 0x2e]),
+            ResponseState::WaitingForTouch
+        );
+        assert_eq!(
+            reader.feed(&[1, 2, 3, 4, 5, 6, 7, 0x40]),
+            ResponseState::Receiving
+        );
+        assert_eq!(
+            reader.feed(&[8, 9, 10, 11, 12, 13, 14, 0x41]),
+            ResponseState::Receiving
+        );
+        assert_eq!(reader.feed(&[0, 0, 0, 0, 0, 0, 0, 0x40]), ResponseState::Complete);
+        assert_eq!(reader.data(), &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]);
+
+        let raw = reader.into_raw();
+        assert_eq!(raw.data(), &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]);
+        assert_eq!(raw.flags, Flags::RESP_PENDING_FLAG);
+    }
+
+    #[test]
+    fn test_exchange() {
+        let mut payload = [0; PAYLOAD_SIZE];
+        payload[..3].copy_from_slice(b"abc");
+        let frame = Frame::new(payload, Command::ChallengeHmac2);
+        let reports: [[u8; REPORT_SIZE]; 2] = {
+            let mut it = frame.reports();
+            [it.next().unwrap(), it.next().unwrap()]
+        };
+
+        let mut exchange = Exchange::command(&frame);
+        assert_eq!(exchange.step(), Step::Read { poll: None });
+        exchange.report_read(&[0, 0, 0, 0, 0, 0, 0, 0x80], REPORT_SIZE);
+        assert_eq!(
+            exchange.step(),
+            Step::Read {
+                poll: Some(Pending::Write)
+            }
+        );
+        let status = [0, 5, 4, 3, 7, 0x03, 0x00, 0x00];
+        exchange.report_read(&status, REPORT_SIZE);
+        assert_eq!(exchange.step(), Step::Ready(Status::from_report(&status)));
+        exchange.proceed();
+
+        assert_eq!(exchange.step(), Step::Write(reports[0]));
+        assert_eq!(exchange.phase(), Phase::WritingFrame { packet_seq: 0 });
+        exchange.report_written();
+        assert_eq!(exchange.step(), Step::Read { poll: None });
+        exchange.report_read(&[0; REPORT_SIZE], REPORT_SIZE);
+        assert_eq!(exchange.step(), Step::Write(reports[1]));
+        assert_eq!(exchange.phase(), Phase::WritingFrame { packet_seq: 1 });
+        exchange.report_written();
+
+        exchange.report_read(&[0, 0, 0, 0, 0, 0, 0, 0x20], REPORT_SIZE);
+        assert_eq!(
+            exchange.step(),
+            Step::Read {
+                poll: Some(Pending::Touch)
+            }
+        );
+        exchange.report_read(&[1, 2, 3, 4, 5, 6, 7, 0x40], REPORT_SIZE);
+        assert_eq!(exchange.step(), Step::Read { poll: None });
+        assert_eq!(exchange.phase(), Phase::ReadingResponse { bytes_read: 7 });
+        exchange.report_read(&[8, 9, 10, 11, 12, 13, 14, 0x41], REPORT_SIZE);
+        exchange.report_read(&[0, 0, 0, 0, 0, 0, 0, 0x40], REPORT_SIZE);
+        assert_eq!(exchange.step(), Step::Write(WRITE_RESET_PAYLOAD));
+        exchange.report_written();
+        exchange.report_read(&[0; REPORT_SIZE], REPORT_SIZE);
+        assert_eq!(exchange.step(), Step::Done);
+        assert_eq!(
+            exchange.into_response().data(),
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]
+        );
+
+        let mut exchange = Exchange::reset();
+        assert_eq!(exchange.step(), Step::Write(WRITE_RESET_PAYLOAD));
+        assert_eq!(exchange.phase(), Phase::Resetting);
+        exchange.report_written();
+        assert_eq!(exchange.phase(), Phase::Polling);
+        exchange.report_read(&[0; REPORT_SIZE], REPORT_SIZE);
+        assert_eq!(exchange.step(), Step::Done);
+    }
+
+    #[test]
+    fn test_status_byte() {
+        let idle = StatusByte::from_report(&[0, 5, 4, 3, 7, 0x03, 0x00, 0x00]);
+        assert_eq!(idle.activity(), Activity::Idle);
+        let pending = StatusByte::from_report(&[0, 0, 0, 0, 0, 0, 0, 0x42]);
+        assert_eq!(pending.activity(), Activity::ResponsePending);
+        assert_eq!(pending.sequence, 2);
+        let touch = StatusByte::from_report(&[0, 0, 0, 0, 0, 0, 0, 0x20]);
+        assert_eq!(touch.activity(), Activity::AwaitingTouch);
+        let busy = StatusByte::from_report(&[0, 0, 0, 0, 0, 0, 0, 0x80]);
+        assert_eq!(busy.activity(), Activity::Busy);
+    }
+
+    #[test]
+    fn test_exchange_clears_stale_response() {
+        let frame = Frame::new([0; PAYLOAD_SIZE], Command::DeviceSerial);
+        let mut exchange = Exchange::command(&frame);
+        exchange.report_read(&[1, 2, 3, 4, 5, 6, 7, 0x42], REPORT_SIZE);
+        assert_eq!(exchange.step(), Step::Write(WRITE_RESET_PAYLOAD));
+        assert_eq!(exchange.phase(), Phase::Resetting);
+        exchange.report_written();
+        assert_eq!(exchange.step(), Step::Read { poll: None });
+        let status = [0, 5, 4, 3, 7, 0x03, 0x00, 0x00];
+        exchange.report_read(&status, REPORT_SIZE);
+        assert_eq!(exchange.step(), Step::Ready(Status::from_report(&status)));
+        assert!(exchange.stale_response_cleared());
+    }
+
+    #[test]
+    fn test_exchange_stuck_pending_response() {
+        let frame = Frame::new([0; PAYLOAD_SIZE], Command::DeviceSerial);
+        let mut exchange = Exchange::command(&frame);
+        let mut resets = 0;
+        for _ in 0..100 {
+            match exchange.step() {
+                Step::Read { .. } => exchange.report_read(&[1, 2, 3, 4, 5, 6, 7, 0x42], REPORT_SIZE),
+                Step::Write(report) => {
+                    assert_eq!(report, WRITE_RESET_PAYLOAD);
+                    resets += 1;
+                    exchange.report_written();
+                }
+                step => {
+                    assert_eq!(step, Step::Stuck);
+                    break;
+                }
+            }
+        }
+        assert_eq!(exchange.step(), Step::Stuck);
+        assert_eq!(exchange.phase(), Phase::Polling);
+        assert_eq!(resets, 1);
+    }
+
+    #[test]
+    fn test_status_from_report() {
+        let status = Status::from_report(&[0, 5, 4, 3, 7, 0x03, 0x00, 0x00]);
+        assert_eq!(
+            (status.version_major, status.version_minor, status.version_build),
+            (5, 4, 3)
+        );
+        assert_eq!(status.pgm_seq, 7);
+        assert_eq!(status.touch_level, 0x03);
+        assert_eq!(status.slot1(), SlotState::Configured { require_touch: false });
+        assert!(status.slot2().is_configured());
+        let status = Status::from_report(&[0, 5, 4, 3, 7, 0x0a, 0x00, 0x00]);
+        assert_eq!(status.slot1(), SlotState::Empty);
+        assert_eq!(status.slot2(), SlotState::Configured { require_touch: true });
+        assert!(!status.is_reconfigured_since(7));
+        assert!(status.is_reconfigured_since(6));
+    }
+
+    #[test]
+    fn test_crc() {
+        let mut data = [0x01, 0x02, 0x03, 0x04, 0, 0];
+        let crc = 0xffff - crc16(&data[..4]);
+        data[4..].copy_from_slice(&crc.to_le_bytes());
+        assert!(check_crc(&data));
+        data[0] = 0xff;
+        assert!(!check_crc(&data));
+    }
+
+    #[test]
+    fn test_verify_hmac_sha1() {
+        // RFC 2202, test case 2.
+        let expected = [
+            0xef, 0xfc, 0xdf, 0x6a, 0xe5, 0xeb, 0x2f, 0xa2, 0xd2, 0x74, 0x16, 0xd5, 0xf1, 0x84, 0xdf, 0x9c,
+            0x25, 0x9a, 0x7c, 0x79,
+        ];
+        assert!(verify_hmac_sha1(
+            b"Jefe",
+            b"what do ya want for nothing?",
+            &expected
+        ));
+        assert!(!verify_hmac_sha1(
+            b"Jefe",
+            b"what do ya want for something?",
+            &expected
+        ));
+    }
+}
@@ -0,0 +1,662 @@
+//! Declarative provisioning of devices, for reproducible fleet configuration.
+//!
+//! A `Profile` describes how a slot is configured, and where its secret comes from. Profiles are written
+//! as `key = value` lines, e.g.:
+//!
+//! ```text
+//! # Touchless HMAC-SHA1 challenge-response in slot 2, with a random secret for every device.
+//! slot = 2
+//! mode = hmac-sha1
+//! variable = true
+//! touch = false
+//! ext-flags = SERIAL_API_VISIBLE | ALLOW_UPDATE
+//! key = random
+//! ```
+//!
+//! The `mode` is either `hmac-sha1` or `yubico-otp`, in which case the private identity is given as
+//! `private-id = <12 hex digits>`. The `key` is either `random` or `hex:<hex digits>`, in which case all
+//! the devices are programmed with the same secret. With the `entropy` feature, it can also be
+//! `device-entropy:<slot>`, for stations whose RNG is not trusted: the secret is then generated by an
+//! `entropy::MixedRng`, mixing the responses of the Yubico OTP slot `<slot>` of the device with the
+//! randomness of the operating system.
+//!
+//! `apply_profile_staged` reprograms a slot which already holds a working configuration without ever
+//! leaving the device without one: the new configuration is written to the other slot and verified there
+//! before the slots are swapped, and is deleted if the verification fails.
+//!
+//! `run_batch` applies a profile to every device plugged in, one after the other, for provisioning a fleet
+//! of keys from a single station.
+//!
+//! With the `signed-profiles` feature, `Profile::load_signed` only accepts a profile with a detached
+//! ed25519 signature made by a trusted key, so the technicians running the batches can only write the
+//! configurations approved by the fleet operators. With the `provisioning-log` feature, the provisioned
+//! devices can be recorded in a tamper-evident log, see the `provisioning_log` module.
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(feature = "signed-profiles")]
+use ed25519_dalek::{Signature, VerifyingKey, SIGNATURE_LENGTH};
+#[cfg(feature = "entropy")]
+use entropy::MixedRng;
+use rand_core::CryptoRng;
+
+use config::{Command, Config, Slot};
+use configure::{DeviceModeConfig, ExtendedFlags};
+use error::ChallengeResponseError;
+use hmacmode::{HmacKey, HMAC_SECRET_SIZE};
+#[cfg(feature = "otp")]
+use otpmode::Aes128Key;
+use usb::CHALLENGE_SIZE;
+use vendor;
+use {ChallengeResponse, Device, DeviceId};
+
+#[cfg(feature = "otp")]
+const AES_KEY_SIZE: usize = 16;
+#[cfg(feature = "otp")]
+const PRIVATE_ID_SIZE: usize = 6;
+const TEST_CHALLENGE_SIZE: usize = 32;
+
+/// The mode a profile configures the slot in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProfileMode {
+    /// HMAC-SHA1 challenge-response, with variable-length challenges if `variable` is `true`.
+    HmacSha1 { variable: bool },
+    /// Yubico OTP challenge-response.
+    #[cfg(feature = "otp")]
+    YubicoOtp { private_id: [u8; PRIVATE_ID_SIZE] },
+}
+
+/// Where the secret programmed by a profile comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeySource {
+    /// A new random secret for every device.
+    Random,
+    /// The same secret for every device.
+    Fixed(Vec<u8>),
+    /// A new secret for every device, mixing the responses of a Yubico OTP slot of the device with OS
+    /// randomness, instead of the RNG given to `apply_profile`.
+    #[cfg(feature = "entropy")]
+    DeviceEntropy(Slot),
+}
+
+/// The declarative configuration of a slot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    pub slot: Slot,
+    pub mode: ProfileMode,
+    pub require_touch: bool,
+    pub ext_flags: ExtendedFlags,
+    pub key_source: KeySource,
+}
+
+impl Profile {
+    /// Reads a profile from a file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Profile, ChallengeResponseError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Reads a profile from a file, after checking that `signature_path` holds a detached signature of the
+    /// file by `key`. See `verify_signed`.
+    #[cfg(feature = "signed-profiles")]
+    pub fn load_signed<P: AsRef<Path>, S: AsRef<Path>>(
+        path: P,
+        signature_path: S,
+        key: &VerifyingKey,
+    ) -> Result<Profile, ChallengeResponseError> {
+        Profile::verify_signed(&std::fs::read(path)?, &std::fs::read(signature_path)?, key)
+    }
+
+    /// Parses a profile after checking its detached ed25519 `signature` by `key`. The signature covers the
+    /// exact bytes of the profile, and is either the 64 raw bytes of the signature or their hex encoding.
+    /// Fails with `ChallengeResponseError::InvalidSignature` if the signature does not match, without
+    /// looking at the content of the profile.
+    #[cfg(feature = "signed-profiles")]
+    pub fn verify_signed(
+        profile: &[u8],
+        signature: &[u8],
+        key: &VerifyingKey,
+    ) -> Result<Profile, ChallengeResponseError> {
+        let signature = if signature.len() == SIGNATURE_LENGTH {
+            signature.to_vec()
+        } else {
+            std::str::from_utf8(signature)
+                .ok()
+                .and_then(|hex| decode_hex(hex.trim()).ok())
+                .ok_or(ChallengeResponseError::InvalidSignature)?
+        };
+        let signature =
+            Signature::from_slice(&signature).map_err(|_| ChallengeResponseError::InvalidSignature)?;
+        key.verify_strict(profile, &signature)
+            .map_err(|_| ChallengeResponseError::InvalidSignature)?;
+        std::str::from_utf8(profile)
+            .map_err(|_| ChallengeResponseError::InvalidFormat)?
+            .parse()
+    }
+
+    fn secret_size(&self) -> usize {
+        match self.mode {
+            ProfileMode::HmacSha1 { .. } => HMAC_SECRET_SIZE,
+            #[cfg(feature = "otp")]
+            ProfileMode::YubicoOtp { .. } => AES_KEY_SIZE,
+        }
+    }
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, ChallengeResponseError> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(ChallengeResponseError::InvalidFormat);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| ChallengeResponseError::InvalidFormat))
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_bool(s: &str) -> Result<bool, ChallengeResponseError> {
+    match s {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(ChallengeResponseError::InvalidFormat),
+    }
+}
+
+impl FromStr for Profile {
+    type Err = ChallengeResponseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut slot = None;
+        let mut mode = None;
+        let mut variable = true;
+        #[cfg(feature = "otp")]
+        let mut private_id = [0; PRIVATE_ID_SIZE];
+        let mut require_touch = false;
+        let mut ext_flags = ExtendedFlags::empty();
+        let mut key_source = None;
+
+        for line in s.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(ChallengeResponseError::InvalidFormat)?;
+            let value = value.trim();
+            match key.trim() {
+                // Only the first two slots can be configured.
+                "slot" => {
+                    slot = Some(
+                        Slot::from_str(value)
+                            .filter(|slot| {
+                                slot.command(Command::Configuration1, Command::Configuration2)
+                                    .is_ok()
+                            })
+                            .ok_or(ChallengeResponseError::InvalidFormat)?,
+                    )
+                }
+                "mode" => mode = Some(value.to_string()),
+                "variable" => variable = parse_bool(value)?,
+                #[cfg(feature = "otp")]
+                "private-id" => {
+                    let id = decode_hex(value)?;
+                    if id.len() != PRIVATE_ID_SIZE {
+                        return Err(ChallengeResponseError::InvalidFormat);
+                    }
+                    private_id.copy_from_slice(&id);
+                }
+                "touch" => require_touch = parse_bool(value)?,
+                "ext-flags" => {
+                    ext_flags =
+                        bitflags::parser::from_str(value).map_err(|_| ChallengeResponseError::InvalidFormat)?
+                }
+                "key" if value == "random" => key_source = Some(KeySource::Random),
+                #[cfg(feature = "entropy")]
+                "key" if value.starts_with("device-entropy:") => {
+                    let slot = Slot::from_str(&value["device-entropy:".len()..])
+                        .filter(|slot| {
+                            slot.command(Command::ChallengeOtp1, Command::ChallengeOtp2)
+                                .is_ok()
+                        })
+                        .ok_or(ChallengeResponseError::InvalidFormat)?;
+                    key_source = Some(KeySource::DeviceEntropy(slot));
+                }
+                "key" => {
+                    let hex = value
+                        .strip_prefix("hex:")
+                        .ok_or(ChallengeResponseError::InvalidFormat)?;
+                    key_source = Some(KeySource::Fixed(decode_hex(hex)?));
+                }
+                _ => return Err(ChallengeResponseError::InvalidFormat),
+            }
+        }
+
+        let mode = match mode.as_deref() {
+            Some("hmac-sha1") => ProfileMode::HmacSha1 { variable },
+            #[cfg(feature = "otp")]
+            Some("yubico-otp") => ProfileMode::YubicoOtp { private_id },
+            _ => return Err(ChallengeResponseError::InvalidFormat),
+        };
+        let profile = Profile {
+            slot: slot.ok_or(ChallengeResponseError::InvalidFormat)?,
+            mode,
+            require_touch,
+            ext_flags,
+            key_source: key_source.ok_or(ChallengeResponseError::InvalidFormat)?,
+        };
+        if let KeySource::Fixed(ref key) = profile.key_source {
+            if key.len() != profile.secret_size() {
+                return Err(ChallengeResponseError::InvalidFormat);
+            }
+        }
+        Ok(profile)
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "slot = {}", self.slot.number())?;
+        match self.mode {
+            ProfileMode::HmacSha1 { variable } => {
+                writeln!(f, "mode = hmac-sha1")?;
+                writeln!(f, "variable = {}", variable)?;
+            }
+            #[cfg(feature = "otp")]
+            ProfileMode::YubicoOtp { ref private_id } => {
+                writeln!(f, "mode = yubico-otp")?;
+                writeln!(f, "private-id = {}", encode_hex(private_id))?;
+            }
+        }
+        writeln!(f, "touch = {}", self.require_touch)?;
+        if !self.ext_flags.is_empty() {
+            write!(f, "ext-flags = ")?;
+            bitflags::parser::to_writer(&self.ext_flags, &mut *f)?;
+            writeln!(f)?;
+        }
+        match self.key_source {
+            KeySource::Random => writeln!(f, "key = random"),
+            KeySource::Fixed(ref key) => writeln!(f, "key = hex:{}", encode_hex(key)),
+            #[cfg(feature = "entropy")]
+            KeySource::DeviceEntropy(ref slot) => writeln!(f, "key = device-entropy:{}", slot.number()),
+        }
+    }
+}
+
+/// The secret programmed in a slot by `apply_profile`.
+pub struct ProvisionedSecret(pub Vec<u8>);
+
+impl Drop for ProvisionedSecret {
+    fn drop(&mut self) {
+        for i in self.0.iter_mut() {
+            *i = 0;
+        }
+    }
+}
+
+impl std::ops::Deref for ProvisionedSecret {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Configures the slot of `device` described by `profile`, generating the secret with `rng` if the profile
+/// asks for a random one, or with the device for `KeySource::DeviceEntropy`. Returns the programmed secret.
+pub fn apply_profile<R: CryptoRng + ?Sized>(
+    cr: &mut ChallengeResponse,
+    device: Device,
+    profile: &Profile,
+    rng: &mut R,
+) -> Result<ProvisionedSecret, ChallengeResponseError> {
+    let secret = match profile.key_source {
+        KeySource::Random => {
+            let mut secret = ProvisionedSecret(vec![0; profile.secret_size()]);
+            rng.fill_bytes(&mut secret.0);
+            secret
+        }
+        KeySource::Fixed(ref key) if key.len() == profile.secret_size() => ProvisionedSecret(key.clone()),
+        KeySource::Fixed(_) => return Err(ChallengeResponseError::InvalidFormat),
+        #[cfg(feature = "entropy")]
+        KeySource::DeviceEntropy(ref slot) => {
+            let mut secret = ProvisionedSecret(vec![0; profile.secret_size()]);
+            let conf = Config::new_from(device.clone()).set_slot(slot.clone());
+            MixedRng::new(cr, conf).try_fill_bytes(&mut secret.0)?;
+            secret
+        }
+    };
+
+    let mut device_config = DeviceModeConfig::default();
+    match profile.mode {
+        ProfileMode::HmacSha1 { variable } => device_config.challenge_response_hmac(
+            &HmacKey::from_slice(&secret),
+            variable,
+            profile.require_touch,
+        ),
+        #[cfg(feature = "otp")]
+        ProfileMode::YubicoOtp { ref private_id } => device_config.challenge_response_otp(
+            &Aes128Key::from_slice(&secret),
+            private_id,
+            profile.require_touch,
+        ),
+    }
+    device_config.ext_flags = profile.ext_flags;
+
+    let command = profile
+        .slot
+        .command_for(&device, Command::Configuration1, Command::Configuration2)?;
+    let conf = Config::new_from(device)
+        .set_slot(profile.slot.clone())
+        .set_command(command);
+    cr.write_config(conf, &mut device_config)?;
+    Ok(secret)
+}
+
+/// Reprograms the slot of `device` described by `profile`, in two phases so that a failed write never
+/// leaves the device without a working configuration in the slot:
+///
+/// 1. the profile is written to the other slot, which must be empty,
+/// 2. the other slot is verified with a test challenge, and deleted if it does not answer as expected,
+/// 3. the slots are swapped, and the previous configuration, now in the other slot, is deleted.
+///
+/// Swapping the slots requires firmware 2.3 or later, and both configurations must have been written
+/// with `ExtendedFlags::ALLOW_UPDATE`: the profile is rejected with `ChallengeResponseError::InvalidFormat`
+/// without it. Fails with `ChallengeResponseError::SlotOccupied` if the other slot is configured, and
+/// with `ChallengeResponseError::VerificationFailed` after rolling back if the new configuration does
+/// not answer the test challenge. If the swap itself fails, the new configuration is deleted as well,
+/// and the slot keeps its previous configuration.
+pub fn apply_profile_staged<R: CryptoRng + ?Sized>(
+    cr: &mut ChallengeResponse,
+    device: Device,
+    profile: &Profile,
+    rng: &mut R,
+) -> Result<ProvisionedSecret, ChallengeResponseError> {
+    if !profile.ext_flags.contains(ExtendedFlags::ALLOW_UPDATE) {
+        return Err(ChallengeResponseError::InvalidFormat);
+    }
+    let staging = staging_slot(&profile.slot)?;
+    let status = cr.read_status(&device)?;
+    if !vendor::supports_slot_update(device.vendor_id, device.product_id, &status) {
+        return Err(ChallengeResponseError::CommandNotSupported);
+    }
+    let staging_state = match staging {
+        Slot::Slot1 => status.slot1(),
+        _ => status.slot2(),
+    };
+    if staging_state.is_configured() {
+        return Err(ChallengeResponseError::SlotOccupied(staging.number()));
+    }
+
+    let staged = Profile {
+        slot: staging.clone(),
+        ..profile.clone()
+    };
+    let secret = match apply_profile(cr, device.clone(), &staged, rng) {
+        Ok(secret) => secret,
+        Err(e) => {
+            // The write may have been applied even if reading its result failed.
+            let _ = delete_slot(cr, device, &staging);
+            return Err(e);
+        }
+    };
+    let result = verify_profile(cr, device.clone(), &staged, &secret, rng).and_then(|()| {
+        let conf = Config::new_from(device.clone()).set_command(Command::Swap);
+        cr.write_config(conf, &mut DeviceModeConfig::default())
+    });
+    if let Err(e) = result {
+        // The original configuration is still in its slot, only the staged one is removed.
+        let _ = delete_slot(cr, device, &staging);
+        return Err(e);
+    }
+    delete_slot(cr, device, &staging)?;
+    Ok(secret)
+}
+
+/// The slot a new configuration of `slot` is staged in by `apply_profile_staged`.
+fn staging_slot(slot: &Slot) -> Result<Slot, ChallengeResponseError> {
+    match *slot {
+        Slot::Slot1 => Ok(Slot::Slot2),
+        Slot::Slot2 => Ok(Slot::Slot1),
+        Slot::Other(number) => Err(ChallengeResponseError::SlotNotSupported(number)),
+    }
+}
+
+/// Deletes the configuration of `slot`, by writing an empty configuration to it.
+fn delete_slot(cr: &mut ChallengeResponse, device: Device, slot: &Slot) -> Result<(), ChallengeResponseError> {
+    let command = slot.command_for(&device, Command::Configuration1, Command::Configuration2)?;
+    let conf = Config::new_from(device)
+        .set_slot(slot.clone())
+        .set_command(command);
+    cr.write_config(conf, &mut DeviceModeConfig::default())
+}
+
+/// Sends a random challenge to the slot configured by `apply_profile`, and checks the response against
+/// the programmed secret.
+fn verify_profile<R: CryptoRng + ?Sized>(
+    cr: &mut ChallengeResponse,
+    device: Device,
+    profile: &Profile,
+    secret: &ProvisionedSecret,
+    rng: &mut R,
+) -> Result<(), ChallengeResponseError> {
+    let conf = Config::new_from(device).set_slot(profile.slot.clone());
+    match profile.mode {
+        ProfileMode::HmacSha1 { variable } => {
+            let mut challenge = [0; CHALLENGE_SIZE];
+            let len = if variable {
+                TEST_CHALLENGE_SIZE
+            } else {
+                CHALLENGE_SIZE
+            };
+            rng.fill_bytes(&mut challenge[..len]);
+            if variable {
+                // The device strips the trailing zeroes used as padding of variable-length challenges.
+                challenge[len - 1] |= 1;
+            }
+            let hmac = cr.challenge_response_hmac(&challenge[..len], conf.set_variable_size(variable))?;
+            if !hmac.check(&HmacKey::from_slice(secret), &challenge[..len]) {
+                return Err(ChallengeResponseError::VerificationFailed);
+            }
+        }
+        #[cfg(feature = "otp")]
+        ProfileMode::YubicoOtp { ref private_id } => {
+            let mut challenge = [0; PRIVATE_ID_SIZE];
+            rng.fill_bytes(&mut challenge);
+            let block = cr.challenge_response_otp(&challenge, conf)?;
+            match block.check(&Aes128Key::from_slice(secret), &challenge) {
+                Ok(ref otp) if otp.uid == *private_id => {}
+                _ => return Err(ChallengeResponseError::VerificationFailed),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Receives the secrets programmed by `run_batch`, for example to store them in an inventory.
+pub trait ProvisioningSink {
+    /// Called once for every device, after its slot was programmed and verified. An error stops the batch.
+    fn record(&mut self, device: &Device, secret: &ProvisionedSecret) -> Result<(), ChallengeResponseError>;
+
+    /// Called when the slot of a device was programmed but did not answer the test challenge as expected,
+    /// before the batch stops with `ChallengeResponseError::VerificationFailed`.
+    fn verification_failed(&mut self, _device: &Device) -> Result<(), ChallengeResponseError> {
+        Ok(())
+    }
+}
+
+impl<F> ProvisioningSink for F
+where
+    F: FnMut(&Device, &ProvisionedSecret) -> Result<(), ChallengeResponseError>,
+{
+    fn record(&mut self, device: &Device, secret: &ProvisionedSecret) -> Result<(), ChallengeResponseError> {
+        self(device, secret)
+    }
+}
+
+/// When `run_batch` stops.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchOptions {
+    /// The number of devices to provision, or `None` to provision devices until none is plugged in for
+    /// `insert_timeout`.
+    pub count: Option<usize>,
+    /// How long to wait for the next device to be plugged in.
+    pub insert_timeout: Duration,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            count: None,
+            insert_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Provisions every device plugged in with `profile`: each device is programmed with `apply_profile`,
+/// verified with a test challenge, and its secret is given to `sink`. Devices already provisioned during
+/// the batch are skipped, so the next key can be plugged in before the previous one is removed.
+///
+/// Returns the number of provisioned devices. Fails with `ChallengeResponseError::DeviceNotFound` if
+/// `options.count` devices were not plugged in in time, and stops at the first device which could not be
+/// provisioned, with `ChallengeResponseError::VerificationFailed` if the slot does not answer as expected.
+pub fn run_batch<R, S>(
+    cr: &mut ChallengeResponse,
+    profile: &Profile,
+    options: &BatchOptions,
+    rng: &mut R,
+    sink: &mut S,
+) -> Result<usize, ChallengeResponseError>
+where
+    R: CryptoRng + ?Sized,
+    S: ProvisioningSink + ?Sized,
+{
+    let mut provisioned: HashSet<DeviceId> = HashSet::new();
+    while options.count.is_none_or(|count| provisioned.len() < count) {
+        let device =
+            match cr.wait_for_device_matching(|d| !provisioned.contains(&d.id()), options.insert_timeout) {
+                Ok(device) => device,
+                Err(ChallengeResponseError::DeviceNotFound) if options.count.is_none() => break,
+                Err(e) => return Err(e),
+            };
+
+        let secret = apply_profile(cr, device.clone(), profile, rng)?;
+        match verify_profile(cr, device.clone(), profile, &secret, rng) {
+            Err(ChallengeResponseError::VerificationFailed) => {
+                sink.verification_failed(&device)?;
+                return Err(ChallengeResponseError::VerificationFailed);
+            }
+            result => result?,
+        }
+        sink.record(&device, &secret)?;
+        provisioned.insert(device.id());
+    }
+    Ok(provisioned.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() {
+        let profile: Profile = "# Slot 2
+            slot = 2
+            mode = hmac-sha1
+            variable = false
+            touch = true
+            ext-flags = SERIAL_API_VISIBLE | ALLOW_UPDATE
+            key = random"
+            .parse()
+            .unwrap();
+        assert_eq!(profile.slot, Slot::Slot2);
+        assert_eq!(profile.mode, ProfileMode::HmacSha1 { variable: false });
+        assert!(profile.require_touch);
+        assert_eq!(
+            profile.ext_flags,
+            ExtendedFlags::SERIAL_API_VISIBLE | ExtendedFlags::ALLOW_UPDATE
+        );
+        assert_eq!(profile.key_source, KeySource::Random);
+        assert_eq!(profile.to_string().parse::<Profile>().unwrap(), profile);
+    }
+
+    #[test]
+    fn test_staging_slot() {
+        assert_eq!(staging_slot(&Slot::Slot1).unwrap(), Slot::Slot2);
+        assert_eq!(staging_slot(&Slot::Slot2).unwrap(), Slot::Slot1);
+        assert!(matches!(
+            staging_slot(&Slot::Other(3)),
+            Err(ChallengeResponseError::SlotNotSupported(3))
+        ));
+    }
+
+    #[test]
+    fn test_parse_invalid_profile() {
+        // The key of an OTP slot is 16 bytes long.
+        assert!("slot = 1\nmode = yubico-otp\nkey = hex:00112233"
+            .parse::<Profile>()
+            .is_err());
+        assert!("slot = 3\nmode = hmac-sha1\nkey = random"
+            .parse::<Profile>()
+            .is_err());
+        assert!("slot = 1\nmode = hmac-sha1".parse::<Profile>().is_err());
+    }
+
+    #[cfg(feature = "entropy")]
+    #[test]
+    fn test_parse_device_entropy_profile() {
+        let profile: Profile = "slot = 2\nmode = hmac-sha1\nkey = device-entropy:1"
+            .parse()
+            .unwrap();
+        assert_eq!(profile.key_source, KeySource::DeviceEntropy(Slot::Slot1));
+        assert_eq!(profile.to_string().parse::<Profile>().unwrap(), profile);
+        assert!("slot = 2\nmode = hmac-sha1\nkey = device-entropy:3"
+            .parse::<Profile>()
+            .is_err());
+    }
+
+    #[cfg(feature = "signed-profiles")]
+    #[test]
+    fn test_verify_signed_profile() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let key = signing_key.verifying_key();
+        let profile = b"slot = 2\nmode = hmac-sha1\nkey = random\n";
+        let signature = signing_key.sign(profile).to_bytes();
+
+        let parsed = Profile::verify_signed(profile, &signature, &key).unwrap();
+        assert_eq!(parsed.slot, Slot::Slot2);
+        // The hex encoding of the signature, as written by most tools, is accepted too.
+        assert!(Profile::verify_signed(profile, encode_hex(&signature).as_bytes(), &key).is_ok());
+
+        let tampered = b"slot = 2\nmode = hmac-sha1\nkey = hex:0000000000000000000000000000000000000000\n";
+        assert!(matches!(
+            Profile::verify_signed(tampered, &signature, &key),
+            Err(ChallengeResponseError::InvalidSignature)
+        ));
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert!(matches!(
+            Profile::verify_signed(profile, &signature, &other_key),
+            Err(ChallengeResponseError::InvalidSignature)
+        ));
+    }
+
+    #[cfg(feature = "otp")]
+    #[test]
+    fn test_parse_otp_profile() {
+        let profile: Profile = "slot = 1\nmode = yubico-otp\nprivate-id = 0a0b0c0d0e0f\nkey = hex:00112233445566778899aabbccddeeff"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            profile.mode,
+            ProfileMode::YubicoOtp {
+                private_id: [0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f]
+            }
+        );
+    }
+}
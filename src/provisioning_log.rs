@@ -0,0 +1,244 @@
+//! An append-only, hash-chained log of the devices provisioned with the `provisioning` module, as tamper
+//! evidence for audits.
+//!
+//! Every entry is a line recording the serial number of a device, the SHA-256 hash of the profile it was
+//! programmed with, and whether the programmed slot answered the test challenge:
+//!
+//! ```text
+//! <sequence> <unix time> <serial or -> <profile hash> <ok|failed> <entry hash>
+//! ```
+//!
+//! The hash of an entry is the SHA-256 hash of the hash of the previous entry (32 zero bytes for the first
+//! entry) followed by the other fields of the line, separated by spaces. Modifying, removing or reordering
+//! entries breaks the chain, which `verify` detects; truncating the log can only be detected by comparing
+//! the hash of its last entry with a copy kept elsewhere.
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use error::ChallengeResponseError;
+use provisioning::{Profile, ProvisionedSecret, ProvisioningSink};
+use Device;
+
+/// The size of the hashes of the log.
+pub const HASH_SIZE: usize = 32;
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hash(s: &str) -> Option<[u8; HASH_SIZE]> {
+    if s.len() != 2 * HASH_SIZE || !s.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; HASH_SIZE];
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// The hash identifying a profile in the log, of its canonical `Display` form.
+pub fn profile_hash(profile: &Profile) -> [u8; HASH_SIZE] {
+    Sha256::digest(profile.to_string().as_bytes()).into()
+}
+
+/// An entry of the log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    /// The position of the entry in the log, from 0.
+    pub sequence: u64,
+    /// When the entry was appended, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub serial: Option<u32>,
+    pub profile_hash: [u8; HASH_SIZE],
+    /// Whether the programmed slot answered the test challenge as expected.
+    pub verified: bool,
+    /// The hash chaining the entry to the previous one.
+    pub hash: [u8; HASH_SIZE],
+}
+
+impl LogEntry {
+    fn fields(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            self.sequence,
+            self.timestamp,
+            self.serial.map_or("-".to_string(), |s| s.to_string()),
+            encode_hex(&self.profile_hash),
+            if self.verified { "ok" } else { "failed" }
+        )
+    }
+
+    fn chain_hash(&self, previous: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+        let mut hasher = Sha256::new();
+        hasher.update(previous);
+        hasher.update(self.fields().as_bytes());
+        hasher.finalize().into()
+    }
+
+    fn to_line(&self) -> String {
+        format!("{} {}\n", self.fields(), encode_hex(&self.hash))
+    }
+
+    fn parse(line: &str) -> Option<LogEntry> {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() != 6 {
+            return None;
+        }
+        Some(LogEntry {
+            sequence: fields[0].parse().ok()?,
+            timestamp: fields[1].parse().ok()?,
+            serial: match fields[2] {
+                "-" => None,
+                serial => Some(serial.parse().ok()?),
+            },
+            profile_hash: decode_hash(fields[3])?,
+            verified: match fields[4] {
+                "ok" => true,
+                "failed" => false,
+                _ => return None,
+            },
+            hash: decode_hash(fields[5])?,
+        })
+    }
+}
+
+/// Reads the entries of a log, checking the whole chain. Fails with `ChallengeResponseError::LogTampered`
+/// and the number of the first invalid line if an entry was modified, removed or reordered.
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<Vec<LogEntry>, ChallengeResponseError> {
+    read_entries(BufReader::new(File::open(path)?))
+}
+
+fn read_entries<R: BufRead>(reader: R) -> Result<Vec<LogEntry>, ChallengeResponseError> {
+    let mut entries: Vec<LogEntry> = Vec::new();
+    let mut previous = [0; HASH_SIZE];
+    for (i, line) in reader.lines().enumerate() {
+        let entry = LogEntry::parse(&line?)
+            .filter(|e| e.sequence == i as u64 && e.hash == e.chain_hash(&previous))
+            .ok_or(ChallengeResponseError::LogTampered(i + 1))?;
+        previous = entry.hash;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// A log opened for appending entries.
+pub struct ProvisioningLog {
+    file: File,
+    next_sequence: u64,
+    last_hash: [u8; HASH_SIZE],
+}
+
+impl ProvisioningLog {
+    /// Opens the log at `path`, creating it if it does not exist. The existing entries are verified first,
+    /// so new entries are never chained to a tampered log.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ProvisioningLog, ChallengeResponseError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let entries = read_entries(BufReader::new(&file))?;
+        Ok(ProvisioningLog {
+            file,
+            next_sequence: entries.len() as u64,
+            last_hash: entries.last().map_or([0; HASH_SIZE], |e| e.hash),
+        })
+    }
+
+    /// The hash of the last entry, to keep outside of the log to detect its truncation.
+    pub fn last_hash(&self) -> [u8; HASH_SIZE] {
+        self.last_hash
+    }
+
+    /// Appends an entry for a device programmed with `profile`, and flushes it to the disk.
+    pub fn append(
+        &mut self,
+        serial: Option<u32>,
+        profile: &Profile,
+        verified: bool,
+    ) -> Result<LogEntry, ChallengeResponseError> {
+        let mut entry = LogEntry {
+            sequence: self.next_sequence,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            serial,
+            profile_hash: profile_hash(profile),
+            verified,
+            hash: [0; HASH_SIZE],
+        };
+        entry.hash = entry.chain_hash(&self.last_hash);
+        self.file.write_all(entry.to_line().as_bytes())?;
+        self.file.sync_data()?;
+        self.next_sequence += 1;
+        self.last_hash = entry.hash;
+        Ok(entry)
+    }
+
+    /// A `ProvisioningSink` for `run_batch` appending an entry for every device provisioned with `profile`,
+    /// and for every device failing the verification of its slot.
+    pub fn sink<'a>(&'a mut self, profile: &'a Profile) -> LogSink<'a> {
+        LogSink { log: self, profile }
+    }
+}
+
+/// The `ProvisioningSink` returned by `ProvisioningLog::sink`.
+pub struct LogSink<'a> {
+    log: &'a mut ProvisioningLog,
+    profile: &'a Profile,
+}
+
+impl<'a> ProvisioningSink for LogSink<'a> {
+    fn record(&mut self, device: &Device, _secret: &ProvisionedSecret) -> Result<(), ChallengeResponseError> {
+        self.log.append(device.serial, self.profile, true).map(|_| ())
+    }
+
+    fn verification_failed(&mut self, device: &Device) -> Result<(), ChallengeResponseError> {
+        self.log.append(device.serial, self.profile, false).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provisioning_log() {
+        let path = std::env::temp_dir().join(format!("challenge-response-log-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let profile: Profile = "slot = 2\nmode = hmac-sha1\nkey = random".parse().unwrap();
+
+        let mut log = ProvisioningLog::open(&path).unwrap();
+        log.append(Some(1234567), &profile, true).unwrap();
+        log.append(None, &profile, false).unwrap();
+        let last_hash = log.last_hash();
+        drop(log);
+
+        // Reopening the log chains the new entries to the existing ones.
+        let mut log = ProvisioningLog::open(&path).unwrap();
+        assert_eq!(log.last_hash(), last_hash);
+        log.append(Some(7654321), &profile, true).unwrap();
+        drop(log);
+        let entries = verify(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].serial, Some(1234567));
+        assert!(!entries[1].verified);
+        assert_eq!(entries[2].profile_hash, profile_hash(&profile));
+
+        // Changing the result of the second entry is detected.
+        let content = std::fs::read_to_string(&path).unwrap();
+        let tampered = content.replacen(" failed ", " ok ", 1);
+        std::fs::write(&path, tampered).unwrap();
+        assert!(matches!(
+            verify(&path),
+            Err(ChallengeResponseError::LogTampered(2))
+        ));
+        assert!(ProvisioningLog::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+//! The differences between the devices and firmware versions, in a single table consulted by the protocol
+//! layer instead of version checks spread through the code.
+//!
+//! Each `Quirk` applies to the devices of a vendor, optionally of a single product, with a firmware older
+//! than a given version. Like the `protocol` module, this module only depends on `core`.
+use protocol::{Command, Status};
+
+// The commands are listed by value, as some of them only exist with the `configuration` feature.
+const UPDATE1: u8 = 0x04;
+const UPDATE2: u8 = 0x05;
+const SWAP: u8 = 0x06;
+const DEVICE_SERIAL: u8 = Command::DeviceSerial as u8;
+const DEVICE_CONFIG: u8 = 0x11;
+const CHALLENGE_OTP1: u8 = Command::ChallengeOtp1 as u8;
+const CHALLENGE_OTP2: u8 = Command::ChallengeOtp2 as u8;
+const CHALLENGE_HMAC1: u8 = Command::ChallengeHmac1 as u8;
+const CHALLENGE_HMAC2: u8 = Command::ChallengeHmac2 as u8;
+
+const YUBICO: u16 = 0x1050;
+
+/// The commands updating and swapping the slot configurations.
+pub const SLOT_UPDATE_COMMANDS: [u8; 3] = [UPDATE1, UPDATE2, SWAP];
+
+/// A difference of some devices from the behaviour expected by the library.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirk {
+    pub vendor_id: u16,
+    /// The product the quirk is restricted to, or `None` for all the products of the vendor.
+    pub product_id: Option<u16>,
+    /// The quirk only applies to firmware versions strictly older than this one, or to all versions if
+    /// `None`.
+    pub firmware_below: Option<(u8, u8, u8)>,
+    /// The values of the commands which the devices do not implement.
+    pub unsupported_commands: &'static [u8],
+}
+
+const QUIRKS: [Quirk; 3] = [
+    // Challenge-response and reading the serial number appeared in firmware 2.2.
+    Quirk {
+        vendor_id: YUBICO,
+        product_id: None,
+        firmware_below: Some((2, 2, 0)),
+        unsupported_commands: &[
+            DEVICE_SERIAL,
+            CHALLENGE_OTP1,
+            CHALLENGE_OTP2,
+            CHALLENGE_HMAC1,
+            CHALLENGE_HMAC2,
+        ],
+    },
+    // Updating and swapping the slot configurations appeared in firmware 2.3.
+    Quirk {
+        vendor_id: YUBICO,
+        product_id: None,
+        firmware_below: Some((2, 3, 0)),
+        unsupported_commands: &[UPDATE1, UPDATE2, SWAP],
+    },
+    // Changing the USB interfaces appeared with the YubiKey NEO, in firmware 3.0.
+    Quirk {
+        vendor_id: YUBICO,
+        product_id: None,
+        firmware_below: Some((3, 0, 0)),
+        unsupported_commands: &[DEVICE_CONFIG],
+    },
+];
+
+impl Quirk {
+    /// Whether the quirk applies to a device with the given IDs and firmware version.
+    pub fn applies(&self, vendor_id: u16, product_id: u16, firmware: (u8, u8, u8)) -> bool {
+        self.vendor_id == vendor_id
+            && self.product_id.is_none_or(|p| p == product_id)
+            && self.firmware_below.is_none_or(|v| firmware < v)
+    }
+}
+
+/// The quirks of a device, from its vendor and product IDs and its status.
+pub fn quirks(vendor_id: u16, product_id: u16, status: &Status) -> impl Iterator<Item = &'static Quirk> {
+    let firmware = (status.version_major, status.version_minor, status.version_build);
+    QUIRKS
+        .iter()
+        .filter(move |q| q.applies(vendor_id, product_id, firmware))
+}
+
+/// Whether a device implements a command.
+pub fn supports_command(vendor_id: u16, product_id: u16, status: &Status, command: Command) -> bool {
+    supports(vendor_id, product_id, status, command as u8)
+}
+
+fn supports(vendor_id: u16, product_id: u16, status: &Status, command: u8) -> bool {
+    quirks(vendor_id, product_id, status).all(|q| !q.unsupported_commands.contains(&command))
+}
+
+/// Whether a device can update and swap its slot configurations.
+pub fn supports_slot_update(vendor_id: u16, product_id: u16, status: &Status) -> bool {
+    SLOT_UPDATE_COMMANDS
+        .iter()
+        .all(|&c| supports(vendor_id, product_id, status, c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::Flags;
+
+    fn status(version: (u8, u8, u8)) -> Status {
+        Status {
+            version_major: version.0,
+            version_minor: version.1,
+            version_build: version.2,
+            pgm_seq: 0,
+            touch_level: 0,
+            flags: Flags::empty(),
+        }
+    }
+
+    #[test]
+    fn test_supports_command() {
+        assert!(!supports_command(
+            YUBICO,
+            0x0010,
+            &status((2, 1, 9)),
+            Command::ChallengeHmac2
+        ));
+        assert!(supports_command(
+            YUBICO,
+            0x0010,
+            &status((2, 2, 0)),
+            Command::ChallengeHmac2
+        ));
+        assert!(supports_command(
+            YUBICO,
+            0x0407,
+            &status((5, 4, 3)),
+            Command::DeviceSerial
+        ));
+        // The other vendors have no quirks yet.
+        assert!(supports_command(
+            0x1D50,
+            0x60FC,
+            &status((0, 0, 0)),
+            Command::ChallengeHmac2
+        ));
+        assert_eq!(quirks(YUBICO, 0x0010, &status((2, 2, 4))).count(), 2);
+    }
+}
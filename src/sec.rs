@@ -1,36 +1,53 @@
-use crate::hmacmode::HmacKey;
+use hmac::digest::{KeyInit, Output};
 use hmac::{Hmac, Mac};
 use sha1::Sha1;
 
-const PRESET_VALUE: u16 = 0xFFFF;
-const POLYNOMIAL: u16 = 0x8408;
-const SHA1_DIGEST_SIZE: usize = 20;
-pub const CRC_RESIDUAL_OK: u16 = 0xf0b8;
+pub type HmacSha1 = Hmac<Sha1>;
 
-type HmacSha1 = Hmac<Sha1>;
-
-pub fn hmac_sha1(key: &HmacKey, data: &[u8]) -> [u8; SHA1_DIGEST_SIZE] {
-    let mut hmac = HmacSha1::new_from_slice(&key.0).unwrap();
-    hmac.update(data);
-    let result = hmac.finalize();
+/// Computes the MAC of `data` under `key`, using any RustCrypto MAC, e.g. `Hmac<Sha256>`.
+///
+/// Returns `None` if the key length is not valid for this MAC.
+pub fn compute_mac<M: Mac + KeyInit>(key: &[u8], data: &[u8]) -> Option<Output<M>> {
+    let mut mac = <M as KeyInit>::new_from_slice(key).ok()?;
+    mac.update(data);
+    Some(mac.finalize().into_bytes())
+}
 
-    let mut code = [0; SHA1_DIGEST_SIZE];
-    code.copy_from_slice(result.into_bytes().as_slice());
+/// Verifies, in constant time, that `response` is the MAC of `data` under `key`, using any RustCrypto
+/// MAC. Truncated responses are rejected.
+pub fn verify_mac<M: Mac + KeyInit>(key: &[u8], data: &[u8], response: &[u8]) -> bool {
+    let mut mac = match <M as KeyInit>::new_from_slice(key) {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+    mac.update(data);
+    mac.verify_slice(response).is_ok()
+}
 
-    code
+/// Verifies, in constant time, that `response` is the HMAC-SHA1 of `data` under `key`.
+pub fn verify_hmac_sha1(key: &[u8], data: &[u8], response: &[u8]) -> bool {
+    verify_mac::<HmacSha1>(key, data, response)
 }
 
-pub fn crc16(data: &[u8]) -> u16 {
-    let mut crc_value = PRESET_VALUE;
-    for &b in data {
-        crc_value ^= b as u16;
-        for _ in 0..8 {
-            let j = crc_value & 1;
-            crc_value >>= 1;
-            if j != 0 {
-                crc_value ^= POLYNOMIAL
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha256;
+
+    #[test]
+    fn test_verify_hmac_sha256() {
+        // RFC 4231, test case 2.
+        let expected = compute_mac::<Hmac<Sha256>>(b"Jefe", b"what do ya want for nothing?").unwrap();
+        assert_eq!(&expected[..4], &[0x5b, 0xdc, 0xc1, 0x46],);
+        assert!(verify_mac::<Hmac<Sha256>>(
+            b"Jefe",
+            b"what do ya want for nothing?",
+            &expected
+        ));
+        assert!(!verify_mac::<Hmac<Sha256>>(
+            b"Jefe",
+            b"what do ya want for nothing?",
+            &expected[..20]
+        ));
     }
-    crc_value
 }
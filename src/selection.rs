@@ -0,0 +1,192 @@
+//! Declarative selection of the device to use among the connected ones.
+//!
+//! A `SelectionPolicy` lists the rules of the application in order of preference, and optionally a prompt
+//! asking the user to choose when no rule matches. `ChallengeResponse::select_device` enumerates the devices
+//! once and returns the first one matching the first rule which matches any device:
+//!
+//! ```no_run
+//! use challenge_response::selection::{Rule, SelectionPolicy};
+//! use challenge_response::ChallengeResponse;
+//!
+//! let mut policy = SelectionPolicy::new()
+//!     .prefer(Rule::Serial(1234567))
+//!     .prefer(Rule::Touchless)
+//!     .or_prompt(|devices| {
+//!         for (i, device) in devices.iter().enumerate() {
+//!             println!("{}: {:?} {:?}", i, device.name, device.serial);
+//!         }
+//!         // Read the choice of the user.
+//!         Some(0)
+//!     });
+//!
+//! let mut cr = ChallengeResponse::new().unwrap();
+//! let device = cr.select_device(&mut policy).unwrap();
+//! ```
+use std::fmt;
+
+use protocol::{SlotState, Status};
+use Device;
+
+/// A rule of a `SelectionPolicy`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rule {
+    /// The device with the given serial number.
+    Serial(u32),
+    /// A device with a slot configured without touch, so it can respond without the user.
+    Touchless,
+    /// A device with at least one configured slot.
+    Configured,
+    /// Any device.
+    Any,
+}
+
+impl Rule {
+    /// Whether `device` matches the rule. `status` reads the status of the device, for the rules on the
+    /// slots, and returns `None` if it can not be read.
+    fn matches<F>(&self, device: &Device, status: &mut F) -> bool
+    where
+        F: FnMut(&Device) -> Option<Status>,
+    {
+        let slots = |status: Status| [status.slot1(), status.slot2()];
+        match *self {
+            Rule::Serial(serial) => device.serial == Some(serial),
+            Rule::Touchless => status(device)
+                .is_some_and(|s| slots(s).contains(&SlotState::Configured { require_touch: false })),
+            Rule::Configured => status(device).is_some_and(|s| slots(s).iter().any(|s| s.is_configured())),
+            Rule::Any => true,
+        }
+    }
+}
+
+/// Asks the user to choose one of the given devices, returning its index.
+type Prompt<'a> = Box<dyn FnMut(&[Device]) -> Option<usize> + 'a>;
+
+/// The rules choosing a device, see the module documentation.
+#[derive(Default)]
+pub struct SelectionPolicy<'a> {
+    rules: Vec<Rule>,
+    prompt: Option<Prompt<'a>>,
+}
+
+impl<'a> SelectionPolicy<'a> {
+    /// Creates a policy without rules, which only selects a device through its prompt.
+    pub fn new() -> Self {
+        SelectionPolicy::default()
+    }
+
+    /// Adds a rule, tried after the rules already added.
+    pub fn prefer(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Sets the prompt called with all the connected devices when no rule matches, which returns the index
+    /// of the chosen device, or `None` if the user cancelled.
+    pub fn or_prompt<F>(mut self, prompt: F) -> Self
+    where
+        F: FnMut(&[Device]) -> Option<usize> + 'a,
+    {
+        self.prompt = Some(Box::new(prompt));
+        self
+    }
+
+    /// Selects a device among `devices`, see `ChallengeResponse::select_device`. The status of each device
+    /// is read at most once.
+    pub(crate) fn select<F>(&mut self, devices: Vec<Device>, mut read_status: F) -> Option<Device>
+    where
+        F: FnMut(&Device) -> Option<Status>,
+    {
+        let mut statuses: Vec<Option<Option<Status>>> = vec![None; devices.len()];
+        for rule in &self.rules {
+            let found = devices.iter().enumerate().position(|(i, device)| {
+                let mut status = |d: &Device| *statuses[i].get_or_insert_with(|| read_status(d));
+                rule.matches(device, &mut status)
+            });
+            if let Some(i) = found {
+                return devices.into_iter().nth(i);
+            }
+        }
+        let chosen = self.prompt.as_mut().and_then(|prompt| prompt(&devices))?;
+        devices.into_iter().nth(chosen)
+    }
+}
+
+impl<'a> fmt::Debug for SelectionPolicy<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SelectionPolicy")
+            .field("rules", &self.rules)
+            .field("prompt", &self.prompt.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::Flags;
+
+    fn device(serial: u32) -> Device {
+        Device {
+            name: None,
+            serial: Some(serial),
+            product_id: 0x0407,
+            vendor_id: 0x1050,
+            bus_id: 1,
+            address_id: serial as u8,
+            port_path: None,
+            manufacturer: None,
+            release: 0x0543,
+            interfaces: Vec::new(),
+        }
+    }
+
+    fn status(device: &Device) -> Option<Status> {
+        // Device 1: both slots empty. Device 2: slot 2 with touch. Device 3: slot 1 without touch.
+        let touch_level = match device.serial {
+            Some(1) => 0x00,
+            Some(2) => 0x0a,
+            Some(3) => 0x01,
+            _ => return None,
+        };
+        Some(Status {
+            version_major: 5,
+            version_minor: 4,
+            version_build: 3,
+            pgm_seq: 1,
+            touch_level,
+            flags: Flags::empty(),
+        })
+    }
+
+    #[test]
+    fn test_select() {
+        let devices = vec![device(1), device(2), device(3)];
+        let select =
+            |policy: &mut SelectionPolicy| policy.select(devices.clone(), status).and_then(|d| d.serial);
+
+        let mut policy = SelectionPolicy::new()
+            .prefer(Rule::Serial(2))
+            .prefer(Rule::Touchless);
+        assert_eq!(select(&mut policy), Some(2));
+        let mut policy = SelectionPolicy::new()
+            .prefer(Rule::Serial(4))
+            .prefer(Rule::Touchless);
+        assert_eq!(select(&mut policy), Some(3));
+        let mut policy = SelectionPolicy::new().prefer(Rule::Configured);
+        assert_eq!(select(&mut policy), Some(2));
+        assert_eq!(select(&mut SelectionPolicy::new().prefer(Rule::Serial(4))), None);
+
+        let mut prompted = 0;
+        let mut policy = SelectionPolicy::new()
+            .prefer(Rule::Serial(4))
+            .or_prompt(|devices| {
+                prompted = devices.len();
+                Some(0)
+            });
+        assert_eq!(select(&mut policy), Some(1));
+        drop(policy);
+        assert_eq!(prompted, 3);
+        // The prompt was cancelled.
+        assert_eq!(select(&mut SelectionPolicy::new().or_prompt(|_| None)), None);
+    }
+}
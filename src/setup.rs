@@ -0,0 +1,126 @@
+//! First-run setup of the access to the devices, on Linux.
+//!
+//! The device nodes of USB devices are only accessible to root by default, so opening a device fails with
+//! a permission error until udev rules grant access to it. `install_udev_rules` writes rules tagging the
+//! supported devices with `uaccess`, which gives access to the user logged in on the seat, reloads the
+//! rules, and checks which of the connected devices can be opened.
+//!
+//! Writing the rules requires root, while checking the access must be done as the user: an application
+//! running the setup with `sudo` should call `check_access` again once it runs without privileges.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use error::ChallengeResponseError;
+use usb::{Device, DeviceModel};
+use ChallengeResponse;
+
+/// Where `install_udev_rules` writes the rules. The `70-` prefix orders them before the rule of systemd
+/// which applies the `uaccess` tag.
+pub const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/70-challenge-response.rules";
+
+/// The udev rules granting the logged-in user access to the devices of `DeviceModel::all`, and to the
+/// devices with the vendor and product IDs of `extra_device_ids`. With the hidapi and hidraw backends, access
+/// is also granted to their hidraw nodes.
+pub fn udev_rules(extra_device_ids: &[(u16, u16)]) -> String {
+    let mut rules = String::from("# Written by challenge-response, to access the devices without root.\n");
+    let models = DeviceModel::all()
+        .iter()
+        .map(|m| (m.vendor_id, m.product_id, m.name));
+    let extra = extra_device_ids.iter().map(|&(v, p)| (v, p, "Extra device"));
+    for (vendor_id, product_id, name) in models.chain(extra) {
+        rules.push_str(&format!(
+            "# {}\nSUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", TAG+=\"uaccess\"\n",
+            name, vendor_id, product_id
+        ));
+        if cfg!(not(any(feature = "rusb", feature = "nusb"))) {
+            rules.push_str(&format!(
+                "KERNEL==\"hidraw*\", SUBSYSTEM==\"hidraw\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", TAG+=\"uaccess\"\n",
+                vendor_id, product_id
+            ));
+        }
+    }
+    rules
+}
+
+/// Whether the current user can operate a connected device.
+#[derive(Debug)]
+pub struct DeviceAccess {
+    pub device: Device,
+    /// Why the device can not be operated, typically a permission error, or `None` if it can.
+    pub error: Option<ChallengeResponseError>,
+}
+
+fn udevadm(args: &[&str]) -> Result<(), ChallengeResponseError> {
+    let status = Command::new("udevadm").args(args).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("udevadm {} failed with {}", args.join(" "), status)).into());
+    }
+    Ok(())
+}
+
+/// Writes the rules of `udev_rules` to `UDEV_RULES_PATH`, reloads the rules, applies them to the
+/// connected devices, and checks the access to them with `check_access`.
+pub fn install_udev_rules() -> Result<Vec<DeviceAccess>, ChallengeResponseError> {
+    install_udev_rules_to(UDEV_RULES_PATH, &[])
+}
+
+/// Installs the rules like `install_udev_rules`, to another path and with extra devices.
+pub fn install_udev_rules_to<P: AsRef<Path>>(
+    path: P,
+    extra_device_ids: &[(u16, u16)],
+) -> Result<Vec<DeviceAccess>, ChallengeResponseError> {
+    fs::write(path, udev_rules(extra_device_ids))?;
+    udevadm(&["control", "--reload-rules"])?;
+    udevadm(&["trigger", "--subsystem-match=usb", "--action=change"])?;
+    udevadm(&["settle"])?;
+
+    let mut cr = ChallengeResponse::new()?;
+    cr.set_extra_device_ids(extra_device_ids.to_vec());
+    check_access(&mut cr)
+}
+
+/// Opens each connected device, by reading its status, to check that the current user can operate it.
+pub fn check_access(cr: &mut ChallengeResponse) -> Result<Vec<DeviceAccess>, ChallengeResponseError> {
+    let devices = match cr.find_all_devices() {
+        Ok(devices) => devices,
+        Err(ChallengeResponseError::DeviceNotFound) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    Ok(devices
+        .into_iter()
+        .map(|device| {
+            let error = cr.read_status(&device).err();
+            DeviceAccess { device, error }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "vendor-yubico")]
+    fn test_udev_rules() {
+        let rules = udev_rules(&[(0x20a0, 0x4287)]);
+        assert!(rules.contains(
+            "SUBSYSTEM==\"usb\", ATTRS{idVendor}==\"1050\", ATTRS{idProduct}==\"0407\", TAG+=\"uaccess\"\n"
+        ));
+        assert!(rules.contains("ATTRS{idVendor}==\"20a0\", ATTRS{idProduct}==\"4287\""));
+        assert_eq!(
+            rules.lines().filter(|l| l.starts_with("SUBSYSTEM")).count(),
+            DeviceModel::all().len() + 1
+        );
+        let hidraw_rules = rules
+            .lines()
+            .filter(|l| l.contains("SUBSYSTEM==\"hidraw\""))
+            .count();
+        if cfg!(not(any(feature = "rusb", feature = "nusb"))) {
+            assert_eq!(hidraw_rules, DeviceModel::all().len() + 1);
+        } else {
+            assert_eq!(hidraw_rules, 0);
+        }
+    }
+}
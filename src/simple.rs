@@ -0,0 +1,57 @@
+//! One-call helpers for scripts and small tools, which use the first connected device with default
+//! settings instead of a `ChallengeResponse` and a `Config`.
+//!
+//! ```no_run
+//! use challenge_response::simple;
+//!
+//! let response = simple::hmac(b"my challenge").unwrap();
+//! ```
+use config::{Config, Slot};
+use error::ChallengeResponseError;
+use protocol::SlotState;
+use ChallengeResponse;
+
+/// Performs a HMAC-SHA1 challenge-response with the first connected device, using slot 2 if it is
+/// configured and slot 1 otherwise, with variable-length challenges. Fails with
+/// `ChallengeResponseError::NoConfiguredSlot` if neither slot is configured.
+///
+/// The status of the device does not tell the mode of its slots, so the slot must be configured for
+/// HMAC-SHA1 challenge-response. Use `hmac_with_slot` to choose the slot.
+pub fn hmac(challenge: &[u8]) -> Result<[u8; 20], ChallengeResponseError> {
+    let mut cr = ChallengeResponse::new()?;
+    let device = cr.find_device()?;
+    let status = cr.read_status(&device)?;
+    let slot = if status.slot2() != SlotState::Empty {
+        Slot::Slot2
+    } else if status.slot1() != SlotState::Empty {
+        Slot::Slot1
+    } else {
+        return Err(ChallengeResponseError::NoConfiguredSlot);
+    };
+    hmac_from(&mut cr, challenge, Config::new_from(device).set_slot(slot))
+}
+
+/// Performs a HMAC-SHA1 challenge-response with a slot of the first connected device, with
+/// variable-length challenges.
+pub fn hmac_with_slot(challenge: &[u8], slot: Slot) -> Result<[u8; 20], ChallengeResponseError> {
+    let mut cr = ChallengeResponse::new()?;
+    let device = cr.find_device()?;
+    hmac_from(&mut cr, challenge, Config::new_from(device).set_slot(slot))
+}
+
+fn hmac_from(
+    cr: &mut ChallengeResponse,
+    challenge: &[u8],
+    conf: Config,
+) -> Result<[u8; 20], ChallengeResponseError> {
+    let mut response = [0; 20];
+    cr.challenge_response_hmac_into(challenge, conf, &mut response)?;
+    Ok(response)
+}
+
+/// Reads the serial number of the first connected device.
+pub fn serial() -> Result<u32, ChallengeResponseError> {
+    let mut cr = ChallengeResponse::new()?;
+    let device = cr.find_device()?;
+    cr.read_serial_number(Config::new_from(device))
+}
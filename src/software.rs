@@ -0,0 +1,195 @@
+//! A software token, for applications which offer a degraded mode when no device is plugged in.
+//!
+//! The HMAC-SHA1 secret is stored in a file, encrypted with a key derived from a passphrase. When the file
+//! holds the secret programmed in the slot of a device, the software token computes the same responses as
+//! the device, in variable-length challenge mode.
+//!
+//! The format of a token file (version 1) is the following:
+//!
+//! | Offset | Size | Content                                                  |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 4    | Magic bytes, `CRST`                                      |
+//! | 4      | 1    | Version, `1`                                             |
+//! | 5      | 4    | Number of PBKDF2 iterations, big-endian                  |
+//! | 9      | 16   | Salt                                                     |
+//! | 25     | 24   | Nonce                                                    |
+//! | 49     | 36   | Ciphertext of the secret, followed by the 16 bytes tag   |
+//!
+//! The XChaCha20-Poly1305 key is `PBKDF2-HMAC-SHA256(passphrase, salt, iterations)`, and the associated
+//! data is the header, i.e. everything before the ciphertext.
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand_core::CryptoRng;
+use sha2::Sha256;
+
+use error::ChallengeResponseError;
+use hmacmode::{Hmac, HmacKey, HMAC_SECRET_SIZE};
+use protocol::{compute_mac, HmacSha1};
+use token::{HmacToken, TokenInfo};
+
+const MAGIC: &[u8; 4] = b"CRST";
+const VERSION: u8 = 1;
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const HEADER_SIZE: usize = 9 + SALT_SIZE + NONCE_SIZE;
+
+/// The number of PBKDF2 iterations used for new token files.
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+
+fn header(iterations: u32, salt: &[u8], nonce: &[u8]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(MAGIC);
+    header.push(VERSION);
+    header.extend_from_slice(&iterations.to_be_bytes());
+    header.extend_from_slice(salt);
+    header.extend_from_slice(nonce);
+    header
+}
+
+fn cipher(passphrase: &[u8], salt: &[u8], iterations: u32) -> XChaCha20Poly1305 {
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, iterations, &mut key);
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    for i in key.iter_mut() {
+        *i = 0;
+    }
+    cipher
+}
+
+/// A HMAC-SHA1 token computing the responses in software.
+pub struct SoftwareToken {
+    secret: HmacKey,
+}
+
+impl SoftwareToken {
+    pub fn new(secret: HmacKey) -> Self {
+        SoftwareToken { secret }
+    }
+
+    /// Serializes the secret of the token, encrypted with `passphrase`.
+    pub fn to_bytes<R: CryptoRng + ?Sized>(&self, passphrase: &[u8], rng: &mut R) -> Vec<u8> {
+        let mut salt = [0; SALT_SIZE];
+        rng.fill_bytes(&mut salt);
+        let mut nonce = [0; NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+        self.seal(passphrase, DEFAULT_ITERATIONS, &salt, &nonce)
+    }
+
+    fn seal(&self, passphrase: &[u8], iterations: u32, salt: &[u8], nonce: &[u8]) -> Vec<u8> {
+        let mut bytes = header(iterations, salt, nonce);
+        let ciphertext = cipher(passphrase, salt, iterations)
+            .encrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: &self.secret.0,
+                    aad: &bytes,
+                },
+            )
+            .expect("the secret is not too long for XChaCha20-Poly1305");
+        bytes.extend_from_slice(&ciphertext);
+        bytes
+    }
+
+    /// Decrypts a token serialized with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8], passphrase: &[u8]) -> Result<Self, ChallengeResponseError> {
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC || bytes[4] != VERSION {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let iterations = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
+        if iterations == 0 {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let (header, ciphertext) = bytes.split_at(HEADER_SIZE);
+        let salt = &header[9..9 + SALT_SIZE];
+        let nonce = &header[9 + SALT_SIZE..];
+
+        let mut secret = cipher(passphrase, salt, iterations)
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| ChallengeResponseError::DecryptionFailed)?;
+        let result = if secret.len() == HMAC_SECRET_SIZE {
+            Ok(SoftwareToken::new(HmacKey::from_slice(&secret)))
+        } else {
+            Err(ChallengeResponseError::InvalidFormat)
+        };
+        for i in secret.iter_mut() {
+            *i = 0;
+        }
+        result
+    }
+
+    /// Writes the token to a new file, only readable by the current user on Unix.
+    pub fn create<P: AsRef<Path>, R: CryptoRng + ?Sized>(
+        &self,
+        path: P,
+        passphrase: &[u8],
+        rng: &mut R,
+    ) -> Result<(), ChallengeResponseError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        file.write_all(&self.to_bytes(passphrase, rng))?;
+        Ok(())
+    }
+
+    /// Reads and decrypts a token file written with `create`.
+    pub fn unlock<P: AsRef<Path>>(path: P, passphrase: &[u8]) -> Result<Self, ChallengeResponseError> {
+        let bytes = std::fs::read(path)?;
+        Self::from_bytes(&bytes, passphrase)
+    }
+}
+
+impl HmacToken for SoftwareToken {
+    fn challenge_response(&mut self, challenge: &[u8]) -> Result<Hmac, ChallengeResponseError> {
+        let mac =
+            compute_mac::<HmacSha1>(&self.secret.0, challenge).ok_or(ChallengeResponseError::InvalidFormat)?;
+        let mut hmac = Hmac([0; 20]);
+        hmac.0.copy_from_slice(&mac);
+        Ok(hmac)
+    }
+
+    fn info(&self) -> TokenInfo {
+        TokenInfo {
+            name: Some("Software token".to_string()),
+            serial: None,
+        }
+    }
+
+    fn requires_touch(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_unlock() {
+        let token = SoftwareToken::new(HmacKey([0x42; HMAC_SECRET_SIZE]));
+        let bytes = token.seal(b"passphrase", 1000, &[1; SALT_SIZE], &[2; NONCE_SIZE]);
+
+        let mut unlocked = SoftwareToken::from_bytes(&bytes, b"passphrase").unwrap();
+        assert_eq!(unlocked.secret.0, [0x42; HMAC_SECRET_SIZE]);
+        assert!(matches!(
+            SoftwareToken::from_bytes(&bytes, b"wrong"),
+            Err(ChallengeResponseError::DecryptionFailed)
+        ));
+
+        let response = unlocked.challenge_response(b"challenge").unwrap();
+        assert!(response.check(&HmacKey([0x42; HMAC_SECRET_SIZE]), b"challenge"));
+    }
+}
@@ -0,0 +1,255 @@
+//! Persistence of the state of tokens between validations.
+//!
+//! The validators of the `validation` module, and the replay protection of Yubico OTP responses, need to
+//! remember some state for each token: the HOTP counter, the last TOTP time step, the counters of the last
+//! accepted OTP, and the programming sequence of the device. A `StateStore` keeps this state, keyed by a
+//! token name chosen by the application, e.g. the serial number of the device or the name of its user.
+//! `MemoryStateStore` keeps it in memory, and `FileStateStore` in one file per token.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use error::ChallengeResponseError;
+#[cfg(feature = "otp")]
+use otpmode::Otp;
+use validation::TotpState;
+
+/// The counters of a Yubico OTP, which increase with every OTP generated by the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OtpCounter {
+    pub use_counter: u16,
+    pub session_counter: u8,
+}
+
+#[cfg(feature = "otp")]
+impl OtpCounter {
+    pub fn from_otp(otp: &Otp) -> OtpCounter {
+        OtpCounter {
+            use_counter: otp.use_counter,
+            session_counter: otp.session_counter,
+        }
+    }
+}
+
+/// The state of a token.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenState {
+    /// The next expected counter of an HOTP token.
+    pub hotp_counter: Option<u64>,
+    pub totp: TotpState,
+    /// The counters of the last accepted Yubico OTP.
+    pub last_otp: Option<OtpCounter>,
+    /// The programming sequence of the device, when it was enrolled.
+    pub pgm_seq: Option<u8>,
+}
+
+#[cfg(feature = "otp")]
+impl TokenState {
+    /// Accepts a Yubico OTP, already decrypted and checked with `Aes128Block::check`, if its counters are
+    /// strictly greater than the ones of the last accepted OTP. Else it is a replay, and
+    /// `ChallengeResponseError::InvalidCode` is returned.
+    pub fn accept_otp(&mut self, otp: &Otp) -> Result<(), ChallengeResponseError> {
+        let counter = OtpCounter::from_otp(otp);
+        if self.last_otp.is_some_and(|last| counter <= last) {
+            return Err(ChallengeResponseError::InvalidCode);
+        }
+        self.last_otp = Some(counter);
+        Ok(())
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, ChallengeResponseError> {
+    value.parse().map_err(|_| ChallengeResponseError::InvalidFormat)
+}
+
+impl FromStr for TokenState {
+    type Err = ChallengeResponseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut state = TokenState::default();
+        for line in s.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(ChallengeResponseError::InvalidFormat)?;
+            let value = value.trim();
+            match key.trim() {
+                "hotp-counter" => state.hotp_counter = Some(parse(value)?),
+                "totp-last-step" => state.totp.last_step = Some(parse(value)?),
+                "totp-drift" => state.totp.drift = parse(value)?,
+                "otp-use-counter" => {
+                    let last = state.last_otp.get_or_insert(OtpCounter {
+                        use_counter: 0,
+                        session_counter: 0,
+                    });
+                    last.use_counter = parse(value)?;
+                }
+                "otp-session-counter" => {
+                    let last = state.last_otp.get_or_insert(OtpCounter {
+                        use_counter: 0,
+                        session_counter: 0,
+                    });
+                    last.session_counter = parse(value)?;
+                }
+                "pgm-seq" => state.pgm_seq = Some(parse(value)?),
+                _ => return Err(ChallengeResponseError::InvalidFormat),
+            }
+        }
+        Ok(state)
+    }
+}
+
+impl fmt::Display for TokenState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(counter) = self.hotp_counter {
+            writeln!(f, "hotp-counter = {}", counter)?;
+        }
+        if let Some(step) = self.totp.last_step {
+            writeln!(f, "totp-last-step = {}", step)?;
+        }
+        if self.totp.drift != 0 {
+            writeln!(f, "totp-drift = {}", self.totp.drift)?;
+        }
+        if let Some(last) = self.last_otp {
+            writeln!(f, "otp-use-counter = {}", last.use_counter)?;
+            writeln!(f, "otp-session-counter = {}", last.session_counter)?;
+        }
+        if let Some(pgm_seq) = self.pgm_seq {
+            writeln!(f, "pgm-seq = {}", pgm_seq)?;
+        }
+        Ok(())
+    }
+}
+
+/// A storage for the state of tokens.
+pub trait StateStore {
+    /// Loads the state of a token. Unknown tokens have the default state.
+    fn load(&mut self, token: &str) -> Result<TokenState, ChallengeResponseError>;
+
+    /// Stores the state of a token, replacing its previous state.
+    fn store(&mut self, token: &str, state: &TokenState) -> Result<(), ChallengeResponseError>;
+}
+
+/// Keeps the state of tokens in memory, e.g. for tests or short-lived validation servers.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStateStore {
+    states: HashMap<String, TokenState>,
+}
+
+impl MemoryStateStore {
+    pub fn new() -> Self {
+        MemoryStateStore::default()
+    }
+}
+
+impl StateStore for MemoryStateStore {
+    fn load(&mut self, token: &str) -> Result<TokenState, ChallengeResponseError> {
+        Ok(self.states.get(token).cloned().unwrap_or_default())
+    }
+
+    fn store(&mut self, token: &str, state: &TokenState) -> Result<(), ChallengeResponseError> {
+        self.states.insert(token.to_string(), state.clone());
+        Ok(())
+    }
+}
+
+/// Keeps the state of each token in a file of a directory, named after the hexadecimal encoding of the
+/// token name. Files are replaced atomically, so a crash never leaves a partially written state.
+#[derive(Clone, Debug)]
+pub struct FileStateStore {
+    directory: PathBuf,
+}
+
+impl FileStateStore {
+    /// Stores the states in `directory`, which is created if needed.
+    pub fn new<P: AsRef<Path>>(directory: P) -> Result<Self, ChallengeResponseError> {
+        fs::create_dir_all(&directory)?;
+        Ok(FileStateStore {
+            directory: directory.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path(&self, token: &str) -> PathBuf {
+        let name: String = token.bytes().map(|b| format!("{:02x}", b)).collect();
+        self.directory.join(name)
+    }
+}
+
+impl StateStore for FileStateStore {
+    fn load(&mut self, token: &str) -> Result<TokenState, ChallengeResponseError> {
+        match fs::read_to_string(self.path(token)) {
+            Ok(s) => s.parse(),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TokenState::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn store(&mut self, token: &str, state: &TokenState) -> Result<(), ChallengeResponseError> {
+        let path = self.path(token);
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(state.to_string().as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_state() {
+        let mut state = TokenState {
+            hotp_counter: Some(42),
+            totp: TotpState {
+                last_step: Some(1000),
+                drift: -1,
+            },
+            last_otp: None,
+            pgm_seq: Some(3),
+        };
+        #[cfg(feature = "otp")]
+        {
+            let otp = Otp {
+                use_counter: 2,
+                session_counter: 5,
+                ..Otp::default()
+            };
+            state.accept_otp(&otp).unwrap();
+            assert!(state.accept_otp(&otp).is_err());
+        }
+        #[cfg(not(feature = "otp"))]
+        {
+            state.last_otp = Some(OtpCounter {
+                use_counter: 2,
+                session_counter: 5,
+            });
+        }
+        assert_eq!(state.to_string().parse::<TokenState>().unwrap(), state);
+    }
+
+    #[test]
+    fn test_file_state_store() {
+        let directory = std::env::temp_dir().join(format!("challenge-response-state-{}", std::process::id()));
+        let mut store = FileStateStore::new(&directory).unwrap();
+        assert_eq!(store.load("alice").unwrap(), TokenState::default());
+
+        let state = TokenState {
+            hotp_counter: Some(7),
+            ..TokenState::default()
+        };
+        store.store("alice", &state).unwrap();
+        assert_eq!(store.load("alice").unwrap(), state);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
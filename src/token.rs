@@ -0,0 +1,71 @@
+//! A hardware-agnostic interface to HMAC-SHA1 challenge-response tokens.
+//!
+//! Applications which only need the challenge-response can code against `HmacToken`, and accept other
+//! implementations than the USB devices, for example a remote agent or a simulator in tests.
+use config::Config;
+use error::ChallengeResponseError;
+use hmacmode::Hmac;
+use ChallengeResponse;
+
+/// A description of a token, for display purposes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenInfo {
+    pub name: Option<String>,
+    pub serial: Option<u32>,
+}
+
+pub trait HmacToken {
+    /// Computes the HMAC-SHA1 response to `challenge`.
+    fn challenge_response(&mut self, challenge: &[u8]) -> Result<Hmac, ChallengeResponseError>;
+
+    fn info(&self) -> TokenInfo;
+
+    /// Whether computing a response requires the user to touch the token.
+    fn requires_touch(&self) -> bool;
+}
+
+/// The slot of a USB device, as a `HmacToken`.
+pub struct UsbToken {
+    cr: ChallengeResponse,
+    conf: Config,
+    requires_touch: bool,
+}
+
+impl UsbToken {
+    /// Creates a token responding with the device and slot targeted by `conf`.
+    pub fn new(cr: ChallengeResponse, conf: Config) -> Self {
+        UsbToken {
+            cr,
+            conf,
+            requires_touch: false,
+        }
+    }
+
+    /// Sets whether the slot was configured to require a touch. The devices do not report it, so it must
+    /// be known by the application.
+    pub fn set_requires_touch(mut self, requires_touch: bool) -> Self {
+        self.requires_touch = requires_touch;
+        self
+    }
+
+    pub fn into_inner(self) -> (ChallengeResponse, Config) {
+        (self.cr, self.conf)
+    }
+}
+
+impl HmacToken for UsbToken {
+    fn challenge_response(&mut self, challenge: &[u8]) -> Result<Hmac, ChallengeResponseError> {
+        self.cr.challenge_response_hmac(challenge, self.conf.clone())
+    }
+
+    fn info(&self) -> TokenInfo {
+        TokenInfo {
+            name: self.conf.device.name.clone(),
+            serial: self.conf.device.serial,
+        }
+    }
+
+    fn requires_touch(&self) -> bool {
+        self.requires_touch
+    }
+}
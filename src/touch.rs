@@ -0,0 +1,114 @@
+//! Notifications for operations which require the user to touch the device.
+//!
+//! When a slot is configured to require a button press, challenge-responses block until the user touches
+//! the device. A `TouchPrompt` registered with `ChallengeResponse::set_touch_prompt` is notified when
+//! this happens, so the application can ask the user to touch the device. A closure can be registered
+//! instead with `ChallengeResponse::on_touch_required`.
+use std::io::Write;
+
+use Device;
+
+pub trait TouchPrompt {
+    /// Called once per operation, when the device starts waiting for the user to touch it.
+    fn touch_required(&self, device: &Device);
+
+    /// Called when an operation for which `touch_required` was called completes. `success` is `false` if
+    /// the operation failed, for example because the user did not touch the device in time.
+    fn touch_completed(&self, _device: &Device, _success: bool) {}
+}
+
+/// A closure called when the device starts waiting for the user to touch it, e.g. to show "touch your key
+/// now" in a GUI. See `ChallengeResponse::on_touch_required`.
+impl<F> TouchPrompt for F
+where
+    F: Fn(&Device),
+{
+    fn touch_required(&self, device: &Device) {
+        self(device)
+    }
+}
+
+fn device_name(device: &Device) -> String {
+    let name = device.name.clone().unwrap_or_else(|| "security key".to_string());
+    match device.serial {
+        Some(serial) => format!("{} ({})", name, serial),
+        None => name,
+    }
+}
+
+/// Prints the prompt on the standard error, for command-line applications.
+pub struct TerminalPrompt;
+
+impl TouchPrompt for TerminalPrompt {
+    fn touch_required(&self, device: &Device) {
+        let mut stderr = std::io::stderr();
+        let _ = write!(stderr, "Touch your {} to continue...", device_name(device));
+        let _ = stderr.flush();
+    }
+
+    fn touch_completed(&self, _device: &Device, _success: bool) {
+        eprintln!();
+    }
+}
+
+/// Shows the prompt as a desktop notification, using `notify-send` on Linux and the BSDs, and
+/// `osascript` on macOS. Failures to show the notification are ignored.
+#[cfg(feature = "desktop-notifications")]
+pub struct DesktopNotification;
+
+#[cfg(feature = "desktop-notifications")]
+impl TouchPrompt for DesktopNotification {
+    fn touch_required(&self, device: &Device) {
+        let body = format!("Touch your {} to continue", device_name(device));
+
+        #[cfg(target_os = "macos")]
+        let mut command = {
+            let mut command = std::process::Command::new("osascript");
+            command.arg("-e").arg(format!(
+                "display notification \"{}\" with title \"Touch required\"",
+                body.replace('"', "'")
+            ));
+            command
+        };
+        #[cfg(not(target_os = "macos"))]
+        let mut command = {
+            let mut command = std::process::Command::new("notify-send");
+            command.args(["--app-name", "challenge-response", "--urgency", "critical"]);
+            command.arg("Touch required").arg(body);
+            command
+        };
+
+        let _ = command.status();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_closure_prompt() {
+        let device = Device {
+            name: Some("YubiKey 5".to_string()),
+            serial: Some(42),
+            product_id: 0x0407,
+            vendor_id: 0x1050,
+            bus_id: 1,
+            address_id: 3,
+            port_path: None,
+            manufacturer: None,
+            release: 0x0543,
+            interfaces: Vec::new(),
+        };
+        let prompted = Rc::new(Cell::new(None));
+        let serial = prompted.clone();
+        let prompt: Box<dyn TouchPrompt> = Box::new(move |device: &Device| serial.set(device.serial));
+
+        prompt.touch_required(&device);
+        prompt.touch_completed(&device, true);
+        assert_eq!(prompted.get(), Some(42));
+        assert_eq!(device_name(&device), "YubiKey 5 (42)");
+    }
+}
@@ -0,0 +1,189 @@
+//! Traces of the reports exchanged with the devices, in the pcapng format.
+//!
+//! Each control transfer is recorded as a submission and a completion event in the Linux usbmon format
+//! (`LINKTYPE_USB_LINUX_MMAPPED`), which Wireshark dissects as USB HID traffic, like the captures made with
+//! usbmon while running ykpers. Set a trace with `ChallengeResponse::set_trace`.
+// Only the backends record the transfers, builds with custom backends alone don't use the writer.
+#![cfg_attr(
+    not(any(
+        feature = "rusb",
+        feature = "nusb",
+        feature = "hidapi",
+        feature = "hidraw",
+        feature = "mock"
+    )),
+    allow(dead_code)
+)]
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+
+const URB_SUBMIT: u8 = b'S';
+const URB_COMPLETE: u8 = b'C';
+const URB_CONTROL: u8 = 2;
+const EINPROGRESS: i32 = -115;
+
+/// A control transfer, as seen on the bus.
+pub(crate) struct ControlTransfer<'a> {
+    pub bus: u16,
+    pub device: u8,
+    pub setup: [u8; 8],
+    /// The data sent to the device, or received from it, depending on the direction in `setup`.
+    pub data: &'a [u8],
+}
+
+impl<'a> ControlTransfer<'a> {
+    fn is_in(&self) -> bool {
+        self.setup[0] & 0x80 != 0
+    }
+
+    /// The usbmon header and data of one event of the transfer.
+    fn usbmon_packet(&self, id: u64, event: u8, timestamp_us: u64) -> Vec<u8> {
+        let with_data = (event == URB_SUBMIT) != self.is_in();
+        let data: &[u8] = if with_data { self.data } else { &[] };
+
+        let mut packet = Vec::with_capacity(64 + data.len());
+        packet.extend_from_slice(&id.to_le_bytes());
+        packet.push(event);
+        packet.push(URB_CONTROL);
+        packet.push(if self.is_in() { 0x80 } else { 0x00 });
+        packet.push(self.device);
+        packet.extend_from_slice(&self.bus.to_le_bytes());
+        // The setup packet is only relevant in the submission.
+        packet.push(if event == URB_SUBMIT { 0 } else { b'-' });
+        packet.push(match (with_data, event) {
+            (true, _) => 0,
+            (false, URB_SUBMIT) => b'<',
+            (false, _) => b'>',
+        });
+        packet.extend_from_slice(&((timestamp_us / 1_000_000) as i64).to_le_bytes());
+        packet.extend_from_slice(&((timestamp_us % 1_000_000) as i32).to_le_bytes());
+        let status = if event == URB_SUBMIT { EINPROGRESS } else { 0 };
+        packet.extend_from_slice(&status.to_le_bytes());
+        packet.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&self.setup);
+        // Interval, start frame, transfer flags and number of isochronous descriptors.
+        packet.extend_from_slice(&[0; 16]);
+        packet.extend_from_slice(data);
+        packet
+    }
+}
+
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let padding = (4 - body.len() % 4) % 4;
+    let length = (12 + body.len() + padding) as u32;
+
+    let mut block = Vec::with_capacity(length as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&length.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&[0; 3][..padding]);
+    block.extend_from_slice(&length.to_le_bytes());
+    block
+}
+
+fn enhanced_packet_block(timestamp_us: u64, packet: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(20 + packet.len());
+    // The only interface.
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((timestamp_us >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp_us as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+    block(ENHANCED_PACKET_BLOCK, &body)
+}
+
+/// A pcapng file being written.
+pub struct PcapngTrace {
+    writer: Box<dyn Write + Send>,
+    next_id: u64,
+}
+
+impl PcapngTrace {
+    /// Starts a trace, writing the pcapng headers to `writer`.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> io::Result<Self> {
+        let mut writer: Box<dyn Write + Send> = Box::new(writer);
+
+        let mut section = Vec::new();
+        section.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        section.extend_from_slice(&1u16.to_le_bytes());
+        section.extend_from_slice(&0u16.to_le_bytes());
+        // The length of the section is not known.
+        section.extend_from_slice(&(-1i64).to_le_bytes());
+        writer.write_all(&block(SECTION_HEADER_BLOCK, &section))?;
+
+        let mut interface = Vec::new();
+        interface.extend_from_slice(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes());
+        interface.extend_from_slice(&0u16.to_le_bytes());
+        // No limit on the size of the packets.
+        interface.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_all(&block(INTERFACE_DESCRIPTION_BLOCK, &interface))?;
+
+        Ok(PcapngTrace { writer, next_id: 0 })
+    }
+
+    /// Starts a trace in a new file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    pub(crate) fn record(&mut self, transfer: &ControlTransfer) -> io::Result<()> {
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        let id = self.next_id;
+        self.next_id += 1;
+
+        for &event in &[URB_SUBMIT, URB_COMPLETE] {
+            let packet = transfer.usbmon_packet(id, event, timestamp_us);
+            self.writer
+                .write_all(&enhanced_packet_block(timestamp_us, &packet))?;
+        }
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usbmon_packet() {
+        let transfer = ControlTransfer {
+            bus: 1,
+            device: 3,
+            setup: [0xa1, 0x01, 0x00, 0x03, 0, 0, 8, 0],
+            data: &[1, 2, 3, 4, 5, 6, 7, 8],
+        };
+
+        let submit = transfer.usbmon_packet(7, URB_SUBMIT, 1_500_000);
+        assert_eq!(submit.len(), 64);
+        assert_eq!(&submit[8..16], &[b'S', URB_CONTROL, 0x80, 3, 1, 0, 0, b'<']);
+        assert_eq!(&submit[16..24], &1i64.to_le_bytes());
+        assert_eq!(&submit[24..28], &500_000i32.to_le_bytes());
+        assert_eq!(&submit[40..48], &transfer.setup);
+
+        let complete = transfer.usbmon_packet(7, URB_COMPLETE, 1_500_000);
+        assert_eq!(complete.len(), 72);
+        assert_eq!(&complete[8..16], &[b'C', URB_CONTROL, 0x80, 3, 1, 0, b'-', 0]);
+        assert_eq!(&complete[64..], transfer.data);
+    }
+
+    #[test]
+    fn test_block_padding() {
+        let block = enhanced_packet_block(0, &[0; 65]);
+        assert_eq!(block.len() % 4, 0);
+        assert_eq!(&block[4..8], &(block.len() as u32).to_le_bytes());
+        assert_eq!(&block[block.len() - 4..], &(block.len() as u32).to_le_bytes());
+    }
+}
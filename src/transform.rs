@@ -0,0 +1,222 @@
+//! Post-processing of the challenge-response results, declared once by the application.
+//!
+//! A `Pipeline` chains `ResponseTransform`s, e.g. truncating the response, expanding it with HKDF or
+//! formatting it as a numeric code, and applies them to the responses of a device or of any `HmacToken`:
+//!
+//! ```no_run
+//! use challenge_response::config::Config;
+//! use challenge_response::transform::{Encoding, Pipeline, Truncate};
+//! use challenge_response::ChallengeResponse;
+//!
+//! let pipeline = Pipeline::new().then(Truncate(16)).then(Encoding::Hex);
+//!
+//! let mut cr = ChallengeResponse::new().unwrap();
+//! let device = cr.find_device().unwrap();
+//! let response = pipeline
+//!     .challenge_response_hmac(&mut cr, b"my challenge", Config::new_from(device))
+//!     .unwrap();
+//! println!("{}", response.to_str().unwrap());
+//! ```
+//!
+//! The intermediate results are zeroed once the next transform consumed them.
+use std::fmt;
+use std::sync::Arc;
+
+#[cfg(feature = "derive")]
+use hkdf::Hkdf;
+#[cfg(feature = "derive")]
+use sha2::Sha256;
+
+use base64;
+use config::Config;
+use error::ChallengeResponseError;
+use modhex;
+use token::HmacToken;
+use validation;
+use ChallengeResponse;
+
+/// A step of a `Pipeline`.
+pub trait ResponseTransform: fmt::Debug + Send + Sync {
+    /// Computes the output of the step from the output of the previous one.
+    fn transform(&self, input: &[u8]) -> Result<Vec<u8>, ChallengeResponseError>;
+}
+
+/// Keeps the first bytes of the response. Fails with `ChallengeResponseError::InvalidFormat` if the
+/// response is shorter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Truncate(pub usize);
+
+impl ResponseTransform for Truncate {
+    fn transform(&self, input: &[u8]) -> Result<Vec<u8>, ChallengeResponseError> {
+        input
+            .get(..self.0)
+            .map(|bytes| bytes.to_vec())
+            .ok_or(ChallengeResponseError::InvalidFormat)
+    }
+}
+
+/// Encodes the response as text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    /// Lowercase hexadecimal.
+    Hex,
+    /// Modhex, see the `modhex` module.
+    Modhex,
+    /// Standard base64, with padding.
+    Base64,
+}
+
+impl ResponseTransform for Encoding {
+    fn transform(&self, input: &[u8]) -> Result<Vec<u8>, ChallengeResponseError> {
+        let encoded = match *self {
+            Encoding::Hex => input.iter().map(|b| format!("{:02x}", b)).collect(),
+            Encoding::Modhex => modhex::encode(input),
+            Encoding::Base64 => base64::encode(input),
+        };
+        Ok(encoded.into_bytes())
+    }
+}
+
+/// Expands the response with HKDF-SHA256 to `length` bytes, with an optional salt and a context `info`,
+/// to get keys of another size than the responses, or different keys for several purposes.
+#[cfg(feature = "derive")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct HkdfExpand {
+    pub salt: Option<Vec<u8>>,
+    pub info: Vec<u8>,
+    pub length: usize,
+}
+
+#[cfg(feature = "derive")]
+impl ResponseTransform for HkdfExpand {
+    fn transform(&self, input: &[u8]) -> Result<Vec<u8>, ChallengeResponseError> {
+        let mut output = vec![0; self.length];
+        Hkdf::<Sha256>::new(self.salt.as_deref(), input)
+            .expand(&self.info, &mut output)
+            .map_err(|_| ChallengeResponseError::InvalidFormat)?;
+        Ok(output)
+    }
+}
+
+/// Formats the response as a numeric code of `digits` digits, with the dynamic truncation of HOTP
+/// (RFC 4226), e.g. for the codes typed by the users. Fails with `ChallengeResponseError::InvalidFormat`
+/// if the response is shorter than a HMAC-SHA1 response, or if `digits` is not between 1 and 9.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Code {
+    pub digits: u8,
+}
+
+impl ResponseTransform for Code {
+    fn transform(&self, input: &[u8]) -> Result<Vec<u8>, ChallengeResponseError> {
+        if input.len() < 20 || self.digits == 0 || self.digits > 9 {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let code = validation::truncate(input, self.digits);
+        Ok(format!("{:0width$}", code, width = self.digits as usize).into_bytes())
+    }
+}
+
+/// The output of a `Pipeline`, zeroed on drop.
+pub struct TransformedResponse(pub Vec<u8>);
+
+impl TransformedResponse {
+    /// The output as text, if the last transform was an encoding or a code.
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+}
+
+impl Drop for TransformedResponse {
+    fn drop(&mut self) {
+        for i in self.0.iter_mut() {
+            *i = 0;
+        }
+    }
+}
+
+impl std::ops::Deref for TransformedResponse {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// A chain of `ResponseTransform`s, applied in the order they were added. An empty pipeline returns the
+/// responses unchanged.
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    transforms: Vec<Arc<dyn ResponseTransform>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    /// Adds a transform at the end of the pipeline.
+    pub fn then<T: ResponseTransform + 'static>(mut self, transform: T) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+
+    /// Applies the transforms to `response`.
+    pub fn apply(&self, response: &[u8]) -> Result<TransformedResponse, ChallengeResponseError> {
+        let mut output = TransformedResponse(response.to_vec());
+        for transform in &self.transforms {
+            // The previous output is zeroed when it is replaced.
+            output = TransformedResponse(transform.transform(&output)?);
+        }
+        Ok(output)
+    }
+
+    /// Performs a HMAC-SHA1 challenge-response with the device and slot targeted by `conf`, and applies
+    /// the transforms to the response.
+    pub fn challenge_response_hmac(
+        &self,
+        cr: &mut ChallengeResponse,
+        challenge: &[u8],
+        conf: Config,
+    ) -> Result<TransformedResponse, ChallengeResponseError> {
+        let hmac = cr.challenge_response_hmac(challenge, conf)?;
+        self.apply(&hmac.0)
+    }
+
+    /// Computes the response of `token` to `challenge`, and applies the transforms to it.
+    pub fn challenge_response<T: HmacToken + ?Sized>(
+        &self,
+        token: &mut T,
+        challenge: &[u8],
+    ) -> Result<TransformedResponse, ChallengeResponseError> {
+        let hmac = token.challenge_response(challenge)?;
+        self.apply(&hmac.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline() {
+        // The HMAC-SHA1 of the RFC 4226 test secret for the counter 0, and its 6-digit HOTP code.
+        let response = [
+            0xcc, 0x93, 0xcf, 0x18, 0x50, 0x8d, 0x94, 0x93, 0x4c, 0x64, 0xb6, 0x5d, 0x8b, 0xa7, 0x66, 0x7f,
+            0xb7, 0xcd, 0xe4, 0xb0,
+        ];
+        assert_eq!(Pipeline::new().apply(&response).unwrap().0, response.to_vec());
+        let code = Pipeline::new().then(Code { digits: 6 });
+        assert_eq!(code.apply(&response).unwrap().to_str(), Some("755224"));
+
+        let pipeline = Pipeline::new().then(Truncate(4)).then(Encoding::Hex);
+        assert_eq!(pipeline.apply(&response).unwrap().to_str(), Some("cc93cf18"));
+        let pipeline = Pipeline::new().then(Truncate(2)).then(Encoding::Modhex);
+        assert_eq!(pipeline.apply(&response).unwrap().to_str(), Some("rrke"));
+
+        assert!(Pipeline::new().then(Truncate(21)).apply(&response).is_err());
+        assert!(Pipeline::new()
+            .then(Truncate(8))
+            .then(Code { digits: 6 })
+            .apply(&response)
+            .is_err());
+    }
+}
@@ -0,0 +1,786 @@
+//! Challenge-response over custom transports.
+//!
+//! The USB backends are not the only way to reach a device: it may be behind a proxy, attached to another
+//! host, or simulated by a test harness. Implementing `Transport`, which only moves feature reports, is
+//! enough to use such a device with `TransportDevice`, with the same protocol handling, timeouts and
+//! errors as the devices of `ChallengeResponse`.
+//!
+//! `TcpTransport` connects to a device served by `serve`, e.g. by a simulator or a remote test rig. Each
+//! message is a 2 bytes big-endian length followed by its payload. The client sends `[OP_READ]` or
+//! `[OP_WRITE, report...]`, and the server answers `[STATUS_OK, report...]` to reads, `[STATUS_OK]` to
+//! writes, or `[STATUS_ERROR]` if the device failed.
+//!
+//! A host with several devices serves them all with `serve_devices`, e.g. with
+//! `ChallengeResponse::serve_devices` for its USB devices. The client lists them with
+//! `TcpTransport::find_all_devices`, which sends `[OP_LIST]` and receives `[STATUS_OK, count, records...]`,
+//! and selects the one to exchange the reports with with `TcpTransport::select_device`, which sends
+//! `[OP_SELECT, index]`. A server of a single device answers `[STATUS_ERROR]` to both.
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::Slot;
+use error::{ChallengeResponseError, CrcError};
+use hmacmode::Hmac;
+use inventory::{Capabilities, InventoryRecord, SlotState};
+#[cfg(feature = "otp")]
+use otpmode::Aes128Block;
+use protocol::{
+    Command, Exchange, Frame, Pending, Phase, RawResponse, Status, StatusByte, Step, CHALLENGE_SIZE,
+    REPORT_SIZE,
+};
+use usb::{ResetPolicy, Timing};
+
+/// Moves the HID feature reports of the OTP interface of a device.
+pub trait Transport {
+    /// Reads a feature report from the device, and returns the number of bytes read.
+    fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, ChallengeResponseError>;
+
+    /// Writes a feature report to the device.
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), ChallengeResponseError>;
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, ChallengeResponseError> {
+        (**self).read_report(report)
+    }
+
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), ChallengeResponseError> {
+        (**self).write_report(report)
+    }
+}
+
+/// Describes a failure of the transfers of an exchange, in the given phase.
+fn exchange_failed(phase: Phase, error: ChallengeResponseError) -> ChallengeResponseError {
+    match phase {
+        Phase::Polling => ChallengeResponseError::StatusPollFailed(Box::new(error)),
+        Phase::WritingFrame { packet_seq } => ChallengeResponseError::WriteFrameFailed {
+            packet_seq,
+            source: Box::new(error),
+        },
+        Phase::ReadingResponse { bytes_read } => ChallengeResponseError::ReadResponseFailed {
+            bytes_read,
+            source: Box::new(error),
+        },
+        Phase::Resetting => error,
+    }
+}
+
+/// Runs `exchange` over `transport` until it is done. `on_ready` can check the status of the device before
+/// the command is written, and `on_touch_required` is called every time the device reports that it waits
+/// for the user to touch it.
+pub(crate) fn run_exchange<T: Transport + ?Sized>(
+    transport: &mut T,
+    timing: &Timing,
+    exchange: &mut Exchange,
+    on_ready: &mut dyn FnMut(&Status) -> Result<(), ChallengeResponseError>,
+    on_touch_required: &mut dyn FnMut(),
+) -> Result<(), ChallengeResponseError> {
+    let mut report = [0; REPORT_SIZE];
+    let mut deadline = None;
+    let mut touch_deadline = None;
+    loop {
+        let phase = exchange.phase();
+        let result = match exchange.step() {
+            Step::Read { poll } => {
+                if let Some(pending) = poll {
+                    if pending != Pending::Write {
+                        let deadline = *deadline
+                            .get_or_insert_with(|| timing.response_timeout.map(|t| Instant::now() + t));
+                        if deadline.is_some_and(|d| Instant::now() >= d) {
+                            return Err(ChallengeResponseError::ResponseTimeout);
+                        }
+                    }
+                    if pending == Pending::Touch {
+                        let touch_deadline = *touch_deadline
+                            .get_or_insert_with(|| timing.touch_timeout.map(|t| Instant::now() + t));
+                        if touch_deadline.is_some_and(|d| Instant::now() >= d) {
+                            return Err(ChallengeResponseError::ResponseTimeout);
+                        }
+                        on_touch_required();
+                    }
+                    thread::sleep(timing.poll_interval);
+                }
+                transport
+                    .read_report(&mut report)
+                    .map(|len| exchange.report_read(&report, len))
+            }
+            Step::Ready(status) => {
+                on_ready(&status)?;
+                exchange.proceed();
+                Ok(())
+            }
+            Step::Write(report) => transport.write_report(&report).map(|_| exchange.report_written()),
+            Step::Done => return Ok(()),
+            Step::Stuck => Err(ChallengeResponseError::ResponseTimeout),
+        };
+        result.map_err(|e| exchange_failed(phase, e))?;
+    }
+}
+
+/// Sends a command with a response over `transport`, and reads the response.
+pub(crate) fn run_command<T: Transport + ?Sized>(
+    transport: &mut T,
+    timing: &Timing,
+    frame: &Frame,
+    on_ready: &mut dyn FnMut(&Status) -> Result<(), ChallengeResponseError>,
+    on_touch_required: &mut dyn FnMut(),
+) -> Result<RawResponse, ChallengeResponseError> {
+    let mut exchange = Exchange::command(frame);
+    run_exchange(transport, timing, &mut exchange, on_ready, on_touch_required)?;
+    Ok(exchange.into_response())
+}
+
+/// Resets the write state of the device, discarding any pending response.
+pub(crate) fn write_reset<T: Transport + ?Sized>(
+    transport: &mut T,
+    timing: &Timing,
+) -> Result<(), ChallengeResponseError> {
+    run_exchange(
+        transport,
+        timing,
+        &mut Exchange::reset(),
+        &mut |_| Ok(()),
+        &mut || {},
+    )
+}
+
+/// Resets the write state of the device if `result` is an error after which `policy` requires it, and
+/// returns `result`.
+pub(crate) fn reset_after<T: Transport + ?Sized, R>(
+    transport: &mut T,
+    timing: &Timing,
+    policy: ResetPolicy,
+    result: Result<R, ChallengeResponseError>,
+) -> Result<R, ChallengeResponseError> {
+    if let Err(ref e) = result {
+        if policy.resets_after(e) {
+            // A failure of the reset would hide the error of the operation.
+            let _ = write_reset(transport, timing);
+        }
+    }
+    result
+}
+
+/// Reads a single feature report, without sending any command.
+pub(crate) fn read_status_report<T: Transport + ?Sized>(
+    transport: &mut T,
+) -> Result<[u8; REPORT_SIZE], ChallengeResponseError> {
+    let mut report = [0; REPORT_SIZE];
+    if transport.read_report(&mut report)? != REPORT_SIZE {
+        return Err(ChallengeResponseError::CanNotReadFromDevice);
+    }
+    Ok(report)
+}
+
+pub(crate) fn read_serial<T: Transport + ?Sized>(
+    transport: &mut T,
+    timing: &Timing,
+) -> Result<u32, ChallengeResponseError> {
+    // The command has no payload.
+    let frame = Frame::new([0; CHALLENGE_SIZE], Command::DeviceSerial);
+    let response = run_command(transport, timing, &frame, &mut |_| Ok(()), &mut || {})?.bytes;
+
+    // Check response.
+    CrcError::check(&response[..6])?;
+
+    Ok(u32::from_be_bytes([
+        response[0],
+        response[1],
+        response[2],
+        response[3],
+    ]))
+}
+
+const OP_READ: u8 = 1;
+const OP_WRITE: u8 = 2;
+const OP_LIST: u8 = 3;
+const OP_SELECT: u8 = 4;
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+const RECORD_SERIAL: u8 = 0x01;
+const RECORD_FIRMWARE: u8 = 0x02;
+const RECORD_MODEL: u8 = 0x04;
+
+fn slot_state_byte(state: Option<SlotState>) -> u8 {
+    match state {
+        None => 0,
+        Some(SlotState::Empty) => 1,
+        Some(SlotState::Configured { require_touch: false }) => 2,
+        Some(SlotState::Configured { require_touch: true }) => 3,
+    }
+}
+
+fn slot_state_from_byte(byte: u8) -> Result<Option<SlotState>, ChallengeResponseError> {
+    match byte {
+        0 => Ok(None),
+        1 => Ok(Some(SlotState::Empty)),
+        2 => Ok(Some(SlotState::Configured { require_touch: false })),
+        3 => Ok(Some(SlotState::Configured { require_touch: true })),
+        _ => Err(ChallengeResponseError::InvalidResponse),
+    }
+}
+
+/// Appends the record of a served device: its flags, serial number, vendor and product IDs, firmware
+/// version, slot states, capabilities and model name, prefixed by its length.
+fn encode_record(record: &InventoryRecord, out: &mut Vec<u8>) {
+    let model = record
+        .model
+        .as_deref()
+        .map(|m| &m.as_bytes()[..m.len().min(u8::MAX as usize)]);
+    let mut flags = 0;
+    if record.serial.is_some() {
+        flags |= RECORD_SERIAL;
+    }
+    if record.firmware.is_some() {
+        flags |= RECORD_FIRMWARE;
+    }
+    if model.is_some() {
+        flags |= RECORD_MODEL;
+    }
+    let firmware = record.firmware.unwrap_or((0, 0, 0));
+    out.push(flags);
+    out.extend_from_slice(&record.serial.unwrap_or(0).to_be_bytes());
+    out.extend_from_slice(&record.vendor_id.to_be_bytes());
+    out.extend_from_slice(&record.product_id.to_be_bytes());
+    out.extend_from_slice(&[firmware.0, firmware.1, firmware.2]);
+    out.push(slot_state_byte(record.slot1));
+    out.push(slot_state_byte(record.slot2));
+    out.push(record.capabilities.bits());
+    let model = model.unwrap_or(&[]);
+    out.push(model.len() as u8);
+    out.extend_from_slice(model);
+}
+
+const RECORD_HEADER_SIZE: usize = 16;
+
+fn decode_records(data: &[u8]) -> Result<Vec<InventoryRecord>, ChallengeResponseError> {
+    let (&count, mut data) = data
+        .split_first()
+        .ok_or(ChallengeResponseError::InvalidResponse)?;
+    let mut records = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if data.len() < RECORD_HEADER_SIZE {
+            return Err(ChallengeResponseError::InvalidResponse);
+        }
+        let (header, rest) = data.split_at(RECORD_HEADER_SIZE);
+        let model_len = header[RECORD_HEADER_SIZE - 1] as usize;
+        if rest.len() < model_len {
+            return Err(ChallengeResponseError::InvalidResponse);
+        }
+        let (model, rest) = rest.split_at(model_len);
+        let flags = header[0];
+        records.push(InventoryRecord {
+            serial: if flags & RECORD_SERIAL != 0 {
+                Some(u32::from_be_bytes([header[1], header[2], header[3], header[4]]))
+            } else {
+                None
+            },
+            model: if flags & RECORD_MODEL != 0 {
+                Some(String::from_utf8_lossy(model).into_owned())
+            } else {
+                None
+            },
+            vendor_id: u16::from_be_bytes([header[5], header[6]]),
+            product_id: u16::from_be_bytes([header[7], header[8]]),
+            firmware: if flags & RECORD_FIRMWARE != 0 {
+                Some((header[9], header[10], header[11]))
+            } else {
+                None
+            },
+            slot1: slot_state_from_byte(header[12])?,
+            slot2: slot_state_from_byte(header[13])?,
+            capabilities: Capabilities::from_bits_truncate(header[14]),
+        });
+        data = rest;
+    }
+    Ok(records)
+}
+
+fn send_message<W: Write>(stream: &mut W, payload: &[u8]) -> Result<(), ChallengeResponseError> {
+    let mut message = Vec::with_capacity(2 + payload.len());
+    message.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Receives a message, or `None` if the connection was closed before it.
+fn receive_message<R: Read>(stream: &mut R) -> Result<Option<Vec<u8>>, ChallengeResponseError> {
+    let mut len = [0; 2];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut payload = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// A `Transport` to a device served over TCP with `serve`.
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    /// Lists the devices of a server started with `serve_devices`. Fails with
+    /// `ChallengeResponseError::CommandNotSupported` if the server only serves a single device.
+    pub fn find_all_devices(&mut self) -> Result<Vec<InventoryRecord>, ChallengeResponseError> {
+        let response = self.request(&[OP_LIST])?;
+        match response.split_first() {
+            Some((&STATUS_OK, data)) => decode_records(data),
+            Some((&STATUS_ERROR, [])) => Err(ChallengeResponseError::CommandNotSupported),
+            _ => Err(ChallengeResponseError::InvalidResponse),
+        }
+    }
+
+    /// Selects the device at `index` of the last list of `find_all_devices`, for the following exchanges,
+    /// e.g. through a `TransportDevice`. Fails with `ChallengeResponseError::DeviceNotFound` if the server
+    /// could not open it.
+    pub fn select_device(&mut self, index: usize) -> Result<(), ChallengeResponseError> {
+        let index = u8::try_from(index).map_err(|_| ChallengeResponseError::DeviceNotFound)?;
+        match self.request(&[OP_SELECT, index])?.as_slice() {
+            [STATUS_OK] => Ok(()),
+            [STATUS_ERROR] => Err(ChallengeResponseError::DeviceNotFound),
+            _ => Err(ChallengeResponseError::InvalidResponse),
+        }
+    }
+
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<Self, ChallengeResponseError> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(TcpTransport { stream })
+    }
+
+    /// Sets the timeout of each message sent or received, or `None` to wait forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), ChallengeResponseError> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    fn request(&mut self, payload: &[u8]) -> Result<Vec<u8>, ChallengeResponseError> {
+        send_message(&mut self.stream, payload)?;
+        receive_message(&mut self.stream)?.ok_or(ChallengeResponseError::InvalidResponse)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, ChallengeResponseError> {
+        let response = self.request(&[OP_READ])?;
+        match response.split_first() {
+            Some((&STATUS_OK, data)) if data.len() <= REPORT_SIZE => {
+                report[..data.len()].copy_from_slice(data);
+                Ok(data.len())
+            }
+            Some((&STATUS_ERROR, _)) => Err(ChallengeResponseError::CanNotReadFromDevice),
+            _ => Err(ChallengeResponseError::InvalidResponse),
+        }
+    }
+
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), ChallengeResponseError> {
+        let mut payload = [OP_WRITE; 1 + REPORT_SIZE];
+        payload[1..].copy_from_slice(report);
+        match self.request(&payload)?.as_slice() {
+            [STATUS_OK] => Ok(()),
+            [STATUS_ERROR] => Err(ChallengeResponseError::CanNotWriteToDevice),
+            _ => Err(ChallengeResponseError::InvalidResponse),
+        }
+    }
+}
+
+/// The devices of a host, served with `serve_devices`. The reports are exchanged with the selected device.
+pub trait DeviceSet: Transport {
+    /// Lists the devices. The indexes of `select` are the ones of this list.
+    fn list(&mut self) -> Result<Vec<InventoryRecord>, ChallengeResponseError>;
+
+    /// Selects the device at `index` of the last list.
+    fn select(&mut self, index: usize) -> Result<(), ChallengeResponseError>;
+}
+
+/// Answers a read or write request with `transport`. Returns `false` if `request` is not one.
+fn serve_report<T: Transport + ?Sized>(
+    stream: &mut TcpStream,
+    transport: &mut T,
+    request: &[u8],
+) -> Result<bool, ChallengeResponseError> {
+    match request.split_first() {
+        Some((&OP_READ, [])) => {
+            let mut response = [STATUS_OK; 1 + REPORT_SIZE];
+            let mut report = [0; REPORT_SIZE];
+            match transport.read_report(&mut report) {
+                Ok(len) => {
+                    response[1..1 + len].copy_from_slice(&report[..len]);
+                    send_message(stream, &response[..1 + len])?;
+                }
+                Err(_) => send_message(stream, &[STATUS_ERROR])?,
+            }
+        }
+        Some((&OP_WRITE, data)) if data.len() == REPORT_SIZE => {
+            let mut report = [0; REPORT_SIZE];
+            report.copy_from_slice(data);
+            let status = match transport.write_report(&report) {
+                Ok(()) => STATUS_OK,
+                Err(_) => STATUS_ERROR,
+            };
+            send_message(stream, &[status])?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Serves `transport` to a `TcpTransport` connected to `stream`, until the connection is closed. The
+/// errors of the transport are reported to the client, while the ones of the connection are returned.
+pub fn serve<T: Transport + ?Sized>(
+    mut stream: TcpStream,
+    transport: &mut T,
+) -> Result<(), ChallengeResponseError> {
+    stream.set_nodelay(true)?;
+    while let Some(request) = receive_message(&mut stream)? {
+        if serve_report(&mut stream, transport, &request)? {
+            continue;
+        }
+        match request.as_slice() {
+            [OP_LIST] | [OP_SELECT, _] => send_message(&mut stream, &[STATUS_ERROR])?,
+            _ => return Err(ChallengeResponseError::InvalidFormat),
+        }
+    }
+    Ok(())
+}
+
+/// Serves the devices of `devices` to a `TcpTransport` connected to `stream`, until the connection is
+/// closed. See `serve`.
+pub fn serve_devices<S: DeviceSet + ?Sized>(
+    mut stream: TcpStream,
+    devices: &mut S,
+) -> Result<(), ChallengeResponseError> {
+    stream.set_nodelay(true)?;
+    while let Some(request) = receive_message(&mut stream)? {
+        if serve_report(&mut stream, devices, &request)? {
+            continue;
+        }
+        match request.as_slice() {
+            [OP_LIST] => match devices.list() {
+                Ok(records) => {
+                    let mut response = vec![STATUS_OK, records.len().min(u8::MAX as usize) as u8];
+                    for record in records.iter().take(u8::MAX as usize) {
+                        encode_record(record, &mut response);
+                    }
+                    send_message(&mut stream, &response)?;
+                }
+                Err(_) => send_message(&mut stream, &[STATUS_ERROR])?,
+            },
+            [OP_SELECT, index] => {
+                let status = match devices.select(*index as usize) {
+                    Ok(()) => STATUS_OK,
+                    Err(_) => STATUS_ERROR,
+                };
+                send_message(&mut stream, &[status])?;
+            }
+            _ => return Err(ChallengeResponseError::InvalidFormat),
+        }
+    }
+    Ok(())
+}
+
+/// A device reached through a custom `Transport`.
+pub struct TransportDevice<T> {
+    transport: T,
+    timing: Timing,
+    reset_policy: ResetPolicy,
+}
+
+impl<T: Transport> TransportDevice<T> {
+    pub fn new(transport: T) -> Self {
+        TransportDevice {
+            transport,
+            timing: Timing::default(),
+            reset_policy: ResetPolicy::default(),
+        }
+    }
+
+    /// Sets the timeouts and polling interval. The transfer timeout is left to the transport.
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    /// Sets what is done with the device after a failed operation.
+    pub fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        self.reset_policy = policy;
+    }
+
+    /// Returns the underlying transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    fn challenge_response(
+        &mut self,
+        challenge: [u8; CHALLENGE_SIZE],
+        command: Command,
+        crc_len: usize,
+    ) -> Result<RawResponse, ChallengeResponseError> {
+        let frame = Frame::new(challenge, command);
+        let result = run_command(
+            &mut self.transport,
+            &self.timing,
+            &frame,
+            &mut |_| Ok(()),
+            &mut || {},
+        )
+        .and_then(|raw| {
+            CrcError::check(&raw.bytes[..crc_len])?;
+            Ok(raw)
+        });
+        reset_after(&mut self.transport, &self.timing, self.reset_policy, result)
+    }
+
+    /// Performs an HMAC-SHA1 challenge-response with a slot. See `Config::variable` for the meaning of
+    /// `variable`.
+    pub fn challenge_response_hmac(
+        &mut self,
+        chall: &[u8],
+        slot: &Slot,
+        variable: bool,
+    ) -> Result<Hmac, ChallengeResponseError> {
+        let command = slot.command(Command::ChallengeHmac1, Command::ChallengeHmac2)?;
+        let mut challenge = [0; CHALLENGE_SIZE];
+        if variable && chall.last() == Some(&0) {
+            challenge = [0xff; CHALLENGE_SIZE];
+        }
+        challenge[..chall.len()].copy_from_slice(chall);
+
+        let raw = self.challenge_response(challenge, command, 22)?;
+        let mut hmac = Hmac([0; 20]);
+        hmac.0.copy_from_slice(&raw.bytes[..20]);
+        Ok(hmac)
+    }
+
+    /// Performs a Yubico OTP challenge-response with a slot.
+    #[cfg(feature = "otp")]
+    pub fn challenge_response_otp(
+        &mut self,
+        chall: &[u8],
+        slot: &Slot,
+    ) -> Result<Aes128Block, ChallengeResponseError> {
+        let command = slot.command(Command::ChallengeOtp1, Command::ChallengeOtp2)?;
+        let mut challenge = [0; CHALLENGE_SIZE];
+        challenge[..chall.len()].copy_from_slice(chall);
+
+        let raw = self.challenge_response(challenge, command, 18)?;
+        Ok(Aes128Block {
+            block: aes::cipher::generic_array::GenericArray::clone_from_slice(&raw.bytes[..16]),
+        })
+    }
+
+    pub fn read_serial_number(&mut self) -> Result<u32, ChallengeResponseError> {
+        let serial = read_serial(&mut self.transport, &self.timing);
+        reset_after(&mut self.transport, &self.timing, self.reset_policy, serial)
+    }
+
+    /// Reads the status of the device: firmware version, programming sequence and touch level.
+    pub fn read_status(&mut self) -> Result<Status, ChallengeResponseError> {
+        Ok(Status::from_report(&read_status_report(&mut self.transport)?))
+    }
+
+    /// Reads the flags of the device, with a single feature report. See
+    /// `ChallengeResponse::poll_status_byte`.
+    pub fn poll_status_byte(&mut self) -> Result<StatusByte, ChallengeResponseError> {
+        Ok(StatusByte::from_report(&read_status_report(&mut self.transport)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{crc16, Flags, WRITE_RESET_PAYLOAD};
+
+    /// Answers every frame with the serial number 1234567.
+    struct FakeTransport {
+        next_report: Option<usize>,
+    }
+
+    impl Transport for FakeTransport {
+        fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, ChallengeResponseError> {
+            let mut response = [0; 28];
+            response[..4].copy_from_slice(&1234567u32.to_be_bytes());
+            let crc = 0xffff - crc16(&response[..4]);
+            response[4..6].copy_from_slice(&crc.to_le_bytes());
+
+            *report = [0; REPORT_SIZE];
+            if let Some(i) = self.next_report {
+                if i < 4 {
+                    report[..7].copy_from_slice(&response[i * 7..i * 7 + 7]);
+                }
+                report[7] = Flags::RESP_PENDING_FLAG.bits() | (i as u8 % 4);
+                self.next_report = Some(i + 1);
+            }
+            Ok(REPORT_SIZE)
+        }
+
+        fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), ChallengeResponseError> {
+            if *report == WRITE_RESET_PAYLOAD {
+                self.next_report = None;
+            } else if report[7] == Flags::SLOT_WRITE_FLAG.bits() + 9 {
+                self.next_report = Some(0);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transport_device() {
+        let mut device = TransportDevice::new(FakeTransport { next_report: None });
+        assert_eq!(device.read_serial_number().unwrap(), 1234567);
+        assert!(matches!(
+            device.challenge_response_hmac(b"abc", &Slot::Other(3), true),
+            Err(ChallengeResponseError::SlotNotSupported(3))
+        ));
+    }
+
+    /// Waits for a touch forever after every frame.
+    struct UntouchedTransport {
+        waiting: bool,
+    }
+
+    impl Transport for UntouchedTransport {
+        fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, ChallengeResponseError> {
+            *report = [0; REPORT_SIZE];
+            if self.waiting {
+                report[7] = Flags::RESP_TIMEOUT_WAIT_FLAG.bits();
+            }
+            Ok(REPORT_SIZE)
+        }
+
+        fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), ChallengeResponseError> {
+            self.waiting = *report != WRITE_RESET_PAYLOAD && report[7] == Flags::SLOT_WRITE_FLAG.bits() + 9;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_touch_timeout() {
+        let mut device = TransportDevice::new(UntouchedTransport { waiting: false });
+        device.set_timing(Timing {
+            touch_timeout: Some(Duration::from_millis(20)),
+            ..Timing::default()
+        });
+        let start = Instant::now();
+        let error = device
+            .challenge_response_hmac(b"abc", &Slot::Slot2, true)
+            .unwrap_err();
+        assert!(matches!(
+            error.root_cause(),
+            ChallengeResponseError::ResponseTimeout
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_tcp_transport() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, &mut FakeTransport { next_report: None }).unwrap();
+        });
+
+        let mut client = TcpTransport::connect(address).unwrap();
+        assert!(matches!(
+            client.find_all_devices(),
+            Err(ChallengeResponseError::CommandNotSupported)
+        ));
+        let mut device = TransportDevice::new(client);
+        assert_eq!(device.read_serial_number().unwrap(), 1234567);
+        drop(device);
+        server.join().unwrap();
+    }
+
+    struct FakeDeviceSet {
+        devices: Vec<FakeTransport>,
+        selected: Option<usize>,
+    }
+
+    impl Transport for FakeDeviceSet {
+        fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, ChallengeResponseError> {
+            let selected = self.selected.ok_or(ChallengeResponseError::DeviceNotFound)?;
+            self.devices[selected].read_report(report)
+        }
+
+        fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), ChallengeResponseError> {
+            let selected = self.selected.ok_or(ChallengeResponseError::DeviceNotFound)?;
+            self.devices[selected].write_report(report)
+        }
+    }
+
+    impl DeviceSet for FakeDeviceSet {
+        fn list(&mut self) -> Result<Vec<InventoryRecord>, ChallengeResponseError> {
+            Ok((0..self.devices.len())
+                .map(|i| InventoryRecord {
+                    serial: Some(1234567),
+                    model: if i == 0 {
+                        Some("YubiKey 5".to_string())
+                    } else {
+                        None
+                    },
+                    vendor_id: 0x1050,
+                    product_id: 0x0407,
+                    firmware: Some((5, 4, i as u8)),
+                    slot1: Some(SlotState::Empty),
+                    slot2: Some(SlotState::Configured { require_touch: true }),
+                    capabilities: Capabilities::CHALLENGE_RESPONSE | Capabilities::SERIAL_NUMBER,
+                })
+                .collect())
+        }
+
+        fn select(&mut self, index: usize) -> Result<(), ChallengeResponseError> {
+            if index >= self.devices.len() {
+                return Err(ChallengeResponseError::DeviceNotFound);
+            }
+            self.selected = Some(index);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_serve_devices() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut devices = FakeDeviceSet {
+                devices: vec![
+                    FakeTransport { next_report: None },
+                    FakeTransport { next_report: None },
+                ],
+                selected: None,
+            };
+            serve_devices(stream, &mut devices).unwrap();
+        });
+
+        let mut client = TcpTransport::connect(address).unwrap();
+        let mut devices = FakeDeviceSet {
+            devices: vec![
+                FakeTransport { next_report: None },
+                FakeTransport { next_report: None },
+            ],
+            selected: None,
+        };
+        assert_eq!(client.find_all_devices().unwrap(), devices.list().unwrap());
+        assert!(matches!(
+            client.select_device(2),
+            Err(ChallengeResponseError::DeviceNotFound)
+        ));
+        client.select_device(1).unwrap();
+        let mut device = TransportDevice::new(client);
+        assert_eq!(device.read_serial_number().unwrap(), 1234567);
+        drop(device);
+        server.join().unwrap();
+    }
+}
@@ -1,76 +1,232 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 use std::time::Duration;
-use std::{slice, thread};
 
-use config::Command;
+use builder::BackendKind;
+use config::Slot;
 use error::ChallengeResponseError;
-use sec::crc16;
+use inventory::InventoryRecord;
+use lock::DeviceLock;
+use protocol::{Exchange, RawResponse, Status, REPORT_SIZE};
+use trace::{ControlTransfer, PcapngTrace};
+use transport::{self, DeviceSet, Transport};
+use vendor;
+use ChallengeResponse;
 
-#[cfg(feature = "rusb")]
-pub type BackendType = rusb::RUSBBackend;
+pub use protocol::{Frame, CHALLENGE_SIZE};
+#[cfg_attr(
+    not(any(
+        feature = "rusb",
+        feature = "nusb",
+        feature = "hidapi",
+        feature = "hidraw",
+        feature = "mock"
+    )),
+    allow(unused_imports)
+)]
+pub(crate) use protocol::{HID_GET_REPORT, HID_SET_REPORT, REPORT_TYPE_FEATURE};
+
+#[cfg(all(feature = "rusb", feature = "nusb"))]
+pub type UsbBackendType = selectable::SelectableBackend;
+#[cfg(all(feature = "rusb", feature = "nusb"))]
+pub type UsbDeviceHandleType = selectable::SelectableHandle;
+#[cfg(all(feature = "rusb", feature = "nusb"))]
+pub(crate) type InterfaceType = selectable::SelectableInterface;
+#[cfg(all(feature = "rusb", not(feature = "nusb")))]
+pub type UsbBackendType = rusb::RUSBBackend;
+#[cfg(all(feature = "rusb", not(feature = "nusb")))]
+pub type UsbDeviceHandleType = ::rusb::DeviceHandle<::rusb::Context>;
+#[cfg(all(feature = "rusb", not(feature = "nusb")))]
+pub(crate) type InterfaceType = u8;
+#[cfg(all(feature = "nusb", not(feature = "rusb")))]
+pub type UsbBackendType = nusb::NUSBBackend;
+#[cfg(all(feature = "nusb", not(feature = "rusb")))]
+pub type UsbDeviceHandleType = ::nusb::Device;
 #[cfg(all(feature = "nusb", not(feature = "rusb")))]
-pub type BackendType = nusb::NUSBBackend;
+pub(crate) type InterfaceType = ::nusb::Interface;
+#[cfg(all(feature = "hidapi", not(any(feature = "rusb", feature = "nusb"))))]
+pub type UsbBackendType = hidapi::HidapiBackend;
+#[cfg(all(feature = "hidapi", not(any(feature = "rusb", feature = "nusb"))))]
+pub type UsbDeviceHandleType = hidapi::HidapiDevice;
+#[cfg(all(feature = "hidapi", not(any(feature = "rusb", feature = "nusb"))))]
+pub(crate) type InterfaceType = ();
+#[cfg(all(
+    feature = "hidraw",
+    not(any(feature = "rusb", feature = "nusb", feature = "hidapi"))
+))]
+pub type UsbBackendType = hidraw::HidrawBackend;
+#[cfg(all(
+    feature = "hidraw",
+    not(any(feature = "rusb", feature = "nusb", feature = "hidapi"))
+))]
+pub type UsbDeviceHandleType = hidraw::HidrawDevice;
+#[cfg(all(
+    feature = "hidraw",
+    not(any(feature = "rusb", feature = "nusb", feature = "hidapi"))
+))]
+pub(crate) type InterfaceType = ();
+#[cfg(not(any(feature = "rusb", feature = "nusb", feature = "hidapi", feature = "hidraw")))]
+pub type UsbBackendType = disabled::DisabledBackend;
+#[cfg(not(any(feature = "rusb", feature = "nusb", feature = "hidapi", feature = "hidraw")))]
+pub type UsbDeviceHandleType = disabled::DisabledDevice;
+#[cfg(not(any(feature = "rusb", feature = "nusb", feature = "hidapi", feature = "hidraw")))]
+pub(crate) type InterfaceType = ();
 
-/// If using a variable-length challenge, the challenge must be stricly smaller than this value.
-/// If using a fixed-length challenge, the challenge must be exactly equal to this value.
-pub const CHALLENGE_SIZE: usize = 64;
+// The backend of a `ChallengeResponse` is either the USB backend or one implemented by the application.
+pub type BackendType = pluggable::PluggableBackend;
+pub type DeviceHandleType = pluggable::PluggableHandle;
 
-const VENDOR_ID: [u16; 3] = [
-    0x1050, // Yubico ( Yubikeys )
-    0x1D50, // OpenMoko ( Onlykey )
-    0x20A0, // Flirc ( Nitrokey )
-];
-const PRODUCT_ID: [u16; 11] = [
-    0x0010, // YubiKey Gen 1 & 2
-    0x0110, 0x0113, 0x0114, 0x0116, // YubiKey NEO
-    0x0401, 0x0403, 0x0405, 0x0407, // Yubikey 4 & 5
-    0x60FC, // Onlykey
-    0x4211, // NitroKey
+/// A model of device supported by the library.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceModel {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub name: &'static str,
+    /// The number of challenge-response slots.
+    pub slots: u8,
+}
+
+#[cfg_attr(
+    not(any(
+        feature = "vendor-yubico",
+        feature = "vendor-onlykey",
+        feature = "vendor-nitrokey",
+        feature = "vendor-token2"
+    )),
+    allow(dead_code)
+)]
+const fn model(vendor_id: u16, product_id: u16, name: &'static str) -> DeviceModel {
+    DeviceModel {
+        vendor_id,
+        product_id,
+        name,
+        slots: 2,
+    }
+}
+
+/// The supported devices. A device has a different product ID for each combination of its enabled
+/// interfaces (OTP, FIDO, CCID), and only the ones with the OTP interface can be used. The models of each
+/// vendor are compiled in with its `vendor-*` feature.
+const MODELS: &[DeviceModel] = &[
+    // Yubico
+    #[cfg(feature = "vendor-yubico")]
+    model(0x1050, 0x0010, "YubiKey 1/2"),
+    #[cfg(feature = "vendor-yubico")]
+    model(0x1050, 0x0110, "YubiKey NEO OTP"),
+    #[cfg(feature = "vendor-yubico")]
+    model(0x1050, 0x0111, "YubiKey NEO OTP+CCID"),
+    #[cfg(feature = "vendor-yubico")]
+    model(0x1050, 0x0114, "YubiKey NEO OTP+FIDO"),
+    #[cfg(feature = "vendor-yubico")]
+    model(0x1050, 0x0116, "YubiKey NEO OTP+FIDO+CCID"),
+    #[cfg(feature = "vendor-yubico")]
+    model(0x1050, 0x0401, "YubiKey 4/5 OTP"),
+    #[cfg(feature = "vendor-yubico")]
+    model(0x1050, 0x0403, "YubiKey 4/5 OTP+FIDO"),
+    #[cfg(feature = "vendor-yubico")]
+    model(0x1050, 0x0405, "YubiKey 4/5 OTP+CCID"),
+    #[cfg(feature = "vendor-yubico")]
+    model(0x1050, 0x0407, "YubiKey 4/5 OTP+FIDO+CCID"),
+    #[cfg(feature = "vendor-yubico")]
+    model(0x1050, 0x0410, "YubiKey Plus OTP+FIDO"),
+    // OpenMoko
+    #[cfg(feature = "vendor-onlykey")]
+    model(0x1D50, 0x60FC, "OnlyKey"),
+    // Flirc
+    #[cfg(feature = "vendor-nitrokey")]
+    model(0x20A0, 0x4211, "Nitrokey"),
+    // Token2, whose programmable keys implement the OTP HID protocol of the YubiKeys, with two slots
+    #[cfg(feature = "vendor-token2")]
+    model(0x349E, 0x0020, "Token2 T2F2 OTP+FIDO"),
+    #[cfg(feature = "vendor-token2")]
+    model(0x349E, 0x0022, "Token2 PIN+ OTP+FIDO"),
 ];
 
-#[cfg(all(feature = "nusb", not(feature = "rusb")))]
+impl DeviceModel {
+    /// All the built-in models. The models of other vendors can be registered with `vendor::register`, and
+    /// other compatible devices can be used with `ChallengeResponseBuilder::extra_device_id`.
+    pub fn all() -> &'static [DeviceModel] {
+        MODELS
+    }
+
+    /// The built-in or registered model with the given IDs.
+    pub fn lookup(vendor_id: u16, product_id: u16) -> Option<&'static DeviceModel> {
+        MODELS
+            .iter()
+            .find(|m| m.vendor_id == vendor_id && m.product_id == product_id)
+            .or_else(|| vendor::lookup(vendor_id, product_id))
+    }
+}
+
+#[cfg(not(any(feature = "rusb", feature = "nusb", feature = "hidapi", feature = "hidraw")))]
+pub mod disabled;
+#[cfg(all(feature = "hidapi", not(any(feature = "rusb", feature = "nusb"))))]
+pub mod hidapi;
+#[cfg(all(
+    feature = "hidraw",
+    not(any(feature = "rusb", feature = "nusb", feature = "hidapi"))
+))]
+pub mod hidraw;
+#[cfg(feature = "nusb")]
 pub mod nusb;
+pub mod pluggable;
 #[cfg(feature = "rusb")]
 pub mod rusb;
+#[cfg(all(feature = "rusb", feature = "nusb"))]
+pub mod selectable;
 
-/// The size of the payload when writing a request to the usb interface.
-pub(crate) const PAYLOAD_SIZE: usize = 64;
-/// The size of the response after writing a request to the usb interface.
-pub(crate) const RESPONSE_SIZE: usize = 36;
-/// The size of the payload to change the state of the device
-pub(crate) const STATUS_UPDATE_PAYLOAD_SIZE: usize = 8;
-
-pub(crate) const HID_GET_REPORT: u8 = 0x01;
-pub(crate) const HID_SET_REPORT: u8 = 0x09;
-pub(crate) const REPORT_TYPE_FEATURE: u16 = 0x03;
-
-pub(crate) const WRITE_RESET_PAYLOAD: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0x8f];
+/// Creates the USB backend `kind`. With both the `rusb` and `nusb` features, the other one is used if `kind`
+/// fails to initialize.
+#[cfg(all(feature = "rusb", feature = "nusb"))]
+pub(crate) fn new_usb_backend(kind: BackendKind) -> Result<UsbBackendType, ChallengeResponseError> {
+    selectable::SelectableBackend::with_kind(kind)
+}
 
-bitflags! {
-    pub struct Flags: u8 {
-        const SLOT_WRITE_FLAG = 0x80;
-        const RESP_PENDING_FLAG = 0x40;
+/// Creates the USB backend `kind`, which must be the one compiled in.
+#[cfg(not(all(feature = "rusb", feature = "nusb")))]
+pub(crate) fn new_usb_backend(kind: BackendKind) -> Result<UsbBackendType, ChallengeResponseError> {
+    if kind != BackendKind::compiled() {
+        return Err(ChallengeResponseError::BackendUnavailable(kind.name()));
     }
+    UsbBackendType::new()
 }
 
-#[repr(C)]
-#[repr(packed)]
-pub struct Frame {
-    pub payload: [u8; PAYLOAD_SIZE],
-    command: Command,
-    crc: u16,
-    filler: [u8; 3],
+/// Whether a device is one of the supported devices, or one of the `extra` vendor and product IDs.
+#[cfg_attr(
+    not(any(feature = "rusb", feature = "nusb", feature = "hidapi", feature = "hidraw")),
+    allow(dead_code)
+)]
+pub(crate) fn is_supported(extra: &[(u16, u16)], vendor_id: u16, product_id: u16) -> bool {
+    DeviceModel::lookup(vendor_id, product_id).is_some() || extra.contains(&(vendor_id, product_id))
 }
 
-impl Frame {
-    pub fn new(payload: [u8; PAYLOAD_SIZE], command: Command) -> Self {
-        let mut f = Frame {
-            payload,
-            command,
-            crc: 0,
-            filler: [0; 3],
-        };
-        f.crc = crc16(&f.payload).to_le();
-        f
+/// The timeouts and polling interval of the exchanges with the devices.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timing {
+    /// The timeout of each feature report transfer.
+    pub transfer_timeout: Duration,
+    /// How long to wait for a response, including the time for the user to touch the device. Waits forever
+    /// if `None`.
+    pub response_timeout: Option<Duration>,
+    /// How long to wait for the user to touch the device, from the first report of the device that it waits
+    /// for the touch. Only `response_timeout` applies if `None`.
+    pub touch_timeout: Option<Duration>,
+    /// The delay between two reads of the state of the device while waiting for it.
+    pub poll_interval: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            transfer_timeout: Duration::from_secs(2),
+            response_timeout: None,
+            touch_timeout: None,
+            poll_interval: Duration::from_millis(1),
+        }
     }
 }
 
@@ -82,142 +238,879 @@ pub struct Device {
     pub vendor_id: u16,
     pub bus_id: u8,
     pub address_id: u8,
+    /// The physical port the device is plugged in, if the backend can report it.
+    pub port_path: Option<PortPath>,
+    /// The manufacturer name reported by the device.
+    pub manufacturer: Option<String>,
+    /// The release number of the device, `bcdDevice` in its descriptor. See `release_version`.
+    pub release: u16,
+    /// The interfaces of the active configuration of the device.
+    pub interfaces: Vec<InterfaceInfo>,
+}
+
+impl Device {
+    /// The identity of the device. See `DeviceId`.
+    pub fn id(&self) -> DeviceId {
+        match self.serial {
+            Some(serial) => DeviceId::Serial(serial),
+            None => DeviceId::Location {
+                vendor_id: self.vendor_id,
+                product_id: self.product_id,
+                bus_id: self.bus_id,
+                address_id: self.address_id,
+            },
+        }
+    }
+
+    /// The model of the device, unless it was added with `ChallengeResponseBuilder::extra_device_id`.
+    pub fn model(&self) -> Option<&'static DeviceModel> {
+        DeviceModel::lookup(self.vendor_id, self.product_id)
+    }
+
+    /// The major, minor and sub-minor numbers of the binary-coded decimal `release`. The YubiKeys report
+    /// their firmware version there, e.g. `(5, 4, 3)` for the firmware 5.4.3.
+    pub fn release_version(&self) -> (u8, u8, u8) {
+        let digit = |shift: u16| ((self.release >> shift) & 0xf) as u8;
+        (10 * digit(12) + digit(8), digit(4), digit(0))
+    }
+
+    /// Whether the device has the slot. The devices of unknown models are assumed to have two slots.
+    pub fn supports_slot(&self, slot: &Slot) -> bool {
+        slot.number() <= self.model().map_or(2, |m| m.slots)
+    }
+
+    /// Reads again the product name, serial number and status of the device, whose properties may have
+    /// changed since it was enumerated, e.g. after it was reconfigured or switched to another mode, and
+    /// returns the status. Fails with `ChallengeResponseError::DeviceNotFound` if the device is not plugged in
+    /// at the same address anymore, e.g. after a reset: it should then be looked up again with
+    /// `ChallengeResponse::find_device_from_id`.
+    pub fn refresh(&mut self, cr: &mut ChallengeResponse) -> Result<Status, ChallengeResponseError> {
+        let mut found = None;
+        for device in cr.devices() {
+            let device = device?;
+            if device.bus_id == self.bus_id && device.address_id == self.address_id {
+                found = Some(device);
+                break;
+            }
+        }
+        let device = found.ok_or(ChallengeResponseError::DeviceNotFound)?;
+        let status = cr.read_status(&device)?;
+        *self = device;
+        Ok(status)
+    }
+}
+
+/// The identity of a device, which can be compared across enumerations.
+///
+/// The serial number is used when it can be read from the device. Otherwise, the device is identified by
+/// its USB IDs and location, which are only stable as long as the device stays plugged in.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DeviceId {
+    Serial(u32),
+    Location {
+        vendor_id: u16,
+        product_id: u16,
+        bus_id: u8,
+        address_id: u8,
+    },
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeviceId::Serial(serial) => write!(f, "serial {}", serial),
+            DeviceId::Location {
+                vendor_id,
+                product_id,
+                bus_id,
+                address_id,
+            } => write!(
+                f,
+                "{:04x}:{:04x} on bus {} address {}",
+                vendor_id, product_id, bus_id, address_id
+            ),
+        }
+    }
+}
+
+/// An interface of a device, as described by its active configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InterfaceInfo {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// The kernel driver bound to the interface, read from sysfs on Linux only.
+    pub kernel_driver: Option<String>,
+}
+
+const CLASS_HID: u8 = 0x03;
+const CLASS_SMART_CARD: u8 = 0x0b;
+const HID_PROTOCOL_KEYBOARD: u8 = 0x01;
+const HID_PROTOCOL_MOUSE: u8 = 0x02;
+
+/// What an interface is used for, from its class and protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterfaceUsage {
+    /// A HID keyboard, which types the OTPs. The challenge-responses are exchanged on this interface.
+    Keyboard,
+    Mouse,
+    /// Another HID interface, e.g. the FIDO interface.
+    Hid,
+    /// A CCID smart card interface, e.g. for the OATH and PIV applications.
+    SmartCard,
+    /// An interface of another class.
+    Other,
+}
+
+impl InterfaceInfo {
+    pub fn usage(&self) -> InterfaceUsage {
+        match (self.class, self.protocol) {
+            (CLASS_HID, HID_PROTOCOL_KEYBOARD) => InterfaceUsage::Keyboard,
+            (CLASS_HID, HID_PROTOCOL_MOUSE) => InterfaceUsage::Mouse,
+            (CLASS_HID, _) => InterfaceUsage::Hid,
+            (CLASS_SMART_CARD, _) => InterfaceUsage::SmartCard,
+            _ => InterfaceUsage::Other,
+        }
+    }
+}
+
+/// The physical location of a device: its bus, and the chain of hub ports leading to it.
+///
+/// Unlike the address of a device, which changes every time it is plugged in, the port path stays the same
+/// as long as the device is plugged in the same port. It is formatted and parsed like on Linux, e.g. `1-2.3`
+/// for the port 3 of the hub plugged in the port 2 of the bus 1.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PortPath {
+    pub bus: u8,
+    pub ports: Vec<u8>,
+}
+
+impl fmt::Display for PortPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-", self.bus)?;
+        for (i, port) in self.ports.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PortPath {
+    type Err = ChallengeResponseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bus, ports) = s.split_once('-').ok_or(ChallengeResponseError::InvalidFormat)?;
+        let bus = bus.parse().map_err(|_| ChallengeResponseError::InvalidFormat)?;
+        let ports = ports
+            .split('.')
+            .map(|p| p.parse().map_err(|_| ChallengeResponseError::InvalidFormat))
+            .collect::<Result<Vec<u8>, _>>()?;
+        Ok(PortPath { bus, ports })
+    }
+}
+
+/// How the backends handle the kernel drivers bound to the interfaces of a device, e.g. `usbhid` on Linux.
+///
+/// The default policy detaches the kernel drivers when opening the device, and reattaches them when closing
+/// it. Kernel drivers can only be detached on Linux. The strict policy never detaches kernel drivers, and
+/// fails with `ChallengeResponseError::InterfaceBusy` when one is bound to an interface of the device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DetachPolicy {
+    /// Whether the kernel drivers are detached from the interfaces when opening the device.
+    pub detach: bool,
+    /// Whether the detached kernel drivers are reattached when closing the device.
+    pub reattach: bool,
+    /// The interfaces the kernel drivers can be detached from, or all of them if `None`.
+    pub interfaces: Option<Vec<u8>>,
+    /// Whether opening the device fails when a kernel driver which can not be detached is bound to one of
+    /// its interfaces, instead of skipping the interface.
+    pub fail_if_held: bool,
+}
+
+impl Default for DetachPolicy {
+    fn default() -> Self {
+        DetachPolicy {
+            detach: true,
+            reattach: true,
+            interfaces: None,
+            fail_if_held: false,
+        }
+    }
+}
+
+impl DetachPolicy {
+    /// A policy which never detaches kernel drivers.
+    pub fn never() -> Self {
+        DetachPolicy {
+            detach: false,
+            reattach: false,
+            interfaces: None,
+            fail_if_held: false,
+        }
+    }
+
+    /// A policy which never detaches kernel drivers, and fails to open devices which have one bound.
+    pub fn strict() -> Self {
+        DetachPolicy {
+            fail_if_held: true,
+            ..DetachPolicy::never()
+        }
+    }
+
+    /// Whether the kernel driver can be detached from the given interface.
+    pub fn can_detach(&self, interface: u8) -> bool {
+        self.detach
+            && match self.interfaces {
+                Some(ref interfaces) => interfaces.contains(&interface),
+                None => true,
+            }
+    }
+}
+
+/// What is done with a device after a failed operation.
+///
+/// A failed exchange can leave the device with a partially written frame or a pending response, which
+/// makes the next operations fail. Resetting the write state of the device discards them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResetPolicy {
+    /// Resets the write state of the device after every failed operation.
+    Always,
+    /// Resets the write state of the device after the errors which can leave it desynchronized: failed
+    /// transfers, timeouts, wrong CRCs and invalid responses. The default.
+    #[default]
+    OnDesync,
+    /// Leaves the device untouched, e.g. to inspect its state after a failure. The retries of the
+    /// `RetryPolicy` still reset it.
+    Never,
+}
+
+impl ResetPolicy {
+    /// Whether the write state of the device is reset after `error`.
+    pub fn resets_after(&self, error: &ChallengeResponseError) -> bool {
+        match *self {
+            ResetPolicy::Always => true,
+            ResetPolicy::Never => false,
+            ResetPolicy::OnDesync => match *error.root_cause() {
+                ChallengeResponseError::ResponseTimeout
+                | ChallengeResponseError::WrongCRC(_)
+                | ChallengeResponseError::InvalidResponse => true,
+                _ => matches!(
+                    *error,
+                    ChallengeResponseError::WriteFrameFailed { .. }
+                        | ChallengeResponseError::ReadResponseFailed { .. }
+                ),
+            },
+        }
+    }
+}
+
+/// The error returned when an interface is held by a kernel driver which the policy forbids to detach.
+/// The driver is looked up in sysfs, on Linux only.
+#[cfg(any(feature = "rusb", feature = "nusb"))]
+pub(crate) fn interface_busy(port_path: Option<&PortPath>, interface: u8) -> ChallengeResponseError {
+    ChallengeResponseError::InterfaceBusy {
+        interface,
+        driver: port_path.and_then(|p| kernel_driver_name(p, interface)),
+    }
+}
+
+#[cfg(all(
+    any(feature = "rusb", feature = "nusb"),
+    any(target_os = "linux", target_os = "android")
+))]
+pub(crate) fn kernel_driver_name(port_path: &PortPath, interface: u8) -> Option<String> {
+    // The interfaces are named after the device, its configuration and the interface, e.g. `1-2.3:1.0`.
+    let prefix = format!("{}:", port_path);
+    let suffix = format!(".{}", interface);
+    for entry in std::fs::read_dir("/sys/bus/usb/devices").ok()? {
+        let entry = entry.ok()?;
+        let name = entry.file_name();
+        let name = name.to_str()?;
+        if name.starts_with(&prefix) && name.ends_with(&suffix) {
+            let driver = std::fs::read_link(entry.path().join("driver")).ok()?;
+            return Some(driver.file_name()?.to_str()?.to_string());
+        }
+    }
+    None
+}
+
+#[cfg(all(
+    any(feature = "rusb", feature = "nusb"),
+    not(any(target_os = "linux", target_os = "android"))
+))]
+pub(crate) fn kernel_driver_name(_port_path: &PortPath, _interface: u8) -> Option<String> {
+    None
+}
+
+/// Records a transfer of a feature report in the trace, if any. Failures to write the trace are ignored.
+#[cfg_attr(
+    not(any(
+        feature = "rusb",
+        feature = "nusb",
+        feature = "hidapi",
+        feature = "hidraw",
+        feature = "mock"
+    )),
+    allow(dead_code)
+)]
+pub(crate) fn trace_report(
+    trace: &Option<Mutex<PcapngTrace>>,
+    bus: u16,
+    device: u8,
+    request_type: u8,
+    request: u8,
+    data: &[u8],
+) {
+    let trace = match *trace {
+        Some(ref t) => t,
+        None => return,
+    };
+    let value = (REPORT_TYPE_FEATURE << 8).to_le_bytes();
+    let length = (data.len() as u16).to_le_bytes();
+    let transfer = ControlTransfer {
+        bus,
+        device,
+        setup: [
+            request_type,
+            request,
+            value[0],
+            value[1],
+            0,
+            0,
+            length[0],
+            length[1],
+        ],
+        data,
+    };
+    if let Ok(mut trace) = trace.lock() {
+        let _ = trace.record(&transfer);
+    }
+}
+
+/// Wraps an error which occurred while opening a device.
+#[cfg_attr(
+    not(any(feature = "rusb", feature = "nusb", feature = "hidapi", feature = "hidraw")),
+    allow(dead_code)
+)]
+pub(crate) fn open_failed<E: Into<ChallengeResponseError>>(error: E) -> ChallengeResponseError {
+    ChallengeResponseError::OpenFailed(Box::new(error.into()))
+}
+
+/// The maximum number of devices queried at the same time during enumeration.
+#[cfg_attr(
+    not(any(feature = "rusb", feature = "nusb", feature = "hidapi", feature = "hidraw")),
+    allow(dead_code)
+)]
+pub(crate) const MAX_PARALLEL_QUERIES: usize = 8;
+
+/// Applies `query` to all the `items` from up to `MAX_PARALLEL_QUERIES` threads, and returns the results in
+/// the order of the items.
+#[cfg_attr(
+    not(any(feature = "rusb", feature = "nusb", feature = "hidapi", feature = "hidraw")),
+    allow(dead_code)
+)]
+pub(crate) fn query_in_parallel<T, R, F>(items: &[T], query: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    if items.len() <= 1 {
+        return items.iter().map(query).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..items.len().min(MAX_PARALLEL_QUERIES))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        match items.get(i) {
+                            Some(item) => results.push((i, query(item))),
+                            None => return results,
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("a device query panicked"))
+            .collect()
+    });
+    results.sort_by_key(|&(i, _)| i);
+    results.into_iter().map(|(_, result)| result).collect()
 }
 
+/// The transport operating the devices, implemented by the USB backends.
+///
+/// Applications can implement it for their own transport, e.g. a proxy over a serial bridge or a remote USB
+/// forwarder, and use it with `ChallengeResponse::from_custom_backend`. The devices are identified by their
+/// `Device::bus_id` and `Device::address_id`, and are operated with feature reports of
+/// `protocol::REPORT_SIZE` bytes: the commands are written as the reports of a `protocol::Frame`, and the
+/// responses are read with the `protocol::Flags` of the last byte of each report. `DeviceHandle` is the
+/// handle of an open device, and `Interface` an interface claimed with it, released when it is closed.
 pub trait Backend<DeviceHandle, Interface> {
     fn new() -> Result<Self, ChallengeResponseError>
     where
         Self: Sized;
 
+    /// The name of the backend, for diagnostics.
+    fn name(&self) -> &'static str;
+
+    /// Sets which kernel drivers may be detached to claim the interfaces of the devices.
+    fn set_detach_policy(&mut self, policy: DetachPolicy);
+
+    /// Sets the trace recording the reports exchanged with the devices, or `None` to stop recording them.
+    fn set_trace(&mut self, trace: Option<PcapngTrace>);
+
+    fn set_timing(&mut self, timing: Timing);
+
+    fn timing(&self) -> &Timing;
+
+    fn set_reset_policy(&mut self, policy: ResetPolicy);
+
+    fn reset_policy(&self) -> ResetPolicy;
+
+    /// Sets the vendor and product IDs of the devices to use in addition to the supported ones, e.g. for new
+    /// models or compatible keys.
+    fn set_extra_device_ids(&mut self, ids: Vec<(u16, u16)>);
+
+    /// Opens a device, and claims the interfaces needed to operate it.
     fn open_device(
         &mut self,
         bus_id: u8,
         address_id: u8,
     ) -> Result<(DeviceHandle, Vec<Interface>), ChallengeResponseError>;
 
+    /// Releases the interfaces claimed by `open_device`, and closes the device.
     fn close_device(
         &self,
         handle: DeviceHandle,
         interfaces: Vec<Interface>,
     ) -> Result<(), ChallengeResponseError>;
 
+    /// Describes the interfaces of a device, without opening it.
+    fn read_interfaces(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<Vec<InterfaceInfo>, ChallengeResponseError>;
+
+    /// Performs a USB reset of the device. The device may be enumerated again, with a new address.
+    fn reset_device(&mut self, bus_id: u8, address_id: u8) -> Result<(), ChallengeResponseError>;
+
+    /// Reads a feature report into `buf`, of `protocol::REPORT_SIZE` bytes. Returns the number of bytes read.
     fn read(&self, handle: &mut DeviceHandle, buf: &mut [u8]) -> Result<usize, ChallengeResponseError>;
+    /// Writes a feature report, of `protocol::REPORT_SIZE` bytes.
     fn raw_write(&self, handle: &mut DeviceHandle, packet: &[u8]) -> Result<(), ChallengeResponseError>;
 
+    /// Finds the first supported device, with its serial number if it can be read.
     fn find_device(&mut self) -> Result<Device, ChallengeResponseError>;
     fn find_device_from_serial(&mut self, serial: u32) -> Result<Device, ChallengeResponseError>;
+    /// Finds all the supported devices. Fails with `ChallengeResponseError::DeviceNotFound` if there are none.
     fn find_all_devices(&mut self) -> Result<Vec<Device>, ChallengeResponseError>;
 
-    fn write_frame(&self, handle: &mut DeviceHandle, frame: &Frame) -> Result<(), ChallengeResponseError> {
-        let mut data = unsafe { slice::from_raw_parts(frame as *const Frame as *const u8, 70) };
+    /// Enumerates the supported devices lazily: each device is opened to read its serial number only when
+    /// the iterator reaches it.
+    fn enumerate_devices<'a>(
+        &'a mut self,
+    ) -> Box<dyn Iterator<Item = Result<Device, ChallengeResponseError>> + 'a>;
 
-        let mut seq = 0;
-        let mut buf = [0; 8];
-        while !data.is_empty() {
-            let (a, b) = data.split_at(7);
+    /// Blocks until a USB device is connected, or until `timeout` expires.
+    fn wait_for_connection(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError>;
 
-            if seq == 0 || b.is_empty() || a.iter().any(|&x| x != 0) {
-                let mut packet = [0; 8];
-                (&mut packet[..7]).copy_from_slice(a);
+    /// Blocks until a USB device is connected or disconnected, or until `timeout` expires.
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError>;
+}
 
-                packet[7] = Flags::SLOT_WRITE_FLAG.bits() + seq;
-                self.wait(handle, |x| !x.contains(Flags::SLOT_WRITE_FLAG), &mut buf)?;
-                self.raw_write(handle, &packet)?;
-            }
-            data = b;
-            seq += 1
+/// The `Transport` of a device opened with a backend.
+struct BackendTransport<'a, B: ?Sized + 'a, H: 'a, I> {
+    backend: &'a B,
+    handle: &'a mut H,
+    interface: PhantomData<I>,
+}
+
+impl<'a, B, H, I> Transport for BackendTransport<'a, B, H, I>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, ChallengeResponseError> {
+        self.backend.read(self.handle, report)
+    }
+
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), ChallengeResponseError> {
+        self.backend.raw_write(self.handle, report)
+    }
+}
+
+fn backend_transport<'a, B, H, I>(backend: &'a B, handle: &'a mut H) -> BackendTransport<'a, B, H, I>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    BackendTransport {
+        backend,
+        handle,
+        interface: PhantomData,
+    }
+}
+
+/// The USB devices of a `ChallengeResponse`, served by `ChallengeResponse::serve_devices`. The selected
+/// device is kept open, and locked if locking is enabled, until another one is selected.
+pub(crate) struct UsbDeviceSet<'a> {
+    cr: &'a mut ChallengeResponse,
+    devices: Vec<Device>,
+    selected: Option<(DeviceHandleType, Vec<InterfaceType>, Option<DeviceLock>)>,
+}
+
+impl<'a> UsbDeviceSet<'a> {
+    pub(crate) fn new(cr: &'a mut ChallengeResponse) -> Self {
+        UsbDeviceSet {
+            cr,
+            devices: Vec::new(),
+            selected: None,
         }
-        Ok(())
     }
 
-    fn wait<F: Fn(Flags) -> bool>(
-        &self,
-        handle: &mut DeviceHandle,
-        f: F,
-        buf: &mut [u8],
-    ) -> Result<(), ChallengeResponseError> {
-        loop {
-            self.read(handle, buf)?;
-            let flags = Flags::from_bits_truncate(buf[7]);
-            if flags.contains(Flags::SLOT_WRITE_FLAG) || flags.is_empty() {
-                // Should store the version
-            }
+    fn close(&mut self) {
+        if let Some((handle, interfaces, _lock)) = self.selected.take() {
+            let _ = self.cr.backend.close_device(handle, interfaces);
+        }
+    }
+}
 
-            if f(flags) {
-                return Ok(());
-            }
-            thread::sleep(Duration::new(0, 1000000));
+impl Drop for UsbDeviceSet<'_> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Transport for UsbDeviceSet<'_> {
+    fn read_report(&mut self, report: &mut [u8; REPORT_SIZE]) -> Result<usize, ChallengeResponseError> {
+        match self.selected {
+            Some((ref mut handle, _, _)) => self.cr.backend.read(handle, report),
+            None => Err(ChallengeResponseError::DeviceNotFound),
         }
     }
 
-    /// Reset the write state after a read.
-    fn write_reset(&self, handle: &mut DeviceHandle) -> Result<(), ChallengeResponseError> {
-        self.raw_write(handle, &WRITE_RESET_PAYLOAD)?;
-        let mut buf = [0; 8];
-        self.wait(handle, |x| !x.contains(Flags::SLOT_WRITE_FLAG), &mut buf)?;
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), ChallengeResponseError> {
+        match self.selected {
+            Some((ref mut handle, _, _)) => self.cr.backend.raw_write(handle, report),
+            None => Err(ChallengeResponseError::DeviceNotFound),
+        }
+    }
+}
+
+impl DeviceSet for UsbDeviceSet<'_> {
+    fn list(&mut self) -> Result<Vec<InventoryRecord>, ChallengeResponseError> {
+        self.close();
+        self.devices = match self.cr.find_all_devices() {
+            Ok(devices) => devices,
+            Err(ChallengeResponseError::DeviceNotFound) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let cr = &mut *self.cr;
+        Ok(self
+            .devices
+            .iter()
+            .map(|device| InventoryRecord::new(device, cr.read_status(device).ok()))
+            .collect())
+    }
+
+    fn select(&mut self, index: usize) -> Result<(), ChallengeResponseError> {
+        self.close();
+        let device = self
+            .devices
+            .get(index)
+            .ok_or(ChallengeResponseError::DeviceNotFound)?;
+        let lock = self.cr.lock(device)?;
+        let (handle, interfaces) = self.cr.backend.open_device(device.bus_id, device.address_id)?;
+        self.selected = Some((handle, interfaces, lock));
         Ok(())
     }
+}
 
-    fn read_response(
-        &self,
-        handle: &mut DeviceHandle,
-        response: &mut [u8],
-    ) -> Result<usize, ChallengeResponseError> {
-        let mut r0 = 0;
-        self.wait(
-            handle,
-            |f| f.contains(Flags::RESP_PENDING_FLAG),
-            &mut response[..8],
-        )?;
-        r0 += 7;
-        loop {
-            if self.read(handle, &mut response[r0..r0 + 8])? < 8 {
-                break;
-            }
-            let flags = Flags::from_bits_truncate(response[r0 + 7]);
-            if flags.contains(Flags::RESP_PENDING_FLAG) {
-                let seq = response[r0 + 7] & 0b00011111;
-                if r0 > 0 && seq == 0 {
-                    // If the sequence number is 0, and we have read at
-                    // least one packet, stop.
-                    break;
-                }
-            } else {
-                break;
-            }
-            r0 += 7;
+/// Runs `exchange` with a device opened with `backend`, until it is done. See `transport::run_exchange`.
+pub(crate) fn run_exchange<B, H, I>(
+    backend: &B,
+    handle: &mut H,
+    exchange: &mut Exchange,
+    on_ready: &mut dyn FnMut(&Status) -> Result<(), ChallengeResponseError>,
+    on_touch_required: &mut dyn FnMut(),
+) -> Result<(), ChallengeResponseError>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    let timing = backend.timing().clone();
+    run_exchange_with_timing(backend, handle, &timing, exchange, on_ready, on_touch_required)
+}
+
+/// Runs `exchange` like `run_exchange`, with other timeouts than the ones of the backend.
+pub(crate) fn run_exchange_with_timing<B, H, I>(
+    backend: &B,
+    handle: &mut H,
+    timing: &Timing,
+    exchange: &mut Exchange,
+    on_ready: &mut dyn FnMut(&Status) -> Result<(), ChallengeResponseError>,
+    on_touch_required: &mut dyn FnMut(),
+) -> Result<(), ChallengeResponseError>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    transport::run_exchange(
+        &mut backend_transport(backend, handle),
+        timing,
+        exchange,
+        on_ready,
+        on_touch_required,
+    )
+}
+
+/// Resets the write state of a device opened with `backend`, discarding any pending response.
+pub(crate) fn write_reset<B, H, I>(backend: &B, handle: &mut H) -> Result<(), ChallengeResponseError>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    let timing = backend.timing().clone();
+    transport::write_reset(&mut backend_transport(backend, handle), &timing)
+}
+
+/// Sends a command with a response to a device opened with `backend`, and reads the response.
+pub(crate) fn run_command<B, H, I>(
+    backend: &B,
+    handle: &mut H,
+    frame: &Frame,
+    on_ready: &mut dyn FnMut(&Status) -> Result<(), ChallengeResponseError>,
+    on_touch_required: &mut dyn FnMut(),
+) -> Result<RawResponse, ChallengeResponseError>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    let mut exchange = Exchange::command(frame);
+    run_exchange(backend, handle, &mut exchange, on_ready, on_touch_required)?;
+    Ok(exchange.into_response())
+}
+
+/// Resets the write state of a device opened with `backend` if `result` is an error after which the reset
+/// policy of the backend requires it, and returns `result`.
+pub(crate) fn reset_after<B, H, I, R>(
+    backend: &B,
+    handle: &mut H,
+    result: Result<R, ChallengeResponseError>,
+) -> Result<R, ChallengeResponseError>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    let timing = backend.timing().clone();
+    transport::reset_after(
+        &mut backend_transport(backend, handle),
+        &timing,
+        backend.reset_policy(),
+        result,
+    )
+}
+
+/// Resets the write state of the device, discarding any pending response.
+pub(crate) fn clear_device_state<B, H, I>(
+    backend: &mut B,
+    device_bus_id: u8,
+    device_address: u8,
+) -> Result<(), ChallengeResponseError>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    let (mut handle, interfaces) = backend.open_device(device_bus_id, device_address)?;
+    let timing = backend.timing().clone();
+    let result = transport::write_reset(&mut backend_transport(backend, &mut handle), &timing);
+    backend.close_device(handle, interfaces)?;
+    result
+}
+
+pub(crate) fn read_status_from_device<B, H, I>(
+    backend: &mut B,
+    device_bus_id: u8,
+    device_address: u8,
+) -> Result<Status, ChallengeResponseError>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    let report = read_report_from_device(backend, device_bus_id, device_address)?;
+    Ok(Status::from_report(&report))
+}
+
+/// Reads a single feature report from the device, without sending any command.
+pub(crate) fn read_report_from_device<B, H, I>(
+    backend: &mut B,
+    device_bus_id: u8,
+    device_address: u8,
+) -> Result<[u8; REPORT_SIZE], ChallengeResponseError>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    let (mut handle, interfaces) = backend.open_device(device_bus_id, device_address)?;
+    let report = transport::read_status_report(&mut backend_transport(backend, &mut handle));
+    backend.close_device(handle, interfaces)?;
+    report
+}
+
+pub(crate) fn read_serial_from_device<B, H, I>(
+    backend: &mut B,
+    device_bus_id: u8,
+    device_address: u8,
+) -> Result<u32, ChallengeResponseError>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    let (mut handle, interfaces) = backend.open_device(device_bus_id, device_address)?;
+    let serial = read_serial_from_handle(backend, &mut handle);
+    backend.close_device(handle, interfaces)?;
+    serial
+}
+
+/// Reads the serial number of a device already opened with `open_device`.
+pub(crate) fn read_serial_from_handle<B, H, I>(
+    backend: &B,
+    handle: &mut H,
+) -> Result<u32, ChallengeResponseError>
+where
+    B: Backend<H, I> + ?Sized,
+{
+    let timing = backend.timing().clone();
+    let serial = transport::read_serial(&mut backend_transport(backend, handle), &timing);
+    reset_after(backend, handle, serial)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn device(serial: Option<u32>, address_id: u8) -> Device {
+        Device {
+            name: None,
+            serial,
+            product_id: 0x0407,
+            vendor_id: 0x1050,
+            bus_id: 1,
+            address_id,
+            port_path: None,
+            manufacturer: None,
+            release: 0x0543,
+            interfaces: Vec::new(),
         }
-        self.write_reset(handle)?;
-        Ok(r0)
     }
 
-    fn read_serial_from_device(
-        &mut self,
-        device_bus_id: u8,
-        device_address: u8,
-    ) -> Result<u32, ChallengeResponseError> {
-        let (mut handle, interfaces) = self.open_device(device_bus_id, device_address)?;
-        let challenge = [0; CHALLENGE_SIZE];
-        let command = Command::DeviceSerial;
+    #[test]
+    fn test_device_id() {
+        // The same key, re-enumerated at a different address.
+        assert_eq!(device(Some(42), 3).id(), device(Some(42), 7).id());
+        assert_ne!(device(None, 3).id(), device(None, 7).id());
 
-        let d = Frame::new(challenge, command); // FIXME: do not need a challange
-        let mut buf = [0; STATUS_UPDATE_PAYLOAD_SIZE];
-        self.wait(&mut handle, |f| !f.contains(Flags::SLOT_WRITE_FLAG), &mut buf)?;
+        let ids: HashSet<DeviceId> = [device(Some(42), 3), device(Some(42), 7), device(None, 3)]
+            .iter()
+            .map(Device::id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+    }
 
-        self.write_frame(&mut handle, &d)?;
+    #[test]
+    fn test_descriptor_details() {
+        let mut device = device(None, 3);
+        assert_eq!(device.release_version(), (5, 4, 3));
+        device.release = 0x1205;
+        assert_eq!(device.release_version(), (12, 0, 5));
 
-        // Read the response.
-        let mut response = [0; RESPONSE_SIZE];
-        self.read_response(&mut handle, &mut response)?;
-        self.close_device(handle, interfaces)?;
+        let interface = |class, protocol| InterfaceInfo {
+            number: 0,
+            class,
+            subclass: 1,
+            protocol,
+            kernel_driver: None,
+        };
+        assert_eq!(interface(0x03, 0x01).usage(), InterfaceUsage::Keyboard);
+        assert_eq!(interface(0x03, 0x00).usage(), InterfaceUsage::Hid);
+        assert_eq!(interface(0x0b, 0x00).usage(), InterfaceUsage::SmartCard);
+        assert_eq!(interface(0xff, 0x00).usage(), InterfaceUsage::Other);
+    }
 
-        // Check response.
-        if crc16(&response[..6]) != crate::sec::CRC_RESIDUAL_OK {
-            return Err(ChallengeResponseError::WrongCRC);
-        }
+    #[test]
+    #[cfg(all(
+        feature = "vendor-yubico",
+        feature = "vendor-onlykey",
+        feature = "vendor-token2"
+    ))]
+    fn test_device_model() {
+        let model = device(None, 3).model().unwrap();
+        assert_eq!(model.name, "YubiKey 4/5 OTP+FIDO+CCID");
+        assert!(is_supported(&[], 0x1D50, 0x60FC));
+        assert!(!is_supported(&[], 0x1D50, 0x0407));
+        assert!(is_supported(&[(0x1D50, 0x0407)], 0x1D50, 0x0407));
+        assert!(device(None, 3).supports_slot(&Slot::Slot2));
+        assert!(!device(None, 3).supports_slot(&Slot::Other(3)));
+        // The YubiKey NEO with only its FIDO interface enabled has no OTP interface.
+        assert!(!is_supported(&[], 0x1050, 0x0113));
+
+        let mut token2 = device(Some(42), 3);
+        token2.vendor_id = 0x349E;
+        token2.product_id = 0x0022;
+        assert_eq!(token2.model().unwrap().name, "Token2 PIN+ OTP+FIDO");
+        assert!(token2.supports_slot(&Slot::Slot2));
+        assert!(!token2.supports_slot(&Slot::Other(3)));
+    }
+
+    #[test]
+    fn test_reset_policy() {
+        let timeout = ChallengeResponseError::ResponseTimeout.on_device(DeviceId::Serial(1), None);
+        assert!(ResetPolicy::OnDesync.resets_after(&timeout));
+        assert!(!ResetPolicy::Never.resets_after(&timeout));
+        assert!(!ResetPolicy::OnDesync.resets_after(&ChallengeResponseError::CommandNotSupported));
+        assert!(ResetPolicy::Always.resets_after(&ChallengeResponseError::CommandNotSupported));
+    }
+
+    #[test]
+    fn test_detach_policy() {
+        assert!(DetachPolicy::default().can_detach(1));
+        assert!(!DetachPolicy::never().can_detach(0));
+        assert!(!DetachPolicy::strict().can_detach(0));
 
-        let serial = structure!("2I").unpack(response[..8].to_vec())?;
+        let policy = DetachPolicy {
+            interfaces: Some(vec![0]),
+            ..DetachPolicy::default()
+        };
+        assert!(policy.can_detach(0));
+        assert!(!policy.can_detach(1));
+    }
+
+    #[test]
+    fn test_query_in_parallel() {
+        let items: Vec<u32> = (0..20).collect();
+        let results = query_in_parallel(&items, |&i| {
+            thread::sleep(Duration::from_millis((20 - i as u64) % 3));
+            i * 2
+        });
+        assert_eq!(results, (0..20).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_port_path() {
+        let path: PortPath = "1-2.3".parse().unwrap();
+        assert_eq!(
+            path,
+            PortPath {
+                bus: 1,
+                ports: vec![2, 3]
+            }
+        );
+        assert_eq!(path.to_string(), "1-2.3");
 
-        Ok(serial.0)
+        assert!("1".parse::<PortPath>().is_err());
+        assert!("1-".parse::<PortPath>().is_err());
+        assert!("1-2.x".parse::<PortPath>().is_err());
     }
 }
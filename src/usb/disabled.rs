@@ -0,0 +1,126 @@
+//! The USB backend of the builds without any of the `rusb`, `nusb`, `hidapi` and `hidraw` features, which
+//! only operate the devices of a custom backend, see `ChallengeResponse::from_custom_backend`.
+//!
+//! The backend can not be created: `ChallengeResponse::new` fails with
+//! `ChallengeResponseError::BackendUnavailable`.
+use std::time::Duration;
+
+use error::ChallengeResponseError;
+use trace::PcapngTrace;
+use usb::{Backend, DetachPolicy, Device, InterfaceInfo, ResetPolicy, Timing};
+
+/// The name of the backend in `ChallengeResponseError::BackendUnavailable`.
+const NAME: &str = "USB";
+
+pub enum DisabledBackend {}
+
+pub enum DisabledDevice {}
+
+impl Backend<DisabledDevice, ()> for DisabledBackend {
+    fn new() -> Result<Self, ChallengeResponseError> {
+        Err(ChallengeResponseError::BackendUnavailable(NAME))
+    }
+
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn set_detach_policy(&mut self, _policy: DetachPolicy) {
+        match *self {}
+    }
+
+    fn set_trace(&mut self, _trace: Option<PcapngTrace>) {
+        match *self {}
+    }
+
+    fn set_timing(&mut self, _timing: Timing) {
+        match *self {}
+    }
+
+    fn timing(&self) -> &Timing {
+        match *self {}
+    }
+
+    fn set_reset_policy(&mut self, _policy: ResetPolicy) {
+        match *self {}
+    }
+
+    fn reset_policy(&self) -> ResetPolicy {
+        match *self {}
+    }
+
+    fn set_extra_device_ids(&mut self, _ids: Vec<(u16, u16)>) {
+        match *self {}
+    }
+
+    fn open_device(
+        &mut self,
+        _bus_id: u8,
+        _address_id: u8,
+    ) -> Result<(DisabledDevice, Vec<()>), ChallengeResponseError> {
+        match *self {}
+    }
+
+    fn close_device(&self, handle: DisabledDevice, _interfaces: Vec<()>) -> Result<(), ChallengeResponseError> {
+        match handle {}
+    }
+
+    fn read_interfaces(
+        &mut self,
+        _bus_id: u8,
+        _address_id: u8,
+    ) -> Result<Vec<InterfaceInfo>, ChallengeResponseError> {
+        match *self {}
+    }
+
+    fn reset_device(&mut self, _bus_id: u8, _address_id: u8) -> Result<(), ChallengeResponseError> {
+        match *self {}
+    }
+
+    fn read(&self, handle: &mut DisabledDevice, _buf: &mut [u8]) -> Result<usize, ChallengeResponseError> {
+        match *handle {}
+    }
+
+    fn raw_write(&self, handle: &mut DisabledDevice, _packet: &[u8]) -> Result<(), ChallengeResponseError> {
+        match *handle {}
+    }
+
+    fn find_device(&mut self) -> Result<Device, ChallengeResponseError> {
+        match *self {}
+    }
+
+    fn find_device_from_serial(&mut self, _serial: u32) -> Result<Device, ChallengeResponseError> {
+        match *self {}
+    }
+
+    fn find_all_devices(&mut self) -> Result<Vec<Device>, ChallengeResponseError> {
+        match *self {}
+    }
+
+    fn enumerate_devices<'a>(
+        &'a mut self,
+    ) -> Box<dyn Iterator<Item = Result<Device, ChallengeResponseError>> + 'a> {
+        match *self {}
+    }
+
+    fn wait_for_connection(&mut self, _timeout: Duration) -> Result<(), ChallengeResponseError> {
+        match *self {}
+    }
+
+    fn wait_for_change(&mut self, _timeout: Duration) -> Result<(), ChallengeResponseError> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        assert!(matches!(
+            DisabledBackend::new(),
+            Err(ChallengeResponseError::BackendUnavailable("USB"))
+        ));
+    }
+}
@@ -0,0 +1,368 @@
+//! A backend using the HID stack of the OS through hidapi, on Linux, macOS and Windows.
+//!
+//! The devices are operated through the HID interface of their OTP application, i.e. the HID keyboard
+//! interface, with the feature reports of the OS, so no kernel driver is ever detached and the other
+//! interfaces stay available to the other applications. On Linux, the pure Rust hidraw implementation of
+//! hidapi is used, which needs access to the `/dev/hidraw*` nodes.
+//!
+//! hidapi does not expose the USB bus and address of the devices, so all the devices are on the bus 0, and
+//! get an address when they are first enumerated, which they keep until they are unplugged. The OS applies
+//! its own timeout to the feature reports, so `Timing::transfer_timeout` is not used, and the `DetachPolicy`
+//! is ignored.
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hidapi::{DeviceInfo, HidApi, HidDevice};
+
+use error::ChallengeResponseError;
+use protocol::REPORT_SIZE;
+use trace::PcapngTrace;
+use usb::{
+    is_supported, open_failed, query_in_parallel, read_serial_from_handle, trace_report, Backend, DetachPolicy,
+    Device, InterfaceInfo, ResetPolicy, Timing, HID_GET_REPORT, HID_SET_REPORT,
+};
+
+/// The usage page and usage of a keyboard, the top-level collection of the OTP interface.
+const USAGE_PAGE_GENERIC_DESKTOP: u16 = 0x01;
+const USAGE_KEYBOARD: u16 = 0x06;
+
+/// The size of the feature reports exchanged with hidapi, which are prefixed with their report number.
+const HID_REPORT_SIZE: usize = REPORT_SIZE + 1;
+
+/// How often the devices are enumerated while waiting for a device to be plugged in or unplugged.
+const HOTPLUG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The OTP interface of a device.
+#[derive(Clone, Debug, PartialEq)]
+struct Node {
+    /// The path of the interface for hidapi, e.g. `/dev/hidraw0` on Linux.
+    path: CString,
+    /// The device, without its serial number.
+    device: Device,
+}
+
+/// Assigns an address to each of the `paths` without one, and forgets the addresses of the paths which are
+/// gone. The addresses are assigned in increasing order, skipping the ones in use, so a device plugged in
+/// gets a new address like on USB.
+fn assign_addresses(addresses: &mut HashMap<CString, u8>, next_address: &mut u8, paths: &[CString]) {
+    addresses.retain(|path, _| paths.contains(path));
+    for path in paths {
+        if addresses.contains_key(path) || addresses.len() >= u8::MAX as usize {
+            continue;
+        }
+        while *next_address == 0 || addresses.values().any(|&a| a == *next_address) {
+            *next_address = next_address.wrapping_add(1);
+        }
+        addresses.insert(path.clone(), *next_address);
+        *next_address = next_address.wrapping_add(1);
+    }
+}
+
+/// Whether `info` is the OTP interface of a device.
+fn is_otp_interface(info: &DeviceInfo) -> bool {
+    info.usage_page() == USAGE_PAGE_GENERIC_DESKTOP && info.usage() == USAGE_KEYBOARD
+}
+
+/// An open OTP interface.
+pub struct HidapiDevice {
+    device: HidDevice,
+    address_id: u8,
+}
+
+pub struct HidapiBackend {
+    api: HidApi,
+    trace: Option<Mutex<PcapngTrace>>,
+    timing: Timing,
+    reset_policy: ResetPolicy,
+    extra_device_ids: Vec<(u16, u16)>,
+    /// The addresses of the devices, by path.
+    addresses: HashMap<CString, u8>,
+    next_address: u8,
+}
+
+impl HidapiBackend {
+    /// Enumerates the OTP interfaces of the supported devices, ordered by address.
+    fn nodes(&mut self) -> Result<Vec<Node>, ChallengeResponseError> {
+        self.api.refresh_devices()?;
+        let extra_device_ids = &self.extra_device_ids;
+        let infos: Vec<&DeviceInfo> = self
+            .api
+            .device_list()
+            .filter(|i| is_otp_interface(i) && is_supported(extra_device_ids, i.vendor_id(), i.product_id()))
+            .collect();
+        let paths: Vec<CString> = infos.iter().map(|i| i.path().to_owned()).collect();
+        assign_addresses(&mut self.addresses, &mut self.next_address, &paths);
+
+        let mut nodes: Vec<Node> = infos
+            .into_iter()
+            .filter_map(|info| {
+                let address_id = *self.addresses.get(info.path())?;
+                Some(Node {
+                    path: info.path().to_owned(),
+                    device: Device {
+                        name: info.product_string().map(|p| p.to_string()),
+                        serial: None,
+                        product_id: info.product_id(),
+                        vendor_id: info.vendor_id(),
+                        bus_id: 0,
+                        address_id,
+                        port_path: None,
+                        manufacturer: info.manufacturer_string().map(|m| m.to_string()),
+                        release: info.release_number(),
+                        // Only the OTP interface is known, with the protocol of a boot keyboard.
+                        interfaces: vec![InterfaceInfo {
+                            number: info.interface_number().max(0) as u8,
+                            class: 0x03,
+                            subclass: 0x01,
+                            protocol: 0x01,
+                            kernel_driver: None,
+                        }],
+                    },
+                })
+            })
+            .collect();
+        nodes.sort_by_key(|n| n.device.address_id);
+        Ok(nodes)
+    }
+
+    fn node(&mut self, bus_id: u8, address_id: u8) -> Result<Node, ChallengeResponseError> {
+        self.nodes()?
+            .into_iter()
+            .find(|n| n.device.bus_id == bus_id && n.device.address_id == address_id)
+            .ok_or(ChallengeResponseError::DeviceNotFound)
+    }
+
+    fn open(&self, node: &Node) -> Result<HidapiDevice, ChallengeResponseError> {
+        let device = self.api.open_path(&node.path).map_err(open_failed)?;
+        Ok(HidapiDevice {
+            device,
+            address_id: node.device.address_id,
+        })
+    }
+
+    /// Describes the device of a node, with its serial number if it can be read.
+    fn read_device(&self, node: &Node) -> Device {
+        let serial = self
+            .open(node)
+            .and_then(|mut handle| read_serial_from_handle(self, &mut handle))
+            .ok();
+        Device {
+            serial,
+            ..node.device.clone()
+        }
+    }
+
+    /// The addresses of the supported devices.
+    fn locations(&mut self) -> Result<Vec<u8>, ChallengeResponseError> {
+        Ok(self.nodes()?.iter().map(|n| n.device.address_id).collect())
+    }
+
+    /// Blocks until `changed` returns `true` for the addresses of the supported devices, or until `timeout`
+    /// expires.
+    fn wait_until<F>(&mut self, timeout: Duration, mut changed: F) -> Result<(), ChallengeResponseError>
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            thread::sleep(HOTPLUG_POLL_INTERVAL.min(deadline - now));
+            if changed(&self.locations()?) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Backend<HidapiDevice, ()> for HidapiBackend {
+    fn new() -> Result<Self, ChallengeResponseError> {
+        Ok(Self {
+            api: HidApi::new()?,
+            trace: None,
+            timing: Timing::default(),
+            reset_policy: ResetPolicy::default(),
+            extra_device_ids: Vec::new(),
+            addresses: HashMap::new(),
+            next_address: 1,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "hidapi"
+    }
+
+    fn set_detach_policy(&mut self, _policy: DetachPolicy) {}
+
+    fn set_trace(&mut self, trace: Option<PcapngTrace>) {
+        self.trace = trace.map(Mutex::new);
+    }
+
+    fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    fn timing(&self) -> &Timing {
+        &self.timing
+    }
+
+    fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        self.reset_policy = policy;
+    }
+
+    fn reset_policy(&self) -> ResetPolicy {
+        self.reset_policy
+    }
+
+    fn set_extra_device_ids(&mut self, ids: Vec<(u16, u16)>) {
+        self.extra_device_ids = ids;
+    }
+
+    fn open_device(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<(HidapiDevice, Vec<()>), ChallengeResponseError> {
+        let node = self.node(bus_id, address_id)?;
+        Ok((self.open(&node)?, Vec::new()))
+    }
+
+    fn close_device(&self, _handle: HidapiDevice, _interfaces: Vec<()>) -> Result<(), ChallengeResponseError> {
+        Ok(())
+    }
+
+    fn read_interfaces(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<Vec<InterfaceInfo>, ChallengeResponseError> {
+        Ok(self.node(bus_id, address_id)?.device.interfaces)
+    }
+
+    /// hidapi can not reset a device, so this always fails.
+    fn reset_device(&mut self, bus_id: u8, address_id: u8) -> Result<(), ChallengeResponseError> {
+        self.node(bus_id, address_id)?;
+        Err(io::Error::new(io::ErrorKind::Unsupported, "hidapi can not reset a device").into())
+    }
+
+    fn read(&self, handle: &mut HidapiDevice, buf: &mut [u8]) -> Result<usize, ChallengeResponseError> {
+        assert_eq!(buf.len(), REPORT_SIZE);
+        // The devices do not number their reports, so the report number is 0.
+        let mut report = [0; HID_REPORT_SIZE];
+        let read = handle
+            .device
+            .get_feature_report(&mut report)
+            .map_err(|_| ChallengeResponseError::CanNotReadFromDevice)?;
+        // The report number is returned before the report.
+        let read = read.saturating_sub(1).min(REPORT_SIZE);
+        buf[..read].copy_from_slice(&report[1..=read]);
+        trace_report(
+            &self.trace,
+            0,
+            handle.address_id,
+            0xa1,
+            HID_GET_REPORT,
+            &buf[..read],
+        );
+        Ok(read)
+    }
+
+    fn raw_write(&self, handle: &mut HidapiDevice, packet: &[u8]) -> Result<(), ChallengeResponseError> {
+        if packet.len() != REPORT_SIZE {
+            return Err(ChallengeResponseError::CanNotWriteToDevice);
+        }
+        let mut report = [0; HID_REPORT_SIZE];
+        report[1..].copy_from_slice(packet);
+        let result = handle
+            .device
+            .send_feature_report(&report)
+            .map_err(|_| ChallengeResponseError::CanNotWriteToDevice);
+        trace_report(&self.trace, 0, handle.address_id, 0x21, HID_SET_REPORT, packet);
+        result
+    }
+
+    fn find_device(&mut self) -> Result<Device, ChallengeResponseError> {
+        match self.nodes()?.first() {
+            Some(node) => Ok(self.read_device(node)),
+            None => Err(ChallengeResponseError::DeviceNotFound),
+        }
+    }
+
+    fn find_device_from_serial(&mut self, serial: u32) -> Result<Device, ChallengeResponseError> {
+        for node in self.nodes()? {
+            let device = self.read_device(&node);
+            if device.serial == Some(serial) {
+                return Ok(device);
+            }
+        }
+        Err(ChallengeResponseError::DeviceNotFound)
+    }
+
+    fn find_all_devices(&mut self) -> Result<Vec<Device>, ChallengeResponseError> {
+        let nodes = self.nodes()?;
+        if nodes.is_empty() {
+            return Err(ChallengeResponseError::DeviceNotFound);
+        }
+        // Reading the serial numbers takes a few milliseconds per device, so the devices are queried in
+        // parallel for the hosts with many devices.
+        Ok(query_in_parallel(&nodes, |node| self.read_device(node)))
+    }
+
+    fn enumerate_devices<'a>(
+        &'a mut self,
+    ) -> Box<dyn Iterator<Item = Result<Device, ChallengeResponseError>> + 'a> {
+        let nodes = match self.nodes() {
+            Ok(nodes) => nodes,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        let backend = &*self;
+        Box::new(nodes.into_iter().map(move |node| Ok(backend.read_device(&node))))
+    }
+
+    fn wait_for_connection(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        let before = self.locations()?;
+        self.wait_until(timeout, |current| current.iter().any(|l| !before.contains(l)))
+    }
+
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        let before = self.locations()?;
+        self.wait_until(timeout, |current| current != &before[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> CString {
+        CString::new(name).unwrap()
+    }
+
+    #[test]
+    fn test_assign_addresses() {
+        let mut addresses = HashMap::new();
+        let mut next_address = 1;
+        assign_addresses(&mut addresses, &mut next_address, &[path("a"), path("b")]);
+        assert_eq!((addresses[&path("a")], addresses[&path("b")]), (1, 2));
+
+        // A device plugged in again gets a new address, and the others keep theirs.
+        assign_addresses(&mut addresses, &mut next_address, &[path("b")]);
+        assign_addresses(&mut addresses, &mut next_address, &[path("a"), path("b")]);
+        assert_eq!((addresses[&path("a")], addresses[&path("b")]), (3, 2));
+
+        // The addresses wrap around, skipping 0 and the ones in use.
+        next_address = u8::MAX;
+        assign_addresses(
+            &mut addresses,
+            &mut next_address,
+            &[path("a"), path("b"), path("c"), path("d")],
+        );
+        assert_eq!((addresses[&path("c")], addresses[&path("d")]), (u8::MAX, 1));
+    }
+}
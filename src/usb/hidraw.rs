@@ -0,0 +1,471 @@
+//! A backend using the hidraw nodes of the Linux kernel, which leaves the devices bound to `usbhid`.
+//!
+//! The other backends claim the USB interfaces of a device, detaching their kernel drivers, which takes the
+//! FIDO interface away from the browsers until the device is plugged in again. This backend only opens the
+//! hidraw node of the OTP interface, i.e. the HID keyboard interface, and exchanges the feature reports
+//! through it, so the FIDO interface is never touched. The devices are found in sysfs.
+//!
+//! The kernel applies its own timeout to the transfers, so `Timing::transfer_timeout` is not used, and the
+//! `DetachPolicy` is ignored as no kernel driver is ever detached.
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use error::ChallengeResponseError;
+use libc;
+use protocol::REPORT_SIZE;
+use trace::PcapngTrace;
+use usb::{
+    is_supported, open_failed, query_in_parallel, read_serial_from_handle, trace_report, Backend, DetachPolicy,
+    Device, InterfaceInfo, InterfaceUsage, ResetPolicy, Timing, HID_GET_REPORT, HID_SET_REPORT,
+};
+
+const SYSFS_HIDRAW: &str = "/sys/class/hidraw";
+const DEV: &str = "/dev";
+
+/// The size of the feature reports exchanged with hidraw, which are prefixed with their report number.
+const HIDRAW_REPORT_SIZE: usize = REPORT_SIZE + 1;
+/// The `_IOC_READ | _IOC_WRITE` direction of the hidraw ioctls.
+const IOC_READ_WRITE: u32 = 3 << 30;
+const HIDIOCSFEATURE: u32 = 0x06;
+const HIDIOCGFEATURE: u32 = 0x07;
+/// `_IO('U', 20)`, which resets a device through its usbfs node.
+const USBDEVFS_RESET: u32 = 0x5514;
+
+/// How often the hidraw nodes are listed while waiting for a device to be plugged in or unplugged.
+const HOTPLUG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The hidraw node of the OTP interface of a device.
+#[derive(Clone, Debug, PartialEq)]
+struct Node {
+    /// The device file, e.g. `/dev/hidraw0`.
+    path: PathBuf,
+    /// The device, without its serial number.
+    device: Device,
+}
+
+fn read_attribute(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+fn read_hex(dir: &Path, name: &str) -> Option<u16> {
+    u16::from_str_radix(&read_attribute(dir, name)?, 16).ok()
+}
+
+fn read_interface(dir: &Path) -> Option<InterfaceInfo> {
+    let driver = fs::read_link(dir.join("driver")).ok();
+    Some(InterfaceInfo {
+        number: read_hex(dir, "bInterfaceNumber")? as u8,
+        class: read_hex(dir, "bInterfaceClass")? as u8,
+        subclass: read_hex(dir, "bInterfaceSubClass")? as u8,
+        protocol: read_hex(dir, "bInterfaceProtocol")? as u8,
+        kernel_driver: driver.and_then(|d| Some(d.file_name()?.to_str()?.to_string())),
+    })
+}
+
+/// The interfaces of the USB device of the sysfs directory `dir`. The directories of the interfaces are
+/// named after the device, its configuration and the interface, e.g. `1-2.3:1.0`.
+fn read_interfaces(dir: &Path) -> Vec<InterfaceInfo> {
+    let prefix = match dir.file_name().and_then(|n| n.to_str()) {
+        Some(name) => format!("{}:", name),
+        None => return Vec::new(),
+    };
+    let mut interfaces: Vec<InterfaceInfo> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| e.file_name().to_str().is_some_and(|n| n.starts_with(&prefix)))
+        .filter_map(|e| read_interface(&e.path()))
+        .collect();
+    interfaces.sort_by_key(|i| i.number);
+    interfaces
+}
+
+/// Reads the node `path`, whose directory in the sysfs class of the hidraw nodes is `class_entry`. Returns
+/// `None` if the node is not the OTP interface of a USB device.
+fn read_node(class_entry: &Path, path: PathBuf) -> Option<Node> {
+    // The HID device of the node is a child of the USB interface, itself a child of the USB device.
+    let hid = fs::canonicalize(class_entry.join("device")).ok()?;
+    let interface_dir = hid.parent()?;
+    let usb_dir = interface_dir.parent()?;
+    if read_interface(interface_dir)?.usage() != InterfaceUsage::Keyboard {
+        return None;
+    }
+    Some(Node {
+        path,
+        device: Device {
+            name: read_attribute(usb_dir, "product"),
+            serial: None,
+            product_id: read_hex(usb_dir, "idProduct")?,
+            vendor_id: read_hex(usb_dir, "idVendor")?,
+            bus_id: read_attribute(usb_dir, "busnum")?.parse().ok()?,
+            address_id: read_attribute(usb_dir, "devnum")?.parse().ok()?,
+            port_path: usb_dir.file_name()?.to_str()?.parse().ok(),
+            manufacturer: read_attribute(usb_dir, "manufacturer"),
+            release: read_hex(usb_dir, "bcdDevice")?,
+            interfaces: read_interfaces(usb_dir),
+        },
+    })
+}
+
+/// Lists the hidraw nodes of the OTP interfaces, from the sysfs class directory `class_dir`, with their
+/// device files in `dev_dir`, ordered by bus and address.
+fn list_nodes(class_dir: &Path, dev_dir: &Path) -> Result<Vec<Node>, ChallengeResponseError> {
+    let entries = match fs::read_dir(class_dir) {
+        Ok(entries) => entries,
+        // The hidraw module is not loaded.
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut nodes = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if let Some(node) = read_node(&entry.path(), dev_dir.join(entry.file_name())) {
+            nodes.push(node);
+        }
+    }
+    nodes.sort_by_key(|n| (n.device.bus_id, n.device.address_id));
+    Ok(nodes)
+}
+
+/// Gets or sets a feature report with the ioctl `nr` of hidraw. `buf` starts with the report number,
+/// which is 0 for the devices without numbered reports. Returns the number of bytes transferred.
+fn feature_ioctl(file: &File, nr: u32, buf: &mut [u8; HIDRAW_REPORT_SIZE]) -> io::Result<usize> {
+    let request = IOC_READ_WRITE | (HIDRAW_REPORT_SIZE as u32) << 16 | (b'H' as u32) << 8 | nr;
+    // The ioctls of hidraw are the only way to exchange the feature reports. The kernel transfers at most
+    // the size encoded in the request, which is the size of `buf`.
+    #[allow(unsafe_code)]
+    let transferred = unsafe { libc::ioctl(file.as_raw_fd(), request as _, buf.as_mut_ptr()) };
+    if transferred < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(transferred as usize)
+}
+
+/// An open hidraw node.
+pub struct HidrawDevice {
+    file: File,
+    bus_id: u8,
+    address_id: u8,
+}
+
+pub struct HidrawBackend {
+    trace: Option<Mutex<PcapngTrace>>,
+    timing: Timing,
+    reset_policy: ResetPolicy,
+    extra_device_ids: Vec<(u16, u16)>,
+}
+
+impl HidrawBackend {
+    /// The nodes of the supported devices.
+    fn nodes(&self) -> Result<Vec<Node>, ChallengeResponseError> {
+        Ok(list_nodes(Path::new(SYSFS_HIDRAW), Path::new(DEV))?
+            .into_iter()
+            .filter(|n| is_supported(&self.extra_device_ids, n.device.vendor_id, n.device.product_id))
+            .collect())
+    }
+
+    fn node(&self, bus_id: u8, address_id: u8) -> Result<Node, ChallengeResponseError> {
+        self.nodes()?
+            .into_iter()
+            .find(|n| n.device.bus_id == bus_id && n.device.address_id == address_id)
+            .ok_or(ChallengeResponseError::DeviceNotFound)
+    }
+
+    fn open(&self, node: &Node) -> Result<HidrawDevice, ChallengeResponseError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&node.path)
+            .map_err(open_failed)?;
+        Ok(HidrawDevice {
+            file,
+            bus_id: node.device.bus_id,
+            address_id: node.device.address_id,
+        })
+    }
+
+    /// Describes the device of a node, with its serial number if it can be read.
+    fn read_device(&self, node: &Node) -> Device {
+        let serial = self
+            .open(node)
+            .and_then(|mut handle| read_serial_from_handle(self, &mut handle))
+            .ok();
+        Device {
+            serial,
+            ..node.device.clone()
+        }
+    }
+
+    /// Blocks until `changed` returns `true` for the locations of the supported devices, or until `timeout`
+    /// expires.
+    fn wait_until<F>(&self, timeout: Duration, mut changed: F) -> Result<(), ChallengeResponseError>
+    where
+        F: FnMut(&[(u8, u8)]) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            thread::sleep(HOTPLUG_POLL_INTERVAL.min(deadline - now));
+            if changed(&self.locations()?) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// The bus and address of the supported devices.
+    fn locations(&self) -> Result<Vec<(u8, u8)>, ChallengeResponseError> {
+        Ok(self
+            .nodes()?
+            .iter()
+            .map(|n| (n.device.bus_id, n.device.address_id))
+            .collect())
+    }
+}
+
+impl Backend<HidrawDevice, ()> for HidrawBackend {
+    fn new() -> Result<Self, ChallengeResponseError> {
+        Ok(Self {
+            trace: None,
+            timing: Timing::default(),
+            reset_policy: ResetPolicy::default(),
+            extra_device_ids: Vec::new(),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "hidraw"
+    }
+
+    fn set_detach_policy(&mut self, _policy: DetachPolicy) {}
+
+    fn set_trace(&mut self, trace: Option<PcapngTrace>) {
+        self.trace = trace.map(Mutex::new);
+    }
+
+    fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    fn timing(&self) -> &Timing {
+        &self.timing
+    }
+
+    fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        self.reset_policy = policy;
+    }
+
+    fn reset_policy(&self) -> ResetPolicy {
+        self.reset_policy
+    }
+
+    fn set_extra_device_ids(&mut self, ids: Vec<(u16, u16)>) {
+        self.extra_device_ids = ids;
+    }
+
+    fn open_device(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<(HidrawDevice, Vec<()>), ChallengeResponseError> {
+        let node = self.node(bus_id, address_id)?;
+        Ok((self.open(&node)?, Vec::new()))
+    }
+
+    fn close_device(&self, _handle: HidrawDevice, _interfaces: Vec<()>) -> Result<(), ChallengeResponseError> {
+        Ok(())
+    }
+
+    fn read_interfaces(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<Vec<InterfaceInfo>, ChallengeResponseError> {
+        Ok(self.node(bus_id, address_id)?.device.interfaces)
+    }
+
+    /// Resets the device through its usbfs node, as hidraw can not reset a device. The FIDO interface is
+    /// reset too, and is bound to `usbhid` again once the device is enumerated again.
+    fn reset_device(&mut self, bus_id: u8, address_id: u8) -> Result<(), ChallengeResponseError> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(format!("/dev/bus/usb/{:03}/{:03}", bus_id, address_id))
+            .map_err(open_failed)?;
+        #[allow(unsafe_code)]
+        let result = unsafe { libc::ioctl(file.as_raw_fd(), USBDEVFS_RESET as _) };
+        if result < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn read(&self, handle: &mut HidrawDevice, buf: &mut [u8]) -> Result<usize, ChallengeResponseError> {
+        assert_eq!(buf.len(), REPORT_SIZE);
+        let mut report = [0; HIDRAW_REPORT_SIZE];
+        let read = feature_ioctl(&handle.file, HIDIOCGFEATURE, &mut report)
+            .map_err(|_| ChallengeResponseError::CanNotReadFromDevice)?;
+        // The report number is returned before the report.
+        let read = read.saturating_sub(1).min(REPORT_SIZE);
+        buf[..read].copy_from_slice(&report[1..=read]);
+        trace_report(
+            &self.trace,
+            handle.bus_id as u16,
+            handle.address_id,
+            0xa1,
+            HID_GET_REPORT,
+            &buf[..read],
+        );
+        Ok(read)
+    }
+
+    fn raw_write(&self, handle: &mut HidrawDevice, packet: &[u8]) -> Result<(), ChallengeResponseError> {
+        if packet.len() != REPORT_SIZE {
+            return Err(ChallengeResponseError::CanNotWriteToDevice);
+        }
+        let mut report = [0; HIDRAW_REPORT_SIZE];
+        report[1..].copy_from_slice(packet);
+        let written = feature_ioctl(&handle.file, HIDIOCSFEATURE, &mut report)
+            .map_err(|_| ChallengeResponseError::CanNotWriteToDevice)?;
+        trace_report(
+            &self.trace,
+            handle.bus_id as u16,
+            handle.address_id,
+            0x21,
+            HID_SET_REPORT,
+            packet,
+        );
+        if written != HIDRAW_REPORT_SIZE {
+            Err(ChallengeResponseError::CanNotWriteToDevice)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn find_device(&mut self) -> Result<Device, ChallengeResponseError> {
+        match self.nodes()?.first() {
+            Some(node) => Ok(self.read_device(node)),
+            None => Err(ChallengeResponseError::DeviceNotFound),
+        }
+    }
+
+    fn find_device_from_serial(&mut self, serial: u32) -> Result<Device, ChallengeResponseError> {
+        for node in self.nodes()? {
+            let device = self.read_device(&node);
+            if device.serial == Some(serial) {
+                return Ok(device);
+            }
+        }
+        Err(ChallengeResponseError::DeviceNotFound)
+    }
+
+    fn find_all_devices(&mut self) -> Result<Vec<Device>, ChallengeResponseError> {
+        let nodes = self.nodes()?;
+        if nodes.is_empty() {
+            return Err(ChallengeResponseError::DeviceNotFound);
+        }
+        // Reading the serial numbers takes a few milliseconds per device, so the devices are queried in
+        // parallel for the hosts with many devices.
+        Ok(query_in_parallel(&nodes, |node| self.read_device(node)))
+    }
+
+    fn enumerate_devices<'a>(
+        &'a mut self,
+    ) -> Box<dyn Iterator<Item = Result<Device, ChallengeResponseError>> + 'a> {
+        let nodes = match self.nodes() {
+            Ok(nodes) => nodes,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        let backend = &*self;
+        Box::new(nodes.into_iter().map(move |node| Ok(backend.read_device(&node))))
+    }
+
+    fn wait_for_connection(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        let before = self.locations()?;
+        self.wait_until(timeout, |current| current.iter().any(|l| !before.contains(l)))
+    }
+
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        let before = self.locations()?;
+        self.wait_until(timeout, |current| current != &before[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use usb::PortPath;
+
+    fn write_attributes(dir: &Path, attributes: &[(&str, &str)]) {
+        fs::create_dir_all(dir).unwrap();
+        for (name, value) in attributes {
+            fs::write(dir.join(name), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    /// Adds the interface `number` of the USB device `usb_dir` to a fake sysfs, with its hidraw node.
+    fn add_interface(usb_dir: &Path, class_dir: &Path, number: u8, protocol: u8) {
+        let interface_dir = usb_dir.join(format!("1-2:1.{}", number));
+        write_attributes(
+            &interface_dir,
+            &[
+                ("bInterfaceNumber", &format!("{:02x}", number)),
+                ("bInterfaceClass", "03"),
+                ("bInterfaceSubClass", "00"),
+                ("bInterfaceProtocol", &format!("{:02x}", protocol)),
+            ],
+        );
+        let hid_dir = interface_dir.join(format!("0003:1050:0407.000{}", number + 1));
+        fs::create_dir_all(&hid_dir).unwrap();
+        let node_dir = class_dir.join(format!("hidraw{}", number));
+        fs::create_dir_all(&node_dir).unwrap();
+        symlink(&hid_dir, node_dir.join("device")).unwrap();
+    }
+
+    #[test]
+    fn test_list_nodes() {
+        let root = std::env::temp_dir().join(format!("challenge-response-hidraw-{}", std::process::id()));
+        let usb_dir = root.join("devices").join("1-2");
+        let class_dir = root.join("class");
+        assert!(list_nodes(&class_dir, Path::new(DEV)).unwrap().is_empty());
+
+        write_attributes(
+            &usb_dir,
+            &[
+                ("idVendor", "1050"),
+                ("idProduct", "0407"),
+                ("bcdDevice", "0543"),
+                ("busnum", "1"),
+                ("devnum", "12"),
+                ("product", "YubiKey OTP+FIDO+CCID"),
+                ("manufacturer", "Yubico"),
+            ],
+        );
+        // The OTP interface, and the FIDO interface which must not be used.
+        add_interface(&usb_dir, &class_dir, 0, 1);
+        add_interface(&usb_dir, &class_dir, 1, 0);
+
+        let nodes = list_nodes(&class_dir, Path::new(DEV)).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].path, Path::new("/dev/hidraw0"));
+        let device = &nodes[0].device;
+        assert_eq!((device.vendor_id, device.product_id), (0x1050, 0x0407));
+        assert_eq!((device.bus_id, device.address_id), (1, 12));
+        assert_eq!(device.release, 0x0543);
+        assert_eq!(device.name.as_deref(), Some("YubiKey OTP+FIDO+CCID"));
+        assert_eq!(device.manufacturer.as_deref(), Some("Yubico"));
+        assert_eq!("1-2".parse::<PortPath>().ok(), device.port_path);
+        let usages: Vec<_> = device.interfaces.iter().map(|i| i.usage()).collect();
+        assert_eq!(usages, vec![InterfaceUsage::Keyboard, InterfaceUsage::Hid]);
+    }
+}
@@ -1,14 +1,207 @@
-use nusb::{Device as NUSBDevice, Interface};
+use futures_core::Stream;
+use nusb::hotplug::HotplugEvent;
+use nusb::{Device as NUSBDevice, DeviceInfo, Interface};
 
 use error::ChallengeResponseError;
-use std::time::Duration;
-use usb::{Backend, Device, HID_GET_REPORT, HID_SET_REPORT, PRODUCT_ID, REPORT_TYPE_FEATURE, VENDOR_ID};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+use trace::PcapngTrace;
+use usb::{
+    interface_busy, is_supported, kernel_driver_name, open_failed, query_in_parallel, read_serial_from_device,
+    read_serial_from_handle, trace_report, Backend, DetachPolicy, Device, InterfaceInfo, PortPath, ResetPolicy,
+    Timing, HID_GET_REPORT, HID_SET_REPORT, REPORT_TYPE_FEATURE,
+};
 
-pub struct NUSBBackend {}
+/// The sysfs directory of a device is named after its port path, e.g. `1-2.3`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn port_path(device_info: &DeviceInfo) -> Option<PortPath> {
+    device_info.sysfs_path().file_name()?.to_str()?.parse().ok()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn port_path(_device_info: &DeviceInfo) -> Option<PortPath> {
+    None
+}
+
+/// Wakes up a thread parked while polling a future.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+pub struct NUSBBackend {
+    detach_policy: DetachPolicy,
+    trace: Option<Mutex<PcapngTrace>>,
+    timing: Timing,
+    reset_policy: ResetPolicy,
+    extra_device_ids: Vec<(u16, u16)>,
+    /// The devices opened by the application, by bus and address.
+    adopted: HashMap<(u8, u8), (DeviceInfo, NUSBDevice)>,
+}
+
+impl Device {
+    /// Describes a device enumerated by the application with nusb. The serial number is not read, as it
+    /// requires opening the device.
+    pub fn from_nusb(device_info: &DeviceInfo) -> Device {
+        Device {
+            name: match device_info.product_string() {
+                Some(name) => Some(name.to_string()),
+                None => Some("unknown".to_string()),
+            },
+            serial: None,
+            product_id: device_info.product_id(),
+            vendor_id: device_info.vendor_id(),
+            bus_id: device_info.bus_number(),
+            address_id: device_info.device_address(),
+            port_path: port_path(device_info),
+            manufacturer: device_info.manufacturer_string().map(|m| m.to_string()),
+            release: device_info.device_version(),
+            interfaces: interfaces(device_info),
+        }
+    }
+}
+
+/// The USB devices of the host. Hosts without a USB subsystem, e.g. containers without `/sys/bus/usb`, have
+/// no devices, instead of failing the enumeration with `ChallengeResponseError::IOError`.
+fn list_devices() -> Result<Vec<DeviceInfo>, ChallengeResponseError> {
+    match nusb::list_devices() {
+        Ok(devices) => Ok(devices.collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The interfaces of a device, with their kernel drivers.
+fn interfaces(device_info: &DeviceInfo) -> Vec<InterfaceInfo> {
+    let port_path = port_path(device_info);
+    device_info
+        .interfaces()
+        .map(|interface| InterfaceInfo {
+            number: interface.interface_number(),
+            class: interface.class(),
+            subclass: interface.subclass(),
+            protocol: interface.protocol(),
+            kernel_driver: port_path
+                .as_ref()
+                .and_then(|p| kernel_driver_name(p, interface.interface_number())),
+        })
+        .collect()
+}
+
+impl NUSBBackend {
+    /// Operates a device already opened by the application, instead of enumerating and opening it again.
+    /// Returns the description of the device, with its serial number if it can be read.
+    pub fn adopt_device(
+        &mut self,
+        device_info: DeviceInfo,
+        device: NUSBDevice,
+    ) -> Result<Device, ChallengeResponseError> {
+        let mut description = Device::from_nusb(&device_info);
+        self.adopted.insert(
+            (device_info.bus_number(), device_info.device_address()),
+            (device_info.clone(), device),
+        );
+        description.serial = self.read_serial(&device_info).ok();
+        Ok(description)
+    }
+
+    /// Opens a device, unless it was adopted, and claims its interfaces, detaching the kernel drivers
+    /// allowed by the detach policy.
+    fn claim_device(
+        &self,
+        device_info: &DeviceInfo,
+    ) -> Result<(NUSBDevice, Vec<Interface>), ChallengeResponseError> {
+        let key = (device_info.bus_number(), device_info.device_address());
+        let device = match self.adopted.get(&key) {
+            Some((_, device)) => device.clone(),
+            None => device_info.open().map_err(open_failed)?,
+        };
+
+        let mut interfaces: Vec<Interface> = Vec::new();
+        for interface in device_info.interfaces() {
+            let number = interface.interface_number();
+            let claimed = if !self.detach_policy.can_detach(number) {
+                device.claim_interface(number)
+            } else if self.detach_policy.reattach {
+                // The kernel driver is reattached when the interface is dropped.
+                device.detach_and_claim_interface(number)
+            } else {
+                device
+                    .detach_kernel_driver(number)
+                    .and_then(|_| device.claim_interface(number))
+            };
+            let interface = match claimed {
+                Ok(interface) => interface,
+                Err(_) if self.detach_policy.fail_if_held && !self.detach_policy.can_detach(number) => {
+                    return Err(interface_busy(port_path(device_info).as_ref(), number));
+                }
+                Err(_) => continue,
+            };
+
+            interfaces.push(interface);
+        }
+        Ok((device, interfaces))
+    }
+
+    /// Reads the serial number of a device, without looking it up again.
+    fn read_serial(&self, device_info: &DeviceInfo) -> Result<u32, ChallengeResponseError> {
+        let (mut handle, interfaces) = self.claim_device(device_info)?;
+        let serial = read_serial_from_handle(self, &mut handle);
+        self.close_device(handle, interfaces)?;
+        serial
+    }
+}
 
 impl Backend<NUSBDevice, Interface> for NUSBBackend {
     fn new() -> Result<Self, ChallengeResponseError> {
-        Ok(Self {})
+        Ok(Self {
+            detach_policy: DetachPolicy::default(),
+            trace: None,
+            timing: Timing::default(),
+            reset_policy: ResetPolicy::default(),
+            extra_device_ids: Vec::new(),
+            adopted: HashMap::new(),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "nusb"
+    }
+
+    fn set_detach_policy(&mut self, policy: DetachPolicy) {
+        self.detach_policy = policy;
+    }
+
+    fn set_trace(&mut self, trace: Option<PcapngTrace>) {
+        self.trace = trace.map(Mutex::new);
+    }
+
+    fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    fn timing(&self) -> &Timing {
+        &self.timing
+    }
+
+    fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        self.reset_policy = policy;
+    }
+
+    fn reset_policy(&self) -> ResetPolicy {
+        self.reset_policy
+    }
+
+    fn set_extra_device_ids(&mut self, ids: Vec<(u16, u16)>) {
+        self.extra_device_ids = ids;
     }
 
     fn open_device(
@@ -16,32 +209,44 @@ impl Backend<NUSBDevice, Interface> for NUSBBackend {
         bus_id: u8,
         address_id: u8,
     ) -> Result<(NUSBDevice, Vec<Interface>), ChallengeResponseError> {
-        let nusb_devices = match nusb::list_devices() {
-            Ok(d) => d,
-            Err(e) => return Err(e.into()),
-        };
+        if let Some((device_info, _)) = self.adopted.get(&(bus_id, address_id)) {
+            return self.claim_device(device_info);
+        }
+
+        let nusb_devices = list_devices()?;
         for device_info in nusb_devices {
             if device_info.bus_number() != bus_id || device_info.device_address() != address_id {
                 continue;
             }
 
-            let device = match device_info.open() {
-                Ok(d) => d,
-                Err(_) => {
-                    return Err(ChallengeResponseError::OpenDeviceError);
-                }
-            };
+            return self.claim_device(&device_info);
+        }
 
-            let mut interfaces: Vec<Interface> = Vec::new();
-            for interface in device_info.interfaces() {
-                let interface = match device.detach_and_claim_interface(interface.interface_number()) {
-                    Ok(interface) => interface,
-                    Err(_) => continue,
-                };
+        Err(ChallengeResponseError::DeviceNotFound)
+    }
+
+    fn read_interfaces(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<Vec<InterfaceInfo>, ChallengeResponseError> {
+        for device_info in list_devices()? {
+            if device_info.bus_number() != bus_id || device_info.device_address() != address_id {
+                continue;
+            }
+            return Ok(interfaces(&device_info));
+        }
 
-                interfaces.push(interface);
+        Err(ChallengeResponseError::DeviceNotFound)
+    }
+
+    fn reset_device(&mut self, bus_id: u8, address_id: u8) -> Result<(), ChallengeResponseError> {
+        for device_info in list_devices()? {
+            if device_info.bus_number() != bus_id || device_info.device_address() != address_id {
+                continue;
             }
-            return Ok((device, interfaces));
+            let device = device_info.open().map_err(open_failed)?;
+            return device.reset().map_err(|e| e.into());
         }
 
         Err(ChallengeResponseError::DeviceNotFound)
@@ -67,8 +272,12 @@ impl Backend<NUSBDevice, Interface> for NUSBBackend {
             index: 0,
         };
 
-        match handle.control_in_blocking(control_in, buf, Duration::new(2, 0)) {
-            Ok(r) => Ok(r),
+        match handle.control_in_blocking(control_in, buf, self.timing.transfer_timeout) {
+            Ok(r) => {
+                // nusb does not expose the location of an open device.
+                trace_report(&self.trace, 0, 0, 0xa1, HID_GET_REPORT, &buf[..r]);
+                Ok(r)
+            }
             Err(_e) => Err(ChallengeResponseError::CanNotReadFromDevice),
         }
     }
@@ -83,8 +292,9 @@ impl Backend<NUSBDevice, Interface> for NUSBBackend {
             index: 0,
         };
 
-        match handle.control_out_blocking(control_out, packet, Duration::new(2, 0)) {
+        match handle.control_out_blocking(control_out, packet, self.timing.transfer_timeout) {
             Ok(bytes_written) => {
+                trace_report(&self.trace, 0, 0, 0x21, HID_SET_REPORT, &packet[..bytes_written]);
                 if bytes_written != 8 {
                     Err(ChallengeResponseError::CanNotWriteToDevice)
                 } else {
@@ -109,32 +319,25 @@ impl Backend<NUSBDevice, Interface> for NUSBBackend {
     }
 
     fn find_device_from_serial(&mut self, serial: u32) -> Result<Device, ChallengeResponseError> {
-        let nusb_devices = nusb::list_devices()?;
+        let nusb_devices = list_devices()?;
         for device_info in nusb_devices {
             let product_id = device_info.product_id();
             let vendor_id = device_info.vendor_id();
 
-            if !VENDOR_ID.contains(&vendor_id) || !PRODUCT_ID.contains(&product_id) {
+            if !is_supported(&self.extra_device_ids, vendor_id, product_id) {
                 continue;
             }
 
             let device_serial =
-                match self.read_serial_from_device(device_info.bus_number(), device_info.device_address()) {
+                match read_serial_from_device(self, device_info.bus_number(), device_info.device_address()) {
                     Ok(s) => s,
                     Err(_) => continue,
                 };
 
             if device_serial == serial {
                 return Ok(Device {
-                    name: match device_info.manufacturer_string() {
-                        Some(name) => Some(name.to_string()),
-                        None => Some("unknown".to_string()),
-                    },
                     serial: Some(serial),
-                    product_id,
-                    vendor_id,
-                    bus_id: device_info.bus_number(),
-                    address_id: device_info.device_address(),
+                    ..Device::from_nusb(&device_info)
                 });
             }
         }
@@ -142,32 +345,76 @@ impl Backend<NUSBDevice, Interface> for NUSBBackend {
     }
 
     fn find_all_devices(&mut self) -> Result<Vec<Device>, ChallengeResponseError> {
-        let mut devices: Vec<Device> = Vec::new();
-        let nusb_devices = nusb::list_devices()?;
-        for device_info in nusb_devices {
-            let product_id = device_info.product_id();
-            let vendor_id = device_info.vendor_id();
+        let candidates: Vec<DeviceInfo> = list_devices()?
+            .into_iter()
+            .filter(|d| is_supported(&self.extra_device_ids, d.vendor_id(), d.product_id()))
+            .collect();
 
-            if !VENDOR_ID.contains(&vendor_id) || !PRODUCT_ID.contains(&product_id) {
-                continue;
-            }
+        // Reading the serial numbers takes a few milliseconds per device, so the devices are queried in
+        // parallel for the hosts with many devices.
+        let serials = query_in_parallel(&candidates, |device_info| self.read_serial(device_info).ok());
 
-            let device_serial = self
-                .read_serial_from_device(device_info.bus_number(), device_info.device_address())
-                .ok();
-
-            devices.push(Device {
-                name: match device_info.manufacturer_string() {
-                    Some(name) => Some(name.to_string()),
-                    None => Some("unknown".to_string()),
-                },
-                serial: device_serial,
-                product_id,
-                vendor_id,
-                bus_id: device_info.bus_number(),
-                address_id: device_info.device_address(),
-            });
+        Ok(candidates
+            .iter()
+            .zip(serials)
+            .map(|(device_info, serial)| Device {
+                serial,
+                ..Device::from_nusb(device_info)
+            })
+            .collect())
+    }
+
+    fn enumerate_devices<'a>(
+        &'a mut self,
+    ) -> Box<dyn Iterator<Item = Result<Device, ChallengeResponseError>> + 'a> {
+        let nusb_devices = match list_devices() {
+            Ok(devices) => devices,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        let backend = &*self;
+        Box::new(
+            nusb_devices
+                .into_iter()
+                .filter(move |d| is_supported(&backend.extra_device_ids, d.vendor_id(), d.product_id()))
+                .map(move |device_info| {
+                    Ok(Device {
+                        serial: backend.read_serial(&device_info).ok(),
+                        ..Device::from_nusb(&device_info)
+                    })
+                }),
+        )
+    }
+
+    fn wait_for_connection(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        wait_for_event(timeout, |event| matches!(event, HotplugEvent::Connected(_)))
+    }
+
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        wait_for_event(timeout, |_| true)
+    }
+}
+
+/// Blocks until a hotplug event for which `matches` returns `true` is received, or until `timeout` expires.
+fn wait_for_event<F>(timeout: Duration, matches: F) -> Result<(), ChallengeResponseError>
+where
+    F: Fn(&HotplugEvent) -> bool,
+{
+    let mut watch = nusb::watch_devices()?;
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match Pin::new(&mut watch).poll_next(&mut context) {
+            Poll::Ready(Some(ref event)) if matches(event) => return Ok(()),
+            Poll::Ready(Some(_)) => continue,
+            Poll::Ready(None) => return Ok(()),
+            Poll::Pending => {}
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(());
         }
-        Ok(devices)
+        thread::park_timeout(deadline - now);
     }
 }
@@ -0,0 +1,379 @@
+//! The backend of a `ChallengeResponse`: the USB backend, or a backend implemented by the application, see
+//! `ChallengeResponse::from_custom_backend`.
+use std::any::Any;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use error::ChallengeResponseError;
+use trace::PcapngTrace;
+use usb::{
+    Backend, DetachPolicy, Device, InterfaceInfo, InterfaceType, ResetPolicy, Timing, UsbBackendType,
+    UsbDeviceHandleType,
+};
+
+/// The handle of a device opened by a custom backend, with its type erased.
+pub type CustomHandle = Box<dyn Any>;
+
+// A single backend is created per `ChallengeResponse`, so its size does not matter.
+#[allow(clippy::large_enum_variant)]
+pub enum PluggableBackend {
+    Usb(UsbBackendType),
+    Custom(Box<dyn Backend<CustomHandle, ()>>),
+}
+
+pub enum PluggableHandle {
+    Usb(UsbDeviceHandleType),
+    Custom(CustomHandle),
+}
+
+/// The error of a handle used with a backend which did not open it.
+fn foreign_handle() -> ChallengeResponseError {
+    ChallengeResponseError::DeviceNotFound
+}
+
+/// A custom backend with the types of its handles and interfaces erased. Its handles hold the interfaces
+/// claimed with them, to close them together.
+struct Erased<B, H, I> {
+    backend: B,
+    types: PhantomData<fn() -> (H, I)>,
+}
+
+impl<B, H, I> Erased<B, H, I>
+where
+    H: 'static,
+    I: 'static,
+{
+    fn handle(handle: &mut CustomHandle) -> Result<&mut H, ChallengeResponseError> {
+        (**handle)
+            .downcast_mut::<(H, Vec<I>)>()
+            .map(|(handle, _)| handle)
+            .ok_or_else(foreign_handle)
+    }
+}
+
+impl<B, H, I> Backend<CustomHandle, ()> for Erased<B, H, I>
+where
+    B: Backend<H, I>,
+    H: 'static,
+    I: 'static,
+{
+    fn new() -> Result<Self, ChallengeResponseError> {
+        Ok(Erased {
+            backend: B::new()?,
+            types: PhantomData,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    fn set_detach_policy(&mut self, policy: DetachPolicy) {
+        self.backend.set_detach_policy(policy)
+    }
+
+    fn set_trace(&mut self, trace: Option<PcapngTrace>) {
+        self.backend.set_trace(trace)
+    }
+
+    fn set_timing(&mut self, timing: Timing) {
+        self.backend.set_timing(timing)
+    }
+
+    fn timing(&self) -> &Timing {
+        self.backend.timing()
+    }
+
+    fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        self.backend.set_reset_policy(policy)
+    }
+
+    fn reset_policy(&self) -> ResetPolicy {
+        self.backend.reset_policy()
+    }
+
+    fn set_extra_device_ids(&mut self, ids: Vec<(u16, u16)>) {
+        self.backend.set_extra_device_ids(ids)
+    }
+
+    fn open_device(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<(CustomHandle, Vec<()>), ChallengeResponseError> {
+        let opened: (H, Vec<I>) = self.backend.open_device(bus_id, address_id)?;
+        Ok((Box::new(opened), Vec::new()))
+    }
+
+    fn close_device(&self, handle: CustomHandle, _interfaces: Vec<()>) -> Result<(), ChallengeResponseError> {
+        let (handle, interfaces) = *handle.downcast::<(H, Vec<I>)>().map_err(|_| foreign_handle())?;
+        self.backend.close_device(handle, interfaces)
+    }
+
+    fn read_interfaces(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<Vec<InterfaceInfo>, ChallengeResponseError> {
+        self.backend.read_interfaces(bus_id, address_id)
+    }
+
+    fn reset_device(&mut self, bus_id: u8, address_id: u8) -> Result<(), ChallengeResponseError> {
+        self.backend.reset_device(bus_id, address_id)
+    }
+
+    fn read(&self, handle: &mut CustomHandle, buf: &mut [u8]) -> Result<usize, ChallengeResponseError> {
+        self.backend.read(Self::handle(handle)?, buf)
+    }
+
+    fn raw_write(&self, handle: &mut CustomHandle, packet: &[u8]) -> Result<(), ChallengeResponseError> {
+        self.backend.raw_write(Self::handle(handle)?, packet)
+    }
+
+    fn find_device(&mut self) -> Result<Device, ChallengeResponseError> {
+        self.backend.find_device()
+    }
+
+    fn find_device_from_serial(&mut self, serial: u32) -> Result<Device, ChallengeResponseError> {
+        self.backend.find_device_from_serial(serial)
+    }
+
+    fn find_all_devices(&mut self) -> Result<Vec<Device>, ChallengeResponseError> {
+        self.backend.find_all_devices()
+    }
+
+    fn enumerate_devices<'a>(
+        &'a mut self,
+    ) -> Box<dyn Iterator<Item = Result<Device, ChallengeResponseError>> + 'a> {
+        self.backend.enumerate_devices()
+    }
+
+    fn wait_for_connection(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        self.backend.wait_for_connection(timeout)
+    }
+
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        self.backend.wait_for_change(timeout)
+    }
+}
+
+impl PluggableBackend {
+    /// Wraps a backend implemented by the application.
+    pub fn custom<B, H, I>(backend: B) -> Self
+    where
+        B: Backend<H, I> + 'static,
+        H: 'static,
+        I: 'static,
+    {
+        PluggableBackend::Custom(Box::new(Erased {
+            backend,
+            types: PhantomData,
+        }))
+    }
+
+    /// See `RUSBBackend::with_context`.
+    #[cfg(feature = "rusb")]
+    pub fn with_context(context: ::rusb::Context) -> Result<Self, ChallengeResponseError> {
+        Ok(PluggableBackend::Usb(UsbBackendType::with_context(context)?))
+    }
+
+    /// See `RUSBBackend::adopt_handle`.
+    #[cfg(feature = "rusb")]
+    pub fn adopt_handle(
+        &mut self,
+        handle: ::rusb::DeviceHandle<::rusb::Context>,
+    ) -> Result<Device, ChallengeResponseError> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.adopt_handle(handle),
+            PluggableBackend::Custom(_) => Err(foreign_handle()),
+        }
+    }
+
+    /// See `NUSBBackend::adopt_device`.
+    #[cfg(feature = "nusb")]
+    pub fn adopt_device(
+        &mut self,
+        device_info: ::nusb::DeviceInfo,
+        device: ::nusb::Device,
+    ) -> Result<Device, ChallengeResponseError> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.adopt_device(device_info, device),
+            PluggableBackend::Custom(_) => Err(foreign_handle()),
+        }
+    }
+}
+
+impl Backend<PluggableHandle, InterfaceType> for PluggableBackend {
+    fn new() -> Result<Self, ChallengeResponseError> {
+        Ok(PluggableBackend::Usb(UsbBackendType::new()?))
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            PluggableBackend::Usb(ref backend) => backend.name(),
+            PluggableBackend::Custom(ref backend) => backend.name(),
+        }
+    }
+
+    fn set_detach_policy(&mut self, policy: DetachPolicy) {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.set_detach_policy(policy),
+            PluggableBackend::Custom(ref mut backend) => backend.set_detach_policy(policy),
+        }
+    }
+
+    fn set_trace(&mut self, trace: Option<PcapngTrace>) {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.set_trace(trace),
+            PluggableBackend::Custom(ref mut backend) => backend.set_trace(trace),
+        }
+    }
+
+    fn set_timing(&mut self, timing: Timing) {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.set_timing(timing),
+            PluggableBackend::Custom(ref mut backend) => backend.set_timing(timing),
+        }
+    }
+
+    fn timing(&self) -> &Timing {
+        match *self {
+            PluggableBackend::Usb(ref backend) => backend.timing(),
+            PluggableBackend::Custom(ref backend) => backend.timing(),
+        }
+    }
+
+    fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.set_reset_policy(policy),
+            PluggableBackend::Custom(ref mut backend) => backend.set_reset_policy(policy),
+        }
+    }
+
+    fn reset_policy(&self) -> ResetPolicy {
+        match *self {
+            PluggableBackend::Usb(ref backend) => backend.reset_policy(),
+            PluggableBackend::Custom(ref backend) => backend.reset_policy(),
+        }
+    }
+
+    fn set_extra_device_ids(&mut self, ids: Vec<(u16, u16)>) {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.set_extra_device_ids(ids),
+            PluggableBackend::Custom(ref mut backend) => backend.set_extra_device_ids(ids),
+        }
+    }
+
+    fn open_device(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<(PluggableHandle, Vec<InterfaceType>), ChallengeResponseError> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => {
+                let (handle, interfaces) = backend.open_device(bus_id, address_id)?;
+                Ok((PluggableHandle::Usb(handle), interfaces))
+            }
+            PluggableBackend::Custom(ref mut backend) => {
+                let (handle, _) = backend.open_device(bus_id, address_id)?;
+                Ok((PluggableHandle::Custom(handle), Vec::new()))
+            }
+        }
+    }
+
+    fn close_device(
+        &self,
+        handle: PluggableHandle,
+        interfaces: Vec<InterfaceType>,
+    ) -> Result<(), ChallengeResponseError> {
+        match (self, handle) {
+            (PluggableBackend::Usb(backend), PluggableHandle::Usb(handle)) => {
+                backend.close_device(handle, interfaces)
+            }
+            (PluggableBackend::Custom(backend), PluggableHandle::Custom(handle)) => {
+                backend.close_device(handle, Vec::new())
+            }
+            _ => Err(foreign_handle()),
+        }
+    }
+
+    fn read_interfaces(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<Vec<InterfaceInfo>, ChallengeResponseError> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.read_interfaces(bus_id, address_id),
+            PluggableBackend::Custom(ref mut backend) => backend.read_interfaces(bus_id, address_id),
+        }
+    }
+
+    fn reset_device(&mut self, bus_id: u8, address_id: u8) -> Result<(), ChallengeResponseError> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.reset_device(bus_id, address_id),
+            PluggableBackend::Custom(ref mut backend) => backend.reset_device(bus_id, address_id),
+        }
+    }
+
+    fn read(&self, handle: &mut PluggableHandle, buf: &mut [u8]) -> Result<usize, ChallengeResponseError> {
+        match (self, handle) {
+            (PluggableBackend::Usb(backend), PluggableHandle::Usb(handle)) => backend.read(handle, buf),
+            (PluggableBackend::Custom(backend), PluggableHandle::Custom(handle)) => backend.read(handle, buf),
+            _ => Err(foreign_handle()),
+        }
+    }
+
+    fn raw_write(&self, handle: &mut PluggableHandle, packet: &[u8]) -> Result<(), ChallengeResponseError> {
+        match (self, handle) {
+            (PluggableBackend::Usb(backend), PluggableHandle::Usb(handle)) => backend.raw_write(handle, packet),
+            (PluggableBackend::Custom(backend), PluggableHandle::Custom(handle)) => {
+                backend.raw_write(handle, packet)
+            }
+            _ => Err(foreign_handle()),
+        }
+    }
+
+    fn find_device(&mut self) -> Result<Device, ChallengeResponseError> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.find_device(),
+            PluggableBackend::Custom(ref mut backend) => backend.find_device(),
+        }
+    }
+
+    fn find_device_from_serial(&mut self, serial: u32) -> Result<Device, ChallengeResponseError> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.find_device_from_serial(serial),
+            PluggableBackend::Custom(ref mut backend) => backend.find_device_from_serial(serial),
+        }
+    }
+
+    fn find_all_devices(&mut self) -> Result<Vec<Device>, ChallengeResponseError> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.find_all_devices(),
+            PluggableBackend::Custom(ref mut backend) => backend.find_all_devices(),
+        }
+    }
+
+    fn enumerate_devices<'a>(
+        &'a mut self,
+    ) -> Box<dyn Iterator<Item = Result<Device, ChallengeResponseError>> + 'a> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.enumerate_devices(),
+            PluggableBackend::Custom(ref mut backend) => backend.enumerate_devices(),
+        }
+    }
+
+    fn wait_for_connection(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.wait_for_connection(timeout),
+            PluggableBackend::Custom(ref mut backend) => backend.wait_for_connection(timeout),
+        }
+    }
+
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        match *self {
+            PluggableBackend::Usb(ref mut backend) => backend.wait_for_change(timeout),
+            PluggableBackend::Custom(ref mut backend) => backend.wait_for_change(timeout),
+        }
+    }
+}
@@ -1,10 +1,296 @@
 use error::ChallengeResponseError;
-use rusb::{request_type, Context, DeviceHandle, Direction, Recipient, RequestType, UsbContext};
-use std::time::Duration;
-use usb::{Backend, Device, HID_GET_REPORT, HID_SET_REPORT, PRODUCT_ID, REPORT_TYPE_FEATURE, VENDOR_ID};
+use rusb::{
+    request_type, Context, Device as RUSBDevice, DeviceDescriptor, DeviceHandle, Direction, Hotplug,
+    HotplugBuilder, Recipient, Registration, RequestType, UsbContext,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use trace::PcapngTrace;
+use usb::{
+    interface_busy, is_supported, kernel_driver_name, open_failed, query_in_parallel, read_serial_from_handle,
+    trace_report, Backend, DetachPolicy, Device, InterfaceInfo, PortPath, ResetPolicy, Timing, HID_GET_REPORT,
+    HID_SET_REPORT, REPORT_TYPE_FEATURE,
+};
+
+fn port_path(device: &RUSBDevice<Context>) -> Option<PortPath> {
+    Some(PortPath {
+        bus: device.bus_number(),
+        ports: device.port_numbers().ok()?,
+    })
+}
+
+/// The `bcdDevice` field of a descriptor, which rusb only exposes decoded.
+fn release(descr: &DeviceDescriptor) -> u16 {
+    let version = descr.device_version();
+    let major = version.major() as u16;
+    (major / 10) << 12 | (major % 10) << 8 | (version.minor() as u16) << 4 | version.sub_minor() as u16
+}
+
+/// The interfaces of the active configuration of a device, with their kernel drivers.
+fn interfaces(device: &RUSBDevice<Context>) -> Result<Vec<InterfaceInfo>, ChallengeResponseError> {
+    let port_path = port_path(device);
+    let config = device.active_config_descriptor()?;
+    let mut interfaces = Vec::new();
+    for interface in config.interfaces() {
+        for descriptor in interface.descriptors() {
+            interfaces.push(InterfaceInfo {
+                number: descriptor.interface_number(),
+                class: descriptor.class_code(),
+                subclass: descriptor.sub_class_code(),
+                protocol: descriptor.protocol_code(),
+                kernel_driver: port_path
+                    .as_ref()
+                    .and_then(|p| kernel_driver_name(p, descriptor.interface_number())),
+            });
+        }
+    }
+    Ok(interfaces)
+}
+
+/// Records that a device was connected.
+struct Arrival(Arc<AtomicBool>);
+
+impl Hotplug<Context> for Arrival {
+    fn device_arrived(&mut self, _device: RUSBDevice<Context>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn device_left(&mut self, _device: RUSBDevice<Context>) {}
+}
+
+/// Records that the list of devices changed.
+struct Invalidation(Arc<AtomicBool>);
+
+impl Hotplug<Context> for Invalidation {
+    fn device_arrived(&mut self, _device: RUSBDevice<Context>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn device_left(&mut self, _device: RUSBDevice<Context>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Keeps the hotplug callback of the backend registered until it is dropped. The registration is behind a
+/// mutex, which is never locked, so the backend can be shared with the threads querying the devices.
+struct HotplugRegistration {
+    _registration: Mutex<Registration<Context>>,
+}
 
 pub struct RUSBBackend {
     context: Context,
+    detach_policy: DetachPolicy,
+    trace: Option<Mutex<PcapngTrace>>,
+    timing: Timing,
+    reset_policy: ResetPolicy,
+    extra_device_ids: Vec<(u16, u16)>,
+    /// Set by the hotplug callback when the cached device list is outdated. Without hotplug support, the
+    /// devices are enumerated for every operation and no handle is cached.
+    stale: Arc<AtomicBool>,
+    hotplug: Option<HotplugRegistration>,
+    devices: Option<Vec<RUSBDevice<Context>>>,
+    /// The handles of the closed devices, by bus and address, with their interfaces released.
+    handles: Mutex<HashMap<(u8, u8), DeviceHandle<Context>>>,
+    /// The bus and address of the devices whose handle was given by the application, which are kept even
+    /// without hotplug support.
+    adopted: HashSet<(u8, u8)>,
+}
+
+impl RUSBBackend {
+    /// Creates a backend using an existing libusb context, e.g. the one of an application which already uses
+    /// rusb for other devices.
+    pub fn with_context(context: Context) -> Result<Self, ChallengeResponseError> {
+        let stale = Arc::new(AtomicBool::new(true));
+        let hotplug = if rusb::has_hotplug() {
+            HotplugBuilder::new()
+                .enumerate(false)
+                .register(&context, Box::new(Invalidation(stale.clone())))
+                .ok()
+                .map(|registration| HotplugRegistration {
+                    _registration: Mutex::new(registration),
+                })
+        } else {
+            None
+        };
+        Ok(Self {
+            context,
+            detach_policy: DetachPolicy::default(),
+            trace: None,
+            timing: Timing::default(),
+            reset_policy: ResetPolicy::default(),
+            extra_device_ids: Vec::new(),
+            stale,
+            hotplug,
+            devices: None,
+            handles: Mutex::new(HashMap::new()),
+            adopted: HashSet::new(),
+        })
+    }
+
+    /// Handles the libusb events until `flag` is set by a hotplug callback, or until `timeout` expires.
+    fn handle_events_until(&self, flag: &AtomicBool, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        let deadline = Instant::now() + timeout;
+        while !flag.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            self.context.handle_events(Some(deadline - now))?;
+        }
+        Ok(())
+    }
+
+    /// Operates the device of a handle opened by the application, instead of opening the device again. The
+    /// handle must belong to the context of the backend. Returns the description of the device.
+    pub fn adopt_handle(&mut self, handle: DeviceHandle<Context>) -> Result<Device, ChallengeResponseError> {
+        let device = handle.device();
+        let descr = device.device_descriptor()?;
+        let key = (device.bus_number(), device.address());
+        self.adopted.insert(key);
+        self.handles.lock().unwrap().insert(key, handle);
+        self.read_device(&device, &descr)
+    }
+
+    /// Lists the connected devices, enumerating them again only if a device was connected or
+    /// disconnected since the last enumeration.
+    fn devices(&mut self) -> Result<Vec<RUSBDevice<Context>>, ChallengeResponseError> {
+        Ok(self.cached_devices()?.to_vec())
+    }
+
+    /// The device list, refreshed like by `devices`, without copying it.
+    fn cached_devices(&mut self) -> Result<&[RUSBDevice<Context>], ChallengeResponseError> {
+        if self.hotplug.is_some() {
+            // Runs the hotplug callback for the pending events, without blocking.
+            self.context.handle_events(Some(Duration::ZERO))?;
+        }
+        if self.hotplug.is_none() || self.stale.swap(false, Ordering::SeqCst) {
+            self.devices = None;
+            // The bus addresses may have been reused by other devices.
+            let adopted = &self.adopted;
+            self.handles
+                .lock()
+                .unwrap()
+                .retain(|key, _| adopted.contains(key));
+        }
+        if self.devices.is_none() {
+            self.devices = Some(self.context.devices()?.iter().collect());
+        }
+        Ok(self.devices.as_deref().unwrap_or_default())
+    }
+
+    /// Reads the product and manufacturer names and the serial number of a device, opening it once for all.
+    /// If the interfaces of the device can not be claimed, its serial number is unknown.
+    fn read_device(
+        &self,
+        device: &RUSBDevice<Context>,
+        descr: &DeviceDescriptor,
+    ) -> Result<Device, ChallengeResponseError> {
+        let (name, manufacturer, serial) = match self.claim_device(device) {
+            Ok((mut handle, interfaces)) => {
+                let name = handle.read_product_string_ascii(descr).ok();
+                let manufacturer = handle.read_manufacturer_string_ascii(descr).ok();
+                let serial = read_serial_from_handle(self, &mut handle).ok();
+                self.close_device(handle, interfaces)?;
+                (name, manufacturer, serial)
+            }
+            Err(_) => {
+                let handle = self.take_handle(device)?;
+                let name = handle.read_product_string_ascii(descr).ok();
+                let manufacturer = handle.read_manufacturer_string_ascii(descr).ok();
+                self.put_handle(handle);
+                (name, manufacturer, None)
+            }
+        };
+        Ok(Device {
+            name,
+            serial,
+            product_id: descr.product_id(),
+            vendor_id: descr.vendor_id(),
+            bus_id: device.bus_number(),
+            address_id: device.address(),
+            port_path: port_path(device),
+            manufacturer,
+            release: release(descr),
+            interfaces: interfaces(device).unwrap_or_default(),
+        })
+    }
+
+    /// Opens a device and claims its interfaces, detaching the kernel drivers allowed by the detach policy.
+    fn claim_device(
+        &self,
+        device: &RUSBDevice<Context>,
+    ) -> Result<(DeviceHandle<Context>, Vec<u8>), ChallengeResponseError> {
+        let handle = self.take_handle(device).map_err(open_failed)?;
+        let config = match device.config_descriptor(0) {
+            Ok(c) => c,
+            Err(_) => return Err(ChallengeResponseError::DeviceNotFound),
+        };
+
+        let mut _interfaces = Vec::new();
+        for interface in config.interfaces() {
+            for usb_int in interface.descriptors() {
+                match handle.kernel_driver_active(usb_int.interface_number()) {
+                    Ok(true) if !self.detach_policy.can_detach(usb_int.interface_number()) => {
+                        if self.detach_policy.fail_if_held {
+                            return Err(interface_busy(
+                                port_path(device).as_ref(),
+                                usb_int.interface_number(),
+                            ));
+                        }
+                        continue;
+                    }
+                    Ok(true) => {
+                        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+                        handle
+                            .detach_kernel_driver(usb_int.interface_number())
+                            .map_err(open_failed)?;
+                    }
+                    _ => continue,
+                };
+
+                if handle.active_configuration().map_err(open_failed)? != config.number() {
+                    handle
+                        .set_active_configuration(config.number())
+                        .map_err(open_failed)?;
+                }
+                #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+                handle
+                    .claim_interface(usb_int.interface_number())
+                    .map_err(open_failed)?;
+                #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+                _interfaces.push(usb_int.interface_number());
+            }
+        }
+
+        Ok((handle, _interfaces))
+    }
+
+    /// Opens a device, reusing the handle of a previous operation if there is one.
+    fn take_handle(&self, device: &RUSBDevice<Context>) -> Result<DeviceHandle<Context>, rusb::Error> {
+        let cached = self
+            .handles
+            .lock()
+            .unwrap()
+            .remove(&(device.bus_number(), device.address()));
+        match cached {
+            Some(handle) => Ok(handle),
+            None => device.open(),
+        }
+    }
+
+    /// Keeps the handle of a device for the next operation, if the device list is invalidated by hotplug
+    /// events or if the handle was adopted. Else the handle is closed.
+    fn put_handle(&self, handle: DeviceHandle<Context>) {
+        let device = handle.device();
+        let key = (device.bus_number(), device.address());
+        if self.hotplug.is_none() && !self.adopted.contains(&key) {
+            return;
+        }
+        self.handles.lock().unwrap().insert(key, handle);
+    }
 }
 
 impl Backend<DeviceHandle<Context>, u8> for RUSBBackend {
@@ -13,7 +299,39 @@ impl Backend<DeviceHandle<Context>, u8> for RUSBBackend {
             Ok(c) => c,
             Err(e) => return Err(ChallengeResponseError::UsbError(e)),
         };
-        Ok(Self { context })
+        Self::with_context(context)
+    }
+
+    fn name(&self) -> &'static str {
+        "rusb"
+    }
+
+    fn set_detach_policy(&mut self, policy: DetachPolicy) {
+        self.detach_policy = policy;
+    }
+
+    fn set_trace(&mut self, trace: Option<PcapngTrace>) {
+        self.trace = trace.map(Mutex::new);
+    }
+
+    fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    fn timing(&self) -> &Timing {
+        &self.timing
+    }
+
+    fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        self.reset_policy = policy;
+    }
+
+    fn reset_policy(&self) -> ResetPolicy {
+        self.reset_policy
+    }
+
+    fn set_extra_device_ids(&mut self, ids: Vec<(u16, u16)>) {
+        self.extra_device_ids = ids;
     }
 
     fn open_device(
@@ -21,14 +339,15 @@ impl Backend<DeviceHandle<Context>, u8> for RUSBBackend {
         bus_id: u8,
         address_id: u8,
     ) -> Result<(DeviceHandle<Context>, Vec<u8>), ChallengeResponseError> {
-        let devices = match self.context.devices() {
+        let devices = match self.cached_devices() {
             Ok(device) => device,
             Err(_) => {
                 return Err(ChallengeResponseError::DeviceNotFound);
             }
         };
 
-        for device in devices.iter() {
+        let mut found = None;
+        for device in devices {
             match device.device_descriptor() {
                 Ok(_) => {}
                 Err(_) => {
@@ -37,41 +356,45 @@ impl Backend<DeviceHandle<Context>, u8> for RUSBBackend {
             };
 
             if device.bus_number() == bus_id && device.address() == address_id {
-                match device.open() {
-                    Ok(handle) => {
-                        let config = match device.config_descriptor(0) {
-                            Ok(c) => c,
-                            Err(_) => continue,
-                        };
-
-                        let mut _interfaces = Vec::new();
-                        for interface in config.interfaces() {
-                            for usb_int in interface.descriptors() {
-                                match handle.kernel_driver_active(usb_int.interface_number()) {
-                                    Ok(true) => {
-                                        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
-                                        handle.detach_kernel_driver(usb_int.interface_number())?;
-                                    }
-                                    _ => continue,
-                                };
-
-                                if handle.active_configuration()? != config.number() {
-                                    handle.set_active_configuration(config.number())?;
-                                }
-                                #[cfg(not(any(target_os = "macos", target_os = "windows")))]
-                                handle.claim_interface(usb_int.interface_number())?;
-                                #[cfg(not(any(target_os = "macos", target_os = "windows")))]
-                                _interfaces.push(usb_int.interface_number());
-                            }
-                        }
+                found = Some(device.clone());
+                break;
+            }
+        }
 
-                        return Ok((handle, _interfaces));
-                    }
-                    Err(_) => {
-                        return Err(ChallengeResponseError::OpenDeviceError);
-                    }
-                }
+        match found {
+            Some(device) => self.claim_device(&device),
+            None => Err(ChallengeResponseError::DeviceNotFound),
+        }
+    }
+
+    fn read_interfaces(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<Vec<InterfaceInfo>, ChallengeResponseError> {
+        for device in self.devices()? {
+            if device.bus_number() != bus_id || device.address() != address_id {
+                continue;
             }
+            return interfaces(&device);
+        }
+
+        Err(ChallengeResponseError::DeviceNotFound)
+    }
+
+    fn reset_device(&mut self, bus_id: u8, address_id: u8) -> Result<(), ChallengeResponseError> {
+        for device in self.devices()? {
+            if device.bus_number() != bus_id || device.address() != address_id {
+                continue;
+            }
+            let handle = self.take_handle(&device).map_err(open_failed)?;
+            // The device is enumerated again after the reset.
+            self.stale.store(true, Ordering::SeqCst);
+            return match handle.reset() {
+                // The device was enumerated again, so the handle is no longer valid.
+                Ok(()) | Err(rusb::Error::NotFound) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
         }
 
         Err(ChallengeResponseError::DeviceNotFound)
@@ -80,9 +403,10 @@ impl Backend<DeviceHandle<Context>, u8> for RUSBBackend {
     #[cfg(any(target_os = "macos", target_os = "windows"))]
     fn close_device(
         &self,
-        mut handle: DeviceHandle<Context>,
-        interfaces: Vec<u8>,
+        handle: DeviceHandle<Context>,
+        _interfaces: Vec<u8>,
     ) -> Result<(), ChallengeResponseError> {
+        self.put_handle(handle);
         Ok(())
     }
 
@@ -94,8 +418,11 @@ impl Backend<DeviceHandle<Context>, u8> for RUSBBackend {
     ) -> Result<(), ChallengeResponseError> {
         for interface in interfaces {
             handle.release_interface(interface)?;
-            handle.attach_kernel_driver(interface)?;
+            if self.detach_policy.reattach {
+                handle.attach_kernel_driver(interface)?;
+            }
         }
+        self.put_handle(handle);
         Ok(())
     }
 
@@ -107,7 +434,24 @@ impl Backend<DeviceHandle<Context>, u8> for RUSBBackend {
         assert_eq!(buf.len(), 8);
         let reqtype = request_type(Direction::In, RequestType::Class, Recipient::Interface);
         let value = REPORT_TYPE_FEATURE << 8;
-        Ok(handle.read_control(reqtype, HID_GET_REPORT, value, 0, buf, Duration::new(2, 0))?)
+        let read = handle.read_control(
+            reqtype,
+            HID_GET_REPORT,
+            value,
+            0,
+            buf,
+            self.timing.transfer_timeout,
+        )?;
+        let device = handle.device();
+        trace_report(
+            &self.trace,
+            device.bus_number() as u16,
+            device.address(),
+            reqtype,
+            HID_GET_REPORT,
+            &buf[..read],
+        );
+        Ok(read)
     }
 
     fn raw_write(
@@ -117,7 +461,24 @@ impl Backend<DeviceHandle<Context>, u8> for RUSBBackend {
     ) -> Result<(), ChallengeResponseError> {
         let reqtype = request_type(Direction::Out, RequestType::Class, Recipient::Interface);
         let value = REPORT_TYPE_FEATURE << 8;
-        if handle.write_control(reqtype, HID_SET_REPORT, value, 0, &packet, Duration::new(2, 0))? != 8 {
+        let written = handle.write_control(
+            reqtype,
+            HID_SET_REPORT,
+            value,
+            0,
+            packet,
+            self.timing.transfer_timeout,
+        )?;
+        let device = handle.device();
+        trace_report(
+            &self.trace,
+            device.bus_number() as u16,
+            device.address(),
+            reqtype,
+            HID_SET_REPORT,
+            &packet[..written],
+        );
+        if written != 8 {
             Err(ChallengeResponseError::CanNotWriteToDevice)
         } else {
             Ok(())
@@ -125,68 +486,31 @@ impl Backend<DeviceHandle<Context>, u8> for RUSBBackend {
     }
 
     fn find_device(&mut self) -> Result<Device, ChallengeResponseError> {
-        let devices = match self.context.devices() {
-            Ok(d) => d,
-            Err(e) => return Err(ChallengeResponseError::UsbError(e)),
-        };
-        for device in devices.iter() {
+        for device in self.devices()? {
             let descr = device
                 .device_descriptor()
-                .map_err(|e| ChallengeResponseError::UsbError(e))?;
-            if !VENDOR_ID.contains(&descr.vendor_id()) || !PRODUCT_ID.contains(&descr.product_id()) {
+                .map_err(ChallengeResponseError::UsbError)?;
+            if !is_supported(&self.extra_device_ids, descr.vendor_id(), descr.product_id()) {
                 continue;
             }
 
-            let name = device.open()?.read_product_string_ascii(&descr).ok();
-            let serial = self
-                .read_serial_from_device(device.bus_number(), device.address())
-                .ok();
-            let device = Device {
-                name,
-                serial,
-                product_id: descr.product_id(),
-                vendor_id: descr.vendor_id(),
-                bus_id: device.bus_number(),
-                address_id: device.address(),
-            };
-
-            return Ok(device);
+            return self.read_device(&device, &descr);
         }
 
         Err(ChallengeResponseError::DeviceNotFound)
     }
 
     fn find_device_from_serial(&mut self, serial: u32) -> Result<Device, ChallengeResponseError> {
-        let devices = match self.context.devices() {
-            Ok(d) => d,
-            Err(e) => return Err(ChallengeResponseError::UsbError(e)),
-        };
-        for device in devices.iter() {
+        for device in self.devices()? {
             let descr = device
                 .device_descriptor()
-                .map_err(|e| ChallengeResponseError::UsbError(e))?;
-            if !VENDOR_ID.contains(&descr.vendor_id()) || !PRODUCT_ID.contains(&descr.product_id()) {
+                .map_err(ChallengeResponseError::UsbError)?;
+            if !is_supported(&self.extra_device_ids, descr.vendor_id(), descr.product_id()) {
                 continue;
             }
 
-            let name = device.open()?.read_product_string_ascii(&descr).ok();
-            let fetched_serial = match self
-                .read_serial_from_device(device.bus_number(), device.address())
-                .ok()
-            {
-                Some(s) => s,
-                None => 0,
-            };
-            if serial == fetched_serial {
-                let device = Device {
-                    name,
-                    serial: Some(serial),
-                    product_id: descr.product_id(),
-                    vendor_id: descr.vendor_id(),
-                    bus_id: device.bus_number(),
-                    address_id: device.address(),
-                };
-
+            let device = self.read_device(&device, &descr)?;
+            if device.serial == Some(serial) {
                 return Ok(device);
             }
         }
@@ -195,38 +519,75 @@ impl Backend<DeviceHandle<Context>, u8> for RUSBBackend {
     }
 
     fn find_all_devices(&mut self) -> Result<Vec<Device>, ChallengeResponseError> {
-        let mut result: Vec<Device> = Vec::new();
-        let devices = match self.context.devices() {
-            Ok(d) => d,
-            Err(e) => return Err(ChallengeResponseError::UsbError(e)),
-        };
-        for device in devices.iter() {
+        let mut candidates = Vec::new();
+        for device in self.devices()? {
             let descr = device
                 .device_descriptor()
-                .map_err(|e| ChallengeResponseError::UsbError(e))?;
-            if !VENDOR_ID.contains(&descr.vendor_id()) || !PRODUCT_ID.contains(&descr.product_id()) {
+                .map_err(ChallengeResponseError::UsbError)?;
+            if !is_supported(&self.extra_device_ids, descr.vendor_id(), descr.product_id()) {
                 continue;
             }
-
-            let name = device.open()?.read_product_string_ascii(&descr).ok();
-            let serial = self
-                .read_serial_from_device(device.bus_number(), device.address())
-                .ok();
-            let device = Device {
-                name,
-                serial,
-                product_id: descr.product_id(),
-                vendor_id: descr.vendor_id(),
-                bus_id: device.bus_number(),
-                address_id: device.address(),
-            };
-            result.push(device);
+            candidates.push((device, descr));
         }
 
+        // Reading the serial numbers takes a few milliseconds per device, so the devices are queried in
+        // parallel for the hosts with many devices.
+        let result = query_in_parallel(&candidates, |(device, descr)| self.read_device(device, descr))
+            .into_iter()
+            .collect::<Result<Vec<Device>, ChallengeResponseError>>()?;
+
         if !result.is_empty() {
             return Ok(result);
         }
 
         Err(ChallengeResponseError::DeviceNotFound)
     }
+
+    fn enumerate_devices<'a>(
+        &'a mut self,
+    ) -> Box<dyn Iterator<Item = Result<Device, ChallengeResponseError>> + 'a> {
+        let devices = match self.devices() {
+            Ok(devices) => devices,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        let backend = &*self;
+        Box::new(devices.into_iter().filter_map(move |device| {
+            let descr = match device.device_descriptor() {
+                Ok(descr) => descr,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if !is_supported(&backend.extra_device_ids, descr.vendor_id(), descr.product_id()) {
+                return None;
+            }
+            Some(backend.read_device(&device, &descr))
+        }))
+    }
+
+    fn wait_for_connection(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        // libusb does not support hotplug on all platforms, e.g. on Windows.
+        if !rusb::has_hotplug() {
+            thread::sleep(timeout);
+            return Ok(());
+        }
+
+        let arrived = Arc::new(AtomicBool::new(false));
+        let _registration = HotplugBuilder::new()
+            .enumerate(false)
+            .register(&self.context, Box::new(Arrival(arrived.clone())))?;
+
+        self.handle_events_until(&arrived, timeout)
+    }
+
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        if !rusb::has_hotplug() {
+            thread::sleep(timeout);
+            return Ok(());
+        }
+
+        let changed = Arc::new(AtomicBool::new(false));
+        let _registration = HotplugBuilder::new()
+            .enumerate(false)
+            .register(&self.context, Box::new(Invalidation(changed.clone())))?;
+        self.handle_events_until(&changed, timeout)
+    }
 }
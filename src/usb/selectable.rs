@@ -0,0 +1,313 @@
+//! The USB backend chosen at runtime, when both the `rusb` and `nusb` features are enabled.
+//!
+//! `ChallengeResponse::new` uses the backend named by the `CHALLENGE_RESPONSE_BACKEND` environment variable,
+//! `rusb` or `nusb`, and `rusb` if it is not set. `ChallengeResponse::with_backend` and
+//! `ChallengeResponseBuilder::backend` choose it in the code instead. Either way, if the backend fails to
+//! initialize, e.g. when libusb can not be loaded, the other backend is used.
+use std::env;
+use std::time::Duration;
+
+use builder::BackendKind;
+use error::ChallengeResponseError;
+use trace::PcapngTrace;
+use usb::nusb::NUSBBackend;
+use usb::rusb::RUSBBackend;
+use usb::{Backend, DetachPolicy, Device, InterfaceInfo, ResetPolicy, Timing};
+
+/// The environment variable naming the backend used by `ChallengeResponse::new`.
+pub const BACKEND_VARIABLE: &str = "CHALLENGE_RESPONSE_BACKEND";
+
+pub enum SelectableBackend {
+    Rusb(RUSBBackend),
+    Nusb(NUSBBackend),
+}
+
+pub enum SelectableHandle {
+    Rusb(::rusb::DeviceHandle<::rusb::Context>),
+    Nusb(::nusb::Device),
+}
+
+pub enum SelectableInterface {
+    Rusb(u8),
+    Nusb(::nusb::Interface),
+}
+
+/// The error of a handle used with a backend which did not open it.
+fn foreign_handle() -> ChallengeResponseError {
+    ChallengeResponseError::DeviceNotFound
+}
+
+/// The backend named by `BACKEND_VARIABLE`, if it is set to the name of a backend.
+fn backend_from_environment() -> Option<BackendKind> {
+    BackendKind::from_name(&env::var(BACKEND_VARIABLE).ok()?)
+}
+
+impl SelectableBackend {
+    /// Creates the backend `kind`, or the other one if it fails to initialize. Fails with
+    /// `ChallengeResponseError::BackendUnavailable` if `kind` is neither `rusb` nor `nusb`.
+    pub fn with_kind(kind: BackendKind) -> Result<Self, ChallengeResponseError> {
+        let (preferred, fallback): (fn() -> _, fn() -> _) = match kind {
+            BackendKind::Rusb => (Self::rusb, Self::nusb),
+            BackendKind::Nusb => (Self::nusb, Self::rusb),
+            _ => return Err(ChallengeResponseError::BackendUnavailable(kind.name())),
+        };
+        preferred().or_else(|_| fallback())
+    }
+
+    fn rusb() -> Result<Self, ChallengeResponseError> {
+        Ok(SelectableBackend::Rusb(RUSBBackend::new()?))
+    }
+
+    fn nusb() -> Result<Self, ChallengeResponseError> {
+        Ok(SelectableBackend::Nusb(NUSBBackend::new()?))
+    }
+
+    /// See `RUSBBackend::with_context`.
+    pub fn with_context(context: ::rusb::Context) -> Result<Self, ChallengeResponseError> {
+        Ok(SelectableBackend::Rusb(RUSBBackend::with_context(context)?))
+    }
+
+    /// See `RUSBBackend::adopt_handle`. Fails with `ChallengeResponseError::BackendUnavailable` if the nusb
+    /// backend is in use.
+    pub fn adopt_handle(
+        &mut self,
+        handle: ::rusb::DeviceHandle<::rusb::Context>,
+    ) -> Result<Device, ChallengeResponseError> {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.adopt_handle(handle),
+            SelectableBackend::Nusb(_) => Err(ChallengeResponseError::BackendUnavailable(
+                BackendKind::Rusb.name(),
+            )),
+        }
+    }
+
+    /// See `NUSBBackend::adopt_device`. Fails with `ChallengeResponseError::BackendUnavailable` if the rusb
+    /// backend is in use.
+    pub fn adopt_device(
+        &mut self,
+        device_info: ::nusb::DeviceInfo,
+        device: ::nusb::Device,
+    ) -> Result<Device, ChallengeResponseError> {
+        match *self {
+            SelectableBackend::Nusb(ref mut backend) => backend.adopt_device(device_info, device),
+            SelectableBackend::Rusb(_) => Err(ChallengeResponseError::BackendUnavailable(
+                BackendKind::Nusb.name(),
+            )),
+        }
+    }
+}
+
+impl Backend<SelectableHandle, SelectableInterface> for SelectableBackend {
+    fn new() -> Result<Self, ChallengeResponseError> {
+        SelectableBackend::with_kind(backend_from_environment().unwrap_or(BackendKind::Rusb))
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            SelectableBackend::Rusb(ref backend) => backend.name(),
+            SelectableBackend::Nusb(ref backend) => backend.name(),
+        }
+    }
+
+    fn set_detach_policy(&mut self, policy: DetachPolicy) {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.set_detach_policy(policy),
+            SelectableBackend::Nusb(ref mut backend) => backend.set_detach_policy(policy),
+        }
+    }
+
+    fn set_trace(&mut self, trace: Option<PcapngTrace>) {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.set_trace(trace),
+            SelectableBackend::Nusb(ref mut backend) => backend.set_trace(trace),
+        }
+    }
+
+    fn set_timing(&mut self, timing: Timing) {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.set_timing(timing),
+            SelectableBackend::Nusb(ref mut backend) => backend.set_timing(timing),
+        }
+    }
+
+    fn timing(&self) -> &Timing {
+        match *self {
+            SelectableBackend::Rusb(ref backend) => backend.timing(),
+            SelectableBackend::Nusb(ref backend) => backend.timing(),
+        }
+    }
+
+    fn set_reset_policy(&mut self, policy: ResetPolicy) {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.set_reset_policy(policy),
+            SelectableBackend::Nusb(ref mut backend) => backend.set_reset_policy(policy),
+        }
+    }
+
+    fn reset_policy(&self) -> ResetPolicy {
+        match *self {
+            SelectableBackend::Rusb(ref backend) => backend.reset_policy(),
+            SelectableBackend::Nusb(ref backend) => backend.reset_policy(),
+        }
+    }
+
+    fn set_extra_device_ids(&mut self, ids: Vec<(u16, u16)>) {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.set_extra_device_ids(ids),
+            SelectableBackend::Nusb(ref mut backend) => backend.set_extra_device_ids(ids),
+        }
+    }
+
+    fn open_device(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<(SelectableHandle, Vec<SelectableInterface>), ChallengeResponseError> {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => {
+                let (handle, interfaces) = backend.open_device(bus_id, address_id)?;
+                Ok((
+                    SelectableHandle::Rusb(handle),
+                    interfaces.into_iter().map(SelectableInterface::Rusb).collect(),
+                ))
+            }
+            SelectableBackend::Nusb(ref mut backend) => {
+                let (handle, interfaces) = backend.open_device(bus_id, address_id)?;
+                Ok((
+                    SelectableHandle::Nusb(handle),
+                    interfaces.into_iter().map(SelectableInterface::Nusb).collect(),
+                ))
+            }
+        }
+    }
+
+    fn close_device(
+        &self,
+        handle: SelectableHandle,
+        interfaces: Vec<SelectableInterface>,
+    ) -> Result<(), ChallengeResponseError> {
+        match (self, handle) {
+            (SelectableBackend::Rusb(backend), SelectableHandle::Rusb(handle)) => {
+                let interfaces = interfaces
+                    .into_iter()
+                    .filter_map(|i| match i {
+                        SelectableInterface::Rusb(number) => Some(number),
+                        SelectableInterface::Nusb(_) => None,
+                    })
+                    .collect();
+                backend.close_device(handle, interfaces)
+            }
+            (SelectableBackend::Nusb(backend), SelectableHandle::Nusb(handle)) => {
+                let interfaces = interfaces
+                    .into_iter()
+                    .filter_map(|i| match i {
+                        SelectableInterface::Nusb(interface) => Some(interface),
+                        SelectableInterface::Rusb(_) => None,
+                    })
+                    .collect();
+                backend.close_device(handle, interfaces)
+            }
+            _ => Err(foreign_handle()),
+        }
+    }
+
+    fn read_interfaces(
+        &mut self,
+        bus_id: u8,
+        address_id: u8,
+    ) -> Result<Vec<InterfaceInfo>, ChallengeResponseError> {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.read_interfaces(bus_id, address_id),
+            SelectableBackend::Nusb(ref mut backend) => backend.read_interfaces(bus_id, address_id),
+        }
+    }
+
+    fn reset_device(&mut self, bus_id: u8, address_id: u8) -> Result<(), ChallengeResponseError> {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.reset_device(bus_id, address_id),
+            SelectableBackend::Nusb(ref mut backend) => backend.reset_device(bus_id, address_id),
+        }
+    }
+
+    fn read(&self, handle: &mut SelectableHandle, buf: &mut [u8]) -> Result<usize, ChallengeResponseError> {
+        match (self, handle) {
+            (SelectableBackend::Rusb(backend), SelectableHandle::Rusb(handle)) => backend.read(handle, buf),
+            (SelectableBackend::Nusb(backend), SelectableHandle::Nusb(handle)) => backend.read(handle, buf),
+            _ => Err(foreign_handle()),
+        }
+    }
+
+    fn raw_write(&self, handle: &mut SelectableHandle, packet: &[u8]) -> Result<(), ChallengeResponseError> {
+        match (self, handle) {
+            (SelectableBackend::Rusb(backend), SelectableHandle::Rusb(handle)) => {
+                backend.raw_write(handle, packet)
+            }
+            (SelectableBackend::Nusb(backend), SelectableHandle::Nusb(handle)) => {
+                backend.raw_write(handle, packet)
+            }
+            _ => Err(foreign_handle()),
+        }
+    }
+
+    fn find_device(&mut self) -> Result<Device, ChallengeResponseError> {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.find_device(),
+            SelectableBackend::Nusb(ref mut backend) => backend.find_device(),
+        }
+    }
+
+    fn find_device_from_serial(&mut self, serial: u32) -> Result<Device, ChallengeResponseError> {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.find_device_from_serial(serial),
+            SelectableBackend::Nusb(ref mut backend) => backend.find_device_from_serial(serial),
+        }
+    }
+
+    fn find_all_devices(&mut self) -> Result<Vec<Device>, ChallengeResponseError> {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.find_all_devices(),
+            SelectableBackend::Nusb(ref mut backend) => backend.find_all_devices(),
+        }
+    }
+
+    fn enumerate_devices<'a>(
+        &'a mut self,
+    ) -> Box<dyn Iterator<Item = Result<Device, ChallengeResponseError>> + 'a> {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.enumerate_devices(),
+            SelectableBackend::Nusb(ref mut backend) => backend.enumerate_devices(),
+        }
+    }
+
+    fn wait_for_connection(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.wait_for_connection(timeout),
+            SelectableBackend::Nusb(ref mut backend) => backend.wait_for_connection(timeout),
+        }
+    }
+
+    fn wait_for_change(&mut self, timeout: Duration) -> Result<(), ChallengeResponseError> {
+        match *self {
+            SelectableBackend::Rusb(ref mut backend) => backend.wait_for_change(timeout),
+            SelectableBackend::Nusb(ref mut backend) => backend.wait_for_change(timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_kind() {
+        // nusb initializes without a device or any system library.
+        assert_eq!(
+            SelectableBackend::with_kind(BackendKind::Nusb).unwrap().name(),
+            "nusb"
+        );
+        assert!(matches!(
+            SelectableBackend::with_kind(BackendKind::Hidraw),
+            Err(ChallengeResponseError::BackendUnavailable("hidraw"))
+        ));
+    }
+}
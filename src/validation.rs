@@ -0,0 +1,393 @@
+//! Server-side validation of HOTP (RFC 4226) and TOTP (RFC 6238) codes, for the OATH-HOTP slots
+//! programmed with `DeviceModeConfig::oath_hotp` and the credentials of the OATH application.
+//!
+//! The validators are stateless: the state of each token, i.e. the next expected counter of HOTP tokens
+//! or the last accepted time step of TOTP tokens, is given by the caller, which must persist it after
+//! each successful validation, for example with a `state::StateStore`. Brute force attacks are limited by a `Throttle`, which is consulted
+//! before each validation and told about its outcome.
+use clock::Clock;
+use error::ChallengeResponseError;
+use hmacmode::HmacKey;
+use sec::{compute_mac, HmacSha1};
+use state::StateStore;
+
+/// Computes the HOTP code of `key` for `counter`, with `digits` digits.
+pub fn hotp(key: &HmacKey, counter: u64, digits: u8) -> u32 {
+    let mac = compute_mac::<HmacSha1>(&key.0, &counter.to_be_bytes()).expect("HMAC accepts keys of any length");
+    truncate(&mac, digits)
+}
+
+/// The dynamic truncation of a HMAC-SHA1 `mac` to a code of `digits` digits, see RFC 4226.
+pub(crate) fn truncate(mac: &[u8], digits: u8) -> u32 {
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let truncated =
+        u32::from_be_bytes([mac[offset], mac[offset + 1], mac[offset + 2], mac[offset + 3]]) & 0x7fff_ffff;
+    truncated % 10u32.pow(digits as u32)
+}
+
+/// Limits the number of validation attempts of a token.
+pub trait Throttle {
+    /// Called before a code is checked. If `false` is returned, the validation fails with
+    /// `ChallengeResponseError::Throttled` without checking the code.
+    fn allow(&mut self) -> bool;
+
+    /// Called after a code was checked.
+    fn record(&mut self, success: bool);
+}
+
+/// Does not limit the validation attempts.
+pub struct NoThrottle;
+
+impl Throttle for NoThrottle {
+    fn allow(&mut self) -> bool {
+        true
+    }
+
+    fn record(&mut self, _success: bool) {}
+}
+
+/// Locks the token after `max_failures` consecutive failed validations, as recommended by RFC 4226. The
+/// token is unlocked by resetting `failures`, e.g. after the user was authenticated by other means.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FailureLimit {
+    pub max_failures: u32,
+    pub failures: u32,
+}
+
+impl Throttle for FailureLimit {
+    fn allow(&mut self) -> bool {
+        self.failures < self.max_failures
+    }
+
+    fn record(&mut self, success: bool) {
+        if success {
+            self.failures = 0;
+        } else {
+            self.failures = self.failures.saturating_add(1);
+        }
+    }
+}
+
+fn throttled<T, F>(throttle: &mut T, check: F) -> Result<(), ChallengeResponseError>
+where
+    T: Throttle + ?Sized,
+    F: FnOnce() -> bool,
+{
+    if !throttle.allow() {
+        return Err(ChallengeResponseError::Throttled);
+    }
+    let success = check();
+    throttle.record(success);
+    if !success {
+        return Err(ChallengeResponseError::InvalidCode);
+    }
+    Ok(())
+}
+
+/// Validates HOTP codes, accepting codes ahead of the expected counter in case the button was pressed
+/// without the code being used.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HotpValidator {
+    pub digits: u8,
+    /// The number of counter values after the expected one which are accepted by `validate`.
+    pub look_ahead: u64,
+    /// The number of counter values after the expected one searched by `resynchronize`.
+    pub resync_window: u64,
+}
+
+impl Default for HotpValidator {
+    fn default() -> Self {
+        HotpValidator {
+            digits: 6,
+            look_ahead: 10,
+            resync_window: 100,
+        }
+    }
+}
+
+impl HotpValidator {
+    fn find(&self, key: &HmacKey, from: u64, window: u64, code: u32) -> Option<u64> {
+        (from..=from.saturating_add(window)).find(|&c| hotp(key, c, self.digits) == code)
+    }
+
+    /// Validates `code` against the counter values from `counter` to `counter + look_ahead`. On success,
+    /// `counter` is set to the value following the one of the code, so the code can not be replayed.
+    pub fn validate<T: Throttle + ?Sized>(
+        &self,
+        key: &HmacKey,
+        counter: &mut u64,
+        code: u32,
+        throttle: &mut T,
+    ) -> Result<(), ChallengeResponseError> {
+        let mut found = None;
+        throttled(throttle, || {
+            found = self.find(key, *counter, self.look_ahead, code);
+            found.is_some()
+        })?;
+        *counter = found.expect("the code was found") + 1;
+        Ok(())
+    }
+
+    /// Validates `code` like `validate`, with the counter of `token` kept in `store`.
+    pub fn validate_stored<S, T>(
+        &self,
+        key: &HmacKey,
+        store: &mut S,
+        token: &str,
+        code: u32,
+        throttle: &mut T,
+    ) -> Result<(), ChallengeResponseError>
+    where
+        S: StateStore + ?Sized,
+        T: Throttle + ?Sized,
+    {
+        let mut state = store.load(token)?;
+        let mut counter = state.hotp_counter.unwrap_or(0);
+        self.validate(key, &mut counter, code, throttle)?;
+        state.hotp_counter = Some(counter);
+        store.store(token, &state)
+    }
+
+    /// Resynchronizes a token whose counter moved beyond the look-ahead window, with two consecutive codes
+    /// searched up to `resync_window` values after `counter`. On success, `counter` is set to the value
+    /// following the one of `second`.
+    pub fn resynchronize<T: Throttle + ?Sized>(
+        &self,
+        key: &HmacKey,
+        counter: &mut u64,
+        first: u32,
+        second: u32,
+        throttle: &mut T,
+    ) -> Result<(), ChallengeResponseError> {
+        let mut found = None;
+        throttled(throttle, || {
+            found = (*counter..=counter.saturating_add(self.resync_window))
+                .find(|&c| hotp(key, c, self.digits) == first && hotp(key, c + 1, self.digits) == second);
+            found.is_some()
+        })?;
+        *counter = found.expect("the codes were found") + 2;
+        Ok(())
+    }
+}
+
+/// The state of a TOTP token, to persist between validations.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TotpState {
+    /// The time step of the last accepted code. Codes of this step or of earlier ones are rejected.
+    pub last_step: Option<u64>,
+    /// The clock drift of the token, in time steps, measured at the last accepted code.
+    pub drift: i64,
+}
+
+/// Validates TOTP codes, accepting codes a few time steps away from the current one to account for the
+/// clock drift of the token and the delay of the transmission.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TotpValidator {
+    pub digits: u8,
+    /// The time step, in seconds.
+    pub period: u64,
+    /// The number of time steps before and after the expected one which are accepted.
+    pub window: u64,
+}
+
+impl Default for TotpValidator {
+    fn default() -> Self {
+        TotpValidator {
+            digits: 6,
+            period: 30,
+            window: 1,
+        }
+    }
+}
+
+impl TotpValidator {
+    /// Validates `code` at the UNIX time `timestamp`. The time step of the code is searched around the
+    /// current one, corrected by the drift measured at the last validation. On success, `state` is updated
+    /// with the time step of the code and the new drift.
+    pub fn validate_at<T: Throttle + ?Sized>(
+        &self,
+        key: &HmacKey,
+        state: &mut TotpState,
+        code: u32,
+        timestamp: u64,
+        throttle: &mut T,
+    ) -> Result<(), ChallengeResponseError> {
+        let current = timestamp / self.period;
+        let expected = (current as i64).saturating_add(state.drift).max(0) as u64;
+        let first = expected.saturating_sub(self.window);
+        let first = match state.last_step {
+            Some(last) => first.max(last + 1),
+            None => first,
+        };
+
+        let mut found = None;
+        throttled(throttle, || {
+            found = (first..=expected.saturating_add(self.window)).find(|&s| hotp(key, s, self.digits) == code);
+            found.is_some()
+        })?;
+        let step = found.expect("the code was found");
+        state.last_step = Some(step);
+        state.drift = step as i64 - current as i64;
+        Ok(())
+    }
+
+    /// Validates `code` like `validate_at`, at the current time of `clock`.
+    pub fn validate<C, T>(
+        &self,
+        key: &HmacKey,
+        state: &mut TotpState,
+        code: u32,
+        clock: &C,
+        throttle: &mut T,
+    ) -> Result<(), ChallengeResponseError>
+    where
+        C: Clock + ?Sized,
+        T: Throttle + ?Sized,
+    {
+        self.validate_at(key, state, code, clock.now(), throttle)
+    }
+
+    /// Validates `code` like `validate_at`, with the state of `token` kept in `store`.
+    pub fn validate_stored_at<S, T>(
+        &self,
+        key: &HmacKey,
+        store: &mut S,
+        token: &str,
+        code: u32,
+        timestamp: u64,
+        throttle: &mut T,
+    ) -> Result<(), ChallengeResponseError>
+    where
+        S: StateStore + ?Sized,
+        T: Throttle + ?Sized,
+    {
+        let mut state = store.load(token)?;
+        self.validate_at(key, &mut state.totp, code, timestamp, throttle)?;
+        store.store(token, &state)
+    }
+
+    /// Validates `code` like `validate_stored_at`, at the current time of `clock`.
+    pub fn validate_stored<C, S, T>(
+        &self,
+        key: &HmacKey,
+        store: &mut S,
+        token: &str,
+        code: u32,
+        clock: &C,
+        throttle: &mut T,
+    ) -> Result<(), ChallengeResponseError>
+    where
+        C: Clock + ?Sized,
+        S: StateStore + ?Sized,
+        T: Throttle + ?Sized,
+    {
+        self.validate_stored_at(key, store, token, code, clock.now(), throttle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::FakeClock;
+    use state::MemoryStateStore;
+
+    const RFC_KEY: &[u8; 20] = b"12345678901234567890";
+
+    #[test]
+    fn test_hotp() {
+        // RFC 4226, appendix D.
+        let codes = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, &code) in codes.iter().enumerate() {
+            assert_eq!(hotp(&HmacKey(*RFC_KEY), counter as u64, 6), code);
+        }
+    }
+
+    #[test]
+    fn test_hotp_validator() {
+        let key = HmacKey(*RFC_KEY);
+        let validator = HotpValidator {
+            look_ahead: 2,
+            ..HotpValidator::default()
+        };
+        let mut counter = 0;
+        validator
+            .validate(&key, &mut counter, 359152, &mut NoThrottle)
+            .unwrap();
+        assert_eq!(counter, 3);
+        // Replayed code.
+        assert!(validator
+            .validate(&key, &mut counter, 359152, &mut NoThrottle)
+            .is_err());
+        // Beyond the look-ahead window.
+        assert!(validator
+            .validate(&key, &mut counter, 162583, &mut NoThrottle)
+            .is_err());
+        validator
+            .resynchronize(&key, &mut counter, 162583, 399871, &mut NoThrottle)
+            .unwrap();
+        assert_eq!(counter, 9);
+
+        let mut store = MemoryStateStore::new();
+        validator
+            .validate_stored(&key, &mut store, "alice", 287082, &mut NoThrottle)
+            .unwrap();
+        assert_eq!(store.load("alice").unwrap().hotp_counter, Some(2));
+    }
+
+    #[test]
+    fn test_failure_limit() {
+        let key = HmacKey(*RFC_KEY);
+        let validator = HotpValidator::default();
+        let mut throttle = FailureLimit {
+            max_failures: 2,
+            failures: 0,
+        };
+        let mut counter = 0;
+        for _ in 0..2 {
+            assert!(matches!(
+                validator.validate(&key, &mut counter, 0, &mut throttle),
+                Err(ChallengeResponseError::InvalidCode)
+            ));
+        }
+        assert!(matches!(
+            validator.validate(&key, &mut counter, 755224, &mut throttle),
+            Err(ChallengeResponseError::Throttled)
+        ));
+    }
+
+    #[test]
+    fn test_totp_validator() {
+        let key = HmacKey(*RFC_KEY);
+        let validator = TotpValidator {
+            digits: 8,
+            ..TotpValidator::default()
+        };
+        let mut state = TotpState::default();
+        // RFC 6238, appendix B, at T = 1111111109, sent during the next time step.
+        validator
+            .validate_at(&key, &mut state, 7081804, 1111111109 + 30, &mut NoThrottle)
+            .unwrap();
+        assert_eq!(state.drift, -1);
+        // Replayed code.
+        assert!(validator
+            .validate_at(&key, &mut state, 7081804, 1111111109 + 30, &mut NoThrottle)
+            .is_err());
+        let clock = FakeClock::new(1234567890);
+        validator
+            .validate(&key, &mut state, 89005924, &clock, &mut NoThrottle)
+            .unwrap();
+        // The code of a time step is rejected once the clock moved beyond the window.
+        let code = hotp(&key, 1234567890 / 30 + 1, 8);
+        let mut state = TotpState::default();
+        clock.advance(3 * 30);
+        assert!(validator
+            .validate(&key, &mut state, code, &clock, &mut NoThrottle)
+            .is_err());
+        clock.set(1234567890 + 30);
+        validator
+            .validate(&key, &mut state, code, &clock, &mut NoThrottle)
+            .unwrap();
+    }
+}
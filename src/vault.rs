@@ -0,0 +1,140 @@
+//! Storage of a secret in a file, unlocked with the HMAC-SHA1 response of a device.
+//!
+//! A vault is the whole lifecycle of a secret protected by a device: `Vault::create` wraps the secret with
+//! the response of a slot to a random challenge and writes it to a new file, and `Vault::unlock` finds the
+//! same device again, from the serial number stored in the file, and decrypts the secret. The wrapping is
+//! the one of the `wrap` module, where the random challenge is also the salt of the key derivation.
+//!
+//! The format of a vault file (version 1) is the following:
+//!
+//! | Offset | Size | Content                                                       |
+//! |--------|------|---------------------------------------------------------------|
+//! | 0      | 4    | Magic bytes, `CRVT`                                           |
+//! | 4      | 1    | Version, `1`                                                  |
+//! | 5      | 1    | Key derivation function, `1` for HKDF-SHA256 of the response  |
+//! | 6      | 1    | Flags, bit 0 set if the serial number of the device is known  |
+//! | 7      | 4    | Serial number of the device, big-endian, or 0                 |
+//! | 11     | ...  | Wrapped secret, see `wrap::WrappedKey::to_bytes`              |
+//!
+//! The header is not authenticated separately: a different serial number only makes `unlock` use
+//! another device, whose response does not decrypt the secret.
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use rand_core::CryptoRng;
+
+use config::Config;
+use error::ChallengeResponseError;
+use wrap::{unwrap_key, wrap_key, UnwrappedKey, WrappedKey};
+use ChallengeResponse;
+
+const MAGIC: &[u8; 4] = b"CRVT";
+const VERSION: u8 = 1;
+const KDF_HKDF_SHA256: u8 = 1;
+const FLAG_SERIAL: u8 = 0x01;
+const HEADER_SIZE: usize = 11;
+
+/// A secret wrapped with the response of a device, as stored in a vault file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Vault {
+    /// The serial number of the device, if it was readable when the vault was created.
+    pub serial: Option<u32>,
+    pub wrapped: WrappedKey,
+}
+
+impl Vault {
+    /// Serializes the vault in the version 1 format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(KDF_HKDF_SHA256);
+        bytes.push(if self.serial.is_some() { FLAG_SERIAL } else { 0 });
+        bytes.extend_from_slice(&self.serial.unwrap_or(0).to_be_bytes());
+        bytes.extend_from_slice(&self.wrapped.to_bytes());
+        bytes
+    }
+
+    /// Parses a vault serialized with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Vault, ChallengeResponseError> {
+        if bytes.len() < HEADER_SIZE
+            || &bytes[..4] != MAGIC
+            || bytes[4] != VERSION
+            || bytes[5] != KDF_HKDF_SHA256
+        {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let serial = u32::from_be_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]);
+        Ok(Vault {
+            serial: if bytes[6] & FLAG_SERIAL != 0 {
+                Some(serial)
+            } else {
+                None
+            },
+            wrapped: WrappedKey::from_bytes(&bytes[HEADER_SIZE..])?,
+        })
+    }
+
+    /// Wraps `secret` with the response of the device and slot targeted by `conf`, and writes it to a new
+    /// file, only readable by the current user on Unix.
+    pub fn create<P: AsRef<Path>, R: CryptoRng + ?Sized>(
+        cr: &mut ChallengeResponse,
+        conf: Config,
+        path: P,
+        secret: &[u8],
+        rng: &mut R,
+    ) -> Result<Vault, ChallengeResponseError> {
+        let vault = Vault {
+            serial: conf.device.serial,
+            wrapped: wrap_key(cr, conf, secret, rng)?,
+        };
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        file.write_all(&vault.to_bytes())?;
+        Ok(vault)
+    }
+
+    /// Reads a vault file written with `create`, and decrypts its secret with the device whose serial number
+    /// is stored in the file, or the first device found if it is not.
+    pub fn unlock<P: AsRef<Path>>(
+        cr: &mut ChallengeResponse,
+        path: P,
+    ) -> Result<UnwrappedKey, ChallengeResponseError> {
+        let vault = Vault::from_bytes(&fs::read(path)?)?;
+        let device = match vault.serial {
+            Some(serial) => cr.find_device_from_serial(serial)?,
+            None => cr.find_device()?,
+        };
+        unwrap_key(cr, Config::new_from(device), &vault.wrapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Slot;
+
+    #[test]
+    fn test_vault_format() {
+        let wrapped =
+            WrappedKey::seal(Slot::Slot2, true, &[0x42; 32], &[0x24; 20], b"secret", [7; 24]).unwrap();
+        let vault = Vault {
+            serial: Some(1234567),
+            wrapped,
+        };
+        let bytes = vault.to_bytes();
+        assert_eq!(&bytes[..HEADER_SIZE], b"CRVT\x01\x01\x01\x00\x12\xd6\x87");
+        assert_eq!(Vault::from_bytes(&bytes).unwrap(), vault);
+
+        let mut bytes = bytes;
+        bytes[5] = 2;
+        assert!(Vault::from_bytes(&bytes).is_err());
+    }
+}
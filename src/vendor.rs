@@ -0,0 +1,216 @@
+//! Registration of the device families supported outside of this crate.
+//!
+//! A `VendorModule` describes the devices of a vendor: their vendor and product IDs, their quirks, and how
+//! to challenge them, if they do not implement the protocol of the YubiKeys. Once registered with
+//! `register`, usually at the start of the application, its models are found by the enumeration of all the
+//! `ChallengeResponse` instances like the built-in ones, and its quirks are consulted before each command.
+//!
+//! ```no_run
+//! use challenge_response::quirks::Quirk;
+//! use challenge_response::vendor::{self, VendorModule};
+//! use challenge_response::DeviceModel;
+//!
+//! struct Acme;
+//!
+//! impl VendorModule for Acme {
+//!     fn name(&self) -> &str {
+//!         "Acme"
+//!     }
+//!
+//!     fn models(&self) -> &'static [DeviceModel] {
+//!         &[DeviceModel {
+//!             vendor_id: 0x1234,
+//!             product_id: 0x5678,
+//!             name: "Acme Key",
+//!             slots: 2,
+//!         }]
+//!     }
+//! }
+//!
+//! vendor::register(Acme);
+//! ```
+use std::sync::RwLock;
+
+use config::Config;
+use protocol::{Command, Status};
+use quirks::{self, Quirk, SLOT_UPDATE_COMMANDS};
+use token::{HmacToken, UsbToken};
+use usb::DeviceModel;
+use ChallengeResponse;
+
+/// The support of the devices of a vendor, implemented by external crates.
+pub trait VendorModule: Send + Sync {
+    /// The name of the vendor, for display purposes.
+    fn name(&self) -> &str;
+
+    /// The models of the vendor, found by the enumeration. The vendor IDs of the models do not need to be
+    /// the one of the quirks.
+    fn models(&self) -> &'static [DeviceModel];
+
+    /// The quirks of the devices of the vendor, in addition to the built-in ones.
+    fn quirks(&self) -> &'static [Quirk] {
+        &[]
+    }
+
+    /// The slot of a device of the vendor, targeted by `conf`, as a `HmacToken`. The default challenges it
+    /// with the protocol of the YubiKeys; the devices using another protocol over the USB device of `conf`
+    /// implement it here.
+    fn token(&self, cr: ChallengeResponse, conf: Config) -> Box<dyn HmacToken> {
+        Box::new(UsbToken::new(cr, conf))
+    }
+}
+
+static MODULES: RwLock<Vec<&'static dyn VendorModule>> = RwLock::new(Vec::new());
+
+fn modules() -> Vec<&'static dyn VendorModule> {
+    MODULES.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Registers a vendor module for the whole process. Modules should only be registered once: their models
+/// would otherwise be listed several times by `registered_models`.
+pub fn register<M: VendorModule + 'static>(module: M) {
+    let module: &'static dyn VendorModule = Box::leak(Box::new(module));
+    MODULES.write().unwrap_or_else(|e| e.into_inner()).push(module);
+}
+
+/// The names of the registered vendors.
+pub fn registered_vendors() -> Vec<String> {
+    modules().iter().map(|m| m.name().to_string()).collect()
+}
+
+/// The models of all the registered vendors.
+pub fn registered_models() -> Vec<&'static DeviceModel> {
+    modules().iter().flat_map(|m| m.models().iter()).collect()
+}
+
+fn module_of(vendor_id: u16, product_id: u16) -> Option<(&'static dyn VendorModule, &'static DeviceModel)> {
+    modules().into_iter().find_map(|m| {
+        m.models()
+            .iter()
+            .find(|d| d.vendor_id == vendor_id && d.product_id == product_id)
+            .map(|d| (m, d))
+    })
+}
+
+/// The registered model with the given IDs.
+pub(crate) fn lookup(vendor_id: u16, product_id: u16) -> Option<&'static DeviceModel> {
+    module_of(vendor_id, product_id).map(|(_, model)| model)
+}
+
+fn supports_registered(vendor_id: u16, product_id: u16, status: &Status, command: u8) -> bool {
+    let firmware = (status.version_major, status.version_minor, status.version_build);
+    modules()
+        .iter()
+        .flat_map(|m| m.quirks().iter())
+        .filter(|q| q.applies(vendor_id, product_id, firmware))
+        .all(|q| !q.unsupported_commands.contains(&command))
+}
+
+/// Whether a device implements a command, according to the built-in and the registered quirks.
+pub fn supports_command(vendor_id: u16, product_id: u16, status: &Status, command: Command) -> bool {
+    quirks::supports_command(vendor_id, product_id, status, command)
+        && supports_registered(vendor_id, product_id, status, command as u8)
+}
+
+/// Whether a device can update and swap its slot configurations, according to the built-in and the
+/// registered quirks.
+pub fn supports_slot_update(vendor_id: u16, product_id: u16, status: &Status) -> bool {
+    quirks::supports_slot_update(vendor_id, product_id, status)
+        && SLOT_UPDATE_COMMANDS
+            .iter()
+            .all(|&c| supports_registered(vendor_id, product_id, status, c))
+}
+
+/// The slot targeted by `conf` as a `HmacToken`, implemented by the vendor module of the device if it is a
+/// registered model, or else with the protocol of the YubiKeys.
+pub fn open_token(cr: ChallengeResponse, conf: Config) -> Box<dyn HmacToken> {
+    match module_of(conf.device.vendor_id, conf.device.product_id) {
+        Some((module, _)) => module.token(cr, conf),
+        None => Box::new(UsbToken::new(cr, conf)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Slot;
+    use error::ChallengeResponseError;
+    use protocol::Flags;
+    use Device;
+
+    struct TestVendor;
+
+    const MODELS: [DeviceModel; 1] = [DeviceModel {
+        vendor_id: 0x1209,
+        product_id: 0xc4a1,
+        name: "Test Key",
+        slots: 1,
+    }];
+
+    const QUIRKS: [Quirk; 1] = [Quirk {
+        vendor_id: 0x1209,
+        product_id: Some(0xc4a1),
+        firmware_below: None,
+        unsupported_commands: &[Command::DeviceSerial as u8],
+    }];
+
+    impl VendorModule for TestVendor {
+        fn name(&self) -> &str {
+            "Test"
+        }
+
+        fn models(&self) -> &'static [DeviceModel] {
+            &MODELS
+        }
+
+        fn quirks(&self) -> &'static [Quirk] {
+            &QUIRKS
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let status = Status {
+            version_major: 1,
+            version_minor: 0,
+            version_build: 0,
+            pgm_seq: 0,
+            touch_level: 0,
+            flags: Flags::empty(),
+        };
+        assert!(DeviceModel::lookup(0x1209, 0xc4a1).is_none());
+        register(TestVendor);
+
+        assert_eq!(DeviceModel::lookup(0x1209, 0xc4a1), Some(&MODELS[0]));
+        assert!(registered_vendors().contains(&"Test".to_string()));
+        assert!(!supports_command(0x1209, 0xc4a1, &status, Command::DeviceSerial));
+        assert!(supports_command(0x1209, 0xc4a1, &status, Command::ChallengeHmac1));
+        assert!(supports_slot_update(0x1209, 0xc4a1, &status));
+
+        // The test key only has one slot.
+        let device = Device {
+            name: None,
+            serial: Some(42),
+            product_id: 0xc4a1,
+            vendor_id: 0x1209,
+            bus_id: 1,
+            address_id: 3,
+            port_path: None,
+            manufacturer: None,
+            release: 0x0100,
+            interfaces: Vec::new(),
+        };
+        assert!(device.supports_slot(&Slot::Slot1));
+        assert!(!device.supports_slot(&Slot::Slot2));
+        assert_eq!(
+            Slot::Slot1
+                .command_for(&device, Command::ChallengeHmac1, Command::ChallengeHmac2)
+                .unwrap(),
+            Command::ChallengeHmac1
+        );
+        assert!(matches!(
+            Slot::Slot2.command_for(&device, Command::ChallengeHmac1, Command::ChallengeHmac2),
+            Err(ChallengeResponseError::SlotNotSupported(2))
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+//! Derivation of WireGuard preshared keys from the HMAC-SHA1 response of a device, for binding the
+//! `PresharedKey` of each peer to a hardware token.
+//!
+//! Each peer is identified by a name, e.g. its public key or its host name. The challenge sent to the device
+//! is the SHA-256 hash of the name, so names of any length can be used, and the preshared key is
+//! `HKDF-SHA256(salt = challenge, ikm = response, info = "challenge-response wireguard v1")`. The keys can be
+//! regenerated at any time from the device, and are written in base64, as expected by `wg` and
+//! `wg-quick`.
+use sha2::{Digest, Sha256};
+
+use base64;
+use config::Config;
+use derive::{combine_responses, DerivedKey};
+use error::ChallengeResponseError;
+use hmacmode::Hmac;
+use ChallengeResponse;
+
+const CHALLENGE_DOMAIN: &[u8] = b"challenge-response wireguard psk\0";
+const HKDF_INFO: &[u8] = b"challenge-response wireguard v1";
+
+/// The challenge sent to the device for the peer `name`.
+pub fn peer_challenge(name: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(CHALLENGE_DOMAIN);
+    hasher.update(name.as_bytes());
+    hasher.finalize().into()
+}
+
+/// A WireGuard preshared key.
+pub struct PresharedKey(DerivedKey);
+
+impl PresharedKey {
+    /// Derives the preshared key of the peer `name` from the slot of `conf`.
+    pub fn derive(
+        cr: &mut ChallengeResponse,
+        conf: Config,
+        name: &str,
+    ) -> Result<PresharedKey, ChallengeResponseError> {
+        let challenge = peer_challenge(name);
+        let response = cr.challenge_response_hmac(&challenge, conf)?;
+        Ok(PresharedKey::from_response(name, &response))
+    }
+
+    /// Derives the preshared key of the peer `name` from a response of the device to `peer_challenge(name)`.
+    pub fn from_response(name: &str, response: &Hmac) -> PresharedKey {
+        PresharedKey(combine_responses(
+            &peer_challenge(name),
+            &[&response.0],
+            HKDF_INFO,
+        ))
+    }
+
+    /// The raw 32 bytes of the key.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The key in base64, as written after `PresharedKey =` in the WireGuard configurations.
+    pub fn to_base64(&self) -> String {
+        base64::encode(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preshared_key() {
+        let response = Hmac([7; 20]);
+        let key = PresharedKey::from_response("peer1", &response);
+        let encoded = key.to_base64();
+        assert_eq!(encoded.len(), 44);
+        assert!(encoded.ends_with('='));
+        assert_eq!(
+            encoded,
+            PresharedKey::from_response("peer1", &response).to_base64()
+        );
+        assert_ne!(
+            encoded,
+            PresharedKey::from_response("peer2", &response).to_base64()
+        );
+        assert_ne!(peer_challenge("peer1"), peer_challenge("peer2"));
+    }
+}
@@ -0,0 +1,254 @@
+//! Wrapping of file encryption keys with the HMAC-SHA1 response of a device.
+//!
+//! A random challenge is stored in the header of the wrapped key. To unwrap the key, the challenge is sent
+//! to the device again and the response is fed through HKDF-SHA256 to derive the key of an
+//! XChaCha20-Poly1305 AEAD, which protects the file encryption key.
+//!
+//! The format of a wrapped key (version 1) is the following, all integers being single bytes:
+//!
+//! | Offset      | Size | Content                                            |
+//! |-------------|------|----------------------------------------------------|
+//! | 0           | 4    | Magic bytes, `CRWK`                                |
+//! | 4           | 1    | Version, `1`                                       |
+//! | 5           | 1    | Slot, `1` or `2`                                   |
+//! | 6           | 1    | Flags, bit 0 set if the slot uses variable-length challenges |
+//! | 7           | 1    | Length `n` of the challenge, from 1 to 63          |
+//! | 8           | n    | Challenge                                          |
+//! | 8 + n       | 24   | Nonce                                              |
+//! | 32 + n      | ...  | Ciphertext of the wrapped key, followed by the 16 bytes tag |
+//!
+//! The AEAD key is `HKDF-SHA256(salt = challenge, ikm = response, info = "challenge-response wrap v1")`,
+//! and the associated data is the header, i.e. everything before the ciphertext.
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use rand_core::CryptoRng;
+use sha2::Sha256;
+
+use config::{Config, Slot};
+use error::ChallengeResponseError;
+use ChallengeResponse;
+
+const MAGIC: &[u8; 4] = b"CRWK";
+const VERSION: u8 = 1;
+const FLAG_VARIABLE: u8 = 0x01;
+const HKDF_INFO: &[u8] = b"challenge-response wrap v1";
+
+/// The size of the challenges generated when wrapping a key.
+pub const WRAP_CHALLENGE_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+const MAX_CHALLENGE_SIZE: usize = 63;
+
+/// A file encryption key, after it was unwrapped.
+pub struct UnwrappedKey(pub Vec<u8>);
+
+impl Drop for UnwrappedKey {
+    fn drop(&mut self) {
+        for i in self.0.iter_mut() {
+            *i = 0;
+        }
+    }
+}
+
+impl std::ops::Deref for UnwrappedKey {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// A key wrapped with the response of a device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WrappedKey {
+    pub slot: Slot,
+    pub variable: bool,
+    pub challenge: Vec<u8>,
+    nonce: [u8; NONCE_SIZE],
+    ciphertext: Vec<u8>,
+}
+
+impl WrappedKey {
+    fn header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(8 + self.challenge.len() + NONCE_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.push(self.slot.number());
+        header.push(if self.variable { FLAG_VARIABLE } else { 0 });
+        header.push(self.challenge.len() as u8);
+        header.extend_from_slice(&self.challenge);
+        header.extend_from_slice(&self.nonce);
+        header
+    }
+
+    /// Serializes the wrapped key in the version 1 format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header();
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    /// Parses a wrapped key serialized with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<WrappedKey, ChallengeResponseError> {
+        if bytes.len() < 8 || &bytes[..4] != MAGIC || bytes[4] != VERSION {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let slot = Slot::from_int(bytes[5] as usize).ok_or(ChallengeResponseError::InvalidFormat)?;
+        let variable = bytes[6] & FLAG_VARIABLE != 0;
+        let challenge_len = bytes[7] as usize;
+        if challenge_len == 0 || challenge_len > MAX_CHALLENGE_SIZE {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+
+        let rest = &bytes[8..];
+        if rest.len() < challenge_len + NONCE_SIZE {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let (challenge, rest) = rest.split_at(challenge_len);
+        let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+
+        let mut wrapped = WrappedKey {
+            slot,
+            variable,
+            challenge: challenge.to_vec(),
+            nonce: [0; NONCE_SIZE],
+            ciphertext: ciphertext.to_vec(),
+        };
+        wrapped.nonce.copy_from_slice(nonce);
+        Ok(wrapped)
+    }
+
+    fn cipher(&self, response: &[u8]) -> XChaCha20Poly1305 {
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(Some(&self.challenge), response)
+            .expand(HKDF_INFO, &mut key)
+            .expect("32 bytes is a valid length for HKDF-SHA256");
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        for i in key.iter_mut() {
+            *i = 0;
+        }
+        cipher
+    }
+
+    /// Wraps `key` using the device response to `challenge`.
+    pub fn seal(
+        slot: Slot,
+        variable: bool,
+        challenge: &[u8],
+        response: &[u8],
+        key: &[u8],
+        nonce: [u8; NONCE_SIZE],
+    ) -> Result<WrappedKey, ChallengeResponseError> {
+        if challenge.is_empty() || challenge.len() > MAX_CHALLENGE_SIZE {
+            return Err(ChallengeResponseError::InvalidFormat);
+        }
+        let mut wrapped = WrappedKey {
+            slot,
+            variable,
+            challenge: challenge.to_vec(),
+            nonce,
+            ciphertext: Vec::new(),
+        };
+        let aad = wrapped.header();
+        wrapped.ciphertext = wrapped
+            .cipher(response)
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: key, aad: &aad })
+            .map_err(|_| ChallengeResponseError::InvalidFormat)?;
+        Ok(wrapped)
+    }
+
+    /// Unwraps the key using the device response to `self.challenge`.
+    pub fn open(&self, response: &[u8]) -> Result<UnwrappedKey, ChallengeResponseError> {
+        let aad = self.header();
+        let key = self
+            .cipher(response)
+            .decrypt(
+                XNonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| ChallengeResponseError::DecryptionFailed)?;
+        Ok(UnwrappedKey(key))
+    }
+}
+
+/// Wraps `key` with the response of the device and slot targeted by `conf` to a new random challenge.
+pub fn wrap_key<R: CryptoRng + ?Sized>(
+    cr: &mut ChallengeResponse,
+    conf: Config,
+    key: &[u8],
+    rng: &mut R,
+) -> Result<WrappedKey, ChallengeResponseError> {
+    let mut challenge = [0; WRAP_CHALLENGE_SIZE];
+    rng.fill_bytes(&mut challenge);
+    let mut nonce = [0; NONCE_SIZE];
+    rng.fill_bytes(&mut nonce);
+
+    let slot = conf.slot.clone();
+    let variable = conf.variable;
+    let response = cr.challenge_response_hmac(&challenge, conf)?;
+    WrappedKey::seal(slot, variable, &challenge, &response, key, nonce)
+}
+
+/// Unwraps a key wrapped with `wrap_key`. The slot and challenge mode stored in the wrapped key take
+/// precedence over the ones of `conf`.
+pub fn unwrap_key(
+    cr: &mut ChallengeResponse,
+    conf: Config,
+    wrapped: &WrappedKey,
+) -> Result<UnwrappedKey, ChallengeResponseError> {
+    let conf = conf
+        .set_slot(wrapped.slot.clone())
+        .set_variable_size(wrapped.variable);
+    let response = cr.challenge_response_hmac(&wrapped.challenge, conf)?;
+    wrapped.open(&response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let challenge = [0x42; WRAP_CHALLENGE_SIZE];
+        let response = [0x24; 20];
+        let wrapped = WrappedKey::seal(
+            Slot::Slot2,
+            true,
+            &challenge,
+            &response,
+            b"file key",
+            [7; NONCE_SIZE],
+        )
+        .unwrap();
+
+        let parsed = WrappedKey::from_bytes(&wrapped.to_bytes()).unwrap();
+        assert_eq!(parsed, wrapped);
+        assert_eq!(&*parsed.open(&response).unwrap(), b"file key");
+        assert!(matches!(
+            parsed.open(&[0x25; 20]),
+            Err(ChallengeResponseError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_header_is_authenticated() {
+        let challenge = [0x42; WRAP_CHALLENGE_SIZE];
+        let response = [0x24; 20];
+        let wrapped = WrappedKey::seal(
+            Slot::Slot2,
+            true,
+            &challenge,
+            &response,
+            b"file key",
+            [7; NONCE_SIZE],
+        )
+        .unwrap();
+
+        let mut bytes = wrapped.to_bytes();
+        bytes[6] = 0;
+        let tampered = WrappedKey::from_bytes(&bytes).unwrap();
+        assert!(tampered.open(&response).is_err());
+    }
+}